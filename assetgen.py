@@ -0,0 +1,96 @@
+#!/usr/bin/env python3
+#
+# assetgen packs icons and fonts into an asset bundle (assets.bin) for
+# homer to load at runtime. Put the bundle in the configs directory (or on
+# the SD card) before running spiffsgen.py.
+#
+#   python3 assetgen.py assets configs/assets.bin
+#
+# The source directory has:
+#   icons/<name>.png  an icon, any dark or opaque pixel is set. The name
+#                     can use `_` for `:`, e.g. mdi_lightbulb.png is
+#                     "mdi:lightbulb"
+#   fonts/<name>.png  a font's glyphs side by side in one row, all the
+#                     same size
+#   fonts/<name>.txt  its glyph width, height and baseline on the first
+#                     line, and the characters in the png's order on
+#                     the second
+#
+# Needs Pillow (pip install pillow).
+
+import argparse
+import os
+import struct
+
+from PIL import Image
+
+KIND_ICON = 0
+KIND_FONT = 1
+
+
+def pack_bits(image):
+    # one bit per pixel, rows padded to whole bytes, high bit on the left
+    image = image.convert('RGBA')
+    width, height = image.size
+    out = bytearray()
+    for y in range(height):
+        row = 0
+        for x in range(width):
+            r, g, b, a = image.getpixel((x, y))
+            if a > 127 and (r + g + b) < 384:
+                row |= 1 << (7 - x % 8)
+            if x % 8 == 7:
+                out.append(row)
+                row = 0
+        if width % 8:
+            out.append(row)
+    return bytes(out)
+
+
+def icons(directory):
+    for file in sorted(os.listdir(directory)):
+        if file.endswith('.png'):
+            image = Image.open(os.path.join(directory, file))
+            name = file[:-4].replace('_', ':', 1)
+            yield KIND_ICON, name, image.size, pack_bits(image)
+
+
+def fonts(directory):
+    for file in sorted(os.listdir(directory)):
+        if file.endswith('.txt'):
+            with open(os.path.join(directory, file), encoding='utf-8') as f:
+                width, height, baseline = (int(n) for n in f.readline().split())
+                chars = f.readline().rstrip('\n').encode('utf-8')
+            image = Image.open(os.path.join(directory, file[:-4] + '.png'))
+            data = struct.pack('<BH', baseline, len(chars)) + chars + pack_bits(image)
+            yield KIND_FONT, file[:-4], (width, height), data
+
+
+def main():
+    parser = argparse.ArgumentParser(description='Pack icons and fonts into a homer asset bundle')
+    parser.add_argument('source', help='directory with icons/ and fonts/')
+    parser.add_argument('output', help='the bundle to write')
+    args = parser.parse_args()
+
+    assets = []
+    for sub, read in (('icons', icons), ('fonts', fonts)):
+        directory = os.path.join(args.source, sub)
+        if os.path.isdir(directory):
+            assets.extend(read(directory))
+
+    index_len = 6 + sum(1 + 1 + len(name.encode('utf-8')) + 2 + 2 + 4 + 4 for _, name, _, _ in assets)
+    index = bytearray(b'HMRA' + struct.pack('<H', len(assets)))
+    blobs = bytearray()
+    for kind, name, (width, height), data in assets:
+        name = name.encode('utf-8')
+        index += struct.pack('<BB', kind, len(name)) + name
+        index += struct.pack('<HHII', width, height, index_len + len(blobs), len(data))
+        blobs += data
+
+    with open(args.output, 'wb') as f:
+        f.write(index + blobs)
+    print('Packed {} assets, {} bytes'.format(len(assets), len(index) + len(blobs)))
+
+
+if __name__ == '__main__':
+    main()
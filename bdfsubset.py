@@ -0,0 +1,66 @@
+#!/usr/bin/env python3
+#
+# bdfsubset cuts a BDF font down to the characters homer will draw, so a
+# large or localized font fits on the config partition. The characters
+# are the printable ASCII ones plus any in the given files (usually the
+# configs), and --chars for ones that only come from HA.
+#
+#   python3 bdfsubset.py unifont.bdf configs/fonts/unifont.bdf configs/*.json
+#
+# U8g2 fonts are made from BDF files, so the BDF they came from is what
+# to use here.
+
+import argparse
+import sys
+
+
+def wanted_chars(paths, extra):
+    chars = set(range(0x20, 0x7F))
+    for path in paths:
+        with open(path, encoding="utf-8", errors="ignore") as f:
+            chars.update(ord(c) for c in f.read())
+    chars.update(ord(c) for c in extra)
+    return chars
+
+
+def subset(lines, chars):
+    header = []
+    glyphs = []
+    glyph = None
+    for line in lines:
+        word = line.split(" ", 1)[0].strip()
+        if glyph is not None:
+            glyph.append(line)
+            if word == "ENDCHAR":
+                code = next(
+                    (int(l.split()[1]) for l in glyph if l.startswith("ENCODING")), -1
+                )
+                if code in chars:
+                    glyphs.append(glyph)
+                glyph = None
+        elif word == "STARTCHAR":
+            glyph = [line]
+        elif word not in ("CHARS", "ENDFONT"):
+            header.append(line)
+    return header + ["CHARS %d\n" % len(glyphs)] + [l for g in glyphs for l in g] + ["ENDFONT\n"]
+
+
+def main():
+    parser = argparse.ArgumentParser(description="Subset a BDF font for homer")
+    parser.add_argument("font")
+    parser.add_argument("out")
+    parser.add_argument("texts", nargs="*", help="files with the characters to keep")
+    parser.add_argument("--chars", default="", help="more characters to keep")
+    args = parser.parse_args()
+
+    with open(args.font, encoding="latin-1") as f:
+        lines = f.readlines()
+    out = subset(lines, wanted_chars(args.texts, args.chars))
+    with open(args.out, "w", encoding="latin-1") as f:
+        f.writelines(out)
+    kept = sum(1 for l in out if l.startswith("STARTCHAR"))
+    print("%s: %d characters" % (args.out, kept), file=sys.stderr)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,81 @@
+#!/usr/bin/env python3
+#
+# sign makes the ed25519 key homer checks firmware and configs with, and
+# signs them. The signature is over the payload's SHA-256, base64 encoded.
+#
+#   python3 sign.py keygen homer.key
+#       writes the private key, and prints the public key to build with
+#       as HOMER_SIGNING_KEY
+#   python3 sign.py sign homer.key firmware.bin
+#       prints the signature, for the manifest's "signature" or the
+#       X-Homer-Signature header of an upload
+#   python3 sign.py manifest homer.key firmware.bin homer.json
+#       puts the signature into the OTA manifest
+#
+# Needs cryptography (pip install cryptography).
+
+import argparse
+import base64
+import hashlib
+import json
+
+from cryptography.hazmat.primitives import serialization
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
+
+
+def load_key(path):
+    with open(path, "rb") as f:
+        return serialization.load_pem_private_key(f.read(), password=None)
+
+
+def public_hex(key):
+    raw = key.public_key().public_bytes(
+        serialization.Encoding.Raw, serialization.PublicFormat.Raw
+    )
+    return raw.hex()
+
+
+def signature(key, path):
+    with open(path, "rb") as f:
+        digest = hashlib.sha256(f.read()).digest()
+    return base64.b64encode(key.sign(digest)).decode()
+
+
+def main():
+    parser = argparse.ArgumentParser(description="Sign homer firmware and configs")
+    commands = parser.add_subparsers(dest="command", required=True)
+    keygen = commands.add_parser("keygen")
+    keygen.add_argument("key")
+    sign = commands.add_parser("sign")
+    sign.add_argument("key")
+    sign.add_argument("file")
+    manifest = commands.add_parser("manifest")
+    manifest.add_argument("key")
+    manifest.add_argument("file")
+    manifest.add_argument("manifest")
+    args = parser.parse_args()
+
+    if args.command == "keygen":
+        key = Ed25519PrivateKey.generate()
+        with open(args.key, "wb") as f:
+            f.write(
+                key.private_bytes(
+                    serialization.Encoding.PEM,
+                    serialization.PrivateFormat.PKCS8,
+                    serialization.NoEncryption(),
+                )
+            )
+        print("HOMER_SIGNING_KEY=%s" % public_hex(key))
+    elif args.command == "sign":
+        print(signature(load_key(args.key), args.file))
+    else:
+        with open(args.manifest) as f:
+            contents = json.load(f)
+        contents["signature"] = signature(load_key(args.key), args.file)
+        with open(args.manifest, "w") as f:
+            json.dump(contents, f, indent=2)
+            f.write("\n")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,96 @@
+use serde_json::json;
+
+use crate::{
+    buttons::ButtonEvent,
+    states::Entity,
+    util::{HAAction, HAConnect},
+};
+
+// the color temperature change per long press
+const KELVIN_STEP: i64 = 250;
+
+// adjusting a light's brightness: the first two buttons dim and
+// brighten it, held they make it warmer and cooler, the third is done
+pub struct Adjust {
+    pub instance: Option<String>,
+    // where the light's state is kept
+    pub key: String,
+    pub ha_id: String,
+    step: u8,
+}
+
+pub enum AdjustResult {
+    Continue(Vec<HAAction>),
+    Done,
+}
+
+impl Adjust {
+    pub fn new(c: &HAConnect) -> Option<Adjust> {
+        match c {
+            HAConnect::Light { ha_id, step, .. } => Some(Adjust {
+                instance: c.instance().map(String::from),
+                key: c.state_key(),
+                ha_id: ha_id.clone(),
+                step: *step,
+            }),
+            _ => None,
+        }
+    }
+
+    // the entity is updated right away so presses in a row add up
+    pub fn on_button(&self, event: ButtonEvent, entity: Option<&mut Entity>) -> AdjustResult {
+        let entity = match entity {
+            Some(e) => e,
+            None => return AdjustResult::Continue(vec![]),
+        };
+        let actions = match event {
+            ButtonEvent::Press(2) => return AdjustResult::Done,
+            ButtonEvent::Press(b) => {
+                let step = self.step as i64;
+                let pct = brightness_pct(entity) as i64 + if b == 0 { -step } else { step };
+                let pct = pct.clamp(1, 100);
+                entity.state = "on".into();
+                entity
+                    .attributes
+                    .insert("brightness".into(), (pct * 255 / 100).to_string());
+                vec![self.turn_on(json!({ "brightness_pct": pct }))]
+            }
+            // lights without color temperature don't have the attribute
+            ButtonEvent::LongPress(b @ (0 | 1)) => match number(entity, "color_temp_kelvin") {
+                Some(kelvin) => {
+                    let min = number(entity, "min_color_temp_kelvin").unwrap_or(2000);
+                    let max = number(entity, "max_color_temp_kelvin").unwrap_or(6500);
+                    let step = if b == 0 { -KELVIN_STEP } else { KELVIN_STEP };
+                    let kelvin = (kelvin + step).clamp(min, max);
+                    entity
+                        .attributes
+                        .insert("color_temp_kelvin".into(), kelvin.to_string());
+                    vec![self.turn_on(json!({ "color_temp_kelvin": kelvin }))]
+                }
+                None => vec![],
+            },
+            _ => vec![],
+        };
+        AdjustResult::Continue(actions)
+    }
+
+    fn turn_on(&self, data: serde_json::Value) -> HAAction {
+        HAAction::service("light", "turn_on", &self.ha_id, data)
+    }
+}
+
+fn number(entity: &Entity, name: &str) -> Option<i64> {
+    entity
+        .attribute(name)?
+        .parse::<f64>()
+        .ok()
+        .map(|n| n.round() as i64)
+}
+
+// HA reports brightness as 0-255, and not at all when the light is off
+pub fn brightness_pct(entity: &Entity) -> u8 {
+    if entity.state != "on" {
+        return 0;
+    }
+    number(entity, "brightness").map_or(100, |b| ((b * 100 + 127) / 255) as u8)
+}
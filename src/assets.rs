@@ -0,0 +1,230 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    sync::{Arc, Mutex},
+};
+
+use embedded_graphics::{
+    image::ImageRaw,
+    mono_font::{mapping::StrGlyphMapping, DecorationDimensions, MonoFont},
+    prelude::Size,
+};
+use log::*;
+
+use crate::{files::open_file, Error, Result};
+
+// the bundle assetgen.py makes, next to the configs (or on the SD card)
+pub const ASSETS_FILE: &str = "assets.bin";
+
+const MAGIC: &[u8; 4] = b"HMRA";
+
+const KIND_ICON: u8 = 0;
+const KIND_FONT: u8 = 1;
+
+// icons are kept in memory (PSRAM if it's there) up to this many bytes,
+// the least recently used go first
+const CACHE_BYTES: usize = 32 * 1024;
+
+// where an asset is in the bundle
+struct Entry {
+    kind: u8,
+    size: Size,
+    offset: u32,
+    len: u32,
+}
+
+// a one bit per pixel image, rows padded to whole bytes and the high bit
+// on the left
+#[derive(Debug, PartialEq)]
+pub struct Bitmap {
+    pub size: Size,
+    pub bits: Vec<u8>,
+}
+
+impl Bitmap {
+    pub fn is_set(&self, x: u32, y: u32) -> bool {
+        let stride = (self.size.width + 7) / 8;
+        let byte = self.bits.get((y * stride + x / 8) as usize);
+        byte.map_or(false, |b| b & (0x80 >> (x % 8)) != 0)
+    }
+}
+
+// the bundle's index, the icons read lately and the fonts, which are
+// kept for good once they're used
+struct Assets {
+    file: File,
+    index: HashMap<String, Entry>,
+    icons: VecDeque<(String, Arc<Bitmap>)>,
+    fonts: HashMap<String, MonoFont<'static>>,
+}
+
+static ASSETS: Mutex<Option<Assets>> = Mutex::new(None);
+
+// read the bundle's index, the assets themselves are read as they're used.
+// Returns how many there are
+pub fn load_assets() -> Result<usize> {
+    let assets = Assets::open(open_file(ASSETS_FILE)?)?;
+    let count = assets.index.len();
+    if let Ok(mut loaded) = ASSETS.lock() {
+        *loaded = Some(assets);
+    }
+    Ok(count)
+}
+
+// an icon from the bundle by name, e.g. "mdi:lightbulb"
+pub fn icon(name: &str) -> Option<Arc<Bitmap>> {
+    let mut assets = ASSETS.lock().ok()?;
+    let assets = assets.as_mut()?;
+    match assets.icon(name) {
+        Ok(icon) => icon,
+        Err(e) => {
+            info!("Failed to read icon {} error {:?}", name, e);
+            None
+        }
+    }
+}
+
+// a font from the bundle by name, to use like the built in ones
+pub fn font(name: &str) -> Option<MonoFont<'static>> {
+    let mut assets = ASSETS.lock().ok()?;
+    let assets = assets.as_mut()?;
+    match assets.font(name) {
+        Ok(font) => font,
+        Err(e) => {
+            info!("Failed to read font {} error {:?}", name, e);
+            None
+        }
+    }
+}
+
+// the bundle is "HMRA", a u16 count and that many index entries (a u8
+// kind, a u8 name length, the name, u16 width and height, and u32 offset
+// and length of the asset's bytes), all little endian
+impl Assets {
+    fn open(mut file: File) -> Result<Assets> {
+        let mut magic = [0_u8; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::Config("Not an asset bundle".into()));
+        }
+        let count = read_u16(&mut file)?;
+        let mut index = HashMap::new();
+        for _ in 0..count {
+            let kind = read_u8(&mut file)?;
+            let mut name = vec![0_u8; read_u8(&mut file)? as usize];
+            file.read_exact(&mut name)?;
+            let size = Size::new(read_u16(&mut file)? as u32, read_u16(&mut file)? as u32);
+            let offset = read_u32(&mut file)?;
+            let len = read_u32(&mut file)?;
+            index.insert(
+                String::from_utf8_lossy(&name).to_string(),
+                Entry {
+                    kind,
+                    size,
+                    offset,
+                    len,
+                },
+            );
+        }
+        Ok(Assets {
+            file,
+            index,
+            icons: VecDeque::new(),
+            fonts: HashMap::new(),
+        })
+    }
+
+    fn read(&mut self, entry_name: &str, kind: u8) -> Result<Option<(Size, Vec<u8>)>> {
+        let entry = match self.index.get(entry_name) {
+            Some(e) if e.kind == kind => e,
+            _ => return Ok(None),
+        };
+        let mut data = vec![0_u8; entry.len as usize];
+        self.file.seek(SeekFrom::Start(entry.offset as u64))?;
+        self.file.read_exact(&mut data)?;
+        Ok(Some((entry.size, data)))
+    }
+
+    fn icon(&mut self, name: &str) -> Result<Option<Arc<Bitmap>>> {
+        if let Some(i) = self.icons.iter().position(|(n, _)| n == name) {
+            let cached = self.icons.remove(i).expect("cached icon");
+            let icon = cached.1.clone();
+            self.icons.push_back(cached);
+            return Ok(Some(icon));
+        }
+
+        let (size, bits) = match self.read(name, KIND_ICON)? {
+            Some(icon) => icon,
+            None => return Ok(None),
+        };
+        let icon = Arc::new(Bitmap { size, bits });
+        self.icons.push_back((name.to_string(), icon.clone()));
+        while self.icons.iter().map(|(_, i)| i.bits.len()).sum::<usize>() > CACHE_BYTES
+            && self.icons.len() > 1
+        {
+            self.icons.pop_front();
+        }
+        Ok(Some(icon))
+    }
+
+    // a font's bytes are its baseline (u8), the length of its characters
+    // (u16) and the characters, then its glyphs side by side in one bitmap
+    // row. A font lives as long as the device, the display can be drawing
+    // with it at any time
+    fn font(&mut self, name: &str) -> Result<Option<MonoFont<'static>>> {
+        if let Some(font) = self.fonts.get(name) {
+            return Ok(Some(*font));
+        }
+
+        let (size, data) = match self.read(name, KIND_FONT)? {
+            Some(font) => font,
+            None => return Ok(None),
+        };
+        if data.len() < 3 {
+            return Err(Error::Config(format!("Font {} is cut short", name)));
+        }
+        let baseline = data[0] as u32;
+        let chars_len = u16::from_le_bytes([data[1], data[2]]) as usize;
+        if data.len() < 3 + chars_len {
+            return Err(Error::Config(format!("Font {} is cut short", name)));
+        }
+        let chars = String::from_utf8(data[3..3 + chars_len].to_vec()).map_err(Error::config)?;
+        let glyphs = chars.chars().count() as u32;
+        let replacement = chars.chars().position(|c| c == '?').unwrap_or(0);
+
+        let chars: &'static str = Box::leak(chars.into_boxed_str());
+        let bits: &'static [u8] = Box::leak(data[3 + chars_len..].to_vec().into_boxed_slice());
+        let mapping: &'static StrGlyphMapping<'static> =
+            Box::leak(Box::new(StrGlyphMapping::new(chars, replacement)));
+        let font = MonoFont {
+            image: ImageRaw::new(bits, size.width * glyphs),
+            character_size: size,
+            character_spacing: 0,
+            baseline,
+            underline: DecorationDimensions::new(baseline + 2, 1),
+            strikethrough: DecorationDimensions::new(size.height / 2, 1),
+            glyph_mapping: mapping,
+        };
+        self.fonts.insert(name.to_string(), font);
+        Ok(Some(font))
+    }
+}
+
+fn read_u8(file: &mut File) -> Result<u8> {
+    let mut buf = [0_u8; 1];
+    file.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16(file: &mut File) -> Result<u16> {
+    let mut buf = [0_u8; 2];
+    file.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32(file: &mut File) -> Result<u32> {
+    let mut buf = [0_u8; 4];
+    file.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
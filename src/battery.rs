@@ -0,0 +1,105 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use esp_idf_sys::{
+    adc1_config_channel_atten, adc1_config_width, adc1_get_raw, adc_atten_t_ADC_ATTEN_DB_11,
+    adc_bits_width_t_ADC_WIDTH_BIT_12, adc_unit_t_ADC_UNIT_1, esp, esp_adc_cal_characteristics_t,
+    esp_adc_cal_characterize, esp_adc_cal_raw_to_voltage,
+};
+use log::*;
+
+use crate::{config::BatteryConfig, status::Status, Error, Result};
+
+// how often the battery is read
+const READ_INTERVAL: Duration = Duration::from_secs(60);
+// readings averaged for each, the ADC is noisy
+const SAMPLES: u32 = 16;
+// how far over the low threshold the battery has to get (e.g. charging)
+// to leave the low battery mode
+const LOW_HYSTERESIS: u8 = 5;
+
+// the battery on a divider to an ADC1 pin
+pub struct Battery {
+    config: BatteryConfig,
+    channel: u32,
+    calibration: esp_adc_cal_characteristics_t,
+}
+
+impl Battery {
+    // on the ESP32-S3 GPIO 1-10 are ADC1 channels 0-9. GPIO 1 is the
+    // buttons' ladder
+    pub fn new(config: BatteryConfig) -> Result<Battery> {
+        if !(2..=10).contains(&config.gpio) {
+            return Err(Error::Config(format!(
+                "The battery has to be on GPIO 2-10, not {}",
+                config.gpio
+            )));
+        }
+        let channel = (config.gpio - 1) as u32;
+        let mut calibration: esp_adc_cal_characteristics_t = unsafe { std::mem::zeroed() };
+        unsafe {
+            esp!(adc1_config_width(adc_bits_width_t_ADC_WIDTH_BIT_12))?;
+            esp!(adc1_config_channel_atten(
+                channel,
+                adc_atten_t_ADC_ATTEN_DB_11
+            ))?;
+            esp_adc_cal_characterize(
+                adc_unit_t_ADC_UNIT_1,
+                adc_atten_t_ADC_ATTEN_DB_11,
+                adc_bits_width_t_ADC_WIDTH_BIT_12,
+                1100,
+                &mut calibration,
+            );
+        }
+        Ok(Battery {
+            config,
+            channel,
+            calibration,
+        })
+    }
+
+    // the battery's voltage in millivolts
+    fn millivolts(&self) -> u32 {
+        let pin_mv: u32 = (0..SAMPLES)
+            .map(|_| unsafe {
+                let raw = adc1_get_raw(self.channel).max(0) as u32;
+                esp_adc_cal_raw_to_voltage(raw, &self.calibration)
+            })
+            .sum::<u32>()
+            / SAMPLES;
+        (pin_mv as f32 * self.config.divider) as u32
+    }
+
+    // a straight line from empty to full, which is close enough for a
+    // LiPo between 3.5 and 4.1V
+    fn percent(&self, mv: u32) -> u8 {
+        let volts = mv as f32 / 1000.0;
+        let range = (self.config.full - self.config.empty).max(0.01);
+        ((volts - self.config.empty) / range * 100.0).clamp(0.0, 100.0) as u8
+    }
+
+    pub fn read(&self, status: &Status) {
+        let mv = self.millivolts();
+        let percent = self.percent(mv);
+        status.battery_mv.store(mv, Ordering::Relaxed);
+        status
+            .battery_percent
+            .store(percent as i8, Ordering::Relaxed);
+
+        let was_low = status.battery_low.load(Ordering::Relaxed);
+        let low = match was_low {
+            true => percent < self.config.low_percent.saturating_add(LOW_HYSTERESIS),
+            false => percent < self.config.low_percent,
+        };
+        if low != was_low {
+            info!("Battery {}% ({}mV), low battery mode {}", percent, mv, low);
+            status.battery_low.store(low, Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn run_battery_monitor(battery: Battery, status: &Status) {
+    loop {
+        std::thread::sleep(READ_INTERVAL);
+        battery.read(status);
+    }
+}
@@ -0,0 +1,176 @@
+// the panel on the desktop: a config's home page drawn in a window, with
+// a stand-in for HA that serves the states from a file
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::PathBuf,
+    sync::mpsc::channel,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use embedded_graphics::{pixelcolor::Rgb565, prelude::*};
+use embedded_graphics_simulator::{
+    sdl2::{Keycode, Mod},
+    OutputSettingsBuilder, SimulatorDisplay, SimulatorEvent, Window,
+};
+use homer::{
+    buttons::ButtonEvent,
+    config::Config,
+    display::{draw, DrawCmd, Theme},
+    fonts::set_line_font,
+    ha::parse_frame,
+    home::{on_home_button, HomePress},
+    render::Renderer,
+    sim::MockHa,
+    states::{apply_message, keep_attributes, Optimistic, States},
+    util::HAConnect,
+    widgets::{self, WidgetState},
+};
+use log::*;
+
+// the TTGO's screen, landscape
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut args = std::env::args().skip(1);
+    let config_path = args.next().unwrap_or("configs/base.json".into());
+    let states_path = args.next().map(PathBuf::from);
+    let config = Config::load(File::open(&config_path)?)?;
+    info!("Simulating {}", config_path);
+
+    set_line_font(config.font.clone());
+    let ha_config: Vec<HAConnect> = config.items;
+    keep_attributes(
+        ha_config
+            .iter()
+            .filter_map(|c| match c {
+                HAConnect::Attribute { attribute, .. } => Some(attribute.clone()),
+                _ => None,
+            })
+            .collect(),
+    );
+    let mut states: States = HashMap::new();
+    for key in ha_config.iter().flat_map(|c| c.state_keys()) {
+        states.entry(key).or_default();
+    }
+
+    let mut display = SimulatorDisplay::<Rgb565>::new(Size::new(WIDTH, HEIGHT));
+    let mut window = Window::new("homer", &OutputSettingsBuilder::new().scale(2).build());
+    let (display_tx, display_rx) = channel::<DrawCmd>();
+    let (frames_tx, frames_rx) = channel::<String>();
+    let mut ha = MockHa::new(states_path, frames_tx);
+
+    let mut renderer = Renderer::new(&ha_config, &display_tx, &config.ha.unavailable_text);
+    let mut optimistic = Optimistic::new();
+    let mut widget_state = WidgetState::new();
+    let mut stale = true;
+    let mut theme = Theme::Day;
+    let mut last_tick = Instant::now();
+
+    display_tx.send(DrawCmd::Erase {
+        color: Rgb565::WHITE,
+    })?;
+    renderer.render(&states, stale, &widget_state);
+    // the window's opened by the first update
+    window.update(&display);
+
+    'running: loop {
+        let mut changed = false;
+
+        if let Err(e) = ha.poll() {
+            info!("Failed to read the states: {:?}", e);
+        }
+        for frame in frames_rx.try_iter() {
+            for msg in parse_frame(&frame)? {
+                let applied = apply_message(&msg, None, &mut states, &mut optimistic);
+                // the first snapshot replaces the greyed out values
+                if applied.snapshot && stale {
+                    stale = false;
+                    renderer.clear();
+                    changed = true;
+                }
+                changed |= applied.changed;
+            }
+        }
+
+        for event in window.events() {
+            let (keycode, keymod) = match event {
+                SimulatorEvent::Quit => break 'running,
+                SimulatorEvent::KeyDown {
+                    keycode, keymod, ..
+                } => (keycode, keymod),
+                _ => continue,
+            };
+            // 1, 2 and 3 are the buttons, with shift for a long press
+            let button = match keycode {
+                Keycode::Num1 => 0,
+                Keycode::Num2 => 1,
+                Keycode::Num3 => 2,
+                _ => continue,
+            };
+            let event = if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                ButtonEvent::LongPress(button)
+            } else {
+                ButtonEvent::Press(button)
+            };
+            for c in &ha_config {
+                let press = on_home_button(
+                    c,
+                    event,
+                    &mut states,
+                    &mut optimistic,
+                    config.ha.optimistic_timeout,
+                    &mut widget_state,
+                );
+                match press {
+                    HomePress::Ignored => {}
+                    HomePress::Unavailable => info!("{} unavailable", c.ha_id()),
+                    HomePress::Send(actions) => {
+                        for cmd in &actions {
+                            ha.send(&cmd.as_json(), &cmd.describe())?;
+                        }
+                        changed = true;
+                    }
+                    // only the home page is simulated
+                    page => info!("{} would open {:?}", c.ha_id(), page),
+                }
+            }
+        }
+
+        if optimistic.expire(
+            &mut states,
+            Duration::from_secs(config.ha.optimistic_timeout),
+        ) {
+            changed = true;
+        }
+        if last_tick.elapsed() >= Duration::from_secs(1) {
+            last_tick = Instant::now();
+            changed |= widget_state.tick(&ha_config, &states);
+        }
+        // a Sun item with `dark` switches to the night theme at sunset
+        let now_theme = widgets::theme(&ha_config, &states);
+        if now_theme != theme {
+            theme = now_theme;
+            display_tx.send(DrawCmd::Theme(theme))?;
+            display_tx.send(DrawCmd::Erase {
+                color: Rgb565::WHITE,
+            })?;
+            renderer.clear();
+            changed = true;
+        }
+        if changed {
+            renderer.render(&states, stale, &widget_state);
+        }
+
+        for cmd in display_rx.try_iter() {
+            draw(&mut display, cmd)?;
+        }
+        window.update(&display);
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    Ok(())
+}
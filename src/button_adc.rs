@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+use std::sync::mpsc::Sender as DisplaySender;
+
+use crossbeam::channel::Sender;
+use embedded_graphics::{
+    pixelcolor::Rgb565,
+    prelude::{Point, RgbColor},
+};
+use esp_idf_hal::{
+    adc::{attenuation, config::Config, AdcChannelDriver, AdcDriver, ADC1},
+    gpio::Gpio1,
+};
+use profont::PROFONT_24_POINT;
+
+use crate::{
+    buttons::ButtonEvent,
+    display::{DrawCmd, DrawPos},
+    watchdog::TaskWatch,
+    Error, Result,
+};
+
+// how long a button has to be held for a long press
+const LONG_PRESS: Duration = Duration::from_millis(1000);
+// how long a button has to be held to open the setup menu
+const HOLD: Duration = Duration::from_millis(4000);
+// how long a button has to be held from boot for a factory reset
+const FACTORY_RESET_HOLD: Duration = Duration::from_secs(10);
+
+// the buttons share GPIO1 through a resistor ladder, so which one is
+// down is in the reading
+fn reading_to_button(reading: u16) -> Option<u8> {
+    if reading > 700 && reading < 1000 {
+        Some(2)
+    } else if reading > 1800 && reading < 2200 {
+        Some(1)
+    } else if reading > 2300 && reading < 2600 {
+        Some(0)
+    } else {
+        None
+    }
+}
+
+// whether a button is held from boot for FACTORY_RESET_HOLD, with a
+// countdown on the screen. The buttons share one ADC pin through a
+// resistor ladder, so holding all of them reads as one and any will do.
+// Letting go cancels it
+pub fn factory_reset_held(
+    gpio1: &mut Gpio1,
+    adc1: &mut ADC1,
+    display_tx: &DisplaySender<DrawCmd>,
+) -> Result<bool> {
+    let mut adc = AdcDriver::new(adc1, &Config::new().calibration(true)).map_err(Error::display)?;
+    let mut adc_pin =
+        AdcChannelDriver::<{ attenuation::DB_11 }, Gpio1>::new(gpio1).map_err(Error::display)?;
+    if reading_to_button(adc.read(&mut adc_pin).map_err(Error::display)?).is_none() {
+        return Ok(false);
+    }
+
+    let text = |y: i32, text: String, color: Rgb565| DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(10, y)),
+        font: Some(PROFONT_24_POINT.into()),
+        text,
+        text_color: color,
+        background: Some(RgbColor::WHITE),
+    };
+    display_tx.send(DrawCmd::Erase {
+        color: Rgb565::WHITE,
+    })?;
+    display_tx.send(text(20, "Factory reset".into(), Rgb565::RED))?;
+    display_tx.send(text(160, "Let go to cancel".into(), Rgb565::BLACK))?;
+
+    let start = Instant::now();
+    let mut shown = None;
+    while start.elapsed() < FACTORY_RESET_HOLD {
+        if reading_to_button(adc.read(&mut adc_pin).map_err(Error::display)?).is_none() {
+            display_tx.send(DrawCmd::Erase {
+                color: Rgb565::WHITE,
+            })?;
+            return Ok(false);
+        }
+        let left = (FACTORY_RESET_HOLD - start.elapsed()).as_secs() + 1;
+        if shown != Some(left) {
+            shown = Some(left);
+            display_tx.send(text(
+                90,
+                format!("Keep holding {:>2}s", left),
+                Rgb565::BLACK,
+            ))?;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Ok(true)
+}
+
+pub fn button_loop(button_tx: Sender<ButtonEvent>, gpio1: Gpio1, adc1: ADC1) -> Result<()> {
+    let mut adc = AdcDriver::new(adc1, &Config::new().calibration(true)).map_err(Error::display)?;
+    let mut adc_pin =
+        AdcChannelDriver::<{ attenuation::DB_11 }, Gpio1>::new(gpio1).map_err(Error::display)?;
+
+    // 700-900 button 3
+    // 1900-2200 button 2
+    // 2300-2500 button 1
+
+    // FIXME - debounce
+
+    // the button being held, when it was pressed and the last event sent for it
+    let mut held: Option<(usize, Instant, Option<ButtonEvent>)> = None;
+    let watch = TaskWatch::new("button");
+    loop {
+        watch.feed();
+        let now = reading_to_button(adc.read(&mut adc_pin).unwrap()).map(|b| b as usize);
+
+        match (now, held) {
+            (Some(b), Some((hb, start, sent))) if b == hb => {
+                let elapsed = start.elapsed();
+                if elapsed >= HOLD && sent == Some(ButtonEvent::LongPress(b)) {
+                    held = Some((b, start, Some(ButtonEvent::Hold(b))));
+                    button_tx.send(ButtonEvent::Hold(b)).unwrap();
+                } else if elapsed >= LONG_PRESS && sent.is_none() {
+                    held = Some((b, start, Some(ButtonEvent::LongPress(b))));
+                    button_tx.send(ButtonEvent::LongPress(b)).unwrap();
+                }
+            }
+            (Some(b), _) => held = Some((b, Instant::now(), None)),
+            (None, Some((hb, _, None))) => {
+                button_tx.send(ButtonEvent::Press(hb)).unwrap();
+                held = None;
+            }
+            (None, _) => held = None,
+        }
+
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
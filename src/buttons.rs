@@ -1,58 +1,22 @@
-use std::time::Duration;
-
-use anyhow::Result;
-use crossbeam::channel::Sender;
-use esp_idf_hal::{
-    adc::{attenuation, config::Config, AdcChannelDriver, AdcDriver, ADC1},
-    gpio::Gpio1,
-};
-
-fn reading_to_button(reading: u16) -> Option<u8> {
-    if reading > 700 && reading < 1000 {
-        Some(2)
-    } else if reading > 1800 && reading < 2200 {
-        Some(1)
-    } else if reading > 2300 && reading < 2600 {
-        Some(0)
-    } else {
-        None
-    }
+// the buttons below the screen
+pub const BUTTON_COUNT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ButtonEvent {
+    // sent when the button is released before a long press
+    Press(usize),
+    // sent while the button is still held
+    LongPress(usize),
+    // sent (after the LongPress) if the button is held even longer
+    Hold(usize),
 }
 
-pub fn button_loop(button_tx: Sender<usize>, gpio1: Gpio1, adc1: ADC1) -> Result<()> {
-    let mut adc = AdcDriver::new(adc1, &Config::new().calibration(true))?;
-    let mut adc_pin = AdcChannelDriver::<{ attenuation::DB_11 }, Gpio1>::new(gpio1)?;
-
-    // 700-900 button 3
-    // 1900-2200 button 2
-    // 2300-2500 button 1
-
-    // FIXME - debounce
-
-    let mut cur = reading_to_button(adc.read(&mut adc_pin).unwrap());
-    let mut last = [false, false, false];
-    loop {
-        let now = reading_to_button(adc.read(&mut adc_pin).unwrap());
-        let mut this = [false, false, false];
-        match now {
-            Some(v) => this[v as usize] = true,
-            _ => (),
-        };
-
-        if last != this {
-            for x in 0..3 {
-                if this[x] && this[x] != last[x] {
-                    button_tx.send(x).unwrap();
-                }
-            }
-
-            last = this;
+impl ButtonEvent {
+    pub fn button(&self) -> usize {
+        match self {
+            ButtonEvent::Press(b) => *b,
+            ButtonEvent::LongPress(b) => *b,
+            ButtonEvent::Hold(b) => *b,
         }
-
-        if now != cur {
-            cur = now;
-        }
-
-        std::thread::sleep(Duration::from_millis(50));
     }
 }
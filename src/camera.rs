@@ -0,0 +1,219 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError},
+    },
+    time::{Duration, Instant},
+};
+
+use crossbeam::channel::Sender as XBSender;
+use embedded_graphics::{
+    pixelcolor::Rgb565,
+    prelude::{Point, Size},
+    primitives::Rectangle,
+};
+use jpeg_decoder::{Decoder, PixelFormat};
+use log::*;
+
+use crate::{config::HaConfig, rest::ha_get, status::Status, util::HAConnect, Error, Result};
+
+// a camera image scaled to fit its item's area, ready to draw
+pub struct Snapshot {
+    pub area: Rectangle,
+    pub pixels: Vec<Rgb565>,
+    // for the doorbell popup rather than the home page
+    pub popup: bool,
+}
+
+pub enum CameraCmd {
+    // fetch all the items' images again, e.g. when the home page is
+    // shown again
+    Refresh,
+    // fetch one image for the doorbell popup
+    Popup(Camera),
+}
+
+// a camera item, fetched from HA's camera_proxy every `refresh`
+pub struct Camera {
+    pub ha_id: String,
+    pub area: Rectangle,
+    pub refresh: Duration,
+}
+
+impl Camera {
+    pub fn from_item(c: &HAConnect) -> Option<Camera> {
+        match c {
+            HAConnect::Camera {
+                ha_id,
+                x,
+                y,
+                width,
+                height,
+                refresh,
+            } => Some(Camera {
+                ha_id: ha_id.clone(),
+                area: Rectangle::new(Point::new(*x, *y), Size::new(*width, *height)),
+                refresh: Duration::from_secs((*refresh).max(1)),
+            }),
+            _ => None,
+        }
+    }
+}
+
+// fetch the camera images as they come due, or when asked, and pass them
+// on to be drawn
+pub fn run_cameras(
+    cameras: Vec<Camera>,
+    ha_url: &str,
+    headers: &[(&str, &str)],
+    ha: &HaConfig,
+    status: &Status,
+    has_network: &AtomicBool,
+    fetch_rx: Receiver<CameraCmd>,
+    snapshot_tx: XBSender<Snapshot>,
+) {
+    let mut fetched: Vec<Option<Instant>> = cameras.iter().map(|_| None).collect();
+    loop {
+        let wait = cameras
+            .iter()
+            .zip(&fetched)
+            .map(|(camera, at)| {
+                at.map_or(Duration::ZERO, |at| {
+                    camera.refresh.saturating_sub(at.elapsed())
+                })
+            })
+            .min()
+            .unwrap_or(Duration::from_secs(60));
+        match fetch_rx.recv_timeout(wait.max(Duration::from_secs(1))) {
+            Ok(CameraCmd::Refresh) => fetched.iter_mut().for_each(|at| *at = None),
+            Ok(CameraCmd::Popup(camera)) => {
+                if !has_network.load(Ordering::Relaxed) {
+                    continue;
+                }
+                match fetch_snapshot(&camera, ha_url, headers, ha, status) {
+                    Ok(snapshot) => {
+                        let snapshot = Snapshot {
+                            popup: true,
+                            ..snapshot
+                        };
+                        if snapshot_tx.send(snapshot).is_err() {
+                            return;
+                        }
+                    }
+                    Err(e) => info!("Failed to get {} error {:?}", camera.ha_id, e),
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        if !has_network.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        for (camera, at) in cameras.iter().zip(fetched.iter_mut()) {
+            if at.map_or(false, |at| at.elapsed() < camera.refresh) {
+                continue;
+            }
+            *at = Some(Instant::now());
+            match fetch_snapshot(camera, ha_url, headers, ha, status) {
+                Ok(snapshot) => {
+                    if snapshot_tx.send(snapshot).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => info!("Failed to get {} error {:?}", camera.ha_id, e),
+            }
+        }
+    }
+}
+
+fn fetch_snapshot(
+    camera: &Camera,
+    ha_url: &str,
+    headers: &[(&str, &str)],
+    ha: &HaConfig,
+    status: &Status,
+) -> Result<Snapshot> {
+    // HA scales the image down to the item's width, a full size one is
+    // too big to fetch
+    let url = ha.rest_url(
+        ha_url,
+        &format!(
+            "camera_proxy/{}?width={}",
+            camera.ha_id, camera.area.size.width
+        ),
+    );
+    let response = ha_get(&url, headers, ha, status)?;
+    if response.status != 200 {
+        return Err(Error::Socket(format!(
+            "Request for {} yielded {}",
+            camera.ha_id, response.status
+        )));
+    }
+    decode(&response.body, &camera.area)
+}
+
+// decode a JPEG to fit in the area, keeping its shape and centered. The
+// decoder shrinks it by up to 8 times while decoding, which keeps the
+// memory down, and the rest is nearest neighbour
+pub fn decode(jpeg: &[u8], area: &Rectangle) -> Result<Snapshot> {
+    let mut decoder = Decoder::new(jpeg);
+    decoder.read_info().map_err(Error::socket)?;
+    let (width, height) = decoder
+        .scale(
+            area.size.width.min(u16::MAX as u32) as u16,
+            area.size.height.min(u16::MAX as u32) as u16,
+        )
+        .map_err(Error::socket)?;
+    let data = decoder.decode().map_err(Error::socket)?;
+    let format = match decoder.info() {
+        Some(info) => info.pixel_format,
+        None => return Err(Error::Socket("No JPEG info".into())),
+    };
+    let (width, height) = (width as u32, height as u32);
+    if width == 0 || height == 0 {
+        return Err(Error::Socket("Empty JPEG".into()));
+    }
+
+    let scale_w = area.size.width as f32 / width as f32;
+    let scale_h = area.size.height as f32 / height as f32;
+    let scale = scale_w.min(scale_h);
+    let size = Size::new(
+        ((width as f32 * scale) as u32).max(1),
+        ((height as f32 * scale) as u32).max(1),
+    );
+    let top_left = area.top_left
+        + Point::new(
+            (area.size.width - size.width) as i32 / 2,
+            (area.size.height - size.height) as i32 / 2,
+        );
+
+    let mut pixels = Vec::with_capacity((size.width * size.height) as usize);
+    for y in 0..size.height {
+        let source_y = y * height / size.height;
+        for x in 0..size.width {
+            let i = (source_y * width + x * width / size.width) as usize;
+            let (r, g, b) = match format {
+                PixelFormat::L8 => (data[i], data[i], data[i]),
+                PixelFormat::L16 => (data[i * 2], data[i * 2], data[i * 2]),
+                PixelFormat::RGB24 => (data[i * 3], data[i * 3 + 1], data[i * 3 + 2]),
+                PixelFormat::CMYK32 => {
+                    let k = data[i * 4 + 3] as u32;
+                    let channel = |c: u8| ((255 - c as u32) * (255 - k) / 255) as u8;
+                    (
+                        channel(data[i * 4]),
+                        channel(data[i * 4 + 1]),
+                        channel(data[i * 4 + 2]),
+                    )
+                }
+            };
+            pixels.push(Rgb565::new(r >> 3, g >> 2, b >> 3));
+        }
+    }
+    Ok(Snapshot {
+        area: Rectangle::new(top_left, size),
+        pixels,
+        popup: false,
+    })
+}
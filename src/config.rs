@@ -0,0 +1,718 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    net::Ipv4Addr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{settings::Settings, util::HAConnect, Error, Result};
+
+// network settings for networks without (reliable) DHCP. If `ip` is set
+// the station interface uses a fixed address, otherwise DHCP is used and
+// `dns`/`secondary_dns` (if set) override the DNS servers handed out by DHCP
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetConfig {
+    pub ip: Option<Ipv4Addr>,
+    pub gateway: Option<Ipv4Addr>,
+    pub mask: Option<u8>,
+    pub dns: Option<Ipv4Addr>,
+    pub secondary_dns: Option<Ipv4Addr>,
+    pub enterprise: Option<EnterpriseConfig>,
+    // use a wired connection instead of WiFi
+    pub ethernet: Option<EthernetConfig>,
+    // seconds without either the network or the HA websocket being healthy
+    // before the device restarts, 0 to disable
+    pub watchdog_timeout: u64,
+    // WiFi modem sleep, trading latency for power
+    pub power_save: PowerSave,
+    // only connect to this AP ("aa:bb:cc:dd:ee:ff"), for mesh networks
+    pub bssid: Option<String>,
+    // look for a better AP when the RSSI stays below this (dBm)
+    pub roam_rssi: Option<i32>,
+    // enable 802.11k/v so mesh APs can steer the device
+    pub roam_11kv: bool,
+    // the DHCP hostname, defaults to homer-<device id> (homer-<last quad>
+    // with a static IP)
+    pub hostname: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum PowerSave {
+    None,
+    // the IDF default
+    #[default]
+    Min,
+    Max,
+}
+
+impl PowerSave {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerSave::None => "none",
+            PowerSave::Min => "min",
+            PowerSave::Max => "max",
+        }
+    }
+}
+
+// trading speed for power. Performance keeps the CPU at 240MHz, balanced
+// lets it drop to 80MHz when it's idle, and power save runs it at 80MHz
+// and light sleeps whenever every task is waiting
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum PowerMode {
+    #[default]
+    Performance,
+    Balanced,
+    PowerSave,
+}
+
+impl PowerMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PowerMode::Performance => "performance",
+            PowerMode::Balanced => "balanced",
+            PowerMode::PowerSave => "power_save",
+        }
+    }
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        NetConfig {
+            ip: None,
+            gateway: None,
+            mask: None,
+            dns: None,
+            secondary_dns: None,
+            enterprise: None,
+            ethernet: None,
+            watchdog_timeout: 900,
+            power_save: PowerSave::default(),
+            bssid: None,
+            roam_rssi: None,
+            roam_11kv: false,
+            hostname: None,
+        }
+    }
+}
+
+// a W5500 SPI Ethernet module. The values are GPIO numbers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EthernetConfig {
+    pub sclk: i32,
+    pub mosi: i32,
+    pub miso: i32,
+    pub cs: i32,
+    pub int: i32,
+    #[serde(default)]
+    pub rst: Option<i32>,
+    #[serde(default = "default_ethernet_mhz")]
+    pub mhz: u32,
+}
+
+fn default_ethernet_mhz() -> u32 {
+    20
+}
+
+// WPA2-Enterprise (EAP-PEAP or EAP-TTLS) credentials. When present, the
+// WiFi password is ignored
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EnterpriseConfig {
+    pub identity: String,
+    pub username: String,
+    pub password: String,
+    // the name of a PEM file on SPIFFS used to validate the auth server
+    pub ca_cert: Option<String>,
+    // only used for EAP-TTLS
+    pub ttls_phase2: Option<TtlsPhase2>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TtlsPhase2 {
+    Eap,
+    Mschapv2,
+    Mschap,
+    Pap,
+    Chap,
+}
+
+impl NetConfig {
+    pub fn is_static(&self) -> bool {
+        self.ip.is_some()
+    }
+
+    pub fn bssid(&self) -> Result<Option<[u8; 6]>> {
+        let s = match &self.bssid {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let bytes: Vec<u8> = s
+            .split(':')
+            .map(|b| u8::from_str_radix(b, 16))
+            .collect::<Result<_, _>>()
+            .map_err(|_| Error::Config(format!("Bad BSSID {}", s)))?;
+        match bytes.try_into() {
+            Ok(bssid) => Ok(Some(bssid)),
+            Err(_) => Err(Error::Config(format!("Bad BSSID {}", s))),
+        }
+    }
+
+    // the name the device shows up as in the router's client list. The
+    // DHCP address isn't known until after the hostname is sent so the
+    // default uses the MAC based device id
+    pub fn hostname(&self, device: &str) -> String {
+        match (&self.hostname, self.ip) {
+            (Some(name), _) => name.clone(),
+            (None, Some(ip)) => format!("homer-{}", ip.octets()[3]),
+            (None, None) => format!("homer-{}", device.replace('_', "")),
+        }
+    }
+
+    // values stored in NVS win over the values in the config file
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        let ip = |key: &str| {
+            settings
+                .get_str(key)
+                .and_then(|s| s.parse::<Ipv4Addr>().ok())
+        };
+
+        if let Some(v) = ip("net_ip") {
+            self.ip = Some(v);
+        }
+        if let Some(v) = ip("net_gateway") {
+            self.gateway = Some(v);
+        }
+        if let Some(v) = settings.get_str("net_mask").and_then(|s| s.parse().ok()) {
+            self.mask = Some(v);
+        }
+        if let Some(v) = ip("net_dns") {
+            self.dns = Some(v);
+        }
+        if let Some(v) = ip("net_dns2") {
+            self.secondary_dns = Some(v);
+        }
+        if let Some(v) = settings.get_str("net_hostname") {
+            self.hostname = Some(v);
+        }
+
+        let identity = settings.get_str("eap_identity");
+        let username = settings.get_str("eap_username");
+        let password = settings.get_str("eap_password");
+        if identity.is_some() || username.is_some() || password.is_some() {
+            let eap = self.enterprise.get_or_insert_with(Default::default);
+            if let Some(v) = identity {
+                eap.identity = v;
+            }
+            if let Some(v) = username {
+                eap.username = v;
+            }
+            if let Some(v) = password {
+                eap.password = v;
+            }
+        }
+    }
+}
+
+// a WireGuard tunnel to a remote HA. Only read from NVS (keys starting
+// with `wg_`) so the private key stays out of the configs partition
+#[derive(Debug, Clone, PartialEq)]
+pub struct WireguardConfig {
+    pub private_key: String,
+    pub peer_public_key: String,
+    pub preshared_key: Option<String>,
+    // the device's address inside the tunnel, HA must be in this subnet
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub endpoint: String,
+    pub port: u16,
+    // seconds, 0 to disable
+    pub keepalive: u16,
+}
+
+impl WireguardConfig {
+    // None unless the keys, address and endpoint are all set
+    pub fn from_settings(settings: &Settings) -> Option<WireguardConfig> {
+        Some(WireguardConfig {
+            private_key: settings.get_str("wg_private_key")?,
+            peer_public_key: settings.get_str("wg_peer_key")?,
+            preshared_key: settings.get_str("wg_psk"),
+            address: settings.get_str("wg_address")?.parse().ok()?,
+            netmask: settings
+                .get_str("wg_netmask")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(Ipv4Addr::new(255, 255, 255, 0)),
+            endpoint: settings.get_str("wg_endpoint")?,
+            port: settings
+                .get_str("wg_port")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(51820),
+            keepalive: settings
+                .get_str("wg_keepalive")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(25),
+        })
+    }
+}
+
+// how to talk to Home Assistant
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HaConfig {
+    // use wss:// and https:// rather than ws:// and http://
+    pub tls: bool,
+    // the name of a PEM file on SPIFFS with the CA that signed HA's
+    // certificate. If not set, the built in certificate bundle is used
+    pub ca_cert: Option<String>,
+    // seconds between websocket pings
+    pub ping_interval: u64,
+    // seconds without any message from HA before the websocket is
+    // considered dead and reconnected
+    pub stall_timeout: u64,
+    // seconds between `homer_status` events (RSSI, etc.) fired on HA's
+    // event bus, 0 to disable
+    pub report_interval: u64,
+    // seconds between `homer_health` events, 0 to disable
+    pub health_interval: u64,
+    // an HTTP proxy for networks without direct access to HA
+    pub proxy: Option<ProxyConfig>,
+    // seconds between polls of HA's REST API while the websocket is
+    // down, 0 to disable
+    pub poll_interval: u64,
+    // seconds a button shows its new state before HA confirms it, after
+    // which it goes back. 0 to wait for HA
+    pub optimistic_timeout: u64,
+    // HA pushes the states to the web server instead of the websocket
+    pub webhook: bool,
+    // shown in place of the "unavailable" and "unknown" states
+    pub unavailable_text: String,
+    // the websocket receive buffer in bytes (512 to 16384). Bigger
+    // messages arrive in pieces that are put back together
+    pub buffer_size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProxyConfig {
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    // the local port the HA clients connect to, to be forwarded through the proxy
+    #[serde(default = "default_proxy_local_port")]
+    pub local_port: u16,
+}
+
+fn default_proxy_local_port() -> u16 {
+    8899
+}
+
+impl Default for HaConfig {
+    fn default() -> Self {
+        HaConfig {
+            tls: false,
+            ca_cert: None,
+            ping_interval: 30,
+            stall_timeout: 90,
+            report_interval: 60,
+            health_interval: 300,
+            proxy: None,
+            poll_interval: 30,
+            optimistic_timeout: 5,
+            webhook: false,
+            unavailable_text: "-".into(),
+            buffer_size: 2048,
+        }
+    }
+}
+
+impl HaConfig {
+    pub fn websocket_url(&self, host: &str) -> String {
+        let scheme = if self.tls { "wss" } else { "ws" };
+        format!("{}://{}/api/websocket", scheme, host)
+    }
+
+    pub fn rest_url(&self, host: &str, path: &str) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{}://{}/api/{}", scheme, host, path)
+    }
+
+    pub fn auth_url(&self, host: &str, path: &str) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{}://{}/auth/{}", scheme, host, path)
+    }
+}
+
+// time sync settings, for networks that block outbound NTP
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SntpConfig {
+    // NTP servers (e.g. the local router or the HA host) to use instead
+    // of pool.ntp.org
+    pub servers: Vec<String>,
+    // seconds between re-syncs
+    pub resync_interval: u64,
+    // seconds after startup without SNTP before getting the time from HA
+    pub fallback_after: u64,
+}
+
+impl Default for SntpConfig {
+    fn default() -> Self {
+        SntpConfig {
+            servers: vec![],
+            resync_interval: 3600,
+            fallback_after: 60,
+        }
+    }
+}
+
+// the HTTP server on the device
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebConfig {
+    pub enabled: bool,
+    pub port: u16,
+    // only take configs and files that are signed with the firmware's
+    // HOMER_SIGNING_KEY
+    pub signed_configs: bool,
+    // the addresses and networks (e.g. 192.168.1.0/24) that can use the
+    // web server, any when it's empty
+    pub allow: Vec<String>,
+}
+
+impl Default for WebConfig {
+    fn default() -> Self {
+        WebConfig {
+            enabled: false,
+            port: 80,
+            signed_configs: false,
+            allow: vec![],
+        }
+    }
+}
+
+// another HA instance items can use by setting `instance` to its name.
+// It uses the `ha` settings of the default instance
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HaInstance {
+    pub name: String,
+    // the host and port, like HOMER_HA_URL
+    pub url: String,
+    // the access token, NVS key ha_token_<name> wins over this
+    #[serde(default)]
+    pub token: String,
+}
+
+impl HaInstance {
+    pub fn token_key(&self) -> String {
+        format!("ha_token_{}", self.name)
+    }
+}
+
+// use HA's MQTT broker instead of the websocket. States come from HA's
+// statestream integration, commands are published as JSON
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MqttConfig {
+    // e.g. mqtt://192.168.1.10:1883
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // statestream's base_topic
+    pub state_prefix: String,
+    pub command_topic: String,
+    // publish discovery configs so the panel shows up as a device in HA
+    pub discovery: bool,
+    pub discovery_prefix: String,
+    // seconds between publishing the panel's sensor values
+    pub state_interval: u64,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        MqttConfig {
+            url: String::new(),
+            username: None,
+            password: None,
+            state_prefix: "homeassistant".into(),
+            command_topic: "homer/command".into(),
+            discovery: true,
+            discovery_prefix: "homeassistant".into(),
+            state_interval: 60,
+        }
+    }
+}
+
+// mirror the log to a syslog server or an MQTT topic
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RemoteLogConfig {
+    // host or host:port (514 by default), sent to over UDP
+    pub syslog: Option<String>,
+    pub mqtt_topic: Option<String>,
+    // the broker for mqtt_topic, the `mqtt` section's by default
+    pub mqtt_url: Option<String>,
+    // the least severe level sent: error, warn, info or debug
+    pub level: String,
+}
+
+impl Default for RemoteLogConfig {
+    fn default() -> Self {
+        RemoteLogConfig {
+            syslog: None,
+            mqtt_topic: None,
+            mqtt_url: None,
+            level: "info".into(),
+        }
+    }
+}
+
+// a debug console on a TCP port, with the web server's allow list and
+// password
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConsoleConfig {
+    pub port: u16,
+}
+
+impl Default for ConsoleConfig {
+    fn default() -> Self {
+        ConsoleConfig { port: 23 }
+    }
+}
+
+// the configuration for a device. The config file can either be
+// a list of `HAConnect` items (the original format) or an object
+// with an `items` list plus the other sections
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub network: NetConfig,
+    pub ha: HaConfig,
+    pub sntp: SntpConfig,
+    pub web: WebConfig,
+    // when set, MQTT is used rather than the websocket
+    pub mqtt: Option<MqttConfig>,
+    // HA instances besides the one built in (websocket only)
+    pub instances: Vec<HaInstance>,
+    pub doorbell: Option<DoorbellConfig>,
+    pub ota: OtaConfig,
+    // deep sleep between updates, for battery powered builds
+    pub power: Option<PowerConfig>,
+    pub display_sleep: Option<DisplaySleepConfig>,
+    pub motion: Option<MotionConfig>,
+    pub battery: Option<BatteryConfig>,
+    pub power_mode: PowerMode,
+    pub remote_log: Option<RemoteLogConfig>,
+    pub console: Option<ConsoleConfig>,
+    // the home page's font by name, from the asset bundle or
+    // fonts/<name>.bdf, rather than the built in one
+    pub font: Option<String>,
+    pub items: Vec<HAConnect>,
+}
+
+// firmware updates pulled over HTTP. The manifest is JSON with the
+// latest `version` and the `url` of its image
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OtaConfig {
+    // the "ota_url" NVS setting wins over this, no updates if neither is set
+    pub manifest_url: Option<String>,
+    // seconds between checks, 0 to only check when HA fires homer_ota
+    pub check_interval: u64,
+    // install a newer firmware as soon as it's found, otherwise only when
+    // HA asks for it
+    pub auto_install: bool,
+}
+
+impl Default for OtaConfig {
+    fn default() -> Self {
+        OtaConfig {
+            manifest_url: None,
+            check_interval: 24 * 60 * 60,
+            auto_install: true,
+        }
+    }
+}
+
+// a battery powered device (e.g. with an e-paper screen) wakes every
+// `interval` seconds or when the wake button is pressed, shows the
+// latest states and goes back into deep sleep
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PowerConfig {
+    // seconds asleep
+    pub interval: u64,
+    // seconds to stay awake once the states are up to date, and after a
+    // button press
+    pub awake_for: u64,
+    // seconds to wait for HA before going back to sleep anyway
+    pub max_awake: u64,
+    // an RTC GPIO that wakes the device when it goes low, the boot
+    // button by default
+    pub wake_gpio: Option<i32>,
+    // seconds asleep while the battery is low
+    pub low_battery_interval: u64,
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        PowerConfig {
+            interval: 15 * 60,
+            awake_for: 10,
+            max_awake: 60,
+            wake_gpio: Some(0),
+            low_battery_interval: 60 * 60,
+        }
+    }
+}
+
+// the screen goes dark and stops drawing from `start` to `end` ("HH:MM",
+// local time), or while `entity` (e.g. an input_boolean) is on
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DisplaySleepConfig {
+    pub start: Option<String>,
+    pub end: Option<String>,
+    pub entity: Option<String>,
+}
+
+// a motion sensor on a GPIO (high on motion) or an HA binary_sensor. The
+// screen dims to `idle_backlight` percent, or turns off at 0, after
+// `idle_timeout` seconds without motion or a button press
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MotionConfig {
+    pub gpio: Option<i32>,
+    pub entity: Option<String>,
+    pub idle_timeout: u64,
+    pub idle_backlight: u8,
+}
+
+impl Default for MotionConfig {
+    fn default() -> Self {
+        MotionConfig {
+            gpio: None,
+            entity: None,
+            idle_timeout: 120,
+            idle_backlight: 0,
+        }
+    }
+}
+
+// a battery read through a resistor divider on an ADC1 GPIO
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BatteryConfig {
+    pub gpio: i32,
+    // the battery's voltage over the pin's, 2 for two equal resistors
+    pub divider: f32,
+    // volts when it's empty and full
+    pub empty: f32,
+    pub full: f32,
+    // the percent below which the device connects less often
+    pub low_percent: u8,
+}
+
+impl Default for BatteryConfig {
+    fn default() -> Self {
+        BatteryConfig {
+            gpio: 10,
+            divider: 2.0,
+            empty: 3.4,
+            full: 4.15,
+            low_percent: 20,
+        }
+    }
+}
+
+// a popup when an HA event fires, e.g. the doorbell being pressed. It
+// goes away after `timeout` seconds or when a button is pressed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DoorbellConfig {
+    // the event type, e.g. one fired by an automation
+    pub event: String,
+    #[serde(default = "default_doorbell_title")]
+    pub title: String,
+    // a camera entity whose image fills the popup
+    #[serde(default)]
+    pub camera: Option<String>,
+    #[serde(default = "default_doorbell_timeout")]
+    pub timeout: u64,
+}
+
+fn default_doorbell_title() -> String {
+    "Doorbell".into()
+}
+
+fn default_doorbell_timeout() -> u64 {
+    30
+}
+
+// the largest config file that's loaded
+pub const MAX_CONFIG: usize = 64 * 1024;
+
+impl Config {
+    pub fn parse(s: &str) -> Result<Config> {
+        Config::from_reader(s.as_bytes())
+    }
+
+    // load a config file straight from the file, it's never all in
+    // memory as text
+    pub fn load(file: File) -> Result<Config> {
+        let size = file.metadata()?.len();
+        if size > MAX_CONFIG as u64 {
+            return Err(Error::Config(format!(
+                "The config is {} bytes, it can't be more than {}",
+                size, MAX_CONFIG
+            )));
+        }
+        Config::from_reader(BufReader::new(file))
+    }
+
+    // the first character says which format it is, so it's parsed in one
+    // pass rather than buffered to try each
+    pub fn from_reader(mut reader: impl BufRead) -> Result<Config> {
+        let first = loop {
+            let buf = reader.fill_buf()?;
+            match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+                Some(i) => {
+                    let first = buf[i];
+                    reader.consume(i);
+                    break first;
+                }
+                None if buf.is_empty() => return Err(Error::Config("The config is empty".into())),
+                None => {
+                    let len = buf.len();
+                    reader.consume(len);
+                }
+            }
+        };
+        match first {
+            b'[' => Ok(Config {
+                items: serde_json::from_reader(reader)?,
+                ..Default::default()
+            }),
+            // the message says where it went wrong, for whoever wrote it
+            b'{' => Ok(serde_json::from_reader(reader)?),
+            _ => Err(Error::Config(
+                "The config isn't a JSON list or object".into(),
+            )),
+        }
+    }
+
+    // the config to display when the real one can't be loaded
+    pub fn failed(msg: &str) -> Config {
+        Config {
+            items: vec![HAConnect::Text {
+                line: 0,
+                text: msg.into(),
+                color: 0,
+            }],
+            ..Default::default()
+        }
+    }
+}
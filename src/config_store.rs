@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use log::*;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{
+    config::Config,
+    files::{read_file, replace_file},
+    settings::Settings,
+    Result,
+};
+
+// the NVS key marking a config on trial: "new" once it's saved, "trying"
+// once the device has started with it. It's removed when the config has
+// lasted CONFIG_TRIAL, so finding "trying" at boot means it crashed
+pub const CONFIG_TRIAL_KEY: &str = "config_trial";
+
+// how long a new config has to run without a crash to be kept
+pub const CONFIG_TRIAL: Duration = Duration::from_secs(120);
+
+// the config a new one replaced is kept next to it with this on the end
+const PREVIOUS_SUFFIX: &str = ".prev";
+
+// the NVS settings that go in a backup. The tokens, passwords and keys
+// stay on the device, as do the address and hostname so a backup can set
+// up another device
+const BACKUP_KEYS: &[&str] = &[
+    "ota_url",
+    "sd_spi",
+    "net_gateway",
+    "net_mask",
+    "net_dns",
+    "net_dns2",
+];
+
+// a backup of a device's setup, from export_config
+#[derive(Debug, Deserialize)]
+struct Backup {
+    config: Value,
+    #[serde(default)]
+    settings: Map<String, Value>,
+}
+
+pub fn config_name(device: &str) -> String {
+    format!("{}.json", device)
+}
+
+// save a new config for the device, which it starts with on the next
+// boot, keeping the one it replaces to go back to. One that doesn't
+// parse isn't saved
+pub fn save_config(settings: &Settings, device: &str, contents: &str) -> Result<()> {
+    Config::parse(contents)?;
+    let name = config_name(device);
+    // a config still on trial isn't known to be good, the one before
+    // it is kept
+    if settings.get_str(CONFIG_TRIAL_KEY).is_none() {
+        if let Ok(current) = read_file(&name) {
+            replace_file(&previous_name(&name), current.as_bytes())?;
+        }
+    }
+    replace_file(&name, contents.as_bytes())?;
+    settings.set_str(CONFIG_TRIAL_KEY, "new")?;
+    info!("Saved a new config for {}", device);
+    Ok(())
+}
+
+// called at boot before the config is read, returns true if the config
+// on trial crashed the device and was rolled back
+pub fn start_config_trial(settings: &Settings, device: &str) -> bool {
+    match settings.get_str(CONFIG_TRIAL_KEY).as_deref() {
+        Some("trying") => {
+            info!("The new config didn't last, rolling back");
+            roll_back_config(settings, device)
+        }
+        Some(_) => {
+            if let Err(e) = settings.set_str(CONFIG_TRIAL_KEY, "trying") {
+                info!("Failed to mark the config on trial error {:?}", e);
+            }
+            false
+        }
+        None => false,
+    }
+}
+
+// the config on trial didn't parse or crashed the device, go back to the
+// one before it. Returns true if there was one to go back to
+pub fn roll_back_config(settings: &Settings, device: &str) -> bool {
+    if settings.get_str(CONFIG_TRIAL_KEY).is_none() {
+        return false;
+    }
+    let _ = settings.remove(CONFIG_TRIAL_KEY);
+    let name = config_name(device);
+    match read_file(&previous_name(&name))
+        .and_then(|previous| replace_file(&name, previous.as_bytes()))
+    {
+        Ok(()) => true,
+        Err(e) => {
+            info!("Failed to roll back the config error {:?}", e);
+            false
+        }
+    }
+}
+
+// the config on trial has lasted, it's the one to go back to from now on
+pub fn keep_config(settings: &Settings) {
+    if settings.get_str(CONFIG_TRIAL_KEY).is_some() {
+        info!("Keeping the new config");
+        let _ = settings.remove(CONFIG_TRIAL_KEY);
+    }
+}
+
+// the device's config and settings in one, to restore later or to set up
+// another device the same way
+pub fn export_config(settings: &Settings, device: &str) -> Result<Value> {
+    let config = read_file(&config_name(device)).or_else(|_| read_file("base.json"))?;
+    let mut saved = Map::new();
+    for key in BACKUP_KEYS {
+        if let Some(value) = settings.get_str(key) {
+            saved.insert(key.to_string(), json!(value));
+        }
+    }
+    Ok(json!({
+        "device": device,
+        "firmware": env!("CARGO_PKG_VERSION"),
+        "config": serde_json::from_str::<Value>(&config)?,
+        "settings": saved,
+    }))
+}
+
+// restore a backup, possibly from another device. The config is saved as
+// this device's, on trial like any new one, and takes effect on the next
+// boot
+pub fn import_config(settings: &Settings, device: &str, backup: &[u8]) -> Result<()> {
+    let backup: Backup = serde_json::from_slice(backup)?;
+    save_config(settings, device, &backup.config.to_string())?;
+    for (key, value) in &backup.settings {
+        match value.as_str() {
+            Some(value) if BACKUP_KEYS.contains(&key.as_str()) => settings.set_str(key, value)?,
+            _ => info!("Not restoring setting {}", key),
+        }
+    }
+    Ok(())
+}
+
+fn previous_name(name: &str) -> String {
+    format!("{}{}", name, PREVIOUS_SUFFIX)
+}
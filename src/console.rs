@@ -0,0 +1,259 @@
+use std::{
+    io::{BufReader, Read, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crossbeam::channel::{bounded, Sender};
+use log::*;
+
+use crate::{
+    buttons::{ButtonEvent, BUTTON_COUNT},
+    config::{Config, ConsoleConfig},
+    config_store::config_name,
+    files::read_file,
+    flashlog::{flush_log, recent_log},
+    memory::{heap_info, stack_marks},
+    webauth::WebAuth,
+    Error, Result,
+};
+
+// a client that's quiet this long is dropped, so another can connect
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+// how long the main loop has to answer
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const MAX_LINE: usize = 200;
+
+// the telnet commands that come up in option negotiation
+const IAC: u8 = 255;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DONT: u8 = 254;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const ECHO: u8 = 1;
+
+const HELP: &str = "states          the states the panel has\r
+redraw          draw the page again\r
+press <n>       press button n (0 to 2)\r
+long <n>        long press button n\r
+reload-config   restart with the config on flash, if it parses\r
+heap            free heap and stacks\r
+log             the last lines of the log\r
+reboot          restart\r
+quit            disconnect\r
+";
+
+// what the console asks of the main loop
+pub enum ConsoleCmd {
+    // the states, a "key = value" line each
+    States(Sender<Vec<String>>),
+    // draw the current page from scratch
+    Redraw,
+}
+
+// a debug console on a TCP port, for telnet or nc. It takes the web
+// server's allow list and password
+pub fn run_console(
+    config: ConsoleConfig,
+    auth: WebAuth,
+    device: String,
+    console_tx: Sender<ConsoleCmd>,
+    button_tx: Sender<ButtonEvent>,
+    has_network: &AtomicBool,
+) -> Result<()> {
+    // wait until there's a network stack
+    while !has_network.load(Ordering::Relaxed) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    let listener = TcpListener::bind(("0.0.0.0", config.port)).map_err(Error::socket)?;
+    info!("Console on port {}", config.port);
+    // one client at a time, the others wait
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                info!("Console accept failed error {:?}", e);
+                continue;
+            }
+        };
+        let peer = stream.peer_addr().ok().and_then(|addr| match addr.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+        });
+        if !auth.allows(peer) {
+            info!("Console refused {:?}", peer);
+            continue;
+        }
+        info!("Console opened by {:?}", peer);
+        match serve(stream, &auth, &device, &console_tx, &button_tx) {
+            Ok(()) => info!("Console closed"),
+            Err(e) => info!("Console closed error {:?}", e),
+        }
+    }
+    Ok(())
+}
+
+fn serve(
+    stream: TcpStream,
+    auth: &WebAuth,
+    device: &str,
+    console_tx: &Sender<ConsoleCmd>,
+    button_tx: &Sender<ButtonEvent>,
+) -> Result<()> {
+    stream
+        .set_read_timeout(Some(IDLE_TIMEOUT))
+        .map_err(Error::socket)?;
+    let mut out = stream.try_clone().map_err(Error::socket)?;
+    let mut input = BufReader::new(stream);
+
+    if auth.has_password() {
+        // telnet stops echoing the password when the console says it echoes
+        out.write_all(b"Password: ").map_err(Error::socket)?;
+        out.write_all(&[IAC, WILL, ECHO]).map_err(Error::socket)?;
+        let password = read_line(&mut input)?.unwrap_or_default();
+        out.write_all(&[IAC, WONT, ECHO]).map_err(Error::socket)?;
+        out.write_all(b"\r\n").map_err(Error::socket)?;
+        if !auth.password_ok(&password) {
+            info!("Wrong console password");
+            out.write_all(b"Wrong password\r\n")
+                .map_err(Error::socket)?;
+            return Ok(());
+        }
+    }
+    write!(
+        out,
+        "homer {} {}, help for the commands\r\n",
+        env!("CARGO_PKG_VERSION"),
+        device
+    )
+    .map_err(Error::socket)?;
+
+    loop {
+        out.write_all(b"> ").map_err(Error::socket)?;
+        let line = match read_line(&mut input)? {
+            Some(line) => line,
+            None => return Ok(()),
+        };
+        let mut words = line.split_whitespace();
+        let reply = match (words.next(), words.next()) {
+            (None, _) => continue,
+            (Some("help"), _) => HELP.to_string(),
+            (Some("states"), _) => {
+                let (tx, rx) = bounded(1);
+                console_tx
+                    .send(ConsoleCmd::States(tx))
+                    .map_err(Error::display)?;
+                lines(rx.recv_timeout(REPLY_TIMEOUT).map_err(Error::display)?)
+            }
+            (Some("redraw"), _) => {
+                console_tx
+                    .send(ConsoleCmd::Redraw)
+                    .map_err(Error::display)?;
+                "ok\r\n".to_string()
+            }
+            (Some(kind @ ("press" | "long")), button) => {
+                match button.and_then(|b| b.parse::<usize>().ok()) {
+                    Some(b) if b < BUTTON_COUNT => {
+                        button_tx
+                            .send(if kind == "press" {
+                                ButtonEvent::Press(b)
+                            } else {
+                                ButtonEvent::LongPress(b)
+                            })
+                            .map_err(Error::display)?;
+                        "ok\r\n".to_string()
+                    }
+                    _ => format!("{} takes a button from 0 to {}\r\n", kind, BUTTON_COUNT - 1),
+                }
+            }
+            (Some("reload-config"), _) => match check_config(device) {
+                Ok(()) => {
+                    out.write_all(b"ok, restarting\r\n")
+                        .map_err(Error::socket)?;
+                    info!("Restarting for the config from the console");
+                    restart();
+                }
+                Err(e) => format!("{}\r\n", e),
+            },
+            (Some("heap"), _) => {
+                let heap = heap_info();
+                let mut text = format!(
+                    "free {} min {} largest block {}\r\n",
+                    heap.free, heap.min_free, heap.largest_block
+                );
+                for (name, free) in stack_marks() {
+                    text += &format!("{} stack free {}\r\n", name, free);
+                }
+                text
+            }
+            (Some("log"), _) => lines(recent_log()),
+            (Some("reboot"), _) => {
+                out.write_all(b"restarting\r\n").map_err(Error::socket)?;
+                info!("Restarting from the console");
+                restart();
+            }
+            (Some("quit" | "exit"), _) => return Ok(()),
+            (Some(command), _) => format!("Unknown command {}, try help\r\n", command),
+        };
+        out.write_all(reply.as_bytes()).map_err(Error::socket)?;
+    }
+}
+
+fn lines(lines: Vec<String>) -> String {
+    lines.iter().map(|l| format!("{}\r\n", l)).collect()
+}
+
+// the device's config (or base.json) parses, so it's safe to restart with
+fn check_config(device: &str) -> Result<()> {
+    let config = read_file(&config_name(device)).or_else(|_| read_file("base.json"))?;
+    Config::parse(&config)?;
+    Ok(())
+}
+
+fn restart() -> ! {
+    flush_log();
+    std::thread::sleep(Duration::from_secs(1));
+    esp_idf_hal::reset::restart();
+}
+
+fn read_byte(input: &mut impl Read) -> Result<Option<u8>> {
+    let mut byte = [0_u8; 1];
+    Ok(match input.read(&mut byte).map_err(Error::socket)? {
+        0 => None,
+        _ => Some(byte[0]),
+    })
+}
+
+// a line without telnet's option negotiation, None once the client's gone
+fn read_line(input: &mut impl Read) -> Result<Option<String>> {
+    let mut line = vec![];
+    loop {
+        let byte = match read_byte(input)? {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+        match byte {
+            IAC => match read_byte(input)? {
+                Some(IAC) => line.push(IAC),
+                Some(WILL..=DONT) => {
+                    read_byte(input)?;
+                }
+                Some(SB) => while !matches!(read_byte(input)?, Some(SE) | None) {},
+                _ => {}
+            },
+            b'\n' => break,
+            // backspace and delete, for clients that send them
+            8 | 127 => {
+                line.pop();
+            }
+            b if b >= b' ' && line.len() < MAX_LINE => line.push(b),
+            _ => {}
+        }
+    }
+    Ok(Some(String::from_utf8_lossy(&line).trim().to_string()))
+}
@@ -0,0 +1,142 @@
+use std::{
+    panic::PanicInfo,
+    sync::{mpsc::Sender, Mutex},
+    time::Duration,
+};
+
+use embedded_graphics::{
+    pixelcolor::Rgb565,
+    prelude::{Point, RgbColor},
+};
+use esp_idf_sys::{esp_backtrace_frame_t, esp_backtrace_get_next_frame, esp_backtrace_get_start};
+use profont::PROFONT_24_POINT;
+
+use crate::{
+    display::{DrawCmd, DrawPos},
+    settings::Settings,
+};
+
+// the NVS key holding a summary of the last panic: the message, where it
+// was and the backtrace's addresses (for addr2line)
+pub const PANIC_KEY: &str = "panic";
+
+// how long the panic stays on the screen before the restart
+const REBOOT_DELAY: Duration = Duration::from_secs(10);
+
+// how many frames of the backtrace are kept
+const FRAMES: usize = 8;
+
+// the message is wrapped to this many characters a line
+const LINE_CHARS: usize = 30;
+
+struct Reporter {
+    display_tx: Sender<DrawCmd>,
+    settings: Settings,
+}
+
+static REPORTER: Mutex<Option<Reporter>> = Mutex::new(None);
+
+// on a panic, after the hooks before this one (the log) have had it,
+// save a summary in NVS and put the panic on the screen in red for a
+// while before the restart, rather than leaving the screen frozen
+pub fn install_panic_handler(display_tx: Sender<DrawCmd>, settings: Settings) {
+    if let Ok(mut reporter) = REPORTER.lock() {
+        *reporter = Some(Reporter {
+            display_tx,
+            settings,
+        });
+    }
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        report(info);
+    }));
+}
+
+fn report(info: &PanicInfo) {
+    let message = match info.payload().downcast_ref::<&str>() {
+        Some(s) => s.to_string(),
+        None => match info.payload().downcast_ref::<String>() {
+            Some(s) => s.clone(),
+            None => "panic".to_string(),
+        },
+    };
+    let location = info.location().map_or("unknown".to_string(), |l| {
+        format!("{}:{}", l.file(), l.line())
+    });
+    let backtrace = backtrace()
+        .iter()
+        .map(|pc| format!("0x{:08x}", pc))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    // the panic could have come from holding it, so it's not waited for
+    let reporter = match REPORTER.try_lock() {
+        Ok(reporter) => reporter,
+        Err(_) => return,
+    };
+    let reporter = match reporter.as_ref() {
+        Some(r) => r,
+        None => return,
+    };
+    let _ = reporter.settings.set_str(
+        PANIC_KEY,
+        &format!("{} at {} backtrace {}", message, location, backtrace),
+    );
+
+    let tx = &reporter.display_tx;
+    let text = |y: i32, font, text: String| DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(10, y)),
+        font,
+        text,
+        text_color: RgbColor::WHITE,
+        background: None,
+    };
+    let _ = tx.send(DrawCmd::Erase { color: Rgb565::RED });
+    let _ = tx.send(text(24, Some(PROFONT_24_POINT.into()), "Crashed".into()));
+    let chars: Vec<char> = message.chars().collect();
+    for (i, line) in chars.chunks(LINE_CHARS).take(5).enumerate() {
+        let _ = tx.send(text(60 + 22 * i as i32, None, line.iter().collect()));
+    }
+    let file = location.rsplit('/').next().unwrap_or(&location);
+    let _ = tx.send(text(
+        200,
+        None,
+        format!("at {}", file).chars().take(LINE_CHARS).collect(),
+    ));
+    let _ = tx.send(text(
+        225,
+        None,
+        format!("(reboot in {}s)", REBOOT_DELAY.as_secs()),
+    ));
+    drop(reporter);
+    std::thread::sleep(REBOOT_DELAY);
+}
+
+// the return addresses of the panicking thread's frames, as the IDF's
+// panic handler would print them
+fn backtrace() -> Vec<u32> {
+    let mut frame: esp_backtrace_frame_t = unsafe { std::mem::zeroed() };
+    unsafe {
+        esp_backtrace_get_start(&mut frame.pc, &mut frame.sp, &mut frame.next_pc);
+    }
+    let mut pcs = vec![stack_pc(frame.pc)];
+    while pcs.len() < FRAMES && frame.next_pc != 0 {
+        if !unsafe { esp_backtrace_get_next_frame(&mut frame) } {
+            break;
+        }
+        pcs.push(stack_pc(frame.pc));
+    }
+    pcs
+}
+
+// what esp_cpu_process_stack_pc does: the call's address from the
+// windowed return address
+fn stack_pc(pc: u32) -> u32 {
+    let pc = if pc & 0x8000_0000 != 0 {
+        (pc & 0x3fff_ffff) | 0x4000_0000
+    } else {
+        pc
+    };
+    pc.saturating_sub(3)
+}
@@ -0,0 +1,268 @@
+use serde_json::{json, Value};
+use std::sync::atomic::Ordering;
+
+use crate::{
+    buttons::BUTTON_COUNT, health::problems, memory::heap_info, ota::update_state,
+    stats::stats_totals, status::Status,
+};
+
+// the MQTT topics of the panel itself, under homer/<node id>
+pub struct Node {
+    pub id: String,
+}
+
+impl Node {
+    // discovery only allows letters, digits, '_' and '-' in ids
+    pub fn new(client_id: &str) -> Node {
+        Node {
+            id: client_id
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || c == '-' {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    fn topic(&self, leaf: &str) -> String {
+        format!("homer/{}/{}", self.id, leaf)
+    }
+
+    pub fn availability_topic(&self) -> String {
+        self.topic("availability")
+    }
+
+    pub fn state_topic(&self) -> String {
+        self.topic("state")
+    }
+
+    pub fn backlight_topic(&self) -> String {
+        self.topic("backlight/set")
+    }
+
+    pub fn update_topic(&self) -> String {
+        self.topic("update")
+    }
+
+    pub fn install_topic(&self) -> String {
+        self.topic("update/install")
+    }
+
+    pub fn button_topic(&self, button: usize) -> String {
+        self.topic(&format!("button/{}", button))
+    }
+
+    fn device(&self) -> Value {
+        json!({
+            "identifiers": [self.id],
+            "name": self.id,
+            "manufacturer": "homer",
+            "model": "ESP32-S3 panel",
+        })
+    }
+
+    // the retained config messages that make the panel show up as a
+    // device in HA, as (topic, payload) pairs
+    pub fn discovery_messages(&self, prefix: &str, status: &Status) -> Vec<(String, String)> {
+        let mut messages = vec![];
+        let mut add = |component: &str, object: &str, mut config: Value| {
+            config["device"] = self.device();
+            config["unique_id"] = json!(format!("{}_{}", self.id, object));
+            messages.push((
+                format!("{}/{}/{}/{}/config", prefix, component, self.id, object),
+                config.to_string(),
+            ));
+        };
+
+        let sensor = |name: &str, field: &str, unit: &str, class: &str| {
+            json!({
+                "name": name,
+                "state_topic": self.state_topic(),
+                "availability_topic": self.availability_topic(),
+                "value_template": format!("{{{{ value_json.{} }}}}", field),
+                "unit_of_measurement": unit,
+                "device_class": class,
+                "entity_category": "diagnostic",
+            })
+        };
+        add(
+            "sensor",
+            "rssi",
+            sensor("RSSI", "rssi", "dBm", "signal_strength"),
+        );
+        add(
+            "sensor",
+            "uptime",
+            sensor("Uptime", "uptime", "s", "duration"),
+        );
+        add(
+            "sensor",
+            "free_heap",
+            sensor("Free heap", "free_heap", "B", "data_size"),
+        );
+        add(
+            "sensor",
+            "largest_free_block",
+            sensor("Largest free block", "largest_free_block", "B", "data_size"),
+        );
+        add(
+            "sensor",
+            "ws_reconnects",
+            json!({
+                "name": "Reconnects",
+                "state_topic": self.state_topic(),
+                "availability_topic": self.availability_topic(),
+                "value_template": "{{ value_json.ws_reconnects }}",
+                "state_class": "total_increasing",
+                "entity_category": "diagnostic",
+            }),
+        );
+        // ok or problem, with what the problems are as an attribute
+        add(
+            "sensor",
+            "health",
+            json!({
+                "name": "Health",
+                "state_topic": self.state_topic(),
+                "availability_topic": self.availability_topic(),
+                "value_template": "{{ value_json.health }}",
+                "json_attributes_topic": self.state_topic(),
+                "json_attributes_template": "{{ {'problems': value_json.problems} | tojson }}",
+                "entity_category": "diagnostic",
+            }),
+        );
+        add(
+            "sensor",
+            "boot_count",
+            json!({
+                "name": "Boots",
+                "state_topic": self.state_topic(),
+                "availability_topic": self.availability_topic(),
+                "value_template": "{{ value_json.boot_count }}",
+                "state_class": "total_increasing",
+                "entity_category": "diagnostic",
+            }),
+        );
+        add(
+            "sensor",
+            "last_crash",
+            json!({
+                "name": "Last crash",
+                "state_topic": self.state_topic(),
+                "availability_topic": self.availability_topic(),
+                "value_template": "{{ value_json.last_crash[:250] if value_json.last_crash else 'none' }}",
+                "entity_category": "diagnostic",
+            }),
+        );
+        add(
+            "sensor",
+            "reset_reason",
+            json!({
+                "name": "Reset reason",
+                "state_topic": self.state_topic(),
+                "availability_topic": self.availability_topic(),
+                "value_template": "{{ value_json.reset_reason }}",
+                "json_attributes_topic": self.state_topic(),
+                "json_attributes_template": "{{ {'brownouts': value_json.brownouts} | tojson }}",
+                "entity_category": "diagnostic",
+            }),
+        );
+
+        if status.battery().is_some() {
+            let mut battery = sensor("Battery", "battery", "%", "battery");
+            // the charge isn't just a diagnostic
+            if let Some(battery) = battery.as_object_mut() {
+                battery.remove("entity_category");
+            }
+            add("sensor", "battery", battery);
+            add(
+                "sensor",
+                "battery_voltage",
+                sensor("Battery voltage", "battery_voltage", "V", "voltage"),
+            );
+        }
+
+        add(
+            "number",
+            "backlight",
+            json!({
+                "name": "Backlight",
+                "state_topic": self.state_topic(),
+                "availability_topic": self.availability_topic(),
+                "value_template": "{{ value_json.backlight }}",
+                "command_topic": self.backlight_topic(),
+                "min": 0,
+                "max": 100,
+                "unit_of_measurement": "%",
+            }),
+        );
+
+        // the firmware, with an Install button when there's a newer one
+        add(
+            "update",
+            "firmware",
+            json!({
+                "name": "Firmware",
+                "state_topic": self.update_topic(),
+                "availability_topic": self.availability_topic(),
+                "command_topic": self.install_topic(),
+                "payload_install": "install",
+                "device_class": "firmware",
+                "entity_category": "config",
+            }),
+        );
+
+        for button in 0..BUTTON_COUNT {
+            for kind in ["short_press", "long_press"] {
+                add(
+                    "device_automation",
+                    &format!("button_{}_{}", button + 1, kind),
+                    json!({
+                        "automation_type": "trigger",
+                        "topic": self.button_topic(button),
+                        "payload": kind,
+                        "type": format!("button_{}", kind),
+                        "subtype": format!("button_{}", button + 1),
+                    }),
+                );
+            }
+        }
+        messages
+    }
+}
+
+// the firmware update entity's state, published to the update topic
+pub fn update_message(status: &Status) -> String {
+    update_state(status).to_string()
+}
+
+// the values of the panel's sensors, published to the state topic
+pub fn state_message(status: &Status) -> String {
+    let uptime = unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000;
+    let heap = heap_info();
+    let totals = stats_totals(status);
+    let problems = problems(status);
+    json!({
+        "rssi": status.rssi.load(Ordering::Relaxed),
+        "uptime": uptime,
+        "free_heap": heap.free,
+        "largest_free_block": heap.largest_block,
+        "backlight": status.backlight.load(Ordering::Relaxed),
+        "boot_count": totals.boot_count,
+        "uptime_total": totals.uptime,
+        "wifi_reconnects": totals.wifi_reconnects,
+        "ws_reconnects": totals.ws_reconnects,
+        "last_crash": totals.last_crash,
+        "reset_reason": totals.reset_reason,
+        "brownouts": totals.brownouts,
+        "health": if problems.is_empty() { "ok" } else { "problem" },
+        "problems": problems,
+        "battery": status.battery(),
+        "battery_voltage": status.battery().map(|_| status.battery_mv.load(Ordering::Relaxed) as f32 / 1000.0),
+    })
+    .to_string()
+}
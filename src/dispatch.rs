@@ -0,0 +1,106 @@
+use std::sync::Mutex;
+
+use crossbeam::channel::{bounded, Receiver as XBReceiver, Sender as XBSender};
+use log::*;
+
+use crate::{
+    ha::{FromHa, HaMessage},
+    notify::NOTIFY_EVENT,
+    status::Status,
+    wifi::queue_for_main,
+};
+
+// which HA messages a subscriber gets
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    All,
+    // state changes (and get_states snapshots) of entities whose id
+    // starts with the prefix, e.g. "media_player."
+    EntityPrefix(String),
+    // events of one type, e.g. "homer_notify"
+    EventType(String),
+    // the results of commands
+    Results,
+    // homer_notify events and updates of HA's persistent notifications
+    Notifications,
+}
+
+impl Filter {
+    pub fn matches(&self, msg: &HaMessage) -> bool {
+        match (self, msg) {
+            (Filter::All, _) => true,
+            (Filter::EntityPrefix(prefix), HaMessage::Event { event, .. }) => event
+                .state_changed()
+                .map_or(false, |c| c.entity_id.starts_with(prefix.as_str())),
+            (Filter::EntityPrefix(prefix), HaMessage::Result(result)) => {
+                result.states().map_or(false, |states| {
+                    states
+                        .iter()
+                        .any(|s| s.entity_id.starts_with(prefix.as_str()))
+                })
+            }
+            (Filter::EventType(event_type), HaMessage::Event { event, .. }) => {
+                event.event_type == *event_type
+            }
+            (Filter::Results, HaMessage::Result(_)) => true,
+            (Filter::Notifications, HaMessage::Event { event, .. }) => {
+                event.event_type == NOTIFY_EVENT || event.notifications.is_some()
+            }
+            _ => false,
+        }
+    }
+}
+
+// fans the messages from the HA threads (websocket, MQTT, webhook, web
+// server) out to the parts of the device interested in them, so a new
+// one only has to subscribe rather than be wired into the main loop
+pub struct Dispatcher {
+    subscribers: Mutex<Vec<(Filter, XBSender<FromHa>)>>,
+    status: &'static Status,
+}
+
+impl Dispatcher {
+    pub fn new(status: &'static Status) -> Dispatcher {
+        Dispatcher {
+            subscribers: Mutex::new(vec![]),
+            status,
+        }
+    }
+
+    // the returned channel holds up to `capacity` messages, more are
+    // dropped (and counted) if the subscriber falls behind
+    pub fn subscribe(&self, filter: Filter, capacity: usize) -> XBReceiver<FromHa> {
+        let (tx, rx) = bounded(capacity);
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push((filter, tx));
+        }
+        rx
+    }
+
+    // pass on the messages until the HA threads have all gone
+    pub fn run(&self, ha_rx: XBReceiver<FromHa>) {
+        for msg in ha_rx.iter() {
+            // the senders are copied out of the lock, so waiting for room
+            // at a subscriber that's behind doesn't hold up subscribe
+            let senders: Vec<XBSender<FromHa>> = match self.subscribers.lock() {
+                Ok(subscribers) => subscribers
+                    .iter()
+                    .filter(|(filter, _)| filter.matches(&msg.msg))
+                    .map(|(_, tx)| tx.clone())
+                    .collect(),
+                Err(_) => continue,
+            };
+            let gone: Vec<XBSender<FromHa>> = senders
+                .into_iter()
+                .filter(|tx| !queue_for_main(tx, msg.clone(), self.status))
+                .collect();
+            // subscribers that have gone are dropped
+            if !gone.is_empty() {
+                if let Ok(mut subscribers) = self.subscribers.lock() {
+                    subscribers.retain(|(_, tx)| !gone.iter().any(|g| g.same_channel(tx)));
+                }
+            }
+        }
+        info!("No more HA messages to dispatch");
+    }
+}
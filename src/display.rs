@@ -1,16 +1,22 @@
-use std::sync::mpsc::Receiver;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
-use anyhow::Result;
-use display_interface_spi::SPIInterfaceNoCS;
 use embedded_graphics::{
+    draw_target::DrawTargetExt,
     mono_font::{ascii::FONT_10X20, MonoFont, MonoTextStyle},
     pixelcolor::Rgb565,
     prelude::*,
-    primitives::Rectangle,
+    primitives::{Circle, Line, Polyline, PrimitiveStyle, Rectangle, Triangle},
     text::Text,
 };
-use esp_idf_hal::{delay, gpio, prelude::*, spi};
-use log::info;
+
+use crate::{
+    assets::{self, Bitmap},
+    fonts::{with_bdf_font, Font},
+    Error, Result,
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum DrawPos {
@@ -23,16 +29,16 @@ impl DrawPos {
     pub fn upper_left(&self) -> Point {
         match self {
             DrawPos::Button(b) => Point::new(20 + 98 * (*b as i32), 220),
-            DrawPos::Pos(p) => p.clone(),
-            DrawPos::Box(r) => r.top_left.clone(),
+            DrawPos::Pos(p) => *p,
+            DrawPos::Box(r) => r.top_left,
         }
     }
 
     pub fn compute_bounding_box(&self, d: Option<&Rectangle>) -> Rectangle {
         let d: Rectangle = match d {
-            Some(r) => r.clone(),
+            Some(r) => *r,
             None => match self {
-                DrawPos::Box(b) => b.clone(),
+                DrawPos::Box(b) => *b,
                 _ => Rectangle {
                     top_left: Point { x: 0, y: 0 },
                     size: Size {
@@ -60,7 +66,7 @@ impl DrawPos {
                     x: r.top_left.x,
                     y: r.top_left.y - d.size.height as i32 + 3,
                 },
-                size: r.size.clone(),
+                size: r.size,
             },
         }
     }
@@ -79,89 +85,377 @@ pub enum DrawCmd {
         pos: DrawPos,
         text: String,
         text_color: Rgb565,
-        font: Option<MonoFont<'static>>,
+        font: Option<Font>,
         background: Option<Rgb565>,
     },
+    // a line graph of the values, scaled to fill the area
+    Sparkline {
+        area: Rectangle,
+        values: Vec<i32>,
+        color: Rgb565,
+        background: Rgb565,
+    },
+    // the backlight brightness in percent
+    Backlight(u8),
+    // cap the backlight at a percent while nobody's around, or lift the cap
+    Dim(Option<u8>),
+    // turn the screen off and stop drawing, or back on. The brightness is
+    // kept for when it's back on
+    Sleep(bool),
+    // an image, a row at a time from the top left
+    Image {
+        area: Rectangle,
+        pixels: Vec<Rgb565>,
+    },
+    // an icon, ICON_SIZE square, in its own colors unless
+    // `color` is set
+    Icon {
+        top_left: Point,
+        icon: Icon,
+        color: Option<Rgb565>,
+        background: Rgb565,
+    },
+    // a one bit icon from the asset bundle, set pixels in `color`
+    Bitmap {
+        top_left: Point,
+        bitmap: Arc<Bitmap>,
+        color: Rgb565,
+        background: Rgb565,
+    },
+    // the colors of what's drawn after it. The page needs drawing again
+    Theme(Theme),
 }
 
-pub fn draw_loop(
-    rx: Receiver<DrawCmd>,
-    backlight: gpio::Gpio45,
-    dc: gpio::Gpio4,
-    rst: gpio::Gpio48,
-    spi: spi::SPI2,
-    sclk: gpio::Gpio7,
-    sdo: gpio::Gpio6,
-    cs: gpio::Gpio5,
-) -> Result<()> {
-    info!("About to initialize the TTGO ST7789 LED driver");
-
-    let mut backlight = gpio::PinDriver::output(backlight)?;
-    backlight.set_low()?;
-
-    let di = SPIInterfaceNoCS::new(
-        spi::SpiDeviceDriver::new_single(
-            spi,
-            sclk,
-            sdo,
-            Option::<gpio::Gpio21>::None,
-            Some(cs),
-            &spi::SpiDriverConfig::new().dma(spi::Dma::Disabled),
-            &spi::SpiConfig::new().baudrate(26.MHz().into()),
-        )?,
-        gpio::PinDriver::output(dc)?,
-    );
-
-    let mut display = mipidsi::Builder::st7789(di)
-        .with_display_size(240, 320)
-        .with_invert_colors(mipidsi::ColorInversion::Inverted)
-        .with_orientation(mipidsi::options::Orientation::LandscapeInverted(true))
-        .init(&mut delay::Ets, Some(gpio::PinDriver::output(rst)?))
-        .map_err(|e| anyhow::anyhow!("Display error: {:?}", e))?;
-
-    loop {
-        let v = rx.recv()?;
-
-        match v {
-            DrawCmd::Erase { color } => {
-                display
-                    .clear(color)
-                    .map_err(|e| anyhow::anyhow!("Display error: {:?}", e))?;
-            }
-            DrawCmd::Clear { color, pos } => {
-                let bb = pos.compute_bounding_box(None);
+// day is the colors as they're given. At night white and black swap, so
+// the pages are light text on black and the rest of the colors stay
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Theme {
+    Day,
+    Night,
+}
+
+impl Theme {
+    pub fn color(self, color: Rgb565) -> Rgb565 {
+        match (self, color) {
+            (Theme::Night, Rgb565::WHITE) => Rgb565::BLACK,
+            (Theme::Night, Rgb565::BLACK) => Rgb565::WHITE,
+            _ => color,
+        }
+    }
+}
 
-                display
-                    .fill_solid(&bb, color)
-                    .map_err(|e| anyhow::anyhow!("Display error: {:?}", e))?;
+// the theme the draw commands are in, set by DrawCmd::Theme
+static NIGHT: AtomicBool = AtomicBool::new(false);
+
+fn theme() -> Theme {
+    if NIGHT.load(Ordering::Relaxed) {
+        Theme::Night
+    } else {
+        Theme::Day
+    }
+}
+
+pub const ICON_SIZE: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Icon {
+    Sun,
+    Moon,
+    PartlyCloudy,
+    Cloud,
+    Rain,
+    Snow,
+    Storm,
+    Fog,
+    Person,
+    // someone's direction of travel: home is to the left
+    Towards,
+    AwayFrom,
+    Stationary,
+    // the battery's charge in percent
+    Battery(u8),
+}
+
+// the icons are drawn from circles and lines on a 20 pixel grid, for
+// the weather, people and their travel
+fn draw_icon<D: DrawTarget<Color = Rgb565>>(
+    target: &mut D,
+    top_left: Point,
+    icon: Icon,
+    color: Option<Rgb565>,
+    background: Rgb565,
+) -> Result<(), D::Error> {
+    let at = |x: i32, y: i32| top_left + Point::new(x, y);
+    let fill = |c: Rgb565| PrimitiveStyle::with_fill(color.unwrap_or(c));
+    let stroke = |c: Rgb565| PrimitiveStyle::with_stroke(color.unwrap_or(c), 2);
+    let sun = Rgb565::new(31, 50, 0);
+    let cloud = Rgb565::new(16, 32, 16);
+
+    target.fill_solid(
+        &Rectangle::new(top_left, Size::new(ICON_SIZE, ICON_SIZE)),
+        background,
+    )?;
+    let draw_cloud = |target: &mut D| -> Result<(), D::Error> {
+        Circle::new(at(1, 6), 9)
+            .into_styled(fill(cloud))
+            .draw(target)?;
+        Circle::new(at(6, 2), 11)
+            .into_styled(fill(cloud))
+            .draw(target)?;
+        Rectangle::new(at(5, 9), Size::new(13, 6))
+            .into_styled(fill(cloud))
+            .draw(target)?;
+        Circle::new(at(12, 7), 8)
+            .into_styled(fill(cloud))
+            .draw(target)
+    };
+
+    match icon {
+        Icon::Sun => Circle::new(at(4, 4), 12)
+            .into_styled(fill(sun))
+            .draw(target)?,
+        Icon::Moon => {
+            Circle::new(at(3, 3), 14)
+                .into_styled(fill(sun))
+                .draw(target)?;
+            Circle::new(at(8, 0), 12)
+                .into_styled(PrimitiveStyle::with_fill(background))
+                .draw(target)?;
+        }
+        Icon::PartlyCloudy => {
+            Circle::new(at(8, 0), 11)
+                .into_styled(fill(sun))
+                .draw(target)?;
+            draw_cloud(target)?;
+        }
+        Icon::Cloud => draw_cloud(target)?,
+        Icon::Rain => {
+            draw_cloud(target)?;
+            for x in [5, 10, 15] {
+                Line::new(at(x, 16), at(x - 2, 19))
+                    .into_styled(stroke(Rgb565::BLUE))
+                    .draw(target)?;
             }
-            DrawCmd::Text {
-                pos,
-                text,
-                text_color,
-                font,
-                background,
-            } => {
-                let upper_left = pos.upper_left();
-
-                let the_font: &MonoFont<'static> = match &font {
-                    Some(v) => v,
-                    None => &FONT_10X20,
-                };
-
-                let t = Text::new(&text, upper_left, MonoTextStyle::new(the_font, text_color));
-
-                let bb = pos.compute_bounding_box(Some(&t.bounding_box()));
-                match background {
-                    Some(bc) => display
-                        .fill_solid(&bb, bc)
-                        .map_err(|e| anyhow::anyhow!("Display error: {:?}", e))?,
-                    None => (),
-                };
-
-                t.draw(&mut display)
-                    .map_err(|e| anyhow::anyhow!("Display error: {:?}", e))?;
+        }
+        Icon::Snow => {
+            draw_cloud(target)?;
+            for x in [4, 9, 14] {
+                Circle::new(at(x, 16), 3)
+                    .into_styled(fill(Rgb565::CYAN))
+                    .draw(target)?;
             }
-        };
+        }
+        Icon::Storm => {
+            draw_cloud(target)?;
+            Polyline::new(&[at(11, 14), at(8, 17), at(12, 17), at(9, 20)])
+                .into_styled(stroke(sun))
+                .draw(target)?;
+        }
+        Icon::Person => {
+            Circle::new(at(6, 1), 8)
+                .into_styled(fill(cloud))
+                .draw(target)?;
+            // the shoulders are the top of a circle
+            let icon_area = Rectangle::new(top_left, Size::new(ICON_SIZE, ICON_SIZE));
+            Circle::new(at(2, 10), 16)
+                .into_styled(fill(cloud))
+                .draw(&mut target.clipped(&icon_area))?;
+        }
+        Icon::Fog => {
+            for y in [5, 10, 15] {
+                Line::new(at(2, y), at(18, y))
+                    .into_styled(stroke(cloud))
+                    .draw(target)?;
+            }
+        }
+        Icon::Towards => Triangle::new(at(2, 10), at(17, 2), at(17, 18))
+            .into_styled(fill(Rgb565::GREEN))
+            .draw(target)?,
+        Icon::AwayFrom => Triangle::new(at(18, 10), at(3, 2), at(3, 18))
+            .into_styled(fill(Rgb565::new(31, 40, 0)))
+            .draw(target)?,
+        Icon::Stationary => Circle::new(at(5, 5), 10)
+            .into_styled(fill(cloud))
+            .draw(target)?,
+        Icon::Battery(percent) => {
+            let level = match percent {
+                0..=19 => Rgb565::RED,
+                20..=49 => Rgb565::new(31, 40, 0),
+                _ => Rgb565::new(0, 48, 0),
+            };
+            Rectangle::new(at(1, 5), Size::new(16, 10))
+                .into_styled(PrimitiveStyle::with_stroke(color.unwrap_or(cloud), 1))
+                .draw(target)?;
+            Rectangle::new(at(17, 8), Size::new(2, 4))
+                .into_styled(fill(cloud))
+                .draw(target)?;
+            let width = 12 * percent.min(100) as u32 / 100;
+            Rectangle::new(at(3, 7), Size::new(width.max(1), 6))
+                .into_styled(fill(level))
+                .draw(target)?;
+        }
+    }
+    Ok(())
+}
+
+// the points of a sparkline, scaled so the min and max values touch
+// the bottom and top of the area
+fn sparkline_points(area: &Rectangle, values: &[i32]) -> Vec<Point> {
+    let min = values.iter().copied().min().unwrap_or(0);
+    let max = values.iter().copied().max().unwrap_or(0);
+    let range = (max - min).max(1);
+    let width = area.size.width as i32 - 1;
+    let height = area.size.height as i32 - 1;
+    let steps = (values.len() as i32 - 1).max(1);
+
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            Point::new(
+                area.top_left.x + (i as i32 * width) / steps,
+                area.top_left.y + height - ((v - min) * height) / range,
+            )
+        })
+        .collect()
+}
+
+// draw a command on any target, the screen or the simulator's window
+pub fn draw<D>(display: &mut D, cmd: DrawCmd) -> Result<()>
+where
+    D: DrawTarget<Color = Rgb565>,
+    D::Error: std::fmt::Debug,
+{
+    let theme = theme();
+    match cmd {
+        DrawCmd::Erase { color } => {
+            display.clear(theme.color(color)).map_err(Error::display)?;
+        }
+        DrawCmd::Clear { color, pos } => {
+            let bb = pos.compute_bounding_box(None);
+
+            display
+                .fill_solid(&bb, theme.color(color))
+                .map_err(Error::display)?;
+        }
+        DrawCmd::Text {
+            pos,
+            text,
+            text_color,
+            font,
+            background,
+        } => {
+            let text_color = theme.color(text_color);
+            let background = background.map(|bc| theme.color(bc));
+            let upper_left = pos.upper_left();
+
+            // a named font is from the asset bundle or a BDF file, and
+            // the default font stands in for one that can't be found
+            let the_font: MonoFont<'static> = match font {
+                Some(Font::Mono(f)) => f,
+                Some(Font::Named(name)) => match assets::font(&name) {
+                    Some(f) => f,
+                    None => {
+                        let drawn = with_bdf_font(&name, |f| -> Result<()> {
+                            let bb =
+                                pos.compute_bounding_box(Some(&f.bounding_box(&text, upper_left)));
+                            if let Some(bc) = background {
+                                display.fill_solid(&bb, bc).map_err(Error::display)?;
+                            }
+                            f.draw(&mut *display, &text, upper_left, text_color)
+                                .map_err(Error::display)
+                        });
+                        match drawn {
+                            Some(r) => return r,
+                            None => FONT_10X20,
+                        }
+                    }
+                },
+                None => FONT_10X20,
+            };
+
+            let t = Text::new(&text, upper_left, MonoTextStyle::new(&the_font, text_color));
+
+            let bb = pos.compute_bounding_box(Some(&t.bounding_box()));
+            if let Some(bc) = background {
+                display.fill_solid(&bb, bc).map_err(Error::display)?;
+            }
+
+            t.draw(display).map_err(Error::display)?;
+        }
+        DrawCmd::Sparkline {
+            area,
+            values,
+            color,
+            background,
+        } => {
+            display
+                .fill_solid(&area, theme.color(background))
+                .map_err(Error::display)?;
+
+            let points = sparkline_points(&area, &values);
+            Polyline::new(&points)
+                .into_styled(PrimitiveStyle::with_stroke(theme.color(color), 1))
+                .draw(display)
+                .map_err(Error::display)?;
+        }
+        // the backlight is the draw loop's
+        DrawCmd::Backlight(_) | DrawCmd::Dim(_) | DrawCmd::Sleep(_) => {}
+        DrawCmd::Theme(theme) => NIGHT.store(theme == Theme::Night, Ordering::Relaxed),
+        DrawCmd::Image { area, pixels } => {
+            display
+                .fill_contiguous(&area, pixels)
+                .map_err(Error::display)?;
+        }
+        DrawCmd::Icon {
+            top_left,
+            icon,
+            color,
+            background,
+        } => {
+            draw_icon(
+                display,
+                top_left,
+                icon,
+                color.map(|c| theme.color(c)),
+                theme.color(background),
+            )
+            .map_err(Error::display)?;
+        }
+        DrawCmd::Bitmap {
+            top_left,
+            bitmap,
+            color,
+            background,
+        } => {
+            let (color, background) = (theme.color(color), theme.color(background));
+            let size = bitmap.size;
+            let pixels = (0..size.height)
+                .flat_map(|y| (0..size.width).map(move |x| (x, y)))
+                .map(|(x, y)| {
+                    if bitmap.is_set(x, y) {
+                        color
+                    } else {
+                        background
+                    }
+                });
+            display
+                .fill_contiguous(&Rectangle::new(top_left, size), pixels)
+                .map_err(Error::display)?;
+        }
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn night_swaps_white_and_black() {
+        assert_eq!(Theme::Night.color(Rgb565::WHITE), Rgb565::BLACK);
+        assert_eq!(Theme::Night.color(Rgb565::BLACK), Rgb565::WHITE);
+        assert_eq!(Theme::Night.color(Rgb565::RED), Rgb565::RED);
+        assert_eq!(Theme::Day.color(Rgb565::WHITE), Rgb565::WHITE);
     }
 }
@@ -0,0 +1,73 @@
+use std::{sync::mpsc::Sender, time::Duration};
+
+use chrono::Local;
+use embedded_graphics::{
+    pixelcolor::Rgb565,
+    prelude::{Point, RgbColor, Size},
+    primitives::Rectangle,
+};
+use profont::PROFONT_24_POINT;
+use serde_json::{json, Value};
+
+use crate::{
+    camera::Camera,
+    config::DoorbellConfig,
+    display::{DrawCmd, DrawPos},
+    pages::Page,
+    util::next_message_id,
+    Result,
+};
+
+// where the camera image goes, between the title and the buttons
+const IMAGE_AREA: Rectangle = Rectangle::new(Point::new(10, 30), Size::new(300, 165));
+
+// the message that subscribes to the doorbell's event, sent after each
+// websocket connection
+pub fn subscription(doorbell: &DoorbellConfig) -> Value {
+    json!({
+        "id": next_message_id(),
+        "type": "subscribe_events",
+        "event_type": doorbell.event,
+    })
+}
+
+// the camera whose image fills the popup, fetched once when it pops up
+pub fn popup_camera(doorbell: &DoorbellConfig) -> Option<Camera> {
+    doorbell.camera.as_ref().map(|ha_id| Camera {
+        ha_id: ha_id.clone(),
+        area: IMAGE_AREA,
+        refresh: Duration::ZERO,
+    })
+}
+
+// draw the popup with the time it went off, the camera image (if any)
+// comes later
+pub fn render_doorbell(doorbell: &DoorbellConfig, display_tx: &Sender<DrawCmd>) -> Result<()> {
+    display_tx.send(DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(10, 20)),
+        font: Some(PROFONT_24_POINT.into()),
+        text: format!(
+            "{:<16}{}",
+            doorbell.title.chars().take(15).collect::<String>(),
+            Local::now().format("%H:%M:%S")
+        ),
+        text_color: Rgb565::BLUE,
+        background: Some(RgbColor::WHITE),
+    })?;
+    if doorbell.camera.is_some() {
+        display_tx.send(DrawCmd::Clear {
+            color: Rgb565::new(28, 56, 28),
+            pos: DrawPos::Box(IMAGE_AREA),
+        })?;
+    }
+    for (i, label) in Page::Doorbell.button_labels().iter().enumerate() {
+        display_tx.send(DrawCmd::Text {
+            pos: DrawPos::Button(i as u8),
+            font: None,
+            text: label.to_string(),
+            text_color: RgbColor::BLACK,
+            background: Some(RgbColor::WHITE),
+        })?;
+    }
+    Ok(())
+}
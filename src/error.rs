@@ -0,0 +1,69 @@
+use std::{fmt::Debug, sync::mpsc::SendError};
+
+use thiserror::Error;
+
+use crate::display::DrawCmd;
+
+// what went wrong, by the part of the device it went wrong in, so a
+// caller can pick how to recover: retry a socket, restart for the WiFi,
+// show a config error on the screen
+#[derive(Debug, Error)]
+pub enum Error {
+    // the panel: the screen, its backlight and the buttons
+    #[error("display: {0}")]
+    Display(String),
+    // WiFi, ethernet and the network stack under them
+    #[error("wifi: {0}")]
+    Wifi(String),
+    // talking to HA or another server: the websocket, REST, MQTT, HTTP
+    #[error("socket: {0}")]
+    Socket(String),
+    // a config, setting or file that doesn't make sense
+    #[error("config: {0}")]
+    Config(String),
+    // the files on flash or the SD card, NVS and the OTA partitions
+    #[error("fs: {0}")]
+    Fs(String),
+    // the errors of the libraries underneath keep their source, rather
+    // than being flattened into one of the strings above
+    #[error("json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+    #[cfg(feature = "hal")]
+    #[error("esp: {0}")]
+    Esp(#[from] esp_idf_sys::EspError),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+// for `map_err`, e.g. `.map_err(Error::fs)?`, for errors without a
+// variant of their own
+impl Error {
+    pub fn display(e: impl Debug) -> Error {
+        Error::Display(format!("{:?}", e))
+    }
+
+    pub fn wifi(e: impl Debug) -> Error {
+        Error::Wifi(format!("{:?}", e))
+    }
+
+    pub fn socket(e: impl Debug) -> Error {
+        Error::Socket(format!("{:?}", e))
+    }
+
+    pub fn config(e: impl Debug) -> Error {
+        Error::Config(format!("{:?}", e))
+    }
+
+    pub fn fs(e: impl Debug) -> Error {
+        Error::Fs(format!("{:?}", e))
+    }
+}
+
+// the display thread has gone
+impl From<SendError<DrawCmd>> for Error {
+    fn from(e: SendError<DrawCmd>) -> Error {
+        Error::display(e)
+    }
+}
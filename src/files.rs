@@ -1,37 +1,381 @@
+#[cfg(feature = "hal")]
+use std::ffi::CString;
 use std::{
-    ffi::CString,
-    fs::File,
-    io::{BufReader, Read},
+    fs::{self, File},
+    io::{BufReader, ErrorKind, Read, Write},
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
-use anyhow::{bail, Result};
+use log::*;
+use serde::Serialize;
 
-use esp_idf_sys::{esp_vfs_spiffs_conf_t, esp_vfs_spiffs_register, ESP_ERR_NOT_FOUND, ESP_OK};
+use crate::{Error, Result};
 
-pub fn mount_spiffs() -> Result<()> {
-    let spiffy = CString::new("/spiffy").expect("CString::new failed");
-    let spiffland = CString::new("spiffland").expect("CString::new failed");
+#[cfg(feature = "hal")]
+use esp_idf_sys::{
+    esp, esp_get_free_heap_size, esp_vfs_fat_mount_config_t, esp_vfs_fat_sdspi_mount,
+    esp_vfs_spiffs_conf_t, esp_vfs_spiffs_register, esp_vfs_spiffs_unregister,
+    littlefs::{esp_littlefs_format, esp_vfs_littlefs_conf_t, esp_vfs_littlefs_register},
+    sdmmc_card_t, sdmmc_host_t, sdspi_device_config_t, sdspi_host_do_transaction, sdspi_host_init,
+    sdspi_host_io_int_enable, sdspi_host_io_int_wait, sdspi_host_remove_device,
+    sdspi_host_set_card_clk, spi_bus_config_t, spi_bus_initialize,
+    spi_common_dma_t_SPI_DMA_CH_AUTO, spi_host_device_t_SPI3_HOST, ESP_ERR_NOT_FOUND, ESP_OK,
+    SDMMC_FREQ_DEFAULT,
+};
+
+// where the config partition is mounted, whichever filesystem it holds.
+// Off the device it's the configs directory the partition is made from
+#[cfg(feature = "hal")]
+const BASE_PATH: &str = "/spiffy";
+#[cfg(not(feature = "hal"))]
+const BASE_PATH: &str = "configs";
+#[cfg(feature = "hal")]
+const PARTITION: &str = "spiffland";
+
+// a replacement is written next to the file with this on the end, and
+// renamed over it once it's all there
+const NEW_SUFFIX: &str = ".new";
+
+// the NVS key of the SD card's SPI pins, "sclk,mosi,miso,cs". They can't
+// be in the config as the config may be on the card
+pub const SD_PINS_KEY: &str = "sd_spi";
+
+// where the SD card is mounted, files there win over the config partition's
+const SD_PATH: &str = "/sd";
+
+static SD_MOUNTED: AtomicBool = AtomicBool::new(false);
+
+// the SD card's pins, as GPIO numbers
+pub struct SdPins {
+    pub sclk: i32,
+    pub mosi: i32,
+    pub miso: i32,
+    pub cs: i32,
+}
+
+impl SdPins {
+    pub fn parse(pins: &str) -> Result<SdPins> {
+        let pins = pins
+            .split(',')
+            .map(|p| p.trim().parse::<i32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Error::config)?;
+        match pins[..] {
+            [sclk, mosi, miso, cs] => Ok(SdPins {
+                sclk,
+                mosi,
+                miso,
+                cs,
+            }),
+            _ => Err(Error::Config(format!(
+                "Expected 4 SD card pins, got {}",
+                pins.len()
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filesystem {
+    LittleFs,
+    // a SPIFFS partition that couldn't be moved to LittleFS
+    Spiffs,
+}
+
+// mount the config partition. LittleFS survives power cuts and has
+// directories, so a SPIFFS partition (e.g. a fresh spiffsgen.py image)
+// has its files copied into memory, the partition formatted as LittleFS
+// and the files written back. If they don't fit in memory it stays SPIFFS
+#[cfg(feature = "hal")]
+pub fn mount_files() -> Result<Filesystem> {
+    let base_path = CString::new(BASE_PATH).expect("CString::new failed");
+    let label = CString::new(PARTITION).expect("CString::new failed");
+
+    if mount_littlefs(&base_path, &label, false) == ESP_OK {
+        return Ok(Filesystem::LittleFs);
+    }
 
     let conf = esp_vfs_spiffs_conf_t {
-        base_path: spiffy.as_ptr(),
-        partition_label: spiffland.as_ptr(),
+        base_path: base_path.as_ptr(),
+        partition_label: label.as_ptr(),
         max_files: 5,
         format_if_mount_failed: true,
     };
-    let ret = unsafe { esp_vfs_spiffs_register(&conf) };
+    match unsafe { esp_vfs_spiffs_register(&conf) } {
+        ESP_OK => {}
+        ESP_ERR_NOT_FOUND => return Err(Error::Fs("The SPIFF partition was not found".into())),
+        err => return Err(Error::Fs(format!("Mounting SPIFF failed {}", err))),
+    }
+
+    let files = match spiffs_files() {
+        Ok(files) => files,
+        Err(e) => {
+            info!("Staying on SPIFFS error {:?}", e);
+            return Ok(Filesystem::Spiffs);
+        }
+    };
+    info!("Moving {} files from SPIFFS to LittleFS", files.len());
+    unsafe {
+        esp_vfs_spiffs_unregister(label.as_ptr());
+        let ret = esp_littlefs_format(label.as_ptr());
+        if ret != ESP_OK {
+            return Err(Error::Fs(format!("Formatting LittleFS failed {}", ret)));
+        }
+    }
+    match mount_littlefs(&base_path, &label, true) {
+        ESP_OK => {}
+        err => return Err(Error::Fs(format!("Mounting LittleFS failed {}", err))),
+    }
+    for (name, contents) in files {
+        make_dirs(&name);
+        write_file(&name, &contents)?;
+    }
+    Ok(Filesystem::LittleFs)
+}
+
+#[cfg(feature = "hal")]
+fn mount_littlefs(base_path: &CString, label: &CString, format: bool) -> i32 {
+    let mut conf: esp_vfs_littlefs_conf_t = unsafe { std::mem::zeroed() };
+    conf.base_path = base_path.as_ptr();
+    conf.partition_label = label.as_ptr();
+    conf.set_format_if_mount_failed(format as u8);
+    unsafe { esp_vfs_littlefs_register(&conf) }
+}
+
+// all the files on the mounted SPIFFS partition, as long as there's
+// plenty of memory left over for holding them
+#[cfg(feature = "hal")]
+fn spiffs_files() -> Result<Vec<(String, Vec<u8>)>> {
+    let mut files = vec![];
+    let mut total = 0;
+    for entry in fs::read_dir(BASE_PATH)? {
+        let entry = entry?;
+        total += entry.metadata()?.len() as usize;
+        files.push(entry.file_name().to_string_lossy().to_string());
+    }
+    let free = unsafe { esp_get_free_heap_size() } as usize;
+    if total > free / 2 {
+        return Err(Error::Fs(format!(
+            "{} bytes of files won't fit in {} free",
+            total, free
+        )));
+    }
+    files
+        .into_iter()
+        .map(|name| Ok((name.clone(), fs::read(path(&name))?)))
+        .collect()
+}
+
+fn path(name: &str) -> String {
+    format!("{}/{}", BASE_PATH, name)
+}
+
+// mount a FAT formatted SD card on the SPI3 bus (so not with W5500
+// Ethernet, which uses it too). It's read only as far as homer goes
+#[cfg(feature = "hal")]
+pub fn mount_sd(pins: &SdPins) -> Result<()> {
+    let mut bus: spi_bus_config_t = unsafe { std::mem::zeroed() };
+    bus.sclk_io_num = pins.sclk;
+    bus.__bindgen_anon_1.mosi_io_num = pins.mosi;
+    bus.__bindgen_anon_2.miso_io_num = pins.miso;
+    bus.__bindgen_anon_3.quadwp_io_num = -1;
+    bus.__bindgen_anon_4.quadhd_io_num = -1;
+    bus.data4_io_num = -1;
+    bus.data5_io_num = -1;
+    bus.data6_io_num = -1;
+    bus.data7_io_num = -1;
+    bus.max_transfer_sz = 4000;
+
+    // what the SDSPI_HOST_DEFAULT() macro fills in
+    let mut host: sdmmc_host_t = unsafe { std::mem::zeroed() };
+    // SDMMC_HOST_FLAG_SPI | SDMMC_HOST_FLAG_DEINIT_ARG
+    host.flags = (1 << 3) | (1 << 5);
+    host.slot = spi_host_device_t_SPI3_HOST as i32;
+    host.max_freq_khz = SDMMC_FREQ_DEFAULT as i32;
+    host.io_voltage = 3.3;
+    host.init = Some(sdspi_host_init);
+    host.set_card_clk = Some(sdspi_host_set_card_clk);
+    host.do_transaction = Some(sdspi_host_do_transaction);
+    host.__bindgen_anon_1.deinit_p = Some(sdspi_host_remove_device);
+    host.io_int_enable = Some(sdspi_host_io_int_enable);
+    host.io_int_wait = Some(sdspi_host_io_int_wait);
 
-    match ret {
-        ESP_OK => Ok(()),
-        ESP_ERR_NOT_FOUND => bail!("The SPIFF partition was not found, error {}", ret),
-        err => bail!("Mounting SPIFF failed {}", err),
+    let mut slot: sdspi_device_config_t = unsafe { std::mem::zeroed() };
+    slot.host_id = spi_host_device_t_SPI3_HOST;
+    slot.gpio_cs = pins.cs;
+    slot.gpio_cd = -1;
+    slot.gpio_wp = -1;
+    slot.gpio_int = -1;
+
+    let mut mount: esp_vfs_fat_mount_config_t = unsafe { std::mem::zeroed() };
+    mount.format_if_mount_failed = false;
+    mount.max_files = 5;
+    mount.allocation_unit_size = 16 * 1024;
+
+    let sd_path = CString::new(SD_PATH).expect("CString::new failed");
+    let mut card: *mut sdmmc_card_t = std::ptr::null_mut();
+    unsafe {
+        esp!(spi_bus_initialize(
+            spi_host_device_t_SPI3_HOST,
+            &bus,
+            spi_common_dma_t_SPI_DMA_CH_AUTO,
+        ))?;
+        esp!(esp_vfs_fat_sdspi_mount(
+            sd_path.as_ptr(),
+            &host,
+            &slot,
+            &mount,
+            &mut card,
+        ))?;
+    }
+    SD_MOUNTED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+// the SD card's copy of a file, if there's a card and it has one
+pub fn sd_file(name: &str) -> Option<File> {
+    if !SD_MOUNTED.load(Ordering::Relaxed) {
+        return None;
+    }
+    File::open(format!("{}/{}", SD_PATH, name)).ok()
+}
+
+// the SD card's copy of a file wins. A file that's missing but has a whole
+// replacement next to it lost power between the old one going and the
+// rename, so the rename is finished
+pub fn open_file(name: &str) -> Result<File> {
+    match sd_file(name).map_or_else(|| File::open(path(name)), Ok) {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let new = path(&format!("{}{}", name, NEW_SUFFIX));
+            if fs::metadata(&new).is_err() {
+                return Err(e.into());
+            }
+            info!("Finishing the replacement of {}", name);
+            match fs::rename(&new, path(name)) {
+                Ok(()) => File::open(path(name)).map_err(Error::from),
+                Err(_) => File::open(&new).map_err(Error::from),
+            }
+        }
+        file => file.map_err(Error::from),
     }
 }
 
 pub fn read_file(name: &str) -> Result<String> {
-    let file = File::open(format!("/spiffy/{}", name))?;
-    let mut buf_reader = BufReader::new(file);
+    let mut buf_reader = BufReader::new(open_file(name)?);
     let mut contents = String::new();
     buf_reader.read_to_string(&mut contents)?;
 
     Ok(contents)
 }
+
+// write a file in place, a power cut part way leaves it cut short on
+// SPIFFS, LittleFS keeps the old one until it's closed
+pub fn write_file(name: &str, contents: &[u8]) -> Result<()> {
+    let mut file = File::create(path(name))?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// write a file so there's always a whole version of it, the old or the
+// new, even if the power goes part way. The new one is written and
+// flushed next to it first then renamed over it, which LittleFS does in
+// one go. SPIFFS won't rename over a file so the old one is removed just
+// before the rename, and read_file picks up the new one if the power
+// goes in between
+pub fn replace_file(name: &str, contents: &[u8]) -> Result<()> {
+    replace_file_with(name, |file| file.write_all(contents).map_err(Error::from))
+}
+
+// replace_file with the contents written a bit at a time, e.g. as they
+// come in over HTTP. If `write` fails the old file is left as it was
+pub fn replace_file_with(name: &str, write: impl FnOnce(&mut File) -> Result<()>) -> Result<()> {
+    let new_name = format!("{}{}", name, NEW_SUFFIX);
+    let mut file = File::create(path(&new_name))?;
+    if let Err(e) = write(&mut file).and_then(|_| file.sync_all().map_err(Error::from)) {
+        drop(file);
+        let _ = fs::remove_file(path(&new_name));
+        return Err(e);
+    }
+    drop(file);
+    if fs::rename(path(&new_name), path(name)).is_ok() {
+        return Ok(());
+    }
+    match fs::remove_file(path(name)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            let _ = fs::remove_file(path(&new_name));
+            return Err(Error::Fs(format!(
+                "Failed to remove the old {} error {:?}",
+                name, e
+            )));
+        }
+        _ => {}
+    }
+    fs::rename(path(&new_name), path(name))?;
+    Ok(())
+}
+
+// a file on the config partition or SD card, for the web UI's file browser
+#[derive(Debug, Clone, Serialize)]
+pub struct FileInfo {
+    // the path from the top of the partition or card, e.g. "fonts/big.bdf"
+    pub name: String,
+    pub size: u64,
+    pub sd: bool,
+}
+
+// all the files on the config partition and SD card, in directories too
+pub fn list_files() -> Result<Vec<FileInfo>> {
+    let mut files = vec![];
+    list_dir(BASE_PATH, "", false, &mut files)?;
+    if SD_MOUNTED.load(Ordering::Relaxed) {
+        list_dir(SD_PATH, "", true, &mut files)?;
+    }
+    Ok(files)
+}
+
+fn list_dir(base: &str, dir: &str, sd: bool, files: &mut Vec<FileInfo>) -> Result<()> {
+    for entry in fs::read_dir(format!("{}/{}", base, dir))? {
+        let entry = entry?;
+        let name = format!("{}{}", dir, entry.file_name().to_string_lossy());
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            list_dir(base, &format!("{}/", name), sd, files)?;
+        } else {
+            files.push(FileInfo {
+                name,
+                size: metadata.len(),
+                sd,
+            });
+        }
+    }
+    Ok(())
+}
+
+// a file name from outside (e.g. the web UI) that stays on the config
+// partition
+pub fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.starts_with('/')
+        || name.split('/').any(|p| p.is_empty() || p == "..")
+    {
+        return Err(Error::Fs(format!("Bad file name {:?}", name)));
+    }
+    Ok(())
+}
+
+// make the directories a file goes in, LittleFS needs them to exist
+// first. SPIFFS has none but takes names with / in, so it can fail
+pub fn make_dirs(name: &str) {
+    if let Some(dir) = Path::new(&path(name)).parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+}
+
+// remove a file from the config partition, the SD card's are left alone
+pub fn delete_file(name: &str) -> Result<()> {
+    check_name(name)?;
+    fs::remove_file(path(name))?;
+    Ok(())
+}
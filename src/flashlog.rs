@@ -0,0 +1,329 @@
+use std::{
+    ffi::c_void,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::log::EspLogger;
+use esp_idf_sys::{
+    esp_partition_erase_range, esp_partition_find_first, esp_partition_read,
+    esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY, esp_partition_t,
+    esp_partition_type_t_ESP_PARTITION_TYPE_DATA, esp_partition_write, esp_reset_reason,
+    esp_reset_reason_t_ESP_RST_BROWNOUT, esp_reset_reason_t_ESP_RST_INT_WDT,
+    esp_reset_reason_t_ESP_RST_PANIC, esp_reset_reason_t_ESP_RST_TASK_WDT,
+    esp_reset_reason_t_ESP_RST_WDT, ESP_OK,
+};
+use log::{Level, Log, Metadata, Record};
+
+use crate::{remotelog::forward_log, stats::reset_reason};
+
+// the partition the log goes in, a ring of flash sectors each starting
+// with its sequence number, followed by log text up to the erased bytes
+const PARTITION: &str = "homerlog";
+const SECTOR: usize = 4096;
+const HEADER: usize = 4;
+const ERASED: u32 = u32::MAX;
+
+// lines are gathered in memory and written once there are this many
+// bytes, this long after the last write, or a warning or error comes
+const FLUSH_BYTES: usize = 1024;
+const FLUSH_AFTER: Duration = Duration::from_secs(10);
+
+// how much of the log before a crash is printed on the serial console
+const CRASH_TAIL: usize = 2048;
+
+// how many lines are kept in memory for the Log page
+const RECENT_LINES: usize = 50;
+
+struct Ring {
+    partition: *const esp_partition_t,
+    sectors: usize,
+    // the sector being written, its sequence number and how far into it
+    sector: usize,
+    seq: u32,
+    offset: usize,
+    pending: Vec<u8>,
+    flushed: Instant,
+}
+
+// the partition table entry lives as long as the device
+unsafe impl Send for Ring {}
+
+static RING: Mutex<Option<Ring>> = Mutex::new(None);
+
+static RECENT: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+static ESP_LOGGER: EspLogger = EspLogger;
+static LOGGER: FlashLogger = FlashLogger;
+
+// log to the console as before and to the flash ring (and a remote log
+// once it's started), and put panics in the ring too. Without the partition (an older partition table) only the
+// console gets the log. After a crash the end of the log from before it
+// is printed on the console
+pub fn init_logging() {
+    if log::set_logger(&LOGGER).is_ok() {
+        ESP_LOGGER.initialize();
+    }
+
+    let ring = match Ring::open() {
+        Some(ring) => ring,
+        None => {
+            log::info!("No {} partition, logging to the console only", PARTITION);
+            return;
+        }
+    };
+    let crashed = matches!(
+        unsafe { esp_reset_reason() },
+        esp_reset_reason_t_ESP_RST_PANIC
+            | esp_reset_reason_t_ESP_RST_INT_WDT
+            | esp_reset_reason_t_ESP_RST_TASK_WDT
+            | esp_reset_reason_t_ESP_RST_WDT
+            | esp_reset_reason_t_ESP_RST_BROWNOUT
+    );
+    if crashed {
+        let log = ring.read();
+        let tail = &log[log.len().saturating_sub(CRASH_TAIL)..];
+        println!(
+            "--- the log before the crash ---\n{}\n---",
+            String::from_utf8_lossy(tail)
+        );
+    }
+    if let Ok(mut shared) = RING.lock() {
+        *shared = Some(ring);
+    }
+    append(
+        &format!(
+            "--- boot, firmware {}, reset {} ---\n",
+            env!("CARGO_PKG_VERSION"),
+            reset_reason()
+        ),
+        true,
+    );
+
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        record_panic(&format!("PANIC {}\n", info));
+        default_hook(info);
+    }));
+}
+
+// the whole log on flash, oldest first, with what's still in memory
+pub fn read_log() -> String {
+    match RING.lock() {
+        Ok(ring) => match ring.as_ref() {
+            Some(ring) => {
+                let mut log = ring.read();
+                log.extend_from_slice(&ring.pending);
+                String::from_utf8_lossy(&log).to_string()
+            }
+            None => String::new(),
+        },
+        Err(_) => String::new(),
+    }
+}
+
+// the last lines logged, oldest first, with the uptime in seconds and the
+// level's initial rather than the whole line
+pub fn recent_log() -> Vec<String> {
+    RECENT.lock().map_or(vec![], |recent| recent.clone())
+}
+
+// write out what's in memory, e.g. before a restart
+pub fn flush_log() {
+    if let Ok(mut ring) = RING.lock() {
+        if let Some(ring) = ring.as_mut() {
+            ring.flush();
+        }
+    }
+}
+
+fn append(line: &str, flush: bool) {
+    if let Ok(mut ring) = RING.lock() {
+        if let Some(ring) = ring.as_mut() {
+            ring.pending.extend_from_slice(line.as_bytes());
+            if flush || ring.pending.len() >= FLUSH_BYTES || ring.flushed.elapsed() > FLUSH_AFTER {
+                ring.flush();
+            }
+        }
+    }
+}
+
+// the panic could have come from inside the ring, so it's not waited for
+fn record_panic(message: &str) {
+    if let Ok(mut ring) = RING.try_lock() {
+        if let Some(ring) = ring.as_mut() {
+            ring.pending.extend_from_slice(message.as_bytes());
+            ring.flush();
+        }
+    }
+}
+
+struct FlashLogger;
+
+impl Log for FlashLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        ESP_LOGGER.enabled(metadata)
+    }
+
+    // the ring doesn't log, it would end up back here
+    fn log(&self, record: &Record) {
+        ESP_LOGGER.log(record);
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        forward_log(record);
+        let uptime = unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000;
+        if let Ok(mut recent) = RECENT.lock() {
+            if recent.len() == RECENT_LINES {
+                recent.remove(0);
+            }
+            let level = record.level().as_str().chars().next().unwrap_or(' ');
+            recent.push(format!("{} {} {}", uptime / 1000, level, record.args()));
+        }
+        append(
+            &format!(
+                "{}.{:03} {} {}: {}\n",
+                uptime / 1000,
+                uptime % 1000,
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+            record.level() <= Level::Warn,
+        );
+    }
+
+    fn flush(&self) {
+        flush_log();
+    }
+}
+
+impl Ring {
+    // carry on from the newest sector, or start afresh if none are in use
+    fn open() -> Option<Ring> {
+        let label = std::ffi::CString::new(PARTITION).ok()?;
+        let partition = unsafe {
+            esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+                label.as_ptr(),
+            )
+        };
+        if partition.is_null() {
+            return None;
+        }
+        let sectors = unsafe { (*partition).size } as usize / SECTOR;
+        if sectors < 2 {
+            return None;
+        }
+        let mut ring = Ring {
+            partition,
+            sectors,
+            sector: 0,
+            seq: 0,
+            offset: 0,
+            pending: vec![],
+            flushed: Instant::now(),
+        };
+
+        let newest = (0..sectors)
+            .filter_map(|s| ring.seq_of(s).map(|seq| (s, seq)))
+            .max_by_key(|(_, seq)| *seq);
+        match newest {
+            Some((sector, seq)) => {
+                ring.sector = sector;
+                ring.seq = seq;
+                let mut data = vec![0_u8; SECTOR - HEADER];
+                ring.read_at(sector * SECTOR + HEADER, &mut data)?;
+                ring.offset = HEADER + data.iter().position(|b| *b == 0xff).unwrap_or(data.len());
+            }
+            None => ring.start_sector(0, 1)?,
+        }
+        Some(ring)
+    }
+
+    fn read_at(&self, at: usize, buf: &mut [u8]) -> Option<()> {
+        let ret = unsafe {
+            esp_partition_read(
+                self.partition,
+                at,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len(),
+            )
+        };
+        (ret == ESP_OK).then_some(())
+    }
+
+    fn seq_of(&self, sector: usize) -> Option<u32> {
+        let mut header = [0_u8; HEADER];
+        self.read_at(sector * SECTOR, &mut header)?;
+        Some(u32::from_le_bytes(header)).filter(|seq| *seq != ERASED)
+    }
+
+    fn start_sector(&mut self, sector: usize, seq: u32) -> Option<()> {
+        let at = sector * SECTOR;
+        unsafe {
+            if esp_partition_erase_range(self.partition, at, SECTOR) != ESP_OK {
+                return None;
+            }
+            let header = seq.to_le_bytes();
+            if esp_partition_write(self.partition, at, header.as_ptr() as *const c_void, HEADER)
+                != ESP_OK
+            {
+                return None;
+            }
+        }
+        self.sector = sector;
+        self.seq = seq;
+        self.offset = HEADER;
+        Some(())
+    }
+
+    // append what's in memory, moving on to (and erasing) the next sector
+    // as each fills up. It's dropped if the flash won't take it
+    fn flush(&mut self) {
+        self.flushed = Instant::now();
+        let pending = std::mem::take(&mut self.pending);
+        let mut rest = &pending[..];
+        while !rest.is_empty() {
+            if self.offset >= SECTOR
+                && self
+                    .start_sector((self.sector + 1) % self.sectors, self.seq.wrapping_add(1))
+                    .is_none()
+            {
+                return;
+            }
+            let len = rest.len().min(SECTOR - self.offset);
+            let ret = unsafe {
+                esp_partition_write(
+                    self.partition,
+                    self.sector * SECTOR + self.offset,
+                    rest.as_ptr() as *const c_void,
+                    len,
+                )
+            };
+            if ret != ESP_OK {
+                return;
+            }
+            self.offset += len;
+            rest = &rest[len..];
+        }
+    }
+
+    // the sectors' text in order, oldest first
+    fn read(&self) -> Vec<u8> {
+        let mut sectors: Vec<(usize, u32)> = (0..self.sectors)
+            .filter_map(|s| self.seq_of(s).map(|seq| (s, seq)))
+            .collect();
+        sectors.sort_by_key(|(_, seq)| *seq);
+        let mut log = vec![];
+        let mut data = vec![0_u8; SECTOR - HEADER];
+        for (sector, _) in sectors {
+            if self.read_at(sector * SECTOR + HEADER, &mut data).is_some() {
+                let end = data.iter().position(|b| *b == 0xff).unwrap_or(data.len());
+                log.extend_from_slice(&data[..end]);
+            }
+        }
+        log
+    }
+}
@@ -0,0 +1,285 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{BufRead, BufReader, Seek, SeekFrom},
+    sync::{Arc, Mutex},
+};
+
+use embedded_graphics::{
+    mono_font::MonoFont,
+    pixelcolor::Rgb565,
+    prelude::{DrawTarget, Point, Size},
+    primitives::Rectangle,
+    Pixel,
+};
+use log::*;
+use profont::PROFONT_24_POINT;
+
+use crate::{files::open_file, Error, Result};
+
+// glyphs kept in memory per font, the least recently used go first
+const GLYPH_CACHE: usize = 128;
+
+// a font for DrawCmd::Text: built in, or by name from the asset bundle
+// or a BDF file (fonts/<name>.bdf) on the config partition or SD card
+#[derive(Debug, Clone, PartialEq)]
+pub enum Font {
+    Mono(MonoFont<'static>),
+    Named(String),
+}
+
+impl From<MonoFont<'static>> for Font {
+    fn from(font: MonoFont<'static>) -> Font {
+        Font::Mono(font)
+    }
+}
+
+// one character of a BDF font
+pub struct Glyph {
+    // the bitmap's size and where it goes from the baseline, y up
+    size: Size,
+    offset: Point,
+    // how far the next character starts
+    advance: i32,
+    // rows of bits, padded to whole bytes, high bit on the left
+    bits: Vec<u8>,
+}
+
+impl Glyph {
+    fn is_set(&self, x: u32, y: u32) -> bool {
+        let stride = (self.size.width + 7) / 8;
+        let byte = self.bits.get((y * stride + x / 8) as usize);
+        byte.map_or(false, |b| b & (0x80 >> (x % 8)) != 0)
+    }
+}
+
+// a BDF font, with where each character is in the file so only the
+// glyphs in use are read
+pub struct BdfFont {
+    file: BufReader<File>,
+    ascent: i32,
+    descent: i32,
+    // the width of a character missing from the font
+    default_advance: i32,
+    index: HashMap<u32, u64>,
+    cache: VecDeque<(u32, Arc<Glyph>)>,
+}
+
+static LINE_FONT: Mutex<Option<String>> = Mutex::new(None);
+
+static BDF_FONTS: Mutex<Option<HashMap<String, Option<BdfFont>>>> = Mutex::new(None);
+
+// the font for the home page's lines, from the config's `font`
+pub fn set_line_font(name: Option<String>) {
+    if let Ok(mut font) = LINE_FONT.lock() {
+        *font = name;
+    }
+}
+
+pub fn line_font() -> Font {
+    match LINE_FONT.lock().ok().and_then(|f| f.clone()) {
+        Some(name) => Font::Named(name),
+        None => PROFONT_24_POINT.into(),
+    }
+}
+
+// with a BDF font by name, read the first time it's used. A font that
+// can't be read isn't tried again
+pub fn with_bdf_font<T>(name: &str, f: impl FnOnce(&mut BdfFont) -> T) -> Option<T> {
+    let mut fonts = BDF_FONTS.lock().ok()?;
+    let fonts = fonts.get_or_insert_with(HashMap::new);
+    let font = fonts.entry(name.to_string()).or_insert_with(|| {
+        match open_file(&format!("fonts/{}.bdf", name)).and_then(BdfFont::open) {
+            Ok(font) => Some(font),
+            Err(e) => {
+                info!("Failed to load font {} error {:?}", name, e);
+                None
+            }
+        }
+    });
+    font.as_mut().map(f)
+}
+
+impl BdfFont {
+    // read through the file once for the font's size and where each
+    // character starts
+    fn open(file: File) -> Result<BdfFont> {
+        let mut file = BufReader::new(file);
+        let (mut ascent, mut descent, mut default_advance) = (0, 0, 0);
+        let mut index = HashMap::new();
+        let mut pos = 0_u64;
+        let mut char_start = 0_u64;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = file.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("STARTCHAR") => char_start = pos,
+                Some("ENCODING") => {
+                    if let Some(code) = words.next().and_then(|c| c.parse::<u32>().ok()) {
+                        index.insert(code, char_start);
+                    }
+                }
+                Some("FONT_ASCENT") => ascent = number(words.next()),
+                Some("FONT_DESCENT") => descent = number(words.next()),
+                Some("FONTBOUNDINGBOX") => {
+                    default_advance = number(words.next());
+                    let height = number(words.next());
+                    words.next();
+                    let y_offset = number(words.next());
+                    // in case there's no FONT_ASCENT or FONT_DESCENT
+                    if ascent == 0 {
+                        ascent = height + y_offset;
+                        descent = -y_offset;
+                    }
+                }
+                _ => {}
+            }
+            pos += read as u64;
+        }
+        if index.is_empty() {
+            return Err(Error::Config("No characters in the font".into()));
+        }
+        Ok(BdfFont {
+            file,
+            ascent,
+            descent,
+            default_advance,
+            index,
+            cache: VecDeque::new(),
+        })
+    }
+
+    pub fn height(&self) -> i32 {
+        self.ascent + self.descent
+    }
+
+    pub fn ascent(&self) -> i32 {
+        self.ascent
+    }
+
+    pub fn glyph(&mut self, c: char) -> Option<Arc<Glyph>> {
+        let code = c as u32;
+        if let Some(i) = self.cache.iter().position(|(cached, _)| *cached == code) {
+            let cached = self.cache.remove(i)?;
+            let glyph = cached.1.clone();
+            self.cache.push_back(cached);
+            return Some(glyph);
+        }
+
+        let at = *self.index.get(&code)?;
+        let glyph = match self.read_glyph(at) {
+            Ok(glyph) => Arc::new(glyph),
+            Err(e) => {
+                info!("Failed to read glyph {} error {:?}", code, e);
+                return None;
+            }
+        };
+        self.cache.push_back((code, glyph.clone()));
+        if self.cache.len() > GLYPH_CACHE {
+            self.cache.pop_front();
+        }
+        Some(glyph)
+    }
+
+    fn read_glyph(&mut self, at: u64) -> Result<Glyph> {
+        self.file.seek(SeekFrom::Start(at))?;
+        let mut glyph = Glyph {
+            size: Size::zero(),
+            offset: Point::zero(),
+            advance: self.default_advance,
+            bits: vec![],
+        };
+        let mut in_bitmap = false;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.file.read_line(&mut line)? == 0 {
+                return Err(Error::Config("The glyph isn't finished".into()));
+            }
+            let mut words = line.split_whitespace();
+            let first = match words.next() {
+                Some(w) => w,
+                None => continue,
+            };
+            match first {
+                "ENDCHAR" => return Ok(glyph),
+                "DWIDTH" => glyph.advance = number(words.next()),
+                "BBX" => {
+                    let width = number(words.next()).max(0) as u32;
+                    let height = number(words.next()).max(0) as u32;
+                    glyph.size = Size::new(width, height);
+                    glyph.offset = Point::new(number(words.next()), number(words.next()));
+                }
+                "BITMAP" => in_bitmap = true,
+                hex if in_bitmap => {
+                    let stride = ((glyph.size.width + 7) / 8) as usize;
+                    let mut row: Vec<u8> = (0..hex.len() / 2)
+                        .filter_map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok())
+                        .collect();
+                    row.resize(stride, 0);
+                    glyph.bits.extend_from_slice(&row);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // the width of some text in pixels
+    pub fn text_width(&mut self, text: &str) -> i32 {
+        text.chars()
+            .map(|c| self.glyph(c).map_or(self.default_advance, |g| g.advance))
+            .sum()
+    }
+
+    // draw text with its baseline starting at `baseline`, only the set
+    // pixels are drawn
+    pub fn draw<D: DrawTarget<Color = Rgb565>>(
+        &mut self,
+        target: &mut D,
+        text: &str,
+        baseline: Point,
+        color: Rgb565,
+    ) -> Result<(), D::Error> {
+        let mut x = baseline.x;
+        for c in text.chars() {
+            let glyph = match self.glyph(c) {
+                Some(g) => g,
+                None => {
+                    x += self.default_advance;
+                    continue;
+                }
+            };
+            let top_left = Point::new(
+                x + glyph.offset.x,
+                baseline.y - glyph.offset.y - glyph.size.height as i32 + 1,
+            );
+            let pixels = (0..glyph.size.height)
+                .flat_map(|y| (0..glyph.size.width).map(move |x| (x, y)))
+                .filter(|(x, y)| glyph.is_set(*x, *y))
+                .map(|(x, y)| Pixel(top_left + Point::new(x as i32, y as i32), color));
+            target.draw_iter(pixels)?;
+            x += glyph.advance;
+        }
+        Ok(())
+    }
+
+    // where the text goes with its baseline at `baseline`
+    pub fn bounding_box(&mut self, text: &str, baseline: Point) -> Rectangle {
+        Rectangle::new(
+            baseline - Point::new(0, self.ascent - 1),
+            Size::new(
+                self.text_width(text).max(0) as u32,
+                self.height().max(0) as u32,
+            ),
+        )
+    }
+}
+
+fn number(word: Option<&str>) -> i32 {
+    word.and_then(|w| w.parse().ok()).unwrap_or(0)
+}
@@ -0,0 +1,394 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use chrono::{DateTime, FixedOffset};
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::Result;
+
+// a message from HA's websocket API. Only the fields the device uses are
+// deserialized, anything else is skipped
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HaMessage {
+    AuthRequired,
+    AuthOk,
+    AuthInvalid {
+        #[serde(default)]
+        message: String,
+    },
+    Pong,
+    Event {
+        id: i64,
+        // boxed, the other messages are much smaller
+        event: Box<HaEvent>,
+    },
+    Result(ResultMsg),
+    #[serde(other)]
+    Other,
+}
+
+// a message and the HA instance it came from, None for the default one
+#[derive(Debug, Clone, PartialEq)]
+pub struct FromHa {
+    pub instance: Option<String>,
+    pub msg: HaMessage,
+}
+
+// an event from a subscription, either from the event bus or a trigger
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HaEvent {
+    #[serde(default)]
+    pub event_type: String,
+    #[serde(default)]
+    pub data: Value,
+    // subscribe_trigger events have the trigger details here
+    #[serde(default)]
+    pub variables: Option<TriggerVariables>,
+    // render_template events have the rendered text here
+    #[serde(default)]
+    pub result: Option<Value>,
+    // persistent_notification/subscribe events: "current", "added",
+    // "updated" or "removed" and the notifications by id
+    #[serde(default, rename = "type")]
+    pub update_type: Option<String>,
+    #[serde(default)]
+    pub notifications: Option<HashMap<String, PersistentNotification>>,
+    // when HA fired a bus event, only set on ones that came live from HA
+    #[serde(default)]
+    pub time_fired: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PersistentNotification {
+    #[serde(default)]
+    pub title: Option<String>,
+    #[serde(default)]
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TriggerVariables {
+    pub trigger: StateTrigger,
+}
+
+// the trigger variables of a state trigger
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StateTrigger {
+    #[serde(default)]
+    pub entity_id: String,
+    // time_pattern triggers have the time they fired instead
+    #[serde(default)]
+    pub now: Option<String>,
+    #[serde(default)]
+    pub from_state: Option<EntityState>,
+    #[serde(default)]
+    pub to_state: Option<EntityState>,
+}
+
+// the data of a state_changed event
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StateChanged {
+    pub entity_id: String,
+    #[serde(default)]
+    pub old_state: Option<EntityState>,
+    #[serde(default)]
+    pub new_state: Option<EntityState>,
+}
+
+// the state of an entity as HA reports it (websocket and REST)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EntityState {
+    pub entity_id: String,
+    pub state: String,
+    #[serde(default)]
+    pub attributes: serde_json::Map<String, Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_updated: Option<String>,
+}
+
+// the reply to a command
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ResultMsg {
+    pub id: i64,
+    pub success: bool,
+    #[serde(default)]
+    pub result: Value,
+    #[serde(default)]
+    pub error: Option<HaError>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct HaError {
+    #[serde(default)]
+    pub code: String,
+    #[serde(default)]
+    pub message: String,
+}
+
+impl HaMessage {
+    // a state_changed event for a state that came some other way than
+    // the websocket (MQTT, webhook)
+    pub fn state_changed(state: &EntityState) -> HaMessage {
+        HaMessage::Event {
+            id: 0,
+            event: Box::new(HaEvent {
+                event_type: "state_changed".into(),
+                data: json!({
+                    "entity_id": state.entity_id,
+                    "new_state": state,
+                }),
+                variables: None,
+                result: None,
+                update_type: None,
+                notifications: None,
+                time_fired: None,
+            }),
+        }
+    }
+}
+
+impl HaEvent {
+    // the state change carried by a state_changed event or a state trigger
+    pub fn state_changed(&self) -> Option<StateChanged> {
+        if let Some(vars) = &self.variables {
+            if vars.trigger.entity_id.is_empty() {
+                return None;
+            }
+            let trigger = vars.trigger.clone();
+            return Some(StateChanged {
+                entity_id: trigger.entity_id,
+                old_state: trigger.from_state,
+                new_state: trigger.to_state,
+            });
+        }
+        if self.event_type == "state_changed" {
+            return StateChanged::deserialize(&self.data).ok();
+        }
+        None
+    }
+
+    // HA's idea of the current time, from a time_pattern trigger or when
+    // it fired an event. For setting the clock when NTP is blocked. A
+    // state's last_updated isn't used, it can be from long ago
+    pub fn ha_time(&self) -> Option<DateTime<FixedOffset>> {
+        let time = match &self.variables {
+            Some(vars) => vars.trigger.now.clone(),
+            None => self.time_fired.clone(),
+        }?;
+        DateTime::parse_from_rfc3339(&time).ok()
+    }
+}
+
+impl ResultMsg {
+    // the states in the reply to get_states
+    pub fn states(&self) -> Option<Vec<EntityState>> {
+        if !self.result.is_array() {
+            return None;
+        }
+        Vec::<EntityState>::deserialize(&self.result).ok()
+    }
+}
+
+// a websocket frame holds one message, or an array of them with
+// coalesce_messages
+pub fn parse_frame(text: &str) -> Result<Vec<HaMessage>> {
+    if text.trim_start().starts_with('[') {
+        Ok(serde_json::from_str(text)?)
+    } else {
+        Ok(vec![serde_json::from_str(text)?])
+    }
+}
+
+// the largest message pieced together from partial frames
+const MAX_MESSAGE: usize = 32 * 1024;
+
+// pieces together text messages split across several frames. The
+// callback doesn't say where a frame sits in the message, so pieces are
+// collected until they parse. Messages that are dropped are counted in
+// `truncated`
+#[derive(Default)]
+pub struct FrameAssembler {
+    buf: String,
+}
+
+impl FrameAssembler {
+    pub fn push(&mut self, data: &str, truncated: &AtomicU32) -> Option<Vec<HaMessage>> {
+        if self.buf.is_empty() {
+            if let Ok(messages) = parse_frame(data) {
+                return Some(messages);
+            }
+            self.buf.push_str(data);
+            return None;
+        }
+
+        self.buf.push_str(data);
+        if let Ok(messages) = parse_frame(&self.buf) {
+            self.buf.clear();
+            return Some(messages);
+        }
+
+        // a piece went missing, start again from a whole message
+        if let Ok(messages) = parse_frame(data) {
+            truncated.fetch_add(1, Ordering::Relaxed);
+            self.buf.clear();
+            return Some(messages);
+        }
+
+        if self.buf.len() > MAX_MESSAGE {
+            info!("Dropping a {} byte partial message", self.buf.len());
+            truncated.fetch_add(1, Ordering::Relaxed);
+            self.buf.clear();
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(frame: &str) -> HaEvent {
+        match parse_frame(frame).unwrap().pop() {
+            Some(HaMessage::Event { event, .. }) => *event,
+            msg => panic!("not an event {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn a_frame_with_one_message() {
+        let msgs = parse_frame(r#"{"type": "auth_ok", "ha_version": "2024.1.0"}"#).unwrap();
+        assert_eq!(msgs, vec![HaMessage::AuthOk]);
+    }
+
+    #[test]
+    fn a_coalesced_frame() {
+        let frame = r#"[
+            {"type": "pong", "id": 3},
+            {"type": "result", "id": 4, "success": false,
+                "error": {"code": "not_found", "message": "Entity not found"}}
+        ]"#;
+        let msgs = parse_frame(frame).unwrap();
+        assert_eq!(msgs.len(), 2);
+        assert_eq!(msgs[0], HaMessage::Pong);
+        match &msgs[1] {
+            HaMessage::Result(result) => {
+                assert_eq!(result.id, 4);
+                assert!(!result.success);
+                assert_eq!(result.error.as_ref().unwrap().code, "not_found");
+            }
+            msg => panic!("not a result {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn a_trigger_carries_the_state_change() {
+        let event = event(
+            r#"{"type": "event", "id": 2, "event": {"variables": {"trigger": {
+                "platform": "state", "entity_id": "light.kitchen",
+                "from_state": {"entity_id": "light.kitchen", "state": "off"},
+                "to_state": {"entity_id": "light.kitchen", "state": "on",
+                    "attributes": {"brightness": 255}}
+            }}}}"#,
+        );
+        let changed = event.state_changed().unwrap();
+        assert_eq!(changed.entity_id, "light.kitchen");
+        assert_eq!(changed.old_state.unwrap().state, "off");
+        let new_state = changed.new_state.unwrap();
+        assert_eq!(new_state.state, "on");
+        assert_eq!(new_state.attributes["brightness"], 255);
+    }
+
+    #[test]
+    fn a_state_changed_event() {
+        let event = event(
+            r#"{"type": "event", "id": 1, "event": {"event_type": "state_changed",
+                "time_fired": "2024-01-02T03:04:05.000000+00:00",
+                "data": {"entity_id": "sensor.outside", "old_state": null,
+                    "new_state": {"entity_id": "sensor.outside", "state": "21.5"}}}}"#,
+        );
+        let changed = event.state_changed().unwrap();
+        assert_eq!(changed.entity_id, "sensor.outside");
+        assert_eq!(changed.old_state, None);
+        assert_eq!(changed.new_state.unwrap().state, "21.5");
+        assert_eq!(
+            event.ha_time().unwrap().to_rfc3339(),
+            "2024-01-02T03:04:05+00:00"
+        );
+    }
+
+    #[test]
+    fn a_time_pattern_trigger_has_the_time() {
+        let event = event(
+            r#"{"type": "event", "id": 5, "event": {"variables": {"trigger": {
+                "platform": "time_pattern", "now": "2024-06-01T12:30:00.123456+02:00"
+            }}}}"#,
+        );
+        assert_eq!(event.state_changed(), None);
+        assert_eq!(
+            event.ha_time().unwrap().to_rfc3339(),
+            "2024-06-01T12:30:00.123456+02:00"
+        );
+    }
+
+    #[test]
+    fn an_unknown_type_is_other() {
+        let msgs = parse_frame(r#"{"type": "supported_features", "id": 6}"#).unwrap();
+        assert_eq!(msgs, vec![HaMessage::Other]);
+    }
+
+    #[test]
+    fn a_message_in_pieces_is_put_back_together() {
+        let truncated = AtomicU32::new(0);
+        let mut frames = FrameAssembler::default();
+        assert_eq!(frames.push(r#"{"type": "auth_"#, &truncated), None);
+        assert_eq!(frames.push(r#"invalid", "message": "#, &truncated), None);
+        assert_eq!(
+            frames.push(r#""Bad token"}"#, &truncated),
+            Some(vec![HaMessage::AuthInvalid {
+                message: "Bad token".into()
+            }])
+        );
+        assert_eq!(truncated.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn a_whole_message_replaces_a_broken_one() {
+        let truncated = AtomicU32::new(0);
+        let mut frames = FrameAssembler::default();
+        assert_eq!(frames.push(r#"{"type": "res"#, &truncated), None);
+        assert_eq!(
+            frames.push(r#"{"type": "pong", "id": 7}"#, &truncated),
+            Some(vec![HaMessage::Pong])
+        );
+        assert_eq!(truncated.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn an_oversized_message_is_dropped() {
+        let truncated = AtomicU32::new(0);
+        let mut frames = FrameAssembler::default();
+        let start = r#"{"type": "result", "id": 1, "success": true, "result": ""#;
+        assert_eq!(frames.push(start, &truncated), None);
+        let piece = "x".repeat(MAX_MESSAGE / 4);
+        for _ in 0..4 {
+            assert_eq!(frames.push(&piece, &truncated), None);
+        }
+        assert_eq!(truncated.load(Ordering::Relaxed), 1);
+
+        // its end doesn't parse on its own, and what comes after it does
+        assert_eq!(frames.push(r#""}"#, &truncated), None);
+        assert_eq!(
+            frames.push(r#"{"type": "auth_ok"}"#, &truncated),
+            Some(vec![HaMessage::AuthOk])
+        );
+        assert_eq!(
+            frames.push(r#"{"type": "pong", "id": 8}"#, &truncated),
+            Some(vec![HaMessage::Pong])
+        );
+    }
+}
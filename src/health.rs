@@ -0,0 +1,57 @@
+use std::sync::atomic::Ordering;
+
+use serde_json::{json, Value};
+
+use crate::{memory::heap_info, stats::stats_totals, status::Status};
+
+// the event fired now and then with how the panel is doing
+pub const HEALTH_EVENT: &str = "homer_health";
+
+// below these the panel is unhealthy
+const WEAK_RSSI: i32 = -80;
+const LOW_HEAP: u32 = 20_000;
+const SMALL_BLOCK: u32 = 8_000;
+
+// what's wrong with the panel, nothing when it's healthy
+pub fn problems(status: &Status) -> Vec<&'static str> {
+    let mut problems = vec![];
+    let rssi = status.rssi.load(Ordering::Relaxed);
+    // 0 is no AP, which shows as HA being disconnected too
+    if rssi != 0 && rssi < WEAK_RSSI {
+        problems.push("weak_wifi");
+    }
+    let heap = heap_info();
+    if heap.free < LOW_HEAP || heap.largest_block < SMALL_BLOCK {
+        problems.push("low_memory");
+    }
+    if !status.ha_connected.load(Ordering::Relaxed) {
+        problems.push("ha_disconnected");
+    }
+    if status.battery_low.load(Ordering::Relaxed) {
+        problems.push("battery_low");
+    }
+    // the power supply couldn't keep up, until the next clean boot
+    if stats_totals(status).reset_reason == "brownout" {
+        problems.push("brownout");
+    }
+    problems
+}
+
+// the health event's data, for a fleet dashboard. The reconnects are
+// this boot's, the brownouts all of them
+pub fn health_data(status: &Status) -> Value {
+    let problems = problems(status);
+    let totals = stats_totals(status);
+    json!({
+        "healthy": problems.is_empty(),
+        "problems": problems,
+        "uptime": unsafe { esp_idf_sys::esp_timer_get_time() } / 1_000_000,
+        "rssi": status.rssi.load(Ordering::Relaxed),
+        "free_heap": heap_info().free,
+        "ws_reconnects": status.ws_reconnects.load(Ordering::Relaxed),
+        "wifi_reconnects": status.wifi_reconnects.load(Ordering::Relaxed),
+        "reset_reason": totals.reset_reason,
+        "brownouts": totals.brownouts,
+        "firmware": env!("CARGO_PKG_VERSION"),
+    })
+}
@@ -0,0 +1,163 @@
+use crate::{
+    buttons::ButtonEvent,
+    states::{Optimistic, States},
+    util::{HAAction, HAConnect},
+    widgets::{self, WidgetState},
+};
+
+// what a button does to an item of the home page
+#[derive(Debug, PartialEq)]
+pub enum HomePress {
+    // the item doesn't use the button
+    Ignored,
+    // its entity is unavailable, so nothing is sent
+    Unavailable,
+    // these go to the item's HA instance, and the page is drawn again
+    Send(Vec<HAAction>),
+    // the sensors that are on get listed on a page of their own
+    List,
+    // an alarm asks for the code for this service first
+    Code(&'static str),
+    // a light is adjusted on a page of its own
+    Adjust,
+}
+
+// a button pressed on the home page, the same on the device and in the
+// simulator. A Button item's state is flipped right away unless
+// `optimistic_timeout` is 0, it goes back if HA doesn't confirm it
+pub fn on_home_button(
+    c: &HAConnect,
+    event: ButtonEvent,
+    states: &mut States,
+    optimistic: &mut Optimistic,
+    optimistic_timeout: u64,
+    widget_state: &mut WidgetState,
+) -> HomePress {
+    match c {
+        // only short presses fire a Button's actions
+        HAConnect::Button {
+            button,
+            cmp,
+            action_off,
+            action_on,
+            ..
+        } => {
+            if event != ButtonEvent::Press(*button as usize) {
+                return HomePress::Ignored;
+            }
+            // don't fire actions at dead entities
+            if states
+                .get(&c.state_key())
+                .map_or(false, |e| !e.is_available())
+            {
+                return HomePress::Unavailable;
+            }
+            let on = c.is_on(states);
+            let cmds = if on { action_off } else { action_on };
+            if optimistic_timeout > 0 {
+                let state = if on { cmp.off_state() } else { cmp.on_state() };
+                optimistic.flip(c.state_key(), states, state);
+            }
+            HomePress::Send(cmds.as_slice().to_vec())
+        }
+        c if widgets::uses_button(c, event.button()) => {
+            if widgets::opens_list(c, event) {
+                return HomePress::List;
+            }
+            if widgets::button_unavailable(c, states, event.button()) {
+                return HomePress::Unavailable;
+            }
+            let key = widgets::button_key(c, event.button());
+            if let Some(service) = widgets::code_service(c, states.get(&key), event) {
+                return HomePress::Code(service);
+            }
+            if widgets::opens_adjust(c, event) {
+                return HomePress::Adjust;
+            }
+            HomePress::Send(widgets::on_button(
+                c,
+                states.get_mut(&key),
+                event,
+                widget_state,
+            ))
+        }
+        _ => HomePress::Ignored,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::states::Entity;
+
+    fn button() -> HAConnect {
+        serde_json::from_value(json!({"Button": {
+            "button": 0, "ha_id": "light.kitchen", "cmp": {"Str": "on"}, "text_on": "On",
+            "text_off": "Off", "action_on": {"Scene": "scene.on"}, "action_off": {"Scene": "scene.off"},
+            "color": 0,
+        }}))
+        .unwrap()
+    }
+
+    fn states(state: &str) -> States {
+        let entity = Entity {
+            state: state.into(),
+            ..Default::default()
+        };
+        [("light.kitchen".to_string(), entity)]
+            .into_iter()
+            .collect()
+    }
+
+    fn press(c: &HAConnect, event: ButtonEvent, states: &mut States, timeout: u64) -> HomePress {
+        let mut optimistic = Optimistic::new();
+        on_home_button(
+            c,
+            event,
+            states,
+            &mut optimistic,
+            timeout,
+            &mut WidgetState::new(),
+        )
+    }
+
+    #[test]
+    fn a_press_sends_the_actions_and_flips_the_state() {
+        let mut states = states("off");
+        let press = press(&button(), ButtonEvent::Press(0), &mut states, 5);
+        assert_eq!(
+            press,
+            HomePress::Send(vec![HAAction::Scene("scene.on".into())])
+        );
+        assert_eq!(states["light.kitchen"].state, "on");
+    }
+
+    #[test]
+    fn no_flip_without_an_optimistic_timeout() {
+        let mut states = states("on");
+        let press = press(&button(), ButtonEvent::Press(0), &mut states, 0);
+        assert_eq!(
+            press,
+            HomePress::Send(vec![HAAction::Scene("scene.off".into())])
+        );
+        assert_eq!(states["light.kitchen"].state, "on");
+    }
+
+    #[test]
+    fn other_buttons_and_long_presses_are_ignored() {
+        let mut states = states("off");
+        for event in [ButtonEvent::Press(1), ButtonEvent::LongPress(0)] {
+            assert_eq!(press(&button(), event, &mut states, 5), HomePress::Ignored);
+        }
+    }
+
+    #[test]
+    fn an_unavailable_entity_sends_nothing() {
+        let mut states = states("unavailable");
+        let press = press(&button(), ButtonEvent::Press(0), &mut states, 5);
+        assert_eq!(press, HomePress::Unavailable);
+        assert_eq!(states["light.kitchen"].state, "unavailable");
+    }
+}
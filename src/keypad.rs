@@ -0,0 +1,116 @@
+use std::sync::mpsc::Sender;
+
+use embedded_graphics::{
+    pixelcolor::Rgb565,
+    prelude::{Point, RgbColor},
+};
+use profont::PROFONT_24_POINT;
+use serde_json::json;
+
+use crate::{
+    buttons::ButtonEvent,
+    display::{DrawCmd, DrawPos},
+    pages::Page,
+    util::HAAction,
+    Result,
+};
+
+// the longest code that can be entered
+const MAX_DIGITS: usize = 10;
+
+// a code being entered with the three buttons: the first steps the
+// digit, the second adds it to the code and the third sends the code.
+// Holding the first deletes the last digit, holding the third cancels
+pub struct CodeEntry {
+    pub instance: Option<String>,
+    // the alarm_control_panel entity and the service to call with the code
+    ha_id: String,
+    service: String,
+    digits: String,
+    current: u8,
+}
+
+pub enum EntryResult {
+    Continue,
+    Cancel,
+    // the service call with the code
+    Done(HAAction),
+}
+
+impl CodeEntry {
+    pub fn new(instance: Option<String>, ha_id: &str, service: &str) -> CodeEntry {
+        CodeEntry {
+            instance,
+            ha_id: ha_id.into(),
+            service: service.into(),
+            digits: String::new(),
+            current: 0,
+        }
+    }
+
+    pub fn on_button(&mut self, event: ButtonEvent) -> EntryResult {
+        match event {
+            ButtonEvent::Press(0) => self.current = (self.current + 1) % 10,
+            ButtonEvent::Press(1) if self.digits.len() < MAX_DIGITS => {
+                self.digits.push((b'0' + self.current) as char);
+                self.current = 0;
+            }
+            ButtonEvent::Press(2) => {
+                return EntryResult::Done(HAAction::service(
+                    "alarm_control_panel",
+                    &self.service,
+                    &self.ha_id,
+                    json!({ "code": self.digits }),
+                ))
+            }
+            ButtonEvent::LongPress(0) => {
+                self.digits.pop();
+            }
+            ButtonEvent::LongPress(2) => return EntryResult::Cancel,
+            _ => {}
+        }
+        EntryResult::Continue
+    }
+}
+
+// draw the code entry page, the digits entered so far are masked
+pub fn render_entry(entry: &CodeEntry, display_tx: &Sender<DrawCmd>) -> Result<()> {
+    display_tx.send(DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(10, 20)),
+        font: Some(PROFONT_24_POINT.into()),
+        text: format!("Code to {}", entry.service.trim_start_matches("alarm_")),
+        text_color: Rgb565::BLUE,
+        background: Some(RgbColor::WHITE),
+    })?;
+    // padded so a deleted digit gets covered
+    let masked = format!(
+        "{}{:<width$}",
+        "*".repeat(entry.digits.len()),
+        entry.current,
+        width = MAX_DIGITS + 1 - entry.digits.len()
+    );
+    display_tx.send(DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(10, 100)),
+        font: Some(PROFONT_24_POINT.into()),
+        text: masked,
+        text_color: RgbColor::BLACK,
+        background: Some(RgbColor::WHITE),
+    })?;
+    display_tx.send(DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(10, 150)),
+        font: None,
+        text: "Hold Digit to delete, OK to cancel".into(),
+        text_color: RgbColor::BLACK,
+        background: Some(RgbColor::WHITE),
+    })?;
+    for (i, label) in Page::Code.button_labels().iter().enumerate() {
+        display_tx.send(DrawCmd::Text {
+            pos: DrawPos::Button(i as u8),
+            font: None,
+            text: label.to_string(),
+            text_color: RgbColor::BLACK,
+            background: Some(RgbColor::WHITE),
+        })?;
+    }
+    Ok(())
+}
@@ -1,9 +1,119 @@
 pub mod display;
+#[cfg(feature = "hal")]
+pub mod screen;
 
+pub mod error;
+pub use error::{Error, Result};
+
+#[cfg(feature = "hal")]
+pub mod button_adc;
 pub mod buttons;
 
+#[cfg(feature = "hal")]
 pub mod wifi;
 
 pub mod util;
 
 pub mod files;
+
+#[cfg(feature = "hal")]
+pub mod flashlog;
+
+pub mod config;
+#[cfg(feature = "hal")]
+pub mod config_store;
+#[cfg(feature = "hal")]
+pub mod console;
+
+pub mod ha;
+
+#[cfg(feature = "hal")]
+pub mod health;
+
+// off the device the settings are kept in memory rather than in NVS
+#[cfg_attr(not(feature = "hal"), path = "sim_settings.rs")]
+pub mod settings;
+
+pub mod signing;
+
+#[cfg(feature = "hal")]
+pub mod status;
+
+#[cfg(feature = "hal")]
+pub mod stats;
+
+#[cfg(feature = "hal")]
+pub mod network;
+
+pub mod proxy;
+
+#[cfg(feature = "hal")]
+pub mod rest;
+
+#[cfg(feature = "hal")]
+pub mod remotelog;
+
+#[cfg(feature = "hal")]
+pub mod watchdog;
+
+#[cfg(feature = "hal")]
+pub mod pages;
+
+pub mod pending;
+
+#[cfg(feature = "hal")]
+pub mod power;
+
+pub mod states;
+
+#[cfg(feature = "hal")]
+pub mod mqtt;
+
+#[cfg(feature = "hal")]
+pub mod discovery;
+
+#[cfg(feature = "hal")]
+pub mod notify;
+
+#[cfg(feature = "hal")]
+pub mod webhook;
+
+#[cfg(feature = "hal")]
+pub mod web;
+#[cfg(feature = "hal")]
+pub mod webauth;
+
+#[cfg(feature = "hal")]
+pub mod oauth;
+
+#[cfg(feature = "hal")]
+pub mod dispatch;
+
+pub mod home;
+pub mod render;
+pub mod widgets;
+
+// the simulator's stand-in for HA
+#[cfg(not(feature = "hal"))]
+pub mod sim;
+
+pub mod adjust;
+pub mod assets;
+#[cfg(feature = "hal")]
+pub mod battery;
+#[cfg(feature = "hal")]
+pub mod camera;
+#[cfg(feature = "hal")]
+pub mod crash;
+#[cfg(feature = "hal")]
+pub mod doorbell;
+pub mod fonts;
+#[cfg(feature = "hal")]
+pub mod keypad;
+#[cfg(feature = "hal")]
+pub mod memory;
+#[cfg(feature = "hal")]
+pub mod ota;
+
+#[cfg(feature = "wireguard")]
+pub mod wireguard;
@@ -2,16 +2,16 @@ use anyhow::Result;
 use chrono::{Local, Timelike};
 use crossbeam::select;
 use embedded_graphics::{
-    pixelcolor::{raw::RawU16, Rgb565},
-    prelude::{Point, RgbColor},
+    pixelcolor::Rgb565,
+    prelude::{Point, RgbColor, Size},
+    primitives::Rectangle,
 };
 use esp_idf_hal::prelude::*;
 use esp_idf_svc::{eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition};
 use esp_idf_sys::{self as _, esp_read_mac, ESP_OK};
-use json::JsonValue;
+use serde_json::json;
 use std::{
     collections::HashMap,
-    ops::Deref,
     sync::{atomic::AtomicI32, mpsc::Sender},
 };
 // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
@@ -19,26 +19,105 @@ use log::*;
 
 use profont::PROFONT_24_POINT;
 
-use crossbeam::channel::bounded;
+use crossbeam::channel::{bounded, never};
 use homer::{
+    adjust::{Adjust, AdjustResult},
+    assets::load_assets,
+    battery::{run_battery_monitor, Battery},
+    button_adc::{button_loop, factory_reset_held},
     buttons::*,
+    camera::{run_cameras, Camera, CameraCmd, Snapshot},
+    config::{Config, HaConfig, SntpConfig},
+    config_store::{config_name, keep_config, roll_back_config, start_config_trial, CONFIG_TRIAL},
+    console::{run_console, ConsoleCmd},
+    crash::{install_panic_handler, PANIC_KEY},
+    dispatch::{Dispatcher, Filter},
     display::*,
-    files::{mount_spiffs, read_file},
+    doorbell::{self, render_doorbell},
+    files::{mount_files, mount_sd, open_file, SdPins, SD_PINS_KEY},
+    flashlog::{flush_log, init_logging},
+    fonts::set_line_font,
+    ha::{FromHa, HaMessage},
+    health::{health_data, HEALTH_EVENT},
+    home::{on_home_button, HomePress},
+    keypad::{render_entry, CodeEntry, EntryResult},
+    memory::{heap_info, log_memory, track_stack, MEMORY_LOG_INTERVAL},
+    mqtt::handle_mqtt,
+    network::NetworkManager,
+    notify::{render_notice, Notifications, DISMISS_BUTTON},
+    oauth::{run_token_refresher, OAuth},
+    ota::{
+        mark_valid, render_update, run_ota, update_state, OtaCmd, OtaProgress, OTA_EVENT,
+        OTA_URL_KEY, UPDATE_EVENT,
+    },
+    pages::{render_adjust, render_list, render_page, Page},
+    pending::PendingRequests,
+    power::{
+        log_wakeup, run_motion_sensor, set_power_mode, DisplaySleep, MotionWake, PowerManager,
+    },
+    proxy::run_proxy_forwarder,
+    remotelog::run_remote_log,
+    render::Renderer,
+    screen::draw_loop,
+    settings::{factory_reset, Settings},
+    states::{
+        apply_message, keep_attributes, load_cache, save_cache, state_key, value_text, Entity,
+        Optimistic, States,
+    },
+    stats::{
+        load_stats, reset_reason, save_stats, stats_totals, unexpected_reset, STATS_SAVE_INTERVAL,
+    },
+    status::Status,
     util::*,
+    watchdog::{
+        configure_task_watchdog, run_network_watchdog, run_task_watchdog, REBOOT_REASON_KEY,
+    },
+    web::start_web_server,
+    webauth::WebAuth,
+    webhook::handle_webhook,
+    widgets::{self, WidgetState},
     wifi::*,
+    Error,
 };
+#[cfg(feature = "wireguard")]
+use homer::{config::WireguardConfig, wireguard::run_wireguard};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     mpsc::{self},
-    Arc,
 };
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-static HAS_WIFI: AtomicBool = AtomicBool::new(false);
+static HAS_NETWORK: AtomicBool = AtomicBool::new(false);
 static HAS_TIME: AtomicBool = AtomicBool::new(false);
 static LAST_QUAD: AtomicI32 = AtomicI32::new(-1);
+static STATUS: Status = Status::new();
 
-fn fetch_config() -> Vec<HAConnect> {
+// how long to wait for the get_states reply before falling back to REST
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(15);
+
+// how long an error stays on the screen
+const ERROR_DISPLAY: Duration = Duration::from_secs(5);
+
+// how long the list page stays up without a button press
+const LIST_DISPLAY: Duration = Duration::from_secs(15);
+
+// how long a new firmware has to run before it's kept rather than
+// rolled back on the next boot
+const FIRMWARE_VALID_AFTER: Duration = Duration::from_secs(60);
+
+// the least time between saving the states to NVS
+const CACHE_INTERVAL: Duration = Duration::from_secs(300);
+
+// how long HA can be unreachable before the values are greyed out, so
+// quick reconnects don't flash the screen
+const OFFLINE_GRACE: Duration = Duration::from_secs(10);
+
+// how long a stopped proxy forwarder waits before starting again
+const PROXY_RETRY: Duration = Duration::from_secs(5);
+
+// the last 3 bytes of the MAC address, used to find the config file and
+// to identify the device to HA
+fn device_id() -> Option<String> {
     let mut mac_buffer: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
     let ok = unsafe {
         esp_read_mac(
@@ -46,33 +125,42 @@ fn fetch_config() -> Vec<HAConnect> {
             esp_idf_sys::esp_mac_type_t_ESP_MAC_WIFI_STA,
         )
     };
-    let filename: String = if ok == ESP_OK {
-        format!(
+    if ok == ESP_OK {
+        Some(format!(
             "{:02x}_{:02x}_{:02x}",
             mac_buffer[3], mac_buffer[4], mac_buffer[5],
-        )
+        ))
     } else {
-        "base".into()
-    };
+        None
+    }
+}
+
+// the config and whether a new one was rolled back, because it crashed
+// the device last time or doesn't parse
+fn fetch_config(settings: &Settings) -> (Config, bool) {
+    let filename: String = device_id().unwrap_or("base".into());
+    let mut rolled_back = start_config_trial(settings, &filename);
 
-    let conf_string = match read_file(&format!("{}.json", filename))
-        .or_else(|_| read_file("base.json"))
-        .ok()
-    {
-        Some(v) => v,
-        None => "this_is_bad".into(),
+    let load = || match open_file(&config_name(&filename)) {
+        Ok(file) => Config::load(file),
+        Err(_) => open_file("base.json").and_then(Config::load),
     };
-    match serde_json::from_str(&conf_string) {
-        Ok(v) => v,
-        Err(e) => {
-            info!("Failed to parse JSON for {} error {:?}", filename, e);
-            vec![HAConnect::Text {
-                line: 0,
-                text: "Failed to load config!".into(),
-                color: 0,
-            }]
+    let mut parsed = load();
+    if let Err(e) = &parsed {
+        info!("Failed to load the config for {} error {:?}", filename, e);
+        if roll_back_config(settings, &filename) {
+            rolled_back = true;
+            parsed = load();
         }
     }
+    // a config that's there but wrong says so, it's fixed differently
+    // from a partition that won't read
+    match parsed {
+        Ok(v) => (v, rolled_back),
+        Err(Error::Config(_)) => (Config::failed("Bad config!"), rolled_back),
+        Err(Error::Json(e)) if !e.is_io() => (Config::failed("Bad config!"), rolled_back),
+        Err(_) => (Config::failed("Failed to load config!"), rolled_back),
+    }
 }
 
 fn main() -> Result<()> {
@@ -80,8 +168,10 @@ fn main() -> Result<()> {
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
     esp_idf_sys::link_patches();
 
-    // Bind the log crate to the ESP Logging facilities
-    esp_idf_svc::log::EspLogger::initialize_default();
+    // Bind the log crate to the ESP Logging facilities, and keep the log
+    // on flash so it's still there after a crash
+    init_logging();
+    log_wakeup();
 
     // set timezone see https://www.gnu.org/software/libc/manual/html_node/TZ-Variable.html
     std::env::set_var("TZ", env!("HOMER_TZ"));
@@ -89,20 +179,144 @@ fn main() -> Result<()> {
         esp_idf_sys::tzset();
     };
 
-    let peripherals = Peripherals::take().unwrap();
+    let mut peripherals = Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take()?;
-    let pins = peripherals.pins;
-    let mut _nvs = EspDefaultNvsPartition::take()?;
+    let mut pins = peripherals.pins;
+    let nvs = EspDefaultNvsPartition::take()?;
+    let settings = Settings::new(nvs);
+
+    let mut crash = None;
+    if let Some(reason) = settings.get_str(REBOOT_REASON_KEY) {
+        info!("Last restart: {}", reason);
+        let _ = settings.remove(REBOOT_REASON_KEY);
+        crash = Some(reason);
+    }
+    if let Some(panic) = settings.get_str(PANIC_KEY) {
+        info!("Last panic: {}", panic);
+        let _ = settings.remove(PANIC_KEY);
+        crash = Some(panic);
+    }
+    load_stats(&settings, &STATUS, crash);
+
+    let filesystem = mount_files()?;
+
+    info!("{:?} mounted!", filesystem);
 
-    mount_spiffs()?;
+    // configs and certificates on an SD card win over the ones in flash
+    if let Some(pins) = settings.get_str(SD_PINS_KEY) {
+        match SdPins::parse(&pins).and_then(|pins| mount_sd(&pins)) {
+            Ok(()) => info!("SD card mounted"),
+            Err(e) => info!("Failed to mount the SD card error {:?}", e),
+        }
+    }
 
-    info!("Spiffs mounted!");
+    // the icons and fonts that aren't built in
+    match load_assets() {
+        Ok(count) => info!("{} assets", count),
+        Err(e) => info!("No asset bundle error {:?}", e),
+    }
+
+    // the config is selected by MAC address so it can be loaded
+    // before the network is up
+    let (config, config_rolled_back) = fetch_config(&settings);
+    let mut net_config = config.network.clone();
+    net_config.apply_settings(&settings);
+    let power_save = net_config.power_save;
+    let power_mode = config.power_mode;
+    if let Err(e) = set_power_mode(power_mode, power_save) {
+        info!("Failed to set the power mode error {:?}", e);
+    }
+    let hostname = net_config.hostname(&device_id().unwrap_or("base".into()));
+    let ha = config.ha.clone();
+    let sntp_config = config.sntp.clone();
+    set_line_font(config.font.clone());
+
+    if let Some(name) = &ha.ca_cert {
+        if let Err(e) = install_ca_cert(name) {
+            info!("Failed to install CA cert {} error {:?}", name, e);
+        }
+    }
+
+    // the threads that keep the panel going restart it if they get stuck
+    configure_task_watchdog();
+    let task_watchdog_settings = settings.clone();
+    std::thread::Builder::new()
+        .stack_size(3000)
+        .spawn(move || run_task_watchdog(task_watchdog_settings))?;
+
+    // with a proxy, HA is reached through the local forwarder
+    let ha_url: &'static str = match &ha.proxy {
+        Some(proxy) => {
+            let proxy = proxy.clone();
+            let local = format!("127.0.0.1:{}", proxy.local_port);
+            std::thread::Builder::new()
+                .stack_size(4000)
+                .spawn(move || {
+                    track_stack("proxy");
+                    // a forwarder that fails, say to bind its port, is
+                    // started again rather than taking the panel down
+                    loop {
+                        if let Err(e) = run_proxy_forwarder(&HAS_NETWORK, proxy.clone(), HA_URL) {
+                            error!("Proxy forwarder stopped error {:?}", e);
+                        }
+                        std::thread::sleep(PROXY_RETRY);
+                    }
+                })?;
+            Box::leak(local.into_boxed_str())
+        }
+        None => HA_URL,
+    };
+
+    // a token saved in NVS replaces the built in one. An OAuth access
+    // token replaces both when the request is made
+    let token = ha_token(&settings);
+    let ha_headers: [(&'static str, &'static str); 2] = [
+        ("Content-Type", "application/json"),
+        (
+            "Authorization",
+            &*Box::leak(format!("Bearer {}", token).into_boxed_str()),
+        ),
+    ];
+
+    // keeps an access token when the device has been linked with OAuth
+    let oauth = OAuth {
+        ha: ha.clone(),
+        ha_url,
+        public_url: HA_URL,
+        settings: settings.clone(),
+        status: &STATUS,
+    };
+    let refresher = oauth.clone();
+    std::thread::Builder::new()
+        .stack_size(6000)
+        .spawn(move || {
+            track_stack("oauth");
+            run_token_refresher(refresher, &HAS_NETWORK)
+        })?;
 
     let (display_tx, display_rx) = mpsc::channel::<DrawCmd>();
+    install_panic_handler(display_tx.clone(), settings.clone());
 
-    let (button_tx, button_rx) = bounded::<usize>(5);
+    let (button_tx, button_rx) = bounded::<ButtonEvent>(5);
+    let console_button_tx = button_tx.clone();
 
-    let (ha_tx, ha_rx) = bounded::<Arc<JsonValue>>(60);
+    // everything from HA goes through the dispatcher. Notifications and
+    // the doorbell get their own channels, so they aren't held up behind
+    // a burst of state changes
+    let (ha_tx, incoming_rx) = bounded::<FromHa>(60);
+    let dispatcher: &'static Dispatcher = Box::leak(Box::new(Dispatcher::new(&STATUS)));
+    let ha_rx = dispatcher.subscribe(Filter::All, 60);
+    let notify_rx = dispatcher.subscribe(Filter::Notifications, 5);
+    let doorbell_rx = match &config.doorbell {
+        Some(doorbell) => dispatcher.subscribe(Filter::EventType(doorbell.event.clone()), 2),
+        None => never(),
+    };
+    std::thread::Builder::new()
+        .stack_size(4000)
+        .spawn(move || {
+            track_stack("dispatch");
+            dispatcher.run(incoming_rx)
+        })?;
 
     let (socket_tx, socket_rx) = mpsc::channel::<SocketCmd>();
 
@@ -124,6 +338,8 @@ fn main() -> Result<()> {
             draw_loop(
                 display_rx,
                 pins.gpio45,
+                peripherals.ledc.timer0,
+                peripherals.ledc.channel0,
                 pins.gpio4,
                 pins.gpio48,
                 peripherals.spi2,
@@ -139,6 +355,16 @@ fn main() -> Result<()> {
         color: Rgb565::WHITE,
     })?;
 
+    // a button held from boot wipes what's been saved on the device
+    if factory_reset_held(&mut pins.gpio1, &mut peripherals.adc1, &display_tx)? {
+        info!("Factory reset");
+        factory_reset()?;
+        show_error("Reset done, restarting", &display_tx)?;
+        flush_log();
+        std::thread::sleep(Duration::from_secs(2));
+        esp_idf_hal::reset::restart();
+    }
+
     // start the thread that watches for button presses
     std::thread::Builder::new()
         .stack_size(3000)
@@ -146,129 +372,1218 @@ fn main() -> Result<()> {
             button_loop(button_tx, pins.gpio1, peripherals.adc1).unwrap();
         })?;
 
-    // start the thread that handles websockets
-    std::thread::Builder::new()
-        .stack_size(4000)
-        .spawn(move || {
-            handle_websocket(&HAS_WIFI, socket_tx, socket_rx, ha_tx, HA_AUTH, HA_URL).unwrap();
-        })?;
+    // a motion sensor wakes the screen. The sender is kept so the channel
+    // stays open without one
+    let (motion_tx, motion_rx) = bounded::<()>(1);
+    if let Some(gpio) = config.motion.as_ref().and_then(|m| m.gpio) {
+        let sensor_tx = motion_tx.clone();
+        std::thread::Builder::new()
+            .stack_size(3000)
+            .spawn(move || {
+                if let Err(e) = run_motion_sensor(gpio, sensor_tx) {
+                    info!("Motion sensor on GPIO {} failed error {:?}", gpio, e);
+                }
+            })?;
+    }
+
+    // the battery is read once before HA is told about it, then every minute
+    if let Some(battery_config) = config.battery.clone() {
+        match Battery::new(battery_config) {
+            Ok(battery) => {
+                battery.read(&STATUS);
+                std::thread::Builder::new()
+                    .stack_size(3000)
+                    .spawn(move || run_battery_monitor(battery, &STATUS))?;
+            }
+            Err(e) => info!("Failed to set up the battery error {:?}", e),
+        }
+    }
+
+    // the other HA instances each get a websocket. Their connection state
+    // is kept apart from the default instance's in the status
+    let mut instance_sockets: HashMap<String, Sender<SocketCmd>> = HashMap::new();
+    if config.mqtt.is_none() {
+        for instance in &config.instances {
+            let (tx, rx) = mpsc::channel::<SocketCmd>();
+            instance_sockets.insert(instance.name.clone(), tx.clone());
+            let target = HaTarget {
+                instance: Some(instance.name.clone()),
+                url: Box::leak(instance.url.clone().into_boxed_str()),
+                token: instance.token.clone(),
+                token_key: instance.token_key(),
+            };
+            let ctx = SocketCtx {
+                has_network: &HAS_NETWORK,
+                socket_tx: tx,
+                socket_rx: rx,
+                ha_tx: ha_tx.clone(),
+                ha: ha.clone(),
+                status: &STATUS,
+                entities: entity_ids(&config.items, Some(&instance.name)),
+            };
+            let ws_settings = settings.clone();
+            std::thread::Builder::new()
+                .stack_size(4000)
+                .spawn(move || {
+                    handle_websocket(ctx, target, ws_settings).unwrap();
+                })?;
+        }
+    }
+
+    // states pushed to the web server go to the main loop too
+    let web_ha_tx = ha_tx.clone();
+
+    // firmware updates can be asked for over MQTT as well as by event
+    let (ota_tx, ota_rx) = mpsc::channel::<OtaCmd>();
+    let (ota_progress_tx, ota_progress_rx) = bounded::<OtaProgress>(4);
+
+    // start the thread that talks to HA, over the websocket, MQTT or webhooks
+    let ws_ha = ha.clone();
+    let mut entities = entity_ids(&config.items, None);
+    // the entities that put the screen to sleep and wake it are watched too
+    let screen_ids = screen_entities(&config);
+    for id in &screen_ids {
+        if !entities.contains(id) {
+            entities.push(id.clone());
+        }
+    }
+    let ws_settings = settings.clone();
+    match config.mqtt.clone() {
+        Some(mqtt) => {
+            let client_id = hostname.clone();
+            let mqtt_display_tx = display_tx.clone();
+            let mqtt_ota_tx = ota_tx.clone();
+            std::thread::Builder::new()
+                .stack_size(4000)
+                .spawn(move || {
+                    track_stack("mqtt");
+                    let ctx = SocketCtx {
+                        has_network: &HAS_NETWORK,
+                        socket_tx,
+                        socket_rx,
+                        ha_tx,
+                        ha: ws_ha,
+                        status: &STATUS,
+                        entities,
+                    };
+                    handle_mqtt(ctx, mqtt_display_tx, mqtt_ota_tx, mqtt, client_id).unwrap();
+                })?;
+        }
+        None if ha.webhook => {
+            let device = device_id().unwrap_or("base".into());
+            let port = config.web.port;
+            std::thread::Builder::new()
+                .stack_size(8000)
+                .spawn(move || {
+                    track_stack("webhook");
+                    let ctx = SocketCtx {
+                        has_network: &HAS_NETWORK,
+                        socket_tx,
+                        socket_rx,
+                        ha_tx,
+                        ha: ws_ha,
+                        status: &STATUS,
+                        entities,
+                    };
+                    handle_webhook(ctx, ha_url, ha_headers, device, port).unwrap();
+                })?;
+        }
+        None => {
+            std::thread::Builder::new()
+                .stack_size(4000)
+                .spawn(move || {
+                    let ctx = SocketCtx {
+                        has_network: &HAS_NETWORK,
+                        socket_tx,
+                        socket_rx,
+                        ha_tx,
+                        ha: ws_ha,
+                        status: &STATUS,
+                        entities,
+                    };
+                    let target = HaTarget {
+                        instance: None,
+                        url: ha_url,
+                        token: HA_AUTH.to_string(),
+                        token_key: HA_TOKEN_KEY.to_string(),
+                    };
+                    handle_websocket(ctx, target, ws_settings).unwrap();
+                })?;
+        }
+    }
+
+    // camera images are fetched and decoded on a thread of their own.
+    // Without cameras the channel is held open by snapshot_tx
+    let cameras: Vec<Camera> = config.items.iter().filter_map(Camera::from_item).collect();
+    let (camera_tx, camera_rx) = mpsc::channel::<CameraCmd>();
+    let (snapshot_tx, snapshot_rx) = bounded::<Snapshot>(1);
+    let doorbell_camera = config
+        .doorbell
+        .as_ref()
+        .map_or(false, |d| d.camera.is_some());
+    if !cameras.is_empty() || doorbell_camera {
+        let camera_ha = ha.clone();
+        let camera_snapshot_tx = snapshot_tx.clone();
+        std::thread::Builder::new()
+            .stack_size(16000)
+            .spawn(move || {
+                track_stack("camera");
+                run_cameras(
+                    cameras,
+                    ha_url,
+                    &ha_headers,
+                    &camera_ha,
+                    &STATUS,
+                    &HAS_NETWORK,
+                    camera_rx,
+                    camera_snapshot_tx,
+                );
+            })?;
+    }
+
+    // firmware updates are checked for and written on a thread of their
+    // own, which restarts the device when it's done
+    let ota_url = settings
+        .get_str(OTA_URL_KEY)
+        .or_else(|| config.ota.manifest_url.clone());
+    let ota_enabled = ota_url.is_some();
+    if let Some(url) = ota_url {
+        let ota_config = config.ota.clone();
+        std::thread::Builder::new()
+            .stack_size(8000)
+            .spawn(move || {
+                track_stack("ota");
+                run_ota(
+                    ota_config,
+                    url,
+                    &HAS_NETWORK,
+                    &STATUS,
+                    ota_rx,
+                    ota_progress_tx,
+                );
+            })?;
+    }
 
     let display_tx_2 = display_tx.clone();
 
-    // start the thread that deals with wifi
+    // the log mirrored to a syslog server or MQTT
+    if let Some(remote_log) = config.remote_log.clone() {
+        let log_mqtt = config.mqtt.clone();
+        let log_hostname = hostname.clone();
+        std::thread::Builder::new()
+            .stack_size(4000)
+            .spawn(move || {
+                if let Err(e) = run_remote_log(remote_log, log_mqtt, log_hostname, &HAS_NETWORK) {
+                    info!("Remote logging failed error {:?}", e);
+                }
+            })?;
+    }
+
+    // the debug console. The sender is kept so the channel stays open
+    // without one
+    let (console_tx, console_rx) = bounded::<ConsoleCmd>(4);
+    if let Some(console) = config.console.clone() {
+        let auth = WebAuth::new(&config.web, settings.clone());
+        let console_device = device_id().unwrap_or("base".into());
+        let console_tx = console_tx.clone();
+        std::thread::Builder::new()
+            .stack_size(6000)
+            .spawn(move || {
+                track_stack("console");
+                if let Err(e) = run_console(
+                    console,
+                    auth,
+                    console_device,
+                    console_tx,
+                    console_button_tx,
+                    &HAS_NETWORK,
+                ) {
+                    info!("Console failed error {:?}", e);
+                }
+            })?;
+    }
+
+    // restart if the network and HA stay unreachable
+    if net_config.watchdog_timeout > 0 {
+        let timeout = Duration::from_secs(net_config.watchdog_timeout);
+        let watchdog_settings = settings.clone();
+        std::thread::Builder::new()
+            .stack_size(3000)
+            .spawn(move || {
+                run_network_watchdog(&STATUS, watchdog_settings, timeout);
+            })?;
+    }
+
+    // a tunnel to a remote HA, configured in NVS
+    #[cfg(feature = "wireguard")]
+    match WireguardConfig::from_settings(&settings) {
+        Some(wg) => {
+            std::thread::Builder::new()
+                .stack_size(4000)
+                .spawn(move || {
+                    if let Err(e) = run_wireguard(wg, &HAS_NETWORK, &HAS_TIME) {
+                        info!("WireGuard failed error {:?}", e);
+                    }
+                })?;
+        }
+        None => info!("WireGuard not configured"),
+    }
+
+    // start the thread that deals with the network (WiFi or Ethernet)
+    let network = NetworkManager {
+        ssid: SSID,
+        password: PASS,
+        net: net_config,
+        hostname,
+        sntp: sntp_config,
+        has_network: &HAS_NETWORK,
+        last_quad: &LAST_QUAD,
+        has_time: &HAS_TIME,
+        status: &STATUS,
+    };
     std::thread::Builder::new()
         .stack_size(5000)
         .spawn(move || {
             // hold the reference so it doesn't get released
-            create_wifi(
-                SSID,
-                PASS,
-                &HAS_WIFI,
-                &LAST_QUAD,
-                display_tx_2,
-                peripherals.modem,
-                sysloop.clone(),
-                &HAS_TIME,
-            )
-            .unwrap();
+            network
+                .run(
+                    display_tx_2,
+                    peripherals.modem,
+                    peripherals.spi3,
+                    sysloop.clone(),
+                )
+                .unwrap();
         })?;
 
     // the main event loop
     let mut last_time: String = "".into();
     let mut first_sample = false;
-    let mut last_state: HashMap<String, String> = HashMap::new();
-    let mut states = HashMap::new();
-    let mut ha_config: Vec<HAConnect> = vec![];
+    let ha_config: Vec<HAConnect> = config.items;
+    let mut renderer = Renderer::new(&ha_config, &display_tx, &ha.unavailable_text);
+    keep_attributes(
+        ha_config
+            .iter()
+            .filter_map(|c| match c {
+                HAConnect::Attribute { attribute, .. } => Some(attribute.clone()),
+                _ => None,
+            })
+            .collect(),
+    );
+    // the states from before the reboot are shown (greyed out) until HA
+    // is reached
+    let mut states = load_cache(&settings);
+    states.retain(|key, _| ha_config.iter().any(|c| c.state_keys().contains(key)));
+    let mut stale = !states.is_empty();
+    let mut cached_states = states.clone();
+    let mut last_cache_save = Instant::now();
+    let mut last_time_fallback = Instant::now();
+    let mut last_status = String::new();
+    let mut last_battery: Option<u8> = None;
+    let mut last_report = Instant::now();
+    let mut last_health = Instant::now();
+    let device = device_id().unwrap_or("base".into());
+    let mut page = Page::Home;
+    let mut page_lines: Vec<String> = vec![];
+    let mut web_started = false;
+    let mut _web_server = None;
+    let mut last_poll = Instant::now();
+    let mut have_snapshot = false;
+    let mut pending = PendingRequests::new();
+    let mut optimistic = Optimistic::new();
+    let mut widget_state = WidgetState::new();
+    let mut code_entry: Option<CodeEntry> = None;
+    let mut adjusting: Option<Adjust> = None;
+    // the backlight while the sun is up, to go back to at sunrise
+    let mut night: Option<u8> = None;
+    let mut day_backlight = STATUS.backlight.load(Ordering::Relaxed);
+    let mut theme = Theme::Day;
+    // the list page goes back to the home page by itself, as does the
+    // doorbell popup
+    let mut list_until: Option<Instant> = None;
+    let mut doorbell_until: Option<Instant> = None;
+    let mut doorbell_rang = false;
+    let mut showing_alarm = false;
+    let mut offline = false;
+    let mut disconnected_since: Option<Instant> = None;
+    let mut notifications = Notifications::new();
+    let mut notice_changed = false;
+    // the render_template subscription ids and the state keys of their templates
+    let mut template_ids: HashMap<i64, String> = HashMap::new();
+    // the calls fetching calendars' events and todo lists' items, and
+    // when each was last fetched
+    let mut fetch_ids: HashMap<i64, String> = HashMap::new();
+    let mut fetched_at: HashMap<String, Instant> = HashMap::new();
+    let mut error_until: Option<Instant> = None;
+    let mut showing_auth_failed = false;
+    let started = Instant::now();
+    // a firmware that's run this long without crashing is kept
+    let mut firmware_valid = false;
+    // and a new config
+    let mut config_kept = false;
+    let mut stats_saved = Instant::now();
+    let mut memory_logged = Instant::now();
+    track_stack("main");
+    let mut power = config.power.clone().map(PowerManager::new);
+    let mut display_sleep = config.display_sleep.as_ref().map(DisplaySleep::new);
+    let mut screen_asleep = false;
+    let mut motion = config.motion.as_ref().map(MotionWake::new);
+    let mut screen_dimmed = false;
+    let mut ota_version = String::new();
+    let mut last_offset = Local::now().offset().local_minus_utc();
+    let mut last_sync_at = None;
+
+    if stale {
+        renderer.render(&states, stale || offline, &widget_state);
+    }
+
+    if config_rolled_back {
+        show_error("New config failed, rolled back", &display_tx)?;
+        error_until = Some(Instant::now() + ERROR_DISPLAY * 3);
+    } else if unexpected_reset(reset_reason()) {
+        // a crash, or a brownout that points at the power supply
+        show_error(
+            &format!("Reset by {}", reset_reason().replace('_', " ")),
+            &display_tx,
+        )?;
+        error_until = Some(Instant::now() + ERROR_DISPLAY * 3);
+    }
 
     loop {
-        // if we haven't sampled, but wifi is up, get the values for the stuff
-        // we're watching
-        if !first_sample && HAS_WIFI.load(Ordering::Relaxed) {
-            // the WIFI is up which means we've got the last quad which means we can load
-            // the correct config
-            ha_config = fetch_config();
-            for connect in &ha_config {
-                states.insert(connect.ha_id().clone(), "".to_string());
-            }
-            for c in &ha_config {
-                match get_ha_state(&c.ha_id(), HA_URL, &HA_HEADERS) {
-                    Ok(json) => {
-                        let val = &json["state"];
-                        states.insert(c.ha_id().clone(), val.to_string());
+        // if we haven't sampled, but the network is up, set up the stuff we're
+        // watching. The values come from the websocket's get_states snapshot
+        if !first_sample && HAS_NETWORK.load(Ordering::Relaxed) {
+            for key in ha_config.iter().flat_map(|c| c.state_keys()) {
+                states.entry(key).or_default();
+            }
+            for id in &screen_ids {
+                states.entry(state_key(None, id)).or_default();
+            }
+            first_sample = true;
+            last_poll = Instant::now();
+
+            // render the layout
+            if page == Page::Home {
+                renderer.render(&states, stale || offline, &widget_state);
+            }
+        }
+
+        // the local API needs the network stack up. The webhook mode needs
+        // it for the states
+        if (config.web.enabled || ha.webhook) && !web_started && HAS_NETWORK.load(Ordering::Relaxed)
+        {
+            web_started = true;
+            match start_web_server(
+                &config.web,
+                display_tx.clone(),
+                settings.clone(),
+                web_ha_tx.clone(),
+                &STATUS,
+                oauth.clone(),
+                device.clone(),
+            ) {
+                Ok(server) => _web_server = Some(server),
+                Err(e) => info!("Failed to start the web server error {:?}", e),
+            }
+        }
+
+        // the websocket is up but the snapshot hasn't come through (e.g. too
+        // big for the socket buffer), get the states one at a time instead
+        if first_sample
+            && !have_snapshot
+            && STATUS.ha_connected.load(Ordering::Relaxed)
+            && last_poll.elapsed() > SNAPSHOT_TIMEOUT
+        {
+            info!("No get_states snapshot, using the REST API");
+            have_snapshot = true;
+            last_poll = Instant::now();
+            let changed = poll_states(&ha_config, &mut states, ha_url, &ha_headers, &ha);
+            if stale {
+                stale = false;
+                renderer.clear();
+            }
+            if (changed || renderer.is_empty()) && page == Page::Home {
+                renderer.render(&states, stale || offline, &widget_state);
+            }
+        }
+
+        // calendars and todo lists are fetched every so often. Calls made
+        // while the websocket is down wait for it to reconnect
+        for c in &ha_config {
+            if let Some(refresh) = widgets::fetch_refresh(c) {
+                let key = c.state_key();
+                let due = fetched_at
+                    .get(&key)
+                    .map_or(true, |at| at.elapsed() > refresh);
+                if !due {
+                    continue;
+                }
+                let id = next_message_id();
+                match (
+                    socket_for(c.instance(), &main_socket_tx, &instance_sockets),
+                    widgets::fetch_request(c, id),
+                ) {
+                    (Some(socket), Some(json)) => {
+                        socket.send(SocketCmd::SendJson(json))?;
+                        fetch_ids.retain(|_, k| *k != key);
+                        fetch_ids.insert(id, key.clone());
                     }
-                    Err(e) => {
-                        info!("Failed to get state for {} error {:?}", c.ha_id(), e);
+                    _ => info!("No HA instance {:?}", c.instance()),
+                }
+                fetched_at.insert(key, Instant::now());
+            }
+        }
+
+        // while the websocket is down, poll HA's REST API so the display
+        // doesn't go stale (e.g. during an HA restart)
+        if first_sample
+            && ha.poll_interval > 0
+            && !STATUS.ha_connected.load(Ordering::Relaxed)
+            && last_poll.elapsed() > Duration::from_secs(ha.poll_interval)
+        {
+            last_poll = Instant::now();
+            let changed = poll_states(&ha_config, &mut states, ha_url, &ha_headers, &ha);
+            if changed && stale {
+                stale = false;
+                renderer.clear();
+            }
+            if changed && page == Page::Home {
+                renderer.render(&states, stale || offline, &widget_state);
+            }
+        }
+
+        // the values go grey and the status bar says so while HA can't be
+        // reached, rather than showing frozen values as if they were current
+        if STATUS.ha_connected.load(Ordering::Relaxed) {
+            disconnected_since = None;
+        } else if disconnected_since.is_none() {
+            disconnected_since = Some(Instant::now());
+        }
+        let now_offline =
+            first_sample && disconnected_since.map_or(false, |at| at.elapsed() > OFFLINE_GRACE);
+        if now_offline != offline {
+            info!("HA is {}", if now_offline { "offline" } else { "back" });
+            offline = now_offline;
+            renderer.clear();
+            if page == Page::Home {
+                renderer.render(&states, stale || offline, &widget_state);
+            }
+        }
+
+        // confirmations that ran out and flashing alerts. A triggered
+        // alarm flashes the whole screen
+        if widget_state.tick(&ha_config, &states) && page == Page::Home {
+            if widgets::alarm_flash(&ha_config, &states, &widget_state) {
+                showing_alarm = true;
+                display_tx.send(DrawCmd::Erase { color: Rgb565::RED })?;
+                display_tx.send(DrawCmd::Text {
+                    pos: DrawPos::Pos(Point::new(110, 130)),
+                    font: Some(PROFONT_24_POINT.into()),
+                    text: "ALARM".into(),
+                    text_color: RgbColor::WHITE,
+                    background: None,
+                })?;
+            } else {
+                if showing_alarm {
+                    showing_alarm = false;
+                    display_tx.send(DrawCmd::Erase {
+                        color: Rgb565::WHITE,
+                    })?;
+                    renderer.clear();
+                    last_time = "".into();
+                    last_status = "".into();
+                }
+                renderer.render(&states, stale || offline, &widget_state);
+            }
+        }
+
+        // button presses HA didn't act on
+        if optimistic.expire(&mut states, Duration::from_secs(ha.optimistic_timeout))
+            && page == Page::Home
+        {
+            renderer.render(&states, stale || offline, &widget_state);
+        }
+
+        // dim the backlight while the sun is down
+        let now_night = widgets::night_backlight(&ha_config, &states);
+        if now_night != night {
+            let percent = match now_night {
+                Some(percent) => {
+                    if night.is_none() {
+                        day_backlight = STATUS.backlight.load(Ordering::Relaxed);
                     }
+                    percent
                 }
+                None => day_backlight,
+            };
+            night = now_night;
+            STATUS.backlight.store(percent, Ordering::Relaxed);
+            display_tx.send(DrawCmd::Backlight(percent))?;
+        }
+
+        // and the pages go dark, drawn again in the new colors
+        let now_theme = widgets::theme(&ha_config, &states);
+        if now_theme != theme {
+            theme = now_theme;
+            display_tx.send(DrawCmd::Theme(theme))?;
+            display_tx.send(DrawCmd::Erase {
+                color: Rgb565::WHITE,
+            })?;
+            if page == Page::Home {
+                let _ = camera_tx.send(CameraCmd::Refresh);
+                renderer.clear();
+                last_time = "".into();
+                last_status = "".into();
+                renderer.render(&states, stale || offline, &widget_state);
+            } else {
+                page_lines.clear();
             }
-            first_sample = true;
+        }
 
-            // render the layout
-            render_states(&ha_config, &states, &mut last_state, &display_tx);
+        // the screen sleeps in quiet hours or while HA says so, and dims or
+        // sleeps when nobody's around, but not during a firmware update.
+        // Waking it redraws the home page as nothing was drawn while it slept
+        let updating = STATUS.ota_percent.load(Ordering::Relaxed) >= 0;
+        if let Some(motion) = motion.as_mut() {
+            motion.check_entity(&states);
+        }
+        let idle = !updating && motion.as_ref().map_or(false, |m| m.idle());
+        let idle_backlight = motion.as_ref().map_or(0, |m| m.idle_backlight);
+        let now_dimmed = idle && idle_backlight > 0;
+        if now_dimmed != screen_dimmed {
+            screen_dimmed = now_dimmed;
+            display_tx.send(DrawCmd::Dim(screen_dimmed.then_some(idle_backlight)))?;
+        }
+        let now_asleep = !updating
+            && ((idle && idle_backlight == 0)
+                || display_sleep.as_ref().map_or(false, |s| {
+                    s.asleep(&states, HAS_TIME.load(Ordering::Relaxed))
+                }));
+        if now_asleep != screen_asleep {
+            screen_asleep = now_asleep;
+            display_tx.send(DrawCmd::Sleep(screen_asleep))?;
+            if !screen_asleep && page != Page::Update {
+                list_until = None;
+                doorbell_until = None;
+                page = Page::Home;
+                let _ = camera_tx.send(CameraCmd::Refresh);
+                display_tx.send(DrawCmd::Erase {
+                    color: Rgb565::WHITE,
+                })?;
+                renderer.clear();
+                last_time = "".into();
+                last_status = "".into();
+                renderer.render(&states, stale || offline, &widget_state);
+            }
+        }
+
+        if !firmware_valid && started.elapsed() > FIRMWARE_VALID_AFTER {
+            firmware_valid = true;
+            mark_valid();
+        }
+        if !config_kept && started.elapsed() > CONFIG_TRIAL {
+            config_kept = true;
+            keep_config(&settings);
+        }
+        if stats_saved.elapsed() > STATS_SAVE_INTERVAL {
+            stats_saved = Instant::now();
+            save_stats(&settings, &STATUS);
+        }
+        if memory_logged.elapsed() > MEMORY_LOG_INTERVAL {
+            memory_logged = Instant::now();
+            log_memory();
+        }
+
+        // a battery powered device sleeps once the screen is up to date,
+        // but not part way through a firmware update
+        if let Some(power) = power.as_mut() {
+            if have_snapshot && !stale {
+                power.up_to_date();
+            }
+            if power.should_sleep() && STATUS.ota_percent.load(Ordering::Relaxed) < 0 {
+                save_stats(&settings, &STATUS);
+                if !stale && states != cached_states {
+                    if let Err(e) = save_cache(&settings, &states) {
+                        info!("Failed to cache the states error {:?}", e);
+                    }
+                }
+                display_tx.send(DrawCmd::Backlight(0))?;
+                flush_log();
+                std::thread::sleep(Duration::from_millis(100));
+                power.sleep(STATUS.battery_low.load(Ordering::Relaxed));
+            }
+        }
+
+        // the doorbell popup takes over the screen
+        if doorbell_rang {
+            doorbell_rang = false;
+            if let Some(doorbell) = config.doorbell.as_ref().filter(|_| page != Page::Update) {
+                page = Page::Doorbell;
+                page_lines.clear();
+                doorbell_until = Some(Instant::now() + Duration::from_secs(doorbell.timeout));
+                display_tx.send(DrawCmd::Erase {
+                    color: Rgb565::WHITE,
+                })?;
+                render_doorbell(doorbell, &display_tx)?;
+                if let Some(camera) = doorbell::popup_camera(doorbell) {
+                    let _ = camera_tx.send(CameraCmd::Popup(camera));
+                }
+            }
+        }
+
+        // the list page and doorbell popup time out
+        let timed_out = |until: Option<Instant>| until.map_or(true, |at| Instant::now() > at);
+        if (page == Page::List && timed_out(list_until))
+            || (page == Page::Doorbell && timed_out(doorbell_until))
+        {
+            list_until = None;
+            doorbell_until = None;
+            page = Page::Home;
+            let _ = camera_tx.send(CameraCmd::Refresh);
+            display_tx.send(DrawCmd::Erase {
+                color: Rgb565::WHITE,
+            })?;
+            renderer.clear();
+            last_time = "".into();
+            last_status = "".into();
+            renderer.render(&states, stale || offline, &widget_state);
+        }
+
+        // notifications are shown over the home page until they're dismissed
+        if notice_changed || (page == Page::Home && notifications.current().is_some()) {
+            notice_changed = false;
+            match (page, notifications.current()) {
+                (Page::Home | Page::Notification, Some(notice)) => {
+                    page = Page::Notification;
+                    render_notice(notice, &display_tx)?;
+                }
+                (Page::Notification, None) => {
+                    page = Page::Home;
+                    let _ = camera_tx.send(CameraCmd::Refresh);
+                    display_tx.send(DrawCmd::Erase {
+                        color: Rgb565::WHITE,
+                    })?;
+                    renderer.clear();
+                    last_time = "".into();
+                    last_status = "".into();
+                    renderer.render(&states, stale || offline, &widget_state);
+                }
+                _ => {}
+            }
+        }
+
+        // the setup menu and diagnostics pages are redrawn as their content changes
+        if page != Page::Home {
+            render_page(&page, &STATUS, &mut page_lines, &display_tx)?;
+        }
+
+        // if SNTP hasn't come through (or has stopped resyncing), try getting
+        // the time from HA
+        if clock_stale(&config.sntp)
+            && HAS_NETWORK.load(Ordering::Relaxed)
+            && last_time_fallback.elapsed() > Duration::from_secs(config.sntp.fallback_after)
+        {
+            last_time_fallback = Instant::now();
+            match set_time_from_ha(ha_url, &ha_headers, &ha, &STATUS) {
+                Ok(_) => {
+                    HAS_TIME.store(true, Ordering::Relaxed);
+                    STATUS.record_sync("HA");
+                }
+                Err(e) => info!("Failed to get the time from HA error {:?}", e),
+            }
+        }
+
+        // redraw the time right away when the clock is set or the UTC
+        // offset changes (DST), rather than waiting for the next minute
+        let now = Local::now();
+        let offset = now.offset().local_minus_utc();
+        let sync_at = STATUS.time_sync.lock().ok().and_then(|s| s.map(|s| s.at));
+        if offset != last_offset || sync_at != last_sync_at {
+            if offset != last_offset {
+                info!("UTC offset is now {}", now.offset());
+            }
+            last_offset = offset;
+            last_sync_at = sync_at;
+            last_time = "".into();
+        }
+
+        // HA rejected the token, say so rather than showing stale states.
+        // The websocket keeps retrying in the background
+        let auth_failed = STATUS.ha_auth_failed.load(Ordering::Relaxed);
+        if page == Page::Home && auth_failed != showing_auth_failed {
+            showing_auth_failed = auth_failed;
+            display_tx.send(DrawCmd::Erase {
+                color: Rgb565::WHITE,
+            })?;
+            renderer.clear();
+            last_time = "".into();
+            last_status = "".into();
+            if auth_failed {
+                display_tx.send(DrawCmd::Text {
+                    pos: DrawPos::Pos(Point::new(10, 90)),
+                    font: Some(PROFONT_24_POINT.into()),
+                    text: "HA auth failed".into(),
+                    text_color: Rgb565::RED,
+                    background: Some(RgbColor::WHITE),
+                })?;
+                display_tx.send(DrawCmd::Text {
+                    pos: DrawPos::Pos(Point::new(10, 130)),
+                    font: None,
+                    text: "Check the access token".into(),
+                    text_color: RgbColor::BLACK,
+                    background: Some(RgbColor::WHITE),
+                })?;
+                let ip = STATUS
+                    .lease
+                    .lock()
+                    .ok()
+                    .and_then(|l| l.as_ref().map(|l| l.ip));
+                if let (true, Some(ip)) = (config.web.enabled, ip) {
+                    display_tx.send(DrawCmd::Text {
+                        pos: DrawPos::Pos(Point::new(10, 152)),
+                        font: None,
+                        text: format!("or link at http://{}:{}/oauth", ip, config.web.port),
+                        text_color: RgbColor::BLACK,
+                        background: Some(RgbColor::WHITE),
+                    })?;
+                }
+            } else {
+                renderer.render(&states, stale || offline, &widget_state);
+            }
+        }
+
+        // requests HA never answered
+        for error in pending.expire() {
+            if page == Page::Home {
+                show_error(&error, &display_tx)?;
+                error_until = Some(Instant::now() + ERROR_DISPLAY);
+            }
+        }
+
+        // an error is shown on the top line for a while, then the time
+        // and status bar come back
+        if let Some(until) = error_until {
+            if Instant::now() > until || page != Page::Home {
+                error_until = None;
+                display_tx.send(DrawCmd::Clear {
+                    color: RgbColor::WHITE,
+                    pos: DrawPos::Box(Rectangle::new(Point::new(0, 22), Size::new(320, 25))),
+                })?;
+                last_time = "".into();
+                last_status = "".into();
+            }
         }
 
         // if the SNTP server has been connected and we've got time, display it
-        if HAS_TIME.load(Ordering::Relaxed) {
-            let now = Local::now();
+        if page == Page::Home && error_until.is_none() && HAS_TIME.load(Ordering::Relaxed) {
             let this_time = format!("{:>9}:{:0>2}", now.hour(), now.minute());
             if this_time != last_time {
                 display_tx.send(DrawCmd::Text {
                     pos: DrawPos::Pos(Point::new(10, 20)),
-                    font: Some(PROFONT_24_POINT),
+                    font: Some(PROFONT_24_POINT.into()),
                     text: this_time.clone(),
                     text_color: RgbColor::BLACK,
                     background: Some(RgbColor::WHITE),
                 })?;
                 last_time = this_time;
+                // drawing the time clears the whole line
+                last_status = String::new();
             }
         }
 
+        // the status bar on the right of the time (until then the line is
+        // used for the WiFi/SNTP messages)
+        let show_hostname = started.elapsed().as_secs() / 5 % 2 == 0;
+        let status_text = if offline {
+            "HA offline".to_string()
+        } else if stale {
+            "stale".to_string()
+        } else {
+            STATUS.status_bar_text(show_hostname)
+        };
+        if page == Page::Home
+            && error_until.is_none()
+            && HAS_TIME.load(Ordering::Relaxed)
+            && status_text != last_status
+        {
+            let pos = DrawPos::Box(Rectangle::new(Point::new(200, 20), Size::new(120, 22)));
+            if status_text.is_empty() {
+                display_tx.send(DrawCmd::Clear {
+                    color: RgbColor::WHITE,
+                    pos,
+                })?;
+            } else {
+                display_tx.send(DrawCmd::Text {
+                    pos,
+                    font: None,
+                    text: status_text.clone(),
+                    text_color: RgbColor::BLACK,
+                    background: Some(RgbColor::WHITE),
+                })?;
+            }
+            last_status = status_text;
+            last_battery = None;
+        }
+        // the battery icon goes to the left of the status bar
+        if page == Page::Home
+            && error_until.is_none()
+            && HAS_TIME.load(Ordering::Relaxed)
+            && STATUS.battery() != last_battery
+        {
+            last_battery = STATUS.battery();
+            if let Some(percent) = last_battery {
+                display_tx.send(DrawCmd::Icon {
+                    top_left: Point::new(176, 2),
+                    icon: Icon::Battery(percent),
+                    color: None,
+                    background: RgbColor::WHITE,
+                })?;
+            }
+        }
+
+        // keep the states for the next boot, not too often as it wears the flash
+        if !stale && last_cache_save.elapsed() > CACHE_INTERVAL && states != cached_states {
+            last_cache_save = Instant::now();
+            match save_cache(&settings, &states) {
+                Ok(_) => cached_states = states.clone(),
+                Err(e) => info!("Failed to cache the states error {:?}", e),
+            }
+        }
+
+        // tell HA how we're doing
+        if first_sample
+            && ha.report_interval > 0
+            && last_report.elapsed() > Duration::from_secs(ha.report_interval)
+        {
+            last_report = Instant::now();
+            let traffic = STATUS.traffic.counts();
+            let rates = STATUS.traffic.rates();
+            let totals = stats_totals(&STATUS);
+            let heap = heap_info();
+            main_socket_tx.send(SocketCmd::SendJson(json!({
+                "id": next_message_id(),
+                "type": "fire_event",
+                "event_type": "homer_status",
+                "event_data": {
+                    "device": device,
+                    "rssi": STATUS.rssi.load(Ordering::Relaxed),
+                    "power_save": power_save.as_str(),
+                    "power_mode": power_mode.as_str(),
+                    "ws_rx_messages": traffic.ws_rx_messages,
+                    "ws_rx_bytes": traffic.ws_rx_bytes,
+                    "ws_tx_messages": traffic.ws_tx_messages,
+                    "ws_tx_bytes": traffic.ws_tx_bytes,
+                    "rest_requests": traffic.rest_requests,
+                    "rest_bytes": traffic.rest_bytes,
+                    "ws_rx_per_minute": rates.ws_rx_messages,
+                    "ws_tx_per_minute": rates.ws_tx_messages,
+                    "ha_dropped": STATUS.ha_dropped.load(Ordering::Relaxed),
+                    "boot_count": totals.boot_count,
+                    "uptime_total": totals.uptime,
+                    "wifi_reconnects": totals.wifi_reconnects,
+                    "ws_reconnects": totals.ws_reconnects,
+                    "last_crash": totals.last_crash,
+                    "reset_reason": totals.reset_reason,
+                    "brownouts": totals.brownouts,
+                    "installed_version": env!("CARGO_PKG_VERSION"),
+                    "latest_version": STATUS.latest_firmware.lock().ok().and_then(|l| l.clone()),
+                    "ws_truncated": STATUS.ws_truncated.load(Ordering::Relaxed),
+                    "battery": STATUS.battery(),
+                    "battery_voltage": STATUS.battery().map(|_| STATUS.battery_mv.load(Ordering::Relaxed) as f32 / 1000.0),
+                    "battery_low": STATUS.battery_low.load(Ordering::Relaxed),
+                    "instances": STATUS.instances.lock().map(|l| l.iter().map(|l| (l.name.clone(), l.connected)).collect::<HashMap<_, _>>()).unwrap_or_default(),
+                    "free_heap": heap.free,
+                    "min_free_heap": heap.min_free,
+                    "largest_free_block": heap.largest_block,
+                }
+            })))?;
+        }
+
+        // and whether it's healthy, for a fleet dashboard
+        if first_sample
+            && ha.health_interval > 0
+            && last_health.elapsed() > Duration::from_secs(ha.health_interval)
+        {
+            last_health = Instant::now();
+            let mut data = health_data(&STATUS);
+            data["device"] = json!(device);
+            main_socket_tx.send(SocketCmd::SendJson(json!({
+                "id": next_message_id(),
+                "type": "fire_event",
+                "event_type": HEALTH_EVENT,
+                "event_data": data,
+            })))?;
+        }
+
         // receive from various channels and perform appropriate actions
         select! {
           // button press
           recv(button_rx) -> msg => {
-            let the_button = msg?;
-            for c in &ha_config {
-              // find the button (there are < 10 items so the cost of looping is low even though it's O(n))
-              match c {
-                  // find the button
-                  HAConnect::Button{button, action_off, action_on, ..} if (*button as usize) == the_button=> {
-                    // is it on?
-                    let on = c.is_on(&states);
-                    // select the command
-                    let cmd = if on {action_off} else {action_on};
+            let event = msg?;
+            if let Some(power) = power.as_mut() {
+              power.button();
+            }
+            // a press on a sleeping screen only wakes it
+            if let Some(motion) = motion.as_mut() {
+              motion.seen();
+            }
+            if let Some(sleep) = display_sleep.as_mut() {
+              sleep.wake();
+            }
+            if screen_asleep {
+              continue;
+            }
+            // MQTT publishes the presses for HA automations
+            if !matches!(event, ButtonEvent::Hold(_)) {
+              main_socket_tx.send(SocketCmd::Button(event))?;
+            }
+            if page == Page::Notification && event == ButtonEvent::Press(DISMISS_BUTTON) {
+              if let Some(json) = notifications.dismiss() {
+                pending.add(&json, "dismiss notification".into());
+                main_socket_tx.send(SocketCmd::SendJson(json))?;
+              }
+              notice_changed = true;
+            }
+            // the code entry page takes all the buttons until it's done
+            if page == Page::Code {
+              if let Some(entry) = code_entry.as_mut() {
+                match entry.on_button(event) {
+                  EntryResult::Continue => render_entry(entry, &display_tx)?,
+                  EntryResult::Cancel => code_entry = None,
+                  EntryResult::Done(cmd) => {
+                    let json = cmd.as_json();
+                    pending.add(&json, cmd.describe());
+                    match socket_for(entry.instance.as_deref(), &main_socket_tx, &instance_sockets) {
+                      Some(socket) => socket.send(SocketCmd::SendJson(json))?,
+                      None => info!("No HA instance {:?}", entry.instance),
+                    }
+                    code_entry = None;
+                  }
+                }
+              }
+            }
+            // so does the light adjust page
+            if page == Page::Adjust {
+              if let Some(adjust) = adjusting.as_ref() {
+                match adjust.on_button(event, states.get_mut(&adjust.key)) {
+                  AdjustResult::Continue(actions) => {
+                    for cmd in &actions {
+                      let json = cmd.as_json();
+                      pending.add(&json, cmd.describe());
+                      match socket_for(adjust.instance.as_deref(), &main_socket_tx, &instance_sockets) {
+                        Some(socket) => socket.send(SocketCmd::SendJson(json))?,
+                        None => info!("No HA instance {:?}", adjust.instance),
+                      }
+                    }
+                    render_adjust(adjust, states.get(&adjust.key), &display_tx)?;
+                  }
+                  AdjustResult::Done => adjusting = None,
+                }
+              }
+            }
+            let next_page = if (page == Page::Code && code_entry.is_none())
+              || (page == Page::Adjust && adjusting.is_none()) {
+              Page::Home
+            } else {
+              page.on_button(event)
+            };
+            // buttons that don't change the page go to the home page's items
+            let on_home = page == Page::Home && next_page == page;
+            if next_page != page {
+              // switching pages, start from a blank screen
+              display_tx.send(DrawCmd::Erase { color: Rgb565::WHITE })?;
+              page_lines.clear();
+              if next_page == Page::Home {
+                showing_auth_failed = false;
+                let _ = camera_tx.send(CameraCmd::Refresh);
+                renderer.clear();
+                last_time = "".into();
+                last_status = "".into();
+                renderer.render(&states, stale || offline, &widget_state);
+              }
+              page = next_page;
+            }
+            for c in ha_config.iter().filter(|_| on_home) {
+              // the same as in the simulator, the pages are the device's
+              let press = on_home_button(c, event, &mut states, &mut optimistic, ha.optimistic_timeout, &mut widget_state);
+              match press {
+                HomePress::Ignored => {}
+                // don't fire actions at dead entities
+                HomePress::Unavailable => {
+                  show_error(&format!("{} unavailable", c.ha_id()), &display_tx)?;
+                  error_until = Some(Instant::now() + ERROR_DISPLAY);
+                }
+                HomePress::Send(actions) => {
+                  for cmd in &actions {
                     // turn it into a JSON message for Home Assistant
                     let json = cmd.as_json();
-                    // send it
-                    main_socket_tx.send(SocketCmd::SendJson(json))?;
+                    // watch for the result
+                    pending.add(&json, cmd.describe());
+                    // send it to the instance the item belongs to
+                    match socket_for(c.instance(), &main_socket_tx, &instance_sockets) {
+                      Some(socket) => socket.send(SocketCmd::SendJson(json))?,
+                      None => info!("No HA instance {:?}", c.instance()),
+                    }
                   }
-                  _ => {}
+                  renderer.render(&states, stale || offline, &widget_state);
+                }
+                // the sensors that are on get listed for a while
+                HomePress::List => {
+                  display_tx.send(DrawCmd::Erase { color: Rgb565::WHITE })?;
+                  page_lines.clear();
+                  render_list("On now", &widgets::sensors_on(c, &states), &display_tx)?;
+                  list_until = Some(Instant::now() + LIST_DISPLAY);
+                  page = Page::List;
+                  break;
+                }
+                // alarms ask for the code on a page of their own
+                HomePress::Code(service) => {
+                  let entry = CodeEntry::new(c.instance().map(String::from), c.ha_id(), service);
+                  display_tx.send(DrawCmd::Erase { color: Rgb565::WHITE })?;
+                  page_lines.clear();
+                  render_entry(&entry, &display_tx)?;
+                  code_entry = Some(entry);
+                  page = Page::Code;
+                  break;
+                }
+                // lights are adjusted on one too
+                HomePress::Adjust => {
+                  if let Some(adjust) = Adjust::new(c) {
+                    display_tx.send(DrawCmd::Erase { color: Rgb565::WHITE })?;
+                    page_lines.clear();
+                    render_adjust(&adjust, states.get(&adjust.key), &display_tx)?;
+                    adjusting = Some(adjust);
+                    page = Page::Adjust;
+                  }
+                  break;
+                }
               }
             }
           },
           // maybe a Home Assistant JSON web socket message
           recv(ha_rx) -> msg => {
             match msg {
-              Ok(json) => {
-                let json: &JsonValue = json.deref();
-                // get the entity_id
-                let entity = traverse(json, &["event","data","entity_id"]);
-                let mut changed = false;
-
-                // if we've got an 'entity_id' and it's one of the states we care about, update the state table
-                // and flag that there's been a change (why?... no need to redraw if there's no change)
-                if let Some(s) = &entity {
-                  if states.contains_key(s) {
-                    if let Some(v) = traverse(json, &["event","data","new_state","state"]) {
-                      states.insert(s.clone(), v);
+              Ok(FromHa { instance, msg }) => {
+                // the states are kept the same way as in the simulator
+                let applied = apply_message(&msg, instance.as_deref(), &mut states, &mut optimistic);
+                let mut changed = applied.changed;
+
+                // the reply to get_states, sent each time the websocket connects
+                if applied.snapshot && instance.is_none() {
+                  have_snapshot = true;
+                  // redraw everything, the cached values may not have changed
+                  if stale {
+                    stale = false;
+                    renderer.clear();
+                    changed = true;
+                  }
+                }
+                // a todo list's count changed, so its items have
+                if let Some(entity_id) = &applied.entity_id {
+                  for c in ha_config.iter().filter(|c| matches!(c, HAConnect::Todo { .. })) {
+                    if c.instance() == instance.as_deref() && c.ha_id() == entity_id {
+                      fetched_at.remove(&c.state_key());
+                    }
+                  }
+                }
+
+                match msg {
+                  // the websocket (re)connected, the templates need new subscriptions
+                  HaMessage::AuthOk => {
+                    if instance.is_none() {
+                      for json in Notifications::subscriptions() {
+                        main_socket_tx.send(SocketCmd::SendJson(json))?;
+                      }
+                      if let Some(doorbell) = &config.doorbell {
+                        main_socket_tx.send(SocketCmd::SendJson(doorbell::subscription(doorbell)))?;
+                      }
+                      if ota_enabled {
+                        main_socket_tx.send(SocketCmd::SendJson(json!({
+                          "id": next_message_id(),
+                          "type": "subscribe_events",
+                          "event_type": OTA_EVENT,
+                        })))?;
+                      }
+                      // HA's clock, for networks that block NTP
+                      if clock_stale(&config.sntp) {
+                        main_socket_tx.send(SocketCmd::SendJson(json!({
+                          "id": next_message_id(),
+                          "type": "subscribe_trigger",
+                          "trigger": {"platform": "time_pattern", "minutes": "/10"},
+                        })))?;
+                      }
+                    }
+                    if let Some(socket) = socket_for(instance.as_deref(), &main_socket_tx, &instance_sockets) {
+                      for c in ha_config.iter().filter(|c| c.instance() == instance.as_deref()) {
+                        // calendars and todo lists are fetched again on the new connection
+                        fetched_at.remove(&c.state_key());
+                        if let HAConnect::Template { template, .. } = c {
+                          let key = c.state_key();
+                          template_ids.retain(|_, k| *k != key);
+                          let id = next_message_id();
+                          template_ids.insert(id, key);
+                          socket.send(SocketCmd::SendJson(json!({
+                            "id": id,
+                            "type": "render_template",
+                            "template": template,
+                          })))?;
+                        }
+                      }
+                    }
+                  }
+                  HaMessage::Result(result) => {
+                    // a failed service call
+                    if let Some(error) = pending.on_result(&result) {
+                      if page == Page::Home {
+                        show_error(&error, &display_tx)?;
+                        error_until = Some(Instant::now() + ERROR_DISPLAY);
+                      }
+                    }
+
+                    // the events of a calendar or items of a todo list
+                    if let Some(key) = fetch_ids.remove(&result.id) {
+                      let item = ha_config.iter().find(|c| c.state_key() == key);
+                      if let Some(entity) = item.and_then(|c| widgets::fetched_entity(c, &result.result)) {
+                        states.insert(key, entity);
+                        changed = true;
+                      }
+                    }
+                  }
+                  // if it's a change of one of the states we care about, update the state table
+                  // and flag that there's been a change (why?... no need to redraw if there's no change)
+                  HaMessage::Event { id, event } => {
+                    // HA asked for a firmware update check, or to install it
+                    if instance.is_none() && event.event_type == OTA_EVENT {
+                      let install = event.data["install"].as_bool().unwrap_or(false);
+                      let _ = ota_tx.send(if install { OtaCmd::Install } else { OtaCmd::Check });
+                    }
+                    if let Some(time) = event.ha_time().filter(|_| clock_stale(&config.sntp)) {
+                      set_clock(time);
+                      HAS_TIME.store(true, Ordering::Relaxed);
+                      STATUS.record_sync("HA events");
+                    }
+                    // a new rendering of a template
+                    if let (Some(key), Some(result)) = (template_ids.get(&id), &event.result) {
+                      states.insert(key.clone(), Entity { state: value_text(result), ..Default::default() });
                       changed = true;
                     }
                   }
+                  _ => {}
+                }
+
+                // the first news from HA replaces the cached values
+                if changed && stale {
+                  stale = false;
+                  renderer.clear();
                 }
 
                 // if there's been a change, update the display
-                if changed {
-                  render_states(&ha_config, &states, &mut last_state, & display_tx);
+                if changed && page == Page::Home {
+                  renderer.render(&states, stale || offline, &widget_state);
+                }
+                if changed && page == Page::Adjust {
+                  if let Some(adjust) = adjusting.as_ref() {
+                    render_adjust(adjust, states.get(&adjust.key), &display_tx)?;
+                  }
                 }
             },
 
@@ -276,6 +1591,104 @@ fn main() -> Result<()> {
           }
         },
 
+        // a notification to show or one that's gone
+        recv(notify_rx) -> msg => {
+          if let Ok(FromHa { instance: None, msg: HaMessage::Event { event, .. } }) = msg {
+            if notifications.on_event(&event) {
+              notice_changed = true;
+            }
+          }
+        },
+
+        // someone rang the doorbell
+        recv(doorbell_rx) -> msg => {
+          if let Ok(FromHa { instance: None, .. }) = msg {
+            doorbell_rang = true;
+          }
+        },
+
+        // someone's near the screen
+        recv(motion_rx) -> _ => {
+          if let Some(motion) = motion.as_mut() {
+            motion.seen();
+          }
+        },
+
+        // a camera image, drawn over the home page or in the doorbell popup
+        recv(snapshot_rx) -> msg => {
+          if let Ok(Snapshot { area, pixels, popup }) = msg {
+            if (page == Page::Home && !showing_alarm && !popup) || (page == Page::Doorbell && popup) {
+              display_tx.send(DrawCmd::Image { area, pixels })?;
+            }
+          }
+        },
+
+        // how a firmware update is going, it takes over the screen until the
+        // device restarts
+        recv(ota_progress_rx) -> msg => {
+          if msg.is_ok() {
+            let mut data = update_state(&STATUS);
+            data["device"] = json!(device);
+            main_socket_tx.send(SocketCmd::SendJson(json!({
+              "id": next_message_id(),
+              "type": "fire_event",
+              "event_type": UPDATE_EVENT,
+              "event_data": data,
+            })))?;
+          }
+          match msg {
+            Ok(OtaProgress::Started(version)) => {
+              ota_version = version;
+              page = Page::Update;
+              page_lines.clear();
+              display_tx.send(DrawCmd::Erase { color: Rgb565::WHITE })?;
+              render_update(&ota_version, 0, &display_tx)?;
+            }
+            Ok(OtaProgress::Written(percent)) => {
+              if page == Page::Update {
+                render_update(&ota_version, percent, &display_tx)?;
+              }
+            }
+            Ok(OtaProgress::Failed(error)) => {
+              page = Page::Home;
+              let _ = camera_tx.send(CameraCmd::Refresh);
+              display_tx.send(DrawCmd::Erase { color: Rgb565::WHITE })?;
+              renderer.clear();
+              last_time = "".into();
+              last_status = "".into();
+              renderer.render(&states, stale || offline, &widget_state);
+              show_error(&format!("Update failed: {}", error), &display_tx)?;
+              error_until = Some(Instant::now() + ERROR_DISPLAY);
+            }
+            Err(_) => {}
+          }
+        },
+
+        // a command from the debug console
+        recv(console_rx) -> msg => {
+          match msg {
+            Ok(ConsoleCmd::States(reply)) => {
+              let mut lines: Vec<String> = states.iter().map(|(key, entity)| format!("{} = {}", key, entity.state)).collect();
+              lines.sort();
+              let _ = reply.send(lines);
+            }
+            Ok(ConsoleCmd::Redraw) => {
+              display_tx.send(DrawCmd::Erase { color: Rgb565::WHITE })?;
+              if page == Page::Home {
+                let _ = camera_tx.send(CameraCmd::Refresh);
+                renderer.clear();
+                last_time = "".into();
+                last_status = "".into();
+                renderer.render(&states, stale || offline, &widget_state);
+              } else {
+                // drawn again at the top of the loop
+                page_lines.clear();
+              }
+            }
+            Err(_) => {}
+          }
+        },
+
         // timeout after a second so we can properly redraw the time even if
         // nothing else has changed
         default(Duration::from_secs(1)) => {}
@@ -284,106 +1697,71 @@ fn main() -> Result<()> {
     // Ok(())
 }
 
-// update the display, only rendering states that have changed
-fn render_states(
-    connect: &[HAConnect],
-    states: &HashMap<String, String>,
-    last_state: &mut HashMap<String, String>,
-    display_tx: &Sender<DrawCmd>,
-) {
-    for c in connect {
-        match c {
-            HAConnect::Text { line, text, color } => {
-                let cu16: RawU16 = (*color).into();
-
-                // don't redisplay
-                if Some(text) != last_state.get(text) {
-                    last_state.insert(text.clone(), text.clone());
-                    display_tx
-                        .send(DrawCmd::Text {
-                            pos: DrawPos::Pos(Point::new(10, 30 * (*line as i32 + 2))),
-                            font: Some(PROFONT_24_POINT),
-                            text: text.clone(),
-                            text_color: cu16.into(),
-                            background: Some(RgbColor::WHITE),
-                        })
-                        .unwrap();
-                }
-            }
-            HAConnect::Line {
-                line,
-                ha_id,
-                text,
-                make_int,
-                color,
-                ..
-            } => {
-                if let Some(st) = states.get(ha_id) {
-                    let line_str = if *make_int {
-                        format!(
-                            "{}{}",
-                            text,
-                            st.parse::<f64>()
-                                .ok()
-                                .map_or("".to_string(), |f| f.round().to_string())
-                        )
-                    } else {
-                        format!("{}{}", text, st)
-                    };
+// the websocket of an HA instance
+fn socket_for<'a>(
+    instance: Option<&str>,
+    main_socket_tx: &'a Sender<SocketCmd>,
+    instance_sockets: &'a HashMap<String, Sender<SocketCmd>>,
+) -> Option<&'a Sender<SocketCmd>> {
+    match instance {
+        Some(name) => instance_sockets.get(name),
+        None => Some(main_socket_tx),
+    }
+}
 
-                    if Some(&line_str) != last_state.get(ha_id) {
-                        last_state.insert(ha_id.clone(), line_str.clone());
+// show an error on the top line, in place of the time
+fn show_error(error: &str, display_tx: &Sender<DrawCmd>) -> Result<()> {
+    display_tx.send(DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(10, 20)),
+        font: None,
+        text: error.chars().take(30).collect(),
+        text_color: Rgb565::RED,
+        background: Some(RgbColor::WHITE),
+    })?;
+    Ok(())
+}
 
-                        let cu16: RawU16 = (*color).into();
+// the HA entities that control the screen rather than being shown
+fn screen_entities(config: &Config) -> Vec<String> {
+    let sleep = config.display_sleep.as_ref().and_then(|s| s.entity.clone());
+    let motion = config.motion.as_ref().and_then(|m| m.entity.clone());
+    sleep.into_iter().chain(motion).collect()
+}
 
-                        display_tx
-                            .send(DrawCmd::Text {
-                                pos: DrawPos::Pos(Point::new(10, 30 * (*line as i32 + 2))),
-                                font: Some(PROFONT_24_POINT),
-                                text: line_str,
-                                text_color: cu16.into(),
-                                background: Some(RgbColor::WHITE),
-                            })
-                            .unwrap();
-                    }
+// get the state of each item of the default HA instance from its REST
+// API, returns true if any state changed
+fn poll_states(
+    connect: &[HAConnect],
+    states: &mut States,
+    ha_url: &str,
+    ha_headers: &[(&str, &str)],
+    ha: &HaConfig,
+) -> bool {
+    let mut changed = false;
+    for id in entity_ids(connect, None) {
+        match get_ha_state(&id, ha_url, ha_headers, ha, &STATUS) {
+            Ok(entity) => {
+                let val = Entity::from_state(&entity);
+                if states.get(&id) != Some(&val) {
+                    states.insert(id, val);
+                    changed = true;
                 }
             }
-            HAConnect::Button {
-                button,
-                ha_id,
-                cmp,
-                text_on,
-                text_off,
-                color,
-                ..
-            } => {
-                let cur = states.get(ha_id);
-                let on = cmp == cur;
-                let disp = if on { text_on } else { text_off };
-                let last = last_state.get(ha_id);
-                let cu16: RawU16 = (*color).into();
-                if Some(disp) != last {
-                    last_state.insert(ha_id.clone(), disp.clone());
-                    display_tx
-                        .send(DrawCmd::Text {
-                            pos: DrawPos::Button(*button),
-                            font: None,
-                            text: disp.clone(),
-                            text_color: cu16.into(),
-                            background: Some(RgbColor::WHITE),
-                        })
-                        .unwrap();
-                }
+            Err(e) => {
+                info!("Failed to get state for {} error {:?}", id, e);
             }
         }
     }
+    changed
+}
+
+// SNTP hasn't set the clock, or has stopped resyncing
+fn clock_stale(sntp: &SntpConfig) -> bool {
+    STATUS.sync_age().map_or(true, |age| {
+        age > Duration::from_secs(sntp.resync_interval * 2)
+    })
 }
 
 const SSID: &str = env!("HOMER_SSID");
 const PASS: &str = env!("HOMER_WIFI_PASSWORD");
-const HA_AUTH: &str = env!("HOMER_HA_AUTH");
 const HA_URL: &str = env!("HOMER_HA_URL");
-const HA_HEADERS: [(&str, &str); 2] = [
-    ("Content-Type", "application/json"),
-    ("Authorization", concat!("Bearer ", env!("HOMER_HA_AUTH"))),
-];
@@ -0,0 +1,67 @@
+use std::{sync::Mutex, time::Duration};
+
+use esp_idf_sys::{
+    esp_get_free_heap_size, esp_get_minimum_free_heap_size, heap_caps_get_largest_free_block,
+    uxTaskGetStackHighWaterMark, xTaskGetCurrentTaskHandle, MALLOC_CAP_8BIT,
+};
+use log::*;
+
+// how often the heap and stacks are logged
+pub const MEMORY_LOG_INTERVAL: Duration = Duration::from_secs(600);
+
+// the threads whose stacks are tracked, with their FreeRTOS task handles.
+// The threads never end, so the handles stay good
+static TASKS: Mutex<Vec<(&'static str, usize)>> = Mutex::new(Vec::new());
+
+pub struct HeapInfo {
+    pub free: u32,
+    // the least there's been since boot
+    pub min_free: u32,
+    // the biggest allocation that would succeed, much less than `free`
+    // when the heap's fragmented
+    pub largest_block: u32,
+}
+
+pub fn heap_info() -> HeapInfo {
+    unsafe {
+        HeapInfo {
+            free: esp_get_free_heap_size(),
+            min_free: esp_get_minimum_free_heap_size(),
+            largest_block: heap_caps_get_largest_free_block(MALLOC_CAP_8BIT) as u32,
+        }
+    }
+}
+
+// track the calling thread's stack under `name`
+pub fn track_stack(name: &'static str) {
+    let handle = unsafe { xTaskGetCurrentTaskHandle() } as usize;
+    if let Ok(mut tasks) = TASKS.lock() {
+        tasks.push((name, handle));
+    }
+}
+
+// the least stack (in bytes) each tracked thread has had free
+pub fn stack_marks() -> Vec<(&'static str, u32)> {
+    TASKS.lock().map_or(vec![], |tasks| {
+        tasks
+            .iter()
+            .map(|(name, handle)| {
+                let free = unsafe { uxTaskGetStackHighWaterMark(*handle as _) };
+                (*name, free as u32)
+            })
+            .collect()
+    })
+}
+
+pub fn log_memory() {
+    let heap = heap_info();
+    let stacks = stack_marks()
+        .iter()
+        .map(|(name, free)| format!("{} {}", name, free))
+        .collect::<Vec<_>>()
+        .join(", ");
+    info!(
+        "Heap free {} min {} largest block {}, stack free {}",
+        heap.free, heap.min_free, heap.largest_block, stacks
+    );
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::Ordering,
+        mpsc::{RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use embedded_svc::mqtt::client::{Client, Event, Message, Publish, QoS};
+use esp_idf_svc::mqtt::client::{
+    EspMqttClient, EspMqttMessage, LwtConfiguration, MqttClientConfiguration,
+};
+use esp_idf_sys::EspError;
+use log::*;
+use serde_json::Value;
+
+use crate::{
+    buttons::ButtonEvent,
+    config::MqttConfig,
+    discovery::{state_message, update_message, Node},
+    display::DrawCmd,
+    ha::{EntityState, FromHa, HaError, HaMessage, ResultMsg},
+    ota::OtaCmd,
+    states::KEPT_ATTRIBUTES,
+    status::Status,
+    wifi::{queue_for_main, SocketCmd, SocketCtx},
+    Error, Result,
+};
+
+// talk to HA through its MQTT broker rather than the websocket. States
+// come from HA's statestream integration and are passed on as
+// state_changed events, commands (the same messages the websocket would
+// send) are published for an automation to carry out. MQTT traffic is
+// counted as websocket traffic. With discovery on, the panel also shows
+// up in HA as a device with its own sensors, backlight, buttons and
+// firmware update
+pub fn handle_mqtt(
+    ctx: SocketCtx,
+    display_tx: Sender<DrawCmd>,
+    ota_tx: Sender<OtaCmd>,
+    mqtt: MqttConfig,
+    client_id: String,
+) -> Result<()> {
+    let SocketCtx {
+        has_network,
+        socket_tx: mqtt_tx,
+        socket_rx: mqtt_rx,
+        ha_tx,
+        status,
+        entities,
+        ..
+    } = ctx;
+    // wait until there's a network stack
+    while !has_network.load(Ordering::Relaxed) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    // the state and attributes are separate topics, the latest of each
+    // are kept to build whole entity states
+    let known: Arc<Mutex<HashMap<String, EntityState>>> = Arc::new(Mutex::new(HashMap::new()));
+    let prefix = mqtt.state_prefix.clone();
+    let node = Node::new(&client_id);
+    let backlight_topic = node.backlight_topic();
+    let install_topic = node.install_topic();
+    let ha_status_topic = format!("{}/status", mqtt.discovery_prefix);
+
+    let callback = move |event: &Result<Event<EspMqttMessage>, EspError>| match event {
+        Ok(Event::Connected(_)) => {
+            info!("MQTT connected");
+            status.ha_connected.store(true, Ordering::Relaxed);
+            mqtt_tx.send(SocketCmd::Subscribe).unwrap();
+        }
+        Ok(Event::Disconnected) => {
+            info!("MQTT disconnected");
+            status.ha_connected.store(false, Ordering::Relaxed);
+        }
+        Ok(Event::Received(msg)) => {
+            let topic = match msg.topic() {
+                Some(t) => t.to_string(),
+                None => return,
+            };
+            let payload = String::from_utf8_lossy(&msg.data()).to_string();
+            status.traffic.ws_received(topic.len() + payload.len());
+            if topic == backlight_topic {
+                match payload.trim().parse::<f32>() {
+                    Ok(v) => {
+                        let percent = v.clamp(0.0, 100.0) as u8;
+                        status.backlight.store(percent, Ordering::Relaxed);
+                        display_tx.send(DrawCmd::Backlight(percent)).unwrap();
+                    }
+                    Err(_) => info!("Bad backlight value {}", payload),
+                }
+                return;
+            }
+            if topic == install_topic {
+                info!("Firmware install asked for over MQTT");
+                let _ = ota_tx.send(OtaCmd::Install);
+                return;
+            }
+            if topic == ha_status_topic {
+                // HA restarted, it needs the discovery configs again
+                if payload == "online" {
+                    mqtt_tx.send(SocketCmd::Subscribe).unwrap();
+                }
+                return;
+            }
+            if let Some(state) = on_state_message(&prefix, &topic, &payload, &known) {
+                queue_for_main(
+                    &ha_tx,
+                    FromHa {
+                        instance: None,
+                        msg: HaMessage::state_changed(&state),
+                    },
+                    status,
+                );
+            }
+        }
+        Err(e) => info!("MQTT error {:?}", e),
+        _ => {}
+    };
+
+    // the broker marks the panel offline if it drops off
+    let availability_topic = node.availability_topic();
+    let conf = MqttClientConfiguration {
+        client_id: Some(&client_id),
+        username: mqtt.username.as_deref(),
+        password: mqtt.password.as_deref(),
+        lwt: mqtt.discovery.then(|| LwtConfiguration {
+            topic: &availability_topic,
+            payload: b"offline",
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        }),
+        ..Default::default()
+    };
+
+    info!("Connecting to MQTT broker at {}", mqtt.url);
+    let mut client = EspMqttClient::new(&mqtt.url, &conf, callback).map_err(Error::socket)?;
+
+    let state_interval = Duration::from_secs(mqtt.state_interval);
+    let mut last_state = Instant::now();
+    let mut last_backlight = status.backlight.load(Ordering::Relaxed);
+    let mut last_update = update_message(status);
+
+    loop {
+        // publish the panel's sensors now and then, and right away when
+        // the backlight changes
+        let backlight = status.backlight.load(Ordering::Relaxed);
+        if mqtt.discovery
+            && status.ha_connected.load(Ordering::Relaxed)
+            && (last_state.elapsed() > state_interval || backlight != last_backlight)
+        {
+            last_state = Instant::now();
+            last_backlight = backlight;
+            send(
+                &mut client,
+                &node.state_topic(),
+                &state_message(status),
+                true,
+                status,
+            );
+        }
+        // and the firmware update entity as it changes
+        let update = update_message(status);
+        if mqtt.discovery && status.ha_connected.load(Ordering::Relaxed) && update != last_update {
+            send(&mut client, &node.update_topic(), &update, true, status);
+            last_update = update;
+        }
+
+        match mqtt_rx.recv_timeout(Duration::from_secs(1)) {
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(e) => {
+                return Err(Error::Socket(format!(
+                    "MQTT command channel closed {:?}",
+                    e
+                )))
+            }
+            // the client reconnects by itself
+            Ok(SocketCmd::Reconnect | SocketCmd::Authenticated) => {}
+            Ok(SocketCmd::SendString(text)) => {
+                send(&mut client, &mqtt.command_topic, &text, false, status);
+            }
+            Ok(SocketCmd::SendJson(json)) => {
+                let sent = send(
+                    &mut client,
+                    &mqtt.command_topic,
+                    &json.to_string(),
+                    false,
+                    status,
+                );
+                // nothing answers over MQTT, so answer like the websocket
+                // would once it's published
+                if let Some(id) = json["id"].as_i64() {
+                    let error = (!sent).then(|| HaError {
+                        code: "mqtt".into(),
+                        message: "publish failed".into(),
+                    });
+                    let msg = HaMessage::Result(ResultMsg {
+                        id,
+                        success: sent,
+                        result: Value::Null,
+                        error,
+                    });
+                    queue_for_main(
+                        &ha_tx,
+                        FromHa {
+                            instance: None,
+                            msg,
+                        },
+                        status,
+                    );
+                }
+            }
+            Ok(SocketCmd::Subscribe) => {
+                subscribe(&mut client, &mqtt, &entities);
+                if mqtt.discovery {
+                    announce(&mut client, &mqtt, &node, status);
+                }
+            }
+            Ok(SocketCmd::Button(event)) => {
+                let kind = match event {
+                    ButtonEvent::LongPress(_) => "long_press",
+                    _ => "short_press",
+                };
+                if mqtt.discovery {
+                    send(
+                        &mut client,
+                        &node.button_topic(event.button()),
+                        kind,
+                        false,
+                        status,
+                    );
+                }
+            }
+        }
+    }
+}
+
+// publish, returns false if that failed
+fn send(
+    client: &mut EspMqttClient,
+    topic: &str,
+    text: &str,
+    retain: bool,
+    status: &Status,
+) -> bool {
+    match client.publish(topic, QoS::AtLeastOnce, retain, text.as_bytes()) {
+        Ok(_) => {
+            status.traffic.ws_sent(text.len());
+            true
+        }
+        Err(e) => {
+            info!("MQTT publish to {} error {:?}", topic, e);
+            false
+        }
+    }
+}
+
+fn subscribe(client: &mut EspMqttClient, mqtt: &MqttConfig, entities: &[String]) {
+    let mut topics = state_topics(mqtt, entities);
+    if mqtt.discovery {
+        topics.push(format!("{}/status", mqtt.discovery_prefix));
+    }
+    for topic in topics {
+        if let Err(e) = client.subscribe(&topic, QoS::AtMostOnce) {
+            info!("MQTT subscribe to {} error {:?}", topic, e);
+        }
+    }
+}
+
+// make the panel show up in HA
+fn announce(client: &mut EspMqttClient, mqtt: &MqttConfig, node: &Node, status: &Status) {
+    for (topic, config) in node.discovery_messages(&mqtt.discovery_prefix, status) {
+        send(client, &topic, &config, true, status);
+    }
+    send(client, &node.availability_topic(), "online", true, status);
+    if let Err(e) = client.subscribe(&node.backlight_topic(), QoS::AtMostOnce) {
+        info!("MQTT subscribe to the backlight error {:?}", e);
+    }
+    if let Err(e) = client.subscribe(&node.install_topic(), QoS::AtMostOnce) {
+        info!("MQTT subscribe to the firmware install error {:?}", e);
+    }
+    send(
+        client,
+        &node.update_topic(),
+        &update_message(status),
+        true,
+        status,
+    );
+    send(
+        client,
+        &node.state_topic(),
+        &state_message(status),
+        true,
+        status,
+    );
+}
+
+// statestream publishes <prefix>/<domain>/<object id>/state and a topic
+// per attribute next to it
+fn state_topics(mqtt: &MqttConfig, entities: &[String]) -> Vec<String> {
+    entities
+        .iter()
+        .map(|e| format!("{}/{}/+", mqtt.state_prefix, e.replacen('.', "/", 1)))
+        .collect()
+}
+
+// update the entity a statestream message is for, returning its new state
+fn on_state_message(
+    prefix: &str,
+    topic: &str,
+    payload: &str,
+    known: &Mutex<HashMap<String, EntityState>>,
+) -> Option<EntityState> {
+    let rest = topic.strip_prefix(prefix)?.strip_prefix('/')?;
+    let mut parts = rest.splitn(3, '/');
+    let (domain, object_id, leaf) = (parts.next()?, parts.next()?, parts.next()?);
+    let entity_id = format!("{}.{}", domain, object_id);
+
+    let mut known = known.lock().ok()?;
+    let entity = known
+        .entry(entity_id.clone())
+        .or_insert_with(|| EntityState {
+            entity_id,
+            state: String::new(),
+            attributes: Default::default(),
+            last_updated: None,
+        });
+
+    if leaf == "state" {
+        entity.state = payload.to_string();
+    } else if KEPT_ATTRIBUTES.contains(&leaf) {
+        // attributes are published as JSON
+        let value = serde_json::from_str(payload).unwrap_or(Value::String(payload.into()));
+        entity.attributes.insert(leaf.to_string(), value);
+    } else {
+        return None;
+    }
+    Some(entity.clone())
+}
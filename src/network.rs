@@ -0,0 +1,388 @@
+use embedded_graphics::{
+    prelude::{Point, RgbColor, Size},
+    primitives::Rectangle,
+};
+use embedded_svc::ipv4;
+use esp_idf_hal::{
+    gpio::AnyIOPin,
+    modem::Modem,
+    prelude::*,
+    spi::{self, SpiDriver, SPI3},
+};
+use esp_idf_svc::{
+    eth::{BlockingEth, EspEth, EthDriver, SpiEth, SpiEthChipset},
+    eventloop::{EspEventLoop, System},
+    netif::{EspNetif, NetifConfiguration},
+    sntp::{self, SyncStatus},
+    wifi::EspWifi,
+};
+use log::*;
+use profont::PROFONT_24_POINT;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        mpsc::Sender,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::{EthernetConfig, NetConfig, SntpConfig},
+    display::{DrawCmd, DrawPos},
+    flashlog::flush_log,
+    status::{LeaseInfo, Status},
+    watchdog::TaskWatch,
+    wifi::{ap_info, reconnect, roam, wifi},
+    Error, Result,
+};
+
+// the network stack in use. Either one keeps the connection alive
+// for as long as it's held
+pub enum NetworkStack {
+    Wifi(Box<EspWifi<'static>>),
+    Ethernet(Box<EspEth<'static, SpiEth<SpiDriver<'static>>>>),
+}
+
+impl NetworkStack {
+    pub fn netif(&self) -> &EspNetif {
+        match self {
+            NetworkStack::Wifi(w) => w.sta_netif(),
+            NetworkStack::Ethernet(e) => e.netif(),
+        }
+    }
+
+    pub fn netif_mut(&mut self) -> &mut EspNetif {
+        match self {
+            NetworkStack::Wifi(w) => w.sta_netif_mut(),
+            NetworkStack::Ethernet(e) => e.netif_mut(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NetworkStack::Wifi(_) => "WiFi",
+            NetworkStack::Ethernet(_) => "Ethernet",
+        }
+    }
+}
+
+// brings up WiFi or (if configured) Ethernet, then keeps the clock in
+// sync and the status up to date
+pub struct NetworkManager {
+    pub ssid: &'static str,
+    pub password: &'static str,
+    pub net: NetConfig,
+    pub hostname: String,
+    pub sntp: SntpConfig,
+    pub has_network: &'static AtomicBool,
+    pub last_quad: &'static AtomicI32,
+    pub has_time: &'static AtomicBool,
+    pub status: &'static Status,
+}
+
+impl NetworkManager {
+    pub fn run(
+        self,
+        display_tx: Sender<DrawCmd>,
+        modem: Modem,
+        spi: SPI3,
+        sysloop: EspEventLoop<System>,
+    ) -> Result<()> {
+        if let Ok(mut hostname) = self.status.hostname.lock() {
+            *hostname = self.hostname.clone();
+        }
+
+        let mut stack = match &self.net.ethernet {
+            Some(eth) => {
+                // display a message while waiting for the link
+                display_tx.send(DrawCmd::Text {
+                    pos: DrawPos::Pos(Point::new(10, 20)),
+                    font: Some(PROFONT_24_POINT.into()),
+                    text: "Starting Ethernet".into(),
+                    text_color: RgbColor::BLACK,
+                    background: Some(RgbColor::WHITE),
+                })?;
+                NetworkStack::Ethernet(ethernet(eth, &self.net, &self.hostname, spi, sysloop)?)
+            }
+            None => {
+                // display a message while searching for WiFi
+                display_tx.send(DrawCmd::Text {
+                    pos: DrawPos::Pos(Point::new(10, 20)),
+                    font: Some(PROFONT_24_POINT.into()),
+                    text: "Looking for WiFi".into(),
+                    text_color: RgbColor::BLACK,
+                    background: Some(RgbColor::WHITE),
+                })?;
+                NetworkStack::Wifi(wifi(
+                    self.ssid,
+                    self.password,
+                    &self.net,
+                    &self.hostname,
+                    modem,
+                    sysloop,
+                )?)
+            }
+        };
+
+        // with DHCP, replace the DNS servers from the lease with the configured ones
+        if !self.net.is_static() {
+            if let Some(dns) = self.net.dns {
+                stack.netif_mut().set_dns(dns.octets().into());
+            }
+            if let Some(dns) = self.net.secondary_dns {
+                stack.netif_mut().set_secondary_dns(dns.octets().into());
+            }
+        }
+
+        let ip_info = stack.netif().get_ip_info().map_err(Error::wifi)?;
+
+        self.last_quad
+            .store((ip_info.ip.octets()[3]) as i32, Ordering::Relaxed);
+
+        self.has_network.store(true, Ordering::Relaxed);
+        self.status.network_ok.store(true, Ordering::Relaxed);
+
+        info!(
+            "{} DHCP info: {:?} quad {}",
+            stack.name(),
+            ip_info,
+            self.last_quad.load(Ordering::Relaxed)
+        );
+
+        // clear the message area
+        display_tx.send(DrawCmd::Clear {
+            color: RgbColor::WHITE,
+            pos: DrawPos::Box(Rectangle::new(Point::new(0, 0), Size::new(400, 30))),
+        })?;
+
+        // display a message with the IP address while waiting for SNTP
+        display_tx.send(DrawCmd::Text {
+            pos: DrawPos::Pos(Point::new(10, 22)),
+            font: None,
+            text: format!(
+                "{} {}, SNTP init",
+                if self.net.is_static() {
+                    "Static"
+                } else {
+                    "IP Addr"
+                },
+                ip_info.ip
+            ),
+            text_color: RgbColor::BLACK,
+            background: Some(RgbColor::WHITE),
+        })?;
+        let mut sntp_reset_cnt = 0;
+
+        unsafe {
+            esp_idf_sys::sntp_set_sync_interval((self.sntp.resync_interval * 1000) as u32);
+        }
+
+        // any configured servers replace the default pool.ntp.org servers
+        let mut conf = sntp::SntpConf::default();
+        for (slot, server) in conf.servers.iter_mut().zip(self.sntp.servers.iter()) {
+            *slot = server.as_str();
+        }
+
+        let _sntp = sntp::EspSntp::new(&conf).map_err(Error::wifi)?;
+
+        info!("SNTP initialized with servers {:?}", conf.servers);
+
+        let mut not_sync = true;
+        let mut weak_polls = 0;
+        let mut last_roam = Instant::now();
+        let mut last_restart = Instant::now();
+        // watched once it's up, connecting can take a while
+        let watch = TaskWatch::new("network");
+        loop {
+            watch.sleep(Duration::from_secs(7));
+
+            match &stack {
+                NetworkStack::Wifi(_) => match ap_info() {
+                    Some((ap, rssi)) => {
+                        self.status.record_rssi(rssi);
+                        self.status.network_ok.store(true, Ordering::Relaxed);
+                        if let Ok(mut cur) = self.status.ap.lock() {
+                            *cur = Some(ap);
+                        }
+
+                        // on a mesh, a weak signal means there's probably a closer AP
+                        match self.net.roam_rssi {
+                            Some(threshold) if rssi < threshold => weak_polls += 1,
+                            _ => weak_polls = 0,
+                        }
+                        if weak_polls >= 3 && last_roam.elapsed() > Duration::from_secs(60) {
+                            info!("RSSI {} is weak, looking for a better AP", rssi);
+                            weak_polls = 0;
+                            last_roam = Instant::now();
+                            self.status.wifi_reconnects.fetch_add(1, Ordering::Relaxed);
+                            if let Err(e) = roam() {
+                                info!("WiFi roam error {:?}", e);
+                            }
+                        }
+                    }
+                    None => {
+                        // lost the AP, the driver doesn't reconnect by itself
+                        self.status.rssi.store(0, Ordering::Relaxed);
+                        self.status.network_ok.store(false, Ordering::Relaxed);
+                        self.status.wifi_reconnects.fetch_add(1, Ordering::Relaxed);
+                        info!("WiFi disconnected, reconnecting");
+                        if let Err(e) = reconnect() {
+                            info!("WiFi reconnect error {:?}", e);
+                        }
+                    }
+                },
+                NetworkStack::Ethernet(_) => {
+                    self.status
+                        .network_ok
+                        .store(stack.netif().is_up().unwrap_or(false), Ordering::Relaxed);
+                }
+            }
+
+            self.update_lease(&stack);
+
+            // the status reads Completed once after each sync (the first one
+            // and every resync) and then goes back to Reset
+            let status: SyncStatus = _sntp.get_sync_status();
+            match status {
+                SyncStatus::Completed => {
+                    info!("SNTP synced");
+                    self.has_time.store(true, Ordering::Relaxed);
+                    self.status.record_sync("SNTP");
+                    not_sync = false;
+                }
+                SyncStatus::InProgress => {
+                    info!("Sync in progress");
+                }
+                SyncStatus::Reset if not_sync => {
+                    info!("SNTP reset");
+                    sntp_reset_cnt += 1;
+                    // if we're struggling to get the SNTP stuff set up
+                    // after 700 seconds (> 10 minutes) and haven't been able
+                    // to get the time from HA either, reset the box
+                    if sntp_reset_cnt > 100 && !self.has_time.load(Ordering::Relaxed) {
+                        flush_log();
+                        esp_idf_hal::reset::restart();
+                    }
+                }
+                SyncStatus::Reset => {}
+            }
+
+            // a resync is overdue (e.g. the server was unreachable when it
+            // was due), start a new one rather than waiting for the next interval
+            let overdue = Duration::from_secs(self.sntp.resync_interval * 2);
+            if !not_sync
+                && self.status.sync_age().map_or(false, |age| age > overdue)
+                && last_restart.elapsed() > overdue
+            {
+                info!("SNTP resync overdue, restarting");
+                last_restart = Instant::now();
+                unsafe {
+                    esp_idf_sys::sntp_restart();
+                }
+            }
+        }
+    }
+}
+
+impl NetworkManager {
+    fn update_lease(&self, stack: &NetworkStack) {
+        if let Ok(ip_info) = stack.netif().get_ip_info() {
+            let lease = LeaseInfo {
+                ip: ip_info.ip.octets().into(),
+                mask: ip_info.subnet.mask.0,
+                gateway: ip_info.subnet.gateway.octets().into(),
+                dns: ip_info.dns.map(|d| d.octets().into()),
+                dhcp: !self.net.is_static(),
+            };
+            if let Ok(mut cur) = self.status.lease.lock() {
+                *cur = Some(lease);
+            }
+        }
+    }
+}
+
+// a W5500 on its own SPI bus. The ESP32-S3 doesn't have an RMII MAC
+fn ethernet(
+    eth: &EthernetConfig,
+    net: &NetConfig,
+    hostname: &str,
+    spi: SPI3,
+    sysloop: EspEventLoop<System>,
+) -> Result<Box<EspEth<'static, SpiEth<SpiDriver<'static>>>>> {
+    // the pins come from the config so they can't be typed peripherals
+    let pin = |n: i32| unsafe { AnyIOPin::new(n) };
+
+    let driver = EthDriver::new_spi(
+        SpiDriver::new(
+            spi,
+            pin(eth.sclk),
+            pin(eth.mosi),
+            Some(pin(eth.miso)),
+            &spi::SpiDriverConfig::new().dma(spi::Dma::Auto(4096)),
+        )
+        .map_err(Error::wifi)?,
+        pin(eth.int),
+        Some(pin(eth.cs)),
+        eth.rst.map(pin),
+        SpiEthChipset::W5500,
+        eth.mhz.MHz().into(),
+        None,
+        None,
+        sysloop.clone(),
+    )
+    .map_err(Error::wifi)?;
+
+    let mut esp_eth = EspEth::wrap_all(
+        driver,
+        client_netif(net, hostname, NetifConfiguration::eth_default_client())?,
+    )
+    .map_err(Error::wifi)?;
+
+    let mut blocking = BlockingEth::wrap(&mut esp_eth, sysloop).map_err(Error::wifi)?;
+    blocking.start().map_err(Error::wifi)?;
+    blocking.wait_netif_up().map_err(Error::wifi)?;
+
+    Ok(Box::new(esp_eth))
+}
+
+// a client network interface, either DHCP or a fixed address
+pub(crate) fn client_netif(
+    net: &NetConfig,
+    hostname: &str,
+    base: NetifConfiguration,
+) -> Result<EspNetif> {
+    let mut netif = static_netif(net, base)?;
+    // has to be set before DHCP starts
+    netif.set_hostname(hostname).map_err(Error::wifi)?;
+    info!("Hostname {}", hostname);
+    Ok(netif)
+}
+
+fn static_netif(net: &NetConfig, base: NetifConfiguration) -> Result<EspNetif> {
+    let ip = match net.ip {
+        Some(ip) => ip,
+        None => return EspNetif::new_with_conf(&base).map_err(Error::wifi),
+    };
+    let gateway = match net.gateway {
+        Some(gw) => gw,
+        None => return Err(Error::Config(format!("A static IP {} needs a gateway", ip))),
+    };
+
+    info!("Using static IP {} gateway {}", ip, gateway);
+
+    EspNetif::new_with_conf(&NetifConfiguration {
+        ip_configuration: ipv4::Configuration::Client(ipv4::ClientConfiguration::Fixed(
+            ipv4::ClientSettings {
+                ip: ip.octets().into(),
+                subnet: ipv4::Subnet {
+                    gateway: gateway.octets().into(),
+                    mask: ipv4::Mask(net.mask.unwrap_or(24)),
+                },
+                dns: net.dns.map(|d| d.octets().into()),
+                secondary_dns: net.secondary_dns.map(|d| d.octets().into()),
+            },
+        )),
+        ..base
+    })
+    .map_err(Error::wifi)
+}
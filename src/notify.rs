@@ -0,0 +1,191 @@
+use std::sync::mpsc::Sender;
+
+use embedded_graphics::{
+    pixelcolor::Rgb565,
+    prelude::{Point, RgbColor, Size},
+    primitives::Rectangle,
+};
+use profont::PROFONT_24_POINT;
+use serde_json::{json, Value};
+
+use crate::{
+    display::{DrawCmd, DrawPos},
+    ha::HaEvent,
+    pages::Page,
+    util::next_message_id,
+    Result,
+};
+
+// the custom event that puts a notification on the panel
+pub const NOTIFY_EVENT: &str = "homer_notify";
+
+// the button that dismisses a notification
+pub const DISMISS_BUTTON: usize = 2;
+
+// characters per line of the message (FONT_10X20 in the modal)
+const LINE_CHARS: usize = 28;
+const MESSAGE_LINES: usize = 5;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notice {
+    // the persistent notification's id, None for homer_notify events
+    pub id: Option<String>,
+    pub title: String,
+    pub message: String,
+}
+
+// notifications waiting to be dismissed, the first one is shown
+#[derive(Default)]
+pub struct Notifications {
+    notices: Vec<Notice>,
+}
+
+impl Notifications {
+    pub fn new() -> Notifications {
+        Notifications::default()
+    }
+
+    // the messages that subscribe to notifications, sent after each
+    // websocket connection
+    pub fn subscriptions() -> Vec<Value> {
+        vec![
+            json!({
+                "id": next_message_id(),
+                "type": "persistent_notification/subscribe",
+            }),
+            json!({
+                "id": next_message_id(),
+                "type": "subscribe_events",
+                "event_type": NOTIFY_EVENT,
+            }),
+        ]
+    }
+
+    pub fn current(&self) -> Option<&Notice> {
+        self.notices.first()
+    }
+
+    // update from an event, returns true if the shown notification changed
+    pub fn on_event(&mut self, event: &HaEvent) -> bool {
+        let before = self.current().cloned();
+
+        if event.event_type == NOTIFY_EVENT {
+            self.notices.push(Notice {
+                id: None,
+                title: event.data["title"].as_str().unwrap_or_default().to_string(),
+                message: event.data["message"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+            });
+        } else if let Some(notifications) = &event.notifications {
+            let update = event.update_type.as_deref().unwrap_or_default();
+            // "current" is the full list, sent when subscribing
+            if update == "current" {
+                self.notices.retain(|n| n.id.is_none());
+            }
+            for (id, n) in notifications {
+                self.notices.retain(|notice| notice.id.as_ref() != Some(id));
+                if update != "removed" {
+                    self.notices.push(Notice {
+                        id: Some(id.clone()),
+                        title: n.title.clone().unwrap_or_default(),
+                        message: n.message.clone(),
+                    });
+                }
+            }
+        } else {
+            return false;
+        }
+
+        before.as_ref() != self.current()
+    }
+
+    // drop the shown notification, returning the call that dismisses it
+    // in HA if it's a persistent notification
+    pub fn dismiss(&mut self) -> Option<Value> {
+        if self.notices.is_empty() {
+            return None;
+        }
+        let notice = self.notices.remove(0);
+        notice.id.map(|id| {
+            json!({
+                "id": next_message_id(),
+                "type": "call_service",
+                "domain": "persistent_notification",
+                "service": "dismiss",
+                "service_data": {"notification_id": id},
+            })
+        })
+    }
+}
+
+// draw a notification over the home page
+pub fn render_notice(notice: &Notice, display_tx: &Sender<DrawCmd>) -> Result<()> {
+    display_tx.send(DrawCmd::Clear {
+        color: Rgb565::BLUE,
+        pos: DrawPos::Box(Rectangle::new(Point::new(5, 40), Size::new(310, 170))),
+    })?;
+    display_tx.send(DrawCmd::Clear {
+        color: Rgb565::WHITE,
+        pos: DrawPos::Box(Rectangle::new(Point::new(8, 43), Size::new(304, 164))),
+    })?;
+    display_tx.send(DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(15, 70)),
+        font: Some(PROFONT_24_POINT.into()),
+        text: notice.title.chars().take(18).collect(),
+        text_color: Rgb565::BLUE,
+        background: None,
+    })?;
+    for (i, line) in wrap(&notice.message, LINE_CHARS)
+        .iter()
+        .take(MESSAGE_LINES)
+        .enumerate()
+    {
+        display_tx.send(DrawCmd::Text {
+            pos: DrawPos::Pos(Point::new(15, 96 + 22 * i as i32)),
+            font: None,
+            text: line.clone(),
+            text_color: RgbColor::BLACK,
+            background: None,
+        })?;
+    }
+    for (i, label) in Page::Notification.button_labels().iter().enumerate() {
+        display_tx.send(DrawCmd::Text {
+            pos: DrawPos::Button(i as u8),
+            font: None,
+            text: label.to_string(),
+            text_color: RgbColor::BLACK,
+            background: Some(RgbColor::WHITE),
+        })?;
+    }
+    Ok(())
+}
+
+// split text into lines of at most `width` characters, at spaces where possible
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let mut word = word.to_string();
+        while word.chars().count() > width {
+            if !line.is_empty() {
+                lines.push(std::mem::take(&mut line));
+            }
+            let rest = word.chars().skip(width).collect();
+            lines.push(word.chars().take(width).collect());
+            word = rest;
+        }
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(&word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
@@ -0,0 +1,198 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use log::*;
+use serde::Deserialize;
+
+use crate::{config::HaConfig, rest::ha_post, settings::Settings, status::Status, Error, Result};
+
+// the NVS keys of the refresh token and the client id it was issued to.
+// HA only refreshes tokens for the same client id
+pub const REFRESH_TOKEN_KEY: &str = "ha_refresh_token";
+pub const CLIENT_ID_KEY: &str = "ha_client_id";
+
+// the path HA sends the browser back to with the authorization code
+pub const CALLBACK_PATH: &str = "/oauth/callback";
+
+// refresh this long before the access token expires
+const REFRESH_EARLY: Duration = Duration::from_secs(300);
+
+// wait before trying again after a failed refresh
+const RETRY: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize)]
+struct TokenReply {
+    access_token: String,
+    expires_in: u64,
+    // only when exchanging an authorization code
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+// HA's OAuth2 (IndieAuth) flow, as an alternative to a long-lived token.
+// The client id is the URL of the device's web server, so the browser
+// doing the login has to reach both the device and HA
+#[derive(Clone)]
+pub struct OAuth {
+    pub ha: HaConfig,
+    // where the device reaches HA (may be the proxy forwarder)
+    pub ha_url: &'static str,
+    // where the browser reaches HA
+    pub public_url: &'static str,
+    pub settings: Settings,
+    pub status: &'static Status,
+}
+
+impl OAuth {
+    // the client id for a device web server at `host` (the Host header
+    // of the browser's request)
+    pub fn client_id(host: &str) -> String {
+        format!("http://{}/", host)
+    }
+
+    // where to send the browser to log in to HA
+    pub fn authorize_url(&self, client_id: &str) -> String {
+        let redirect = format!("{}{}", client_id.trim_end_matches('/'), CALLBACK_PATH);
+        format!(
+            "{}?response_type=code&client_id={}&redirect_uri={}",
+            self.ha.auth_url(self.public_url, "authorize"),
+            url_encode(client_id),
+            url_encode(&redirect)
+        )
+    }
+
+    // swap the code HA handed the browser for tokens. The refresh token
+    // is saved in NVS and the access token used from the next connection
+    pub fn exchange_code(&self, client_id: &str, code: &str) -> Result<()> {
+        let reply = self.token_request(&format!(
+            "grant_type=authorization_code&code={}&client_id={}",
+            url_encode(code),
+            url_encode(client_id)
+        ))?;
+        let refresh_token = match reply.refresh_token {
+            Some(t) => t,
+            None => return Err(Error::Socket("HA didn't send a refresh token".into())),
+        };
+        self.settings.set_str(CLIENT_ID_KEY, client_id)?;
+        self.settings.set_str(REFRESH_TOKEN_KEY, &refresh_token)?;
+        self.set_access_token(reply.access_token);
+        info!("Linked to HA with OAuth");
+        Ok(())
+    }
+
+    // get a new access token with the saved refresh token. Returns how
+    // long the access token is good for, None if the device isn't linked
+    pub fn refresh(&self) -> Result<Option<Duration>> {
+        let (client_id, refresh_token) = match (
+            self.settings.get_str(CLIENT_ID_KEY),
+            self.settings.get_str(REFRESH_TOKEN_KEY),
+        ) {
+            (Some(c), Some(r)) => (c, r),
+            _ => return Ok(None),
+        };
+        let reply = self.token_request(&format!(
+            "grant_type=refresh_token&refresh_token={}&client_id={}",
+            url_encode(&refresh_token),
+            url_encode(&client_id)
+        ))?;
+        self.set_access_token(reply.access_token);
+        Ok(Some(Duration::from_secs(reply.expires_in)))
+    }
+
+    fn set_access_token(&self, token: String) {
+        if let Ok(mut t) = self.status.access_token.lock() {
+            *t = Some(token);
+        }
+    }
+
+    fn token_request(&self, form: &str) -> Result<TokenReply> {
+        let response = ha_post(
+            &self.ha.auth_url(self.ha_url, "token"),
+            &[("Content-Type", "application/x-www-form-urlencoded")],
+            form.as_bytes(),
+            &self.ha,
+            self.status,
+        )?;
+        if response.status != 200 {
+            return Err(Error::Socket(format!(
+                "HA token request failed {} {}",
+                response.status,
+                String::from_utf8_lossy(&response.body)
+            )));
+        }
+        Ok(serde_json::from_slice(&response.body)?)
+    }
+}
+
+// keep an access token from the refresh token (if the device has been
+// linked) in `status`, where it's used in place of the configured token
+pub fn run_token_refresher(oauth: OAuth, has_network: &AtomicBool) {
+    let mut next_refresh = Instant::now();
+    loop {
+        std::thread::sleep(Duration::from_secs(10));
+        if !has_network.load(Ordering::Relaxed) || Instant::now() < next_refresh {
+            continue;
+        }
+
+        next_refresh = match oauth.refresh() {
+            Ok(Some(expires_in)) => {
+                info!("Refreshed the HA access token");
+                Instant::now() + expires_in.saturating_sub(REFRESH_EARLY).max(RETRY)
+            }
+            // check again in case the device gets linked
+            Ok(None) => Instant::now() + RETRY,
+            Err(e) => {
+                info!("Failed to refresh the HA access token error {:?}", e);
+                Instant::now() + RETRY
+            }
+        };
+    }
+}
+
+// percent encoding for query strings and form bodies
+pub fn url_encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+// the value of a parameter in the query string of a request URI
+pub fn query_param(uri: &str, name: &str) -> Option<String> {
+    let query = uri.split_once('?')?.1;
+    query.split('&').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        (key == name).then(|| url_decode(value))
+    })
+}
+
+fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => out.push(b),
+                    None => out.extend_from_slice(&bytes[i..i + 3]),
+                }
+                i += 3;
+                continue;
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
@@ -0,0 +1,340 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError, Sender},
+    },
+    time::Duration,
+};
+
+use crossbeam::channel::Sender as XBSender;
+use embedded_graphics::{
+    pixelcolor::Rgb565,
+    prelude::{Point, RgbColor, Size},
+    primitives::Rectangle,
+};
+use embedded_svc::http::{client::Client, Headers, Method};
+use esp_idf_svc::{
+    http::client::{Configuration, EspHttpConnection},
+    ota::EspOta,
+};
+use log::*;
+use profont::PROFONT_24_POINT;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::OtaConfig,
+    display::{DrawCmd, DrawPos},
+    flashlog::flush_log,
+    signing::{signing_enabled, verify_digest},
+    status::Status,
+    Error, Result,
+};
+
+// the NVS key of a manifest URL that overrides the config's
+pub const OTA_URL_KEY: &str = "ota_url";
+
+// the event that makes the device check for an update now, or install
+// it if the event data has `"install": true`
+pub const OTA_EVENT: &str = "homer_ota";
+
+// the event fired as an update starts, moves along and ends, with the
+// update_state
+pub const UPDATE_EVENT: &str = "homer_update";
+
+// the firmware image is streamed to flash in pieces this big
+const CHUNK: usize = 4096;
+
+// what the manifest says is the latest firmware
+#[derive(Debug, Deserialize)]
+struct Manifest {
+    version: String,
+    // the image's URL
+    url: String,
+    // the image's signature, needed with a HOMER_SIGNING_KEY
+    signature: Option<String>,
+}
+
+// what the OTA thread is asked to do
+pub enum OtaCmd {
+    Check,
+    // install the latest firmware if it's newer, even without auto_install
+    Install,
+}
+
+// how an update is going, for the main loop to show
+#[derive(Debug, Clone, PartialEq)]
+pub enum OtaProgress {
+    Started(String),
+    // percent written
+    Written(u8),
+    Failed(String),
+}
+
+// check the manifest every `check_interval` seconds, or when asked, and
+// install a newer version than the one running (with auto_install, or
+// when asked). The device restarts into it once it's written
+pub fn run_ota(
+    config: OtaConfig,
+    manifest_url: String,
+    has_network: &AtomicBool,
+    status: &Status,
+    ota_rx: Receiver<OtaCmd>,
+    progress_tx: XBSender<OtaProgress>,
+) {
+    let interval = match config.check_interval {
+        0 => Duration::MAX,
+        secs => Duration::from_secs(secs),
+    };
+    // the first check waits for the network to come up
+    let mut wait = Duration::from_secs(60);
+    loop {
+        let install_now = match ota_rx.recv_timeout(wait) {
+            Ok(OtaCmd::Install) => true,
+            Ok(OtaCmd::Check) | Err(RecvTimeoutError::Timeout) => config.auto_install,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        wait = interval;
+        if !has_network.load(Ordering::Relaxed) {
+            continue;
+        }
+
+        let manifest = match fetch_manifest(&manifest_url) {
+            Ok(m) => m,
+            Err(e) => {
+                info!("Failed to get the OTA manifest error {:?}", e);
+                continue;
+            }
+        };
+        if let Ok(mut latest) = status.latest_firmware.lock() {
+            *latest = Some(manifest.version.clone());
+        }
+        if !newer(&manifest.version, env!("CARGO_PKG_VERSION")) {
+            info!("Firmware {} is up to date", env!("CARGO_PKG_VERSION"));
+            continue;
+        }
+        if !install_now {
+            info!("Firmware {} is available", manifest.version);
+            continue;
+        }
+
+        info!(
+            "Updating to firmware {} from {}",
+            manifest.version, manifest.url
+        );
+        if let Ok(mut error) = status.ota_error.lock() {
+            *error = None;
+        }
+        status.ota_percent.store(0, Ordering::Relaxed);
+        let _ = progress_tx.send(OtaProgress::Started(manifest.version.clone()));
+        match install(&manifest, status, &progress_tx) {
+            Ok(()) => {
+                info!("Firmware {} written, restarting", manifest.version);
+                flush_log();
+                std::thread::sleep(Duration::from_secs(1));
+                esp_idf_hal::reset::restart();
+            }
+            Err(e) => {
+                info!("Firmware update failed error {:?}", e);
+                status.ota_percent.store(-1, Ordering::Relaxed);
+                if let Ok(mut error) = status.ota_error.lock() {
+                    *error = Some(format!("{}", e));
+                }
+                let _ = progress_tx.send(OtaProgress::Failed(format!("{}", e)));
+            }
+        }
+    }
+}
+
+// a running image that got this far works, so the bootloader doesn't
+// roll back to the previous one on the next boot
+pub fn mark_valid() {
+    match EspOta::new().and_then(|mut ota| ota.mark_running_slot_valid()) {
+        Ok(_) => info!("Firmware marked valid"),
+        Err(e) => info!("Failed to mark the firmware valid error {:?}", e),
+    }
+}
+
+fn client() -> Result<Client<EspHttpConnection>> {
+    Ok(Client::wrap(
+        EspHttpConnection::new(&Configuration {
+            crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+            ..Default::default()
+        })
+        .map_err(Error::socket)?,
+    ))
+}
+
+fn fetch_manifest(url: &str) -> Result<Manifest> {
+    use embedded_svc::utils::io;
+
+    let mut client = client()?;
+    let mut response = client
+        .request(Method::Get, url, &[])
+        .and_then(|r| r.submit())
+        .map_err(Error::socket)?;
+    if response.status() != 200 {
+        return Err(Error::Socket(format!(
+            "Manifest request yielded {}",
+            response.status()
+        )));
+    }
+    let mut body = vec![];
+    let mut buf = [0_u8; 512];
+    loop {
+        let read =
+            io::try_read_full(&mut response, &mut buf).map_err(|err| Error::socket(err.0))?;
+        if read == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[0..read]);
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+// stream the image to the partition that isn't running and make it the
+// one to boot. A failed write, or a bad signature, leaves the running one
+// to boot
+fn install(
+    manifest: &Manifest,
+    status: &Status,
+    progress_tx: &XBSender<OtaProgress>,
+) -> Result<()> {
+    use embedded_svc::utils::io;
+
+    if signing_enabled() && manifest.signature.is_none() {
+        return Err(Error::Config("The manifest has no signature".into()));
+    }
+    let mut client = client()?;
+    let mut response = client
+        .request(Method::Get, &manifest.url, &[])
+        .and_then(|r| r.submit())
+        .map_err(Error::socket)?;
+    if response.status() != 200 {
+        return Err(Error::Socket(format!(
+            "Image request yielded {}",
+            response.status()
+        )));
+    }
+    let size: Option<usize> = response
+        .header("Content-Length")
+        .and_then(|l| l.parse().ok());
+
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    let mut buf = vec![0_u8; CHUNK];
+    let mut written = 0;
+    let mut last_percent = 0;
+    let mut hasher = Sha256::new();
+    loop {
+        let read = match io::try_read_full(&mut response, &mut buf) {
+            Ok(read) => read,
+            Err(err) => {
+                update.abort()?;
+                return Err(Error::Socket(format!(
+                    "Download failed after {} bytes: {:?}",
+                    written, err.0
+                )));
+            }
+        };
+        if read == 0 {
+            break;
+        }
+        if let Err(e) = update.write(&buf[0..read]) {
+            update.abort()?;
+            return Err(Error::Fs(format!(
+                "Flash write failed after {} bytes: {:?}",
+                written, e
+            )));
+        }
+        hasher.update(&buf[0..read]);
+        written += read;
+        if let Some(size) = size.filter(|s| *s > 0) {
+            let percent = (written * 100 / size).min(100) as u8;
+            if percent != last_percent {
+                last_percent = percent;
+                status.ota_percent.store(percent as i8, Ordering::Relaxed);
+                let _ = progress_tx.send(OtaProgress::Written(percent));
+            }
+        }
+    }
+    if size.map_or(false, |s| s != written) {
+        update.abort()?;
+        return Err(Error::Socket(format!(
+            "Got {} of {:?} bytes",
+            written, size
+        )));
+    }
+    if let Err(e) = verify_digest(&hasher.finalize(), manifest.signature.as_deref()) {
+        update.abort()?;
+        return Err(Error::Config(format!("Bad firmware signature: {}", e)));
+    }
+    update.complete()?;
+    Ok(())
+}
+
+// the firmware versions and how an update is going, in the form of an
+// MQTT update entity's state
+pub fn update_state(status: &Status) -> Value {
+    let installed = env!("CARGO_PKG_VERSION");
+    let latest = status.latest_firmware.lock().ok().and_then(|l| l.clone());
+    let percent = status.ota_percent.load(Ordering::Relaxed);
+    let error = status.ota_error.lock().ok().and_then(|e| e.clone());
+    json!({
+        "installed_version": installed,
+        "latest_version": latest.unwrap_or(installed.to_string()),
+        "in_progress": percent >= 0,
+        "update_percentage": (percent >= 0).then_some(percent),
+        "release_summary": error.map(|e| format!("Update failed: {}", e)),
+    })
+}
+
+// whether version `a` is newer than `b`, comparing the dotted numbers
+fn newer(a: &str, b: &str) -> bool {
+    let parts = |v: &str| -> Vec<u64> {
+        v.trim_start_matches('v')
+            .split('.')
+            .map(|p| p.parse().unwrap_or(0))
+            .collect()
+    };
+    parts(a) > parts(b)
+}
+
+// draw the update page with how much of the image has been written
+pub fn render_update(version: &str, percent: u8, display_tx: &Sender<DrawCmd>) -> Result<()> {
+    display_tx.send(DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(10, 20)),
+        font: Some(PROFONT_24_POINT.into()),
+        text: "Updating firmware".into(),
+        text_color: Rgb565::BLUE,
+        background: Some(RgbColor::WHITE),
+    })?;
+    display_tx.send(DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(10, 90)),
+        font: Some(PROFONT_24_POINT.into()),
+        text: format!("{:<18}", format!("{} {}%", version, percent)),
+        text_color: RgbColor::BLACK,
+        background: Some(RgbColor::WHITE),
+    })?;
+    let area = Rectangle::new(Point::new(10, 110), Size::new(300, 24));
+    display_tx.send(DrawCmd::Clear {
+        color: Rgb565::new(28, 56, 28),
+        pos: DrawPos::Box(area),
+    })?;
+    display_tx.send(DrawCmd::Clear {
+        color: Rgb565::GREEN,
+        pos: DrawPos::Box(Rectangle::new(
+            area.top_left,
+            Size::new(area.size.width * percent as u32 / 100, area.size.height),
+        )),
+    })?;
+    display_tx.send(DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(10, 160)),
+        font: None,
+        text: "Don't turn the power off".into(),
+        text_color: RgbColor::BLACK,
+        background: Some(RgbColor::WHITE),
+    })?;
+    Ok(())
+}
@@ -0,0 +1,529 @@
+use std::{
+    sync::{atomic::Ordering, mpsc::Sender},
+    time::Duration,
+};
+
+use chrono::Local;
+use embedded_graphics::{
+    pixelcolor::Rgb565,
+    prelude::{Point, RgbColor, Size},
+    primitives::Rectangle,
+};
+use profont::PROFONT_24_POINT;
+
+use crate::{
+    adjust::{brightness_pct, Adjust},
+    buttons::ButtonEvent,
+    display::{DrawCmd, DrawPos},
+    flashlog::recent_log,
+    memory::{heap_info, stack_marks},
+    states::Entity,
+    stats::stats_totals,
+    status::Status,
+    Result,
+};
+
+// what's on the screen. Home is the normal display of HA states, the
+// other pages are reached by holding a button to get the setup menu
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Page {
+    Home,
+    // the setup menu with the index of the selected item
+    Menu(usize),
+    Connection,
+    Time,
+    Traffic,
+    // the counts kept across restarts
+    Stats,
+    // the free heap and how close each thread has come to the end of its stack
+    Memory,
+    // the recent log, scrolled this many rows back from the newest
+    Log(usize),
+    // an HA notification shown over the home page until it's dismissed
+    Notification,
+    // entering an alarm code
+    Code,
+    // adjusting a light's brightness
+    Adjust,
+    // a list of entities for a while, e.g. the doors that are open
+    List,
+    // someone's at the door, for a while
+    Doorbell,
+    // a firmware update being written, until the device restarts
+    Update,
+}
+
+// the setup menu entries
+const MENU: &[(&str, Page)] = &[
+    ("Connection", Page::Connection),
+    ("Time", Page::Time),
+    ("Traffic", Page::Traffic),
+    ("Stats", Page::Stats),
+    ("Memory", Page::Memory),
+    ("Log", Page::Log(0)),
+];
+
+// the rows of the log under the Log page's title, and their width
+const LOG_ROWS: usize = 8;
+const LOG_CHARS: usize = 31;
+
+impl Page {
+    // the page to show after a button event
+    pub fn on_button(self, event: ButtonEvent) -> Page {
+        match (self, event) {
+            (Page::Home, ButtonEvent::Hold(_)) => Page::Menu(0),
+            (Page::Menu(i), ButtonEvent::Press(0)) => Page::Menu((i + 1) % MENU.len()),
+            (Page::Menu(i), ButtonEvent::Press(1)) => MENU[i].1,
+            (Page::Menu(_), ButtonEvent::Press(2)) => Page::Home,
+            (
+                Page::Connection | Page::Time | Page::Traffic | Page::Stats | Page::Memory,
+                ButtonEvent::Press(2),
+            ) => Page::Menu(0),
+            (Page::Log(back), ButtonEvent::Press(0)) => {
+                let most = log_rows().len().saturating_sub(LOG_ROWS);
+                Page::Log((back + LOG_ROWS).min(most))
+            }
+            (Page::Log(back), ButtonEvent::Press(1)) => Page::Log(back.saturating_sub(LOG_ROWS)),
+            (Page::Log(_), ButtonEvent::Press(2)) => Page::Menu(0),
+            (Page::List | Page::Doorbell, ButtonEvent::Press(_)) => Page::Home,
+            (page, _) => page,
+        }
+    }
+
+    pub fn button_labels(&self) -> [&'static str; 3] {
+        match self {
+            Page::Home => ["", "", ""],
+            Page::Menu(_) => ["Next", "Open", "Exit"],
+            Page::Connection | Page::Time | Page::Traffic | Page::Stats | Page::Memory => {
+                ["", "", "Back"]
+            }
+            Page::Log(_) => ["Older", "Newer", "Back"],
+            Page::Notification => ["", "", "Dismiss"],
+            Page::Code => ["Digit", "Add", "OK"],
+            Page::Adjust => ["-", "+", "Done"],
+            Page::List => ["", "", "Back"],
+            Page::Doorbell => ["", "", "Dismiss"],
+            Page::Update => ["", "", ""],
+        }
+    }
+
+    // the title followed by the lines of text on the page
+    pub fn lines(&self, status: &Status) -> Vec<String> {
+        match self {
+            // drawn by render_notice, render_entry, render_adjust, render_list,
+            // render_doorbell and render_update
+            Page::Home
+            | Page::Notification
+            | Page::Code
+            | Page::Adjust
+            | Page::List
+            | Page::Doorbell
+            | Page::Update => vec![],
+            Page::Menu(selected) => {
+                let mut lines = vec!["Setup".to_string()];
+                for (i, (name, _)) in MENU.iter().enumerate() {
+                    let marker = if i == *selected { ">" } else { " " };
+                    lines.push(format!("{} {}", marker, name));
+                }
+                lines
+            }
+            Page::Connection => connection_lines(status),
+            Page::Time => time_lines(status),
+            Page::Traffic => traffic_lines(status),
+            Page::Stats => stats_lines(status),
+            Page::Memory => memory_lines(),
+            Page::Log(back) => log_lines(*back),
+        }
+    }
+
+    // the values to graph below the text, if the page has a graph
+    fn graph(&self, status: &Status) -> Option<Vec<i32>> {
+        match self {
+            Page::Connection => status.rssi_history.lock().ok().map(|h| h.clone()),
+            _ => None,
+        }
+    }
+}
+
+fn connection_lines(status: &Status) -> Vec<String> {
+    let mut lines = vec!["Connection".to_string()];
+
+    if let Ok(hostname) = status.hostname.lock() {
+        lines.push(format!("Host {}", hostname));
+    }
+
+    match status.ap.lock().ok().and_then(|ap| ap.clone()) {
+        Some(ap) => {
+            lines.push(format!("SSID {} ch {}", ap.ssid, ap.channel));
+            lines.push(format!(
+                "BSSID {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+                ap.bssid[0], ap.bssid[1], ap.bssid[2], ap.bssid[3], ap.bssid[4], ap.bssid[5]
+            ));
+        }
+        None => {
+            lines.push("Not associated".into());
+            lines.push("BSSID -".into());
+        }
+    }
+
+    lines.push(format!(
+        "RSSI {}dB reconnects {}",
+        status.rssi.load(Ordering::Relaxed),
+        status.wifi_reconnects.load(Ordering::Relaxed)
+    ));
+
+    match status.lease.lock().ok().and_then(|l| l.clone()) {
+        Some(lease) => {
+            lines.push(format!(
+                "IP {}/{} {}",
+                lease.ip,
+                lease.mask,
+                if lease.dhcp { "DHCP" } else { "static" }
+            ));
+            lines.push(format!("GW {}", lease.gateway));
+            lines.push(format!(
+                "DNS {}",
+                lease.dns.map_or("none".to_string(), |d| d.to_string())
+            ));
+        }
+        None => lines.push("No IP address".into()),
+    }
+
+    if let Ok(instances) = status.instances.lock() {
+        for link in instances.iter() {
+            let state = match (link.connected, link.auth_failed) {
+                (true, _) => "connected",
+                (false, true) => "auth failed",
+                (false, false) => "down",
+            };
+            lines.push(format!("HA {} {}", link.name, state));
+        }
+    }
+
+    lines
+}
+
+fn time_lines(status: &Status) -> Vec<String> {
+    let now = Local::now();
+    let mut lines = vec![
+        "Time".to_string(),
+        now.format("%Y-%m-%d %H:%M:%S").to_string(),
+        format!("UTC offset {}", now.offset()),
+    ];
+
+    match status.time_sync.lock().ok().and_then(|s| *s) {
+        Some(sync) => lines.push(format!(
+            "Synced {} ago via {}",
+            format_age(sync.at.elapsed()),
+            sync.source
+        )),
+        None => lines.push("Not synced".into()),
+    }
+
+    lines
+}
+
+// cumulative counts with the per minute rates below them
+fn traffic_lines(status: &Status) -> Vec<String> {
+    let counts = status.traffic.counts();
+    let rates = status.traffic.rates();
+    vec![
+        "Traffic".to_string(),
+        format!(
+            "WS in {} msgs {}",
+            counts.ws_rx_messages,
+            format_bytes(counts.ws_rx_bytes)
+        ),
+        format!(
+            "  {}/min {}/min",
+            rates.ws_rx_messages,
+            format_bytes(rates.ws_rx_bytes)
+        ),
+        format!(
+            "WS out {} msgs {}",
+            counts.ws_tx_messages,
+            format_bytes(counts.ws_tx_bytes)
+        ),
+        format!(
+            "  {}/min {}/min",
+            rates.ws_tx_messages,
+            format_bytes(rates.ws_tx_bytes)
+        ),
+        format!(
+            "REST {} reqs {}",
+            counts.rest_requests,
+            format_bytes(counts.rest_bytes)
+        ),
+        format!(
+            "  {}/min {}/min",
+            rates.rest_requests,
+            format_bytes(rates.rest_bytes)
+        ),
+        format!(
+            "Dropped {} truncated {}",
+            status.ha_dropped.load(Ordering::Relaxed),
+            status.ws_truncated.load(Ordering::Relaxed)
+        ),
+    ]
+}
+
+// the counts since the first boot (or the last factory reset)
+fn stats_lines(status: &Status) -> Vec<String> {
+    let totals = stats_totals(status);
+    let uptime = unsafe { esp_idf_sys::esp_timer_get_time() } as u64 / 1_000_000;
+    let mut lines = vec![
+        "Stats".to_string(),
+        format!("Boots {}", totals.boot_count),
+        format!(
+            "Up {} this boot {}",
+            format_age(Duration::from_secs(totals.uptime)),
+            format_age(Duration::from_secs(uptime))
+        ),
+        format!(
+            "Reconnects WiFi {} WS {}",
+            totals.wifi_reconnects, totals.ws_reconnects
+        ),
+        format!("Reset {}", totals.reset_reason),
+        format!("Brownouts {}", totals.brownouts),
+    ];
+    match totals.last_crash {
+        Some(crash) => {
+            let chars: Vec<char> = crash.chars().collect();
+            lines.push("Last crash".into());
+            lines.extend(chars.chunks(30).take(2).map(|c| c.iter().collect()));
+        }
+        None => lines.push("No crashes".into()),
+    }
+    lines
+}
+
+// the heap, then the least free stack of each thread, two to a line
+fn memory_lines() -> Vec<String> {
+    let heap = heap_info();
+    let mut lines = vec![
+        "Memory".to_string(),
+        format!(
+            "Heap free {} min {}",
+            format_bytes(heap.free),
+            format_bytes(heap.min_free)
+        ),
+        format!("Largest block {}", format_bytes(heap.largest_block)),
+        "Stack free".to_string(),
+    ];
+    let stacks: Vec<String> = stack_marks()
+        .iter()
+        .map(|(name, free)| format!("{} {}", name, free))
+        .collect();
+    lines.extend(
+        stacks
+            .chunks(2)
+            .take(5)
+            .map(|pair| format!("{:<15}{}", pair[0], pair.get(1).map_or("", |s| s))),
+    );
+    lines
+}
+
+// the recent log with long lines wrapped
+fn log_rows() -> Vec<String> {
+    recent_log()
+        .iter()
+        .flat_map(|line| {
+            let chars: Vec<char> = line.chars().collect();
+            chars
+                .chunks(LOG_CHARS)
+                .map(|c| c.iter().collect::<String>())
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+// a page of the log, the newest at the bottom when it's not scrolled back
+fn log_lines(back: usize) -> Vec<String> {
+    let rows = log_rows();
+    let end = rows
+        .len()
+        .saturating_sub(back)
+        .max(rows.len().min(LOG_ROWS));
+    let start = end.saturating_sub(LOG_ROWS);
+    let mut lines = match rows.len() {
+        0 => vec!["Log".to_string()],
+        len => vec![format!("Log {}-{} of {}", start + 1, end, len)],
+    };
+    lines.extend(rows[start..end].iter().cloned());
+    lines
+}
+
+fn format_bytes(bytes: u32) -> String {
+    match bytes {
+        b if b < 10_000 => format!("{}B", b),
+        b if b < 10_000_000 => format!("{}kB", b / 1000),
+        b => format!("{}MB", b / 1_000_000),
+    }
+}
+
+fn format_age(age: Duration) -> String {
+    match age.as_secs() {
+        s if s < 120 => format!("{}s", s),
+        s if s < 7200 => format!("{}m", s / 60),
+        s if s < 172_800 => format!("{}h", s / 3600),
+        s => format!("{}d", s / 86_400),
+    }
+}
+
+// draw the list page, as many of the names as fit below the title
+pub fn render_list(title: &str, names: &[String], display_tx: &Sender<DrawCmd>) -> Result<()> {
+    display_tx.send(DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(10, 20)),
+        font: Some(PROFONT_24_POINT.into()),
+        text: title.into(),
+        text_color: Rgb565::BLUE,
+        background: Some(RgbColor::WHITE),
+    })?;
+    for (i, name) in names.iter().take(7).enumerate() {
+        display_tx.send(DrawCmd::Text {
+            pos: DrawPos::Pos(Point::new(10, 44 + 22 * i as i32)),
+            font: None,
+            text: name.chars().take(30).collect(),
+            text_color: RgbColor::BLACK,
+            background: Some(RgbColor::WHITE),
+        })?;
+    }
+    for (i, label) in Page::List.button_labels().iter().enumerate() {
+        display_tx.send(DrawCmd::Text {
+            pos: DrawPos::Button(i as u8),
+            font: None,
+            text: label.to_string(),
+            text_color: RgbColor::BLACK,
+            background: Some(RgbColor::WHITE),
+        })?;
+    }
+    Ok(())
+}
+
+// draw the adjust page with the light's brightness as a bar
+pub fn render_adjust(
+    adjust: &Adjust,
+    entity: Option<&Entity>,
+    display_tx: &Sender<DrawCmd>,
+) -> Result<()> {
+    let name = entity
+        .and_then(|e| e.attribute("friendly_name"))
+        .unwrap_or(&adjust.ha_id);
+    display_tx.send(DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(10, 20)),
+        font: Some(PROFONT_24_POINT.into()),
+        text: name.chars().take(18).collect(),
+        text_color: Rgb565::BLUE,
+        background: Some(RgbColor::WHITE),
+    })?;
+
+    let pct = entity.map_or(0, brightness_pct);
+    let kelvin = entity
+        .and_then(|e| e.attribute("color_temp_kelvin"))
+        .map_or(String::new(), |k| format!(" {}K", k));
+    display_tx.send(DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(10, 90)),
+        font: Some(PROFONT_24_POINT.into()),
+        text: format!("{:<14}", format!("{}%{}", pct, kelvin)),
+        text_color: RgbColor::BLACK,
+        background: Some(RgbColor::WHITE),
+    })?;
+
+    let area = Rectangle::new(Point::new(10, 110), Size::new(300, 24));
+    display_tx.send(DrawCmd::Clear {
+        color: Rgb565::new(28, 56, 28),
+        pos: DrawPos::Box(area),
+    })?;
+    if pct > 0 {
+        display_tx.send(DrawCmd::Clear {
+            color: Rgb565::YELLOW,
+            pos: DrawPos::Box(Rectangle::new(
+                area.top_left,
+                Size::new(area.size.width * pct as u32 / 100, area.size.height),
+            )),
+        })?;
+    }
+
+    display_tx.send(DrawCmd::Text {
+        pos: DrawPos::Pos(Point::new(10, 160)),
+        font: None,
+        text: "Hold - or + for warmer or cooler".into(),
+        text_color: RgbColor::BLACK,
+        background: Some(RgbColor::WHITE),
+    })?;
+    for (i, label) in Page::Adjust.button_labels().iter().enumerate() {
+        display_tx.send(DrawCmd::Text {
+            pos: DrawPos::Button(i as u8),
+            font: None,
+            text: label.to_string(),
+            text_color: RgbColor::BLACK,
+            background: Some(RgbColor::WHITE),
+        })?;
+    }
+    Ok(())
+}
+
+// draw a page other than Home, only redrawing the lines that changed
+// since `last`. Clear `last` to force a full redraw
+pub fn render_page(
+    page: &Page,
+    status: &Status,
+    last: &mut Vec<String>,
+    display_tx: &Sender<DrawCmd>,
+) -> Result<()> {
+    let lines = page.lines(status);
+    if lines == *last {
+        return Ok(());
+    }
+
+    if last.is_empty() {
+        for (i, label) in page.button_labels().iter().enumerate() {
+            display_tx.send(DrawCmd::Text {
+                pos: DrawPos::Button(i as u8),
+                font: None,
+                text: label.to_string(),
+                text_color: RgbColor::BLACK,
+                background: Some(RgbColor::WHITE),
+            })?;
+        }
+    }
+
+    let line_y = |i: usize| if i == 0 { 20 } else { 22 + 22 * i as i32 };
+
+    for (i, line) in lines.iter().enumerate() {
+        if last.get(i) == Some(line) {
+            continue;
+        }
+        display_tx.send(DrawCmd::Text {
+            pos: DrawPos::Pos(Point::new(10, line_y(i))),
+            font: if i == 0 {
+                Some(PROFONT_24_POINT.into())
+            } else {
+                None
+            },
+            text: line.clone(),
+            text_color: if i == 0 { Rgb565::BLUE } else { Rgb565::BLACK },
+            background: Some(RgbColor::WHITE),
+        })?;
+    }
+
+    // clear any lines left over from a longer version of the page
+    for i in lines.len()..last.len() {
+        display_tx.send(DrawCmd::Clear {
+            color: RgbColor::WHITE,
+            pos: DrawPos::Box(Rectangle::new(Point::new(0, line_y(i)), Size::new(320, 22))),
+        })?;
+    }
+
+    if let Some(values) = page.graph(status) {
+        display_tx.send(DrawCmd::Sparkline {
+            area: Rectangle::new(Point::new(10, 178), Size::new(300, 20)),
+            values,
+            color: Rgb565::BLUE,
+            background: Rgb565::WHITE,
+        })?;
+    }
+
+    *last = lines;
+    Ok(())
+}
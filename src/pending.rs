@@ -0,0 +1,165 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use log::*;
+use serde_json::Value;
+
+use crate::ha::ResultMsg;
+
+// how long to wait for HA's result before giving up on a request
+pub const RESULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Pending {
+    what: String,
+    sent: Instant,
+}
+
+// requests sent over the websocket that are waiting for a result from
+// HA, keyed by message id, so failures don't go unnoticed
+#[derive(Default)]
+pub struct PendingRequests {
+    requests: HashMap<i64, Pending>,
+}
+
+impl PendingRequests {
+    pub fn new() -> PendingRequests {
+        PendingRequests::default()
+    }
+
+    // track a message about to be sent. `what` describes it for errors
+    pub fn add(&mut self, msg: &Value, what: String) {
+        if let Some(id) = msg["id"].as_i64() {
+            self.requests.insert(
+                id,
+                Pending {
+                    what,
+                    sent: Instant::now(),
+                },
+            );
+        }
+    }
+
+    // look at a result from HA. If it's for a pending request that
+    // failed, returns the error to show
+    pub fn on_result(&mut self, msg: &ResultMsg) -> Option<String> {
+        let pending = self.requests.remove(&msg.id)?;
+        if msg.success {
+            return None;
+        }
+
+        let error = match &msg.error {
+            Some(e) if !e.message.is_empty() => e.message.as_str(),
+            Some(e) if !e.code.is_empty() => e.code.as_str(),
+            _ => "failed",
+        };
+        info!("{} failed: {:?}", pending.what, msg.error);
+        Some(format!("{}: {}", pending.what, error))
+    }
+
+    // drop requests that never got a result (e.g. the websocket
+    // reconnected), returning the errors to show
+    pub fn expire(&mut self) -> Vec<String> {
+        self.expire_at(Instant::now())
+    }
+
+    fn expire_at(&mut self, now: Instant) -> Vec<String> {
+        let mut errors = vec![];
+        self.requests.retain(|id, pending| {
+            if now.saturating_duration_since(pending.sent) < RESULT_TIMEOUT {
+                return true;
+            }
+            info!("No result for {} (id {})", pending.what, id);
+            errors.push(format!("{}: no reply", pending.what));
+            false
+        });
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn result(id: i64, reply: Value) -> ResultMsg {
+        let mut msg = json!({"id": id, "type": "result"});
+        msg.as_object_mut()
+            .unwrap()
+            .extend(reply.as_object().unwrap().clone());
+        serde_json::from_value(msg).unwrap()
+    }
+
+    fn pending() -> PendingRequests {
+        let mut pending = PendingRequests::new();
+        pending.add(
+            &json!({"id": 1, "type": "call_service"}),
+            "Kitchen light".into(),
+        );
+        pending.add(
+            &json!({"id": 2, "type": "call_service"}),
+            "Front door".into(),
+        );
+        pending
+    }
+
+    #[test]
+    fn results_are_matched_by_id() {
+        let mut pending = pending();
+        let failed = json!({"success": false,
+            "error": {"code": "not_found", "message": "Service not found"}});
+        assert_eq!(
+            pending.on_result(&result(2, failed.clone())),
+            Some("Front door: Service not found".into())
+        );
+        // a result for nothing pending, or one already answered, is ignored
+        assert_eq!(pending.on_result(&result(3, failed.clone())), None);
+        assert_eq!(pending.on_result(&result(2, failed)), None);
+        assert_eq!(
+            pending.on_result(&result(1, json!({"success": true}))),
+            None
+        );
+        assert!(pending
+            .expire_at(Instant::now() + RESULT_TIMEOUT)
+            .is_empty());
+    }
+
+    #[test]
+    fn an_error_without_a_message_shows_its_code() {
+        let mut pending = pending();
+        let failed = json!({"success": false, "error": {"code": "unauthorized"}});
+        assert_eq!(
+            pending.on_result(&result(1, failed)),
+            Some("Kitchen light: unauthorized".into())
+        );
+        assert_eq!(
+            pending.on_result(&result(2, json!({"success": false}))),
+            Some("Front door: failed".into())
+        );
+    }
+
+    #[test]
+    fn requests_without_a_result_time_out() {
+        let mut pending = pending();
+        assert!(pending.expire().is_empty());
+        pending.on_result(&result(1, json!({"success": true})));
+        assert_eq!(
+            pending.expire_at(Instant::now() + RESULT_TIMEOUT),
+            vec!["Front door: no reply".to_string()]
+        );
+        assert!(pending
+            .expire_at(Instant::now() + RESULT_TIMEOUT)
+            .is_empty());
+    }
+
+    #[test]
+    fn messages_without_an_id_arent_tracked() {
+        let mut pending = PendingRequests::new();
+        pending.add(&json!({"type": "ping"}), "Ping".into());
+        assert!(pending
+            .expire_at(Instant::now() + RESULT_TIMEOUT)
+            .is_empty());
+    }
+}
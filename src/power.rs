@@ -0,0 +1,226 @@
+use std::time::{Duration, Instant};
+
+use chrono::{Local, NaiveTime};
+use crossbeam::channel::Sender as XBSender;
+use esp_idf_hal::gpio::{AnyIOPin, PinDriver};
+
+use esp_idf_sys::{
+    esp, esp_deep_sleep_start, esp_pm_config_esp32s3_t, esp_pm_configure,
+    esp_sleep_enable_ext0_wakeup, esp_sleep_enable_timer_wakeup, esp_sleep_get_wakeup_cause,
+    esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0, esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER,
+};
+use log::*;
+
+use crate::{
+    config::{DisplaySleepConfig, MotionConfig, PowerConfig, PowerMode, PowerSave},
+    states::{state_key, States},
+    Error, Result,
+};
+
+// how long a button press wakes the screen while it's meant to be asleep
+const WAKE_FOR: Duration = Duration::from_secs(60);
+
+// when to go into deep sleep: a while after the screen has the latest
+// states, or after the last button press, or when HA can't be reached
+// in time
+pub struct PowerManager {
+    config: PowerConfig,
+    started: Instant,
+    awake_until: Option<Instant>,
+}
+
+impl PowerManager {
+    pub fn new(config: PowerConfig) -> PowerManager {
+        PowerManager {
+            config,
+            started: Instant::now(),
+            awake_until: None,
+        }
+    }
+
+    // the screen has HA's states, the first time starts the countdown
+    pub fn up_to_date(&mut self) {
+        if self.awake_until.is_none() {
+            self.awake_until = Some(Instant::now() + Duration::from_secs(self.config.awake_for));
+        }
+    }
+
+    // a button press keeps the device awake a while longer
+    pub fn button(&mut self) {
+        self.awake_until = Some(Instant::now() + Duration::from_secs(self.config.awake_for));
+    }
+
+    pub fn should_sleep(&self) -> bool {
+        match self.awake_until {
+            Some(at) => Instant::now() > at,
+            None => self.started.elapsed() > Duration::from_secs(self.config.max_awake),
+        }
+    }
+
+    // sleep until the interval is up or the wake button is pressed, for
+    // longer on a low battery. The device starts from scratch when it wakes
+    pub fn sleep(&self, low_battery: bool) -> ! {
+        let interval = match low_battery {
+            true => self.config.low_battery_interval,
+            false => self.config.interval,
+        };
+        info!(
+            "Deep sleep for {}s after {}s awake",
+            interval,
+            self.started.elapsed().as_secs()
+        );
+        unsafe {
+            esp_sleep_enable_timer_wakeup(interval * 1_000_000);
+            if let Some(gpio) = self.config.wake_gpio {
+                if let Err(e) = esp_idf_sys::esp!(esp_sleep_enable_ext0_wakeup(gpio, 0)) {
+                    info!("Can't wake on GPIO {} error {:?}", gpio, e);
+                }
+            }
+            esp_deep_sleep_start();
+        }
+    }
+}
+
+// set the CPU's clock range and light sleep. The radio stops light sleep
+// unless the WiFi's in power save, and the backlight's PWM stops while
+// it sleeps so a dimmed backlight can flicker
+pub fn set_power_mode(mode: PowerMode, wifi_power_save: PowerSave) -> Result<()> {
+    let (max_freq_mhz, min_freq_mhz, light_sleep_enable) = match mode {
+        PowerMode::Performance => (240, 240, false),
+        PowerMode::Balanced => (240, 80, false),
+        PowerMode::PowerSave => (80, 40, true),
+    };
+    let config = esp_pm_config_esp32s3_t {
+        max_freq_mhz,
+        min_freq_mhz,
+        light_sleep_enable,
+    };
+    esp!(unsafe { esp_pm_configure(&config as *const _ as *const std::ffi::c_void) })?;
+    info!(
+        "CPU {}-{}MHz, light sleep {}",
+        min_freq_mhz, max_freq_mhz, light_sleep_enable
+    );
+    if light_sleep_enable && wifi_power_save == PowerSave::None {
+        info!("The WiFi power_save is None, so the device won't light sleep");
+    }
+    Ok(())
+}
+
+// what woke the device, if it was asleep
+pub fn log_wakeup() {
+    match unsafe { esp_sleep_get_wakeup_cause() } {
+        esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER => info!("Woke from deep sleep on the timer"),
+        esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0 => info!("Woke from deep sleep on the button"),
+        _ => {}
+    }
+}
+
+// when the screen is off: in quiet hours, or while an HA entity is on.
+// A button press wakes it for a while
+pub struct DisplaySleep {
+    start: Option<NaiveTime>,
+    end: Option<NaiveTime>,
+    entity_key: Option<String>,
+    woken_until: Option<Instant>,
+}
+
+impl DisplaySleep {
+    pub fn new(config: &DisplaySleepConfig) -> DisplaySleep {
+        let time = |t: &Option<String>| {
+            let t = t.as_ref()?;
+            match NaiveTime::parse_from_str(t, "%H:%M") {
+                Ok(t) => Some(t),
+                Err(e) => {
+                    info!("Bad display sleep time {} error {:?}", t, e);
+                    None
+                }
+            }
+        };
+        DisplaySleep {
+            start: time(&config.start),
+            end: time(&config.end),
+            entity_key: config.entity.as_ref().map(|id| state_key(None, id)),
+            woken_until: None,
+        }
+    }
+
+    // quiet hours can go past midnight, e.g. 23:00 to 06:30
+    fn quiet_hours(&self, now: NaiveTime) -> bool {
+        match (self.start, self.end) {
+            (Some(start), Some(end)) if start <= end => now >= start && now < end,
+            (Some(start), Some(end)) => now >= start || now < end,
+            _ => false,
+        }
+    }
+
+    // the clock isn't right until it's been set, so quiet hours wait for it
+    pub fn asleep(&self, states: &States, has_time: bool) -> bool {
+        let scheduled = (has_time && self.quiet_hours(Local::now().time()))
+            || self.entity_key.as_ref().map_or(false, |key| {
+                states.get(key).map_or(false, |e| e.state == "on")
+            });
+        scheduled && self.woken_until.map_or(true, |at| Instant::now() > at)
+    }
+
+    pub fn wake(&mut self) {
+        self.woken_until = Some(Instant::now() + WAKE_FOR);
+    }
+}
+
+// the screen dims (or turns off) when nobody's been near it for a while,
+// going by a motion sensor on a GPIO or HA's binary_sensor, and the
+// buttons
+pub struct MotionWake {
+    idle_timeout: Duration,
+    pub idle_backlight: u8,
+    entity_key: Option<String>,
+    last_motion: Instant,
+}
+
+impl MotionWake {
+    pub fn new(config: &MotionConfig) -> MotionWake {
+        MotionWake {
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+            idle_backlight: config.idle_backlight,
+            entity_key: config.entity.as_ref().map(|id| state_key(None, id)),
+            last_motion: Instant::now(),
+        }
+    }
+
+    pub fn seen(&mut self) {
+        self.last_motion = Instant::now();
+    }
+
+    // HA's sensor counts as motion for as long as it's on
+    pub fn check_entity(&mut self, states: &States) {
+        let on = self.entity_key.as_ref().map_or(false, |key| {
+            states.get(key).map_or(false, |e| e.state == "on")
+        });
+        if on {
+            self.seen();
+        }
+    }
+
+    pub fn idle(&self) -> bool {
+        self.last_motion.elapsed() > self.idle_timeout
+    }
+}
+
+// watch a PIR or mmWave sensor's output, which is high while it sees
+// someone. Motion is passed on as it starts and every few seconds while
+// it goes on
+pub fn run_motion_sensor(gpio: i32, motion_tx: XBSender<()>) -> Result<()> {
+    let pin = PinDriver::input(unsafe { AnyIOPin::new(gpio) }).map_err(Error::config)?;
+    let mut last_sent: Option<Instant> = None;
+    loop {
+        std::thread::sleep(Duration::from_millis(100));
+        if !pin.is_high() {
+            last_sent = None;
+            continue;
+        }
+        if last_sent.map_or(true, |at| at.elapsed() > Duration::from_secs(5)) {
+            last_sent = Some(Instant::now());
+            let _ = motion_tx.try_send(());
+        }
+    }
+}
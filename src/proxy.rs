@@ -0,0 +1,160 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::*;
+
+use crate::{config::ProxyConfig, Error, Result};
+
+// the local port of each forwarder and the host it tunnels to, whose name
+// TLS certificates have to match rather than 127.0.0.1
+static TUNNELS: Mutex<Vec<(u16, String)>> = Mutex::new(Vec::new());
+
+// each tunnel's thread reads one way then the other, waiting this long
+// on each for something to forward
+const TURN: Duration = Duration::from_millis(10);
+
+// The IDF HTTP and websocket clients can't talk through a proxy. So when a
+// proxy is configured, they connect to this local forwarder which tunnels
+// each connection to Home Assistant through the proxy with CONNECT
+pub fn run_proxy_forwarder(
+    has_network: &AtomicBool,
+    proxy: ProxyConfig,
+    target: &'static str,
+) -> Result<()> {
+    if let Ok(mut tunnels) = TUNNELS.lock() {
+        let host = target.rsplit_once(':').map_or(target, |(host, _)| host);
+        // the forwarder is started again if it stops
+        if !tunnels.iter().any(|(port, _)| *port == proxy.local_port) {
+            tunnels.push((proxy.local_port, host.to_string()));
+        }
+    }
+
+    // wait until there's a network stack
+    while !has_network.load(Ordering::Relaxed) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    let listener = TcpListener::bind(("127.0.0.1", proxy.local_port)).map_err(Error::socket)?;
+
+    info!(
+        "Forwarding 127.0.0.1:{} to {} via proxy {}:{}",
+        proxy.local_port, target, proxy.host, proxy.port
+    );
+
+    for conn in listener.incoming() {
+        let client = match conn {
+            Ok(c) => c,
+            Err(e) => {
+                info!("Proxy forwarder accept error {:?}", e);
+                continue;
+            }
+        };
+
+        match connect_tunnel(&proxy, target) {
+            Ok(upstream) => {
+                // out of memory for a thread drops this connection, the
+                // next one may fare better
+                if let Err(e) = std::thread::Builder::new()
+                    .stack_size(3000)
+                    .spawn(move || pump(client, upstream))
+                {
+                    error!("Failed to start proxy tunnel error {:?}", e);
+                }
+            }
+            Err(e) => {
+                info!("Failed to open proxy tunnel to {} error {:?}", target, e);
+                let _ = client.shutdown(Shutdown::Both);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// open a CONNECT tunnel to the target through the proxy
+fn connect_tunnel(proxy: &ProxyConfig, target: &str) -> Result<TcpStream> {
+    let mut stream =
+        TcpStream::connect((proxy.host.as_str(), proxy.port)).map_err(Error::socket)?;
+
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some(user) = &proxy.username {
+        let pass = proxy.password.as_deref().unwrap_or("");
+        let auth = STANDARD.encode(format!("{}:{}", user, pass));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", auth));
+    }
+    request.push_str("\r\n");
+    stream
+        .write_all(request.as_bytes())
+        .map_err(Error::socket)?;
+
+    // read the response headers a byte at a time so nothing after them
+    // (the start of the tunnelled data) gets consumed
+    let mut head: Vec<u8> = vec![];
+    let mut byte = [0_u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte).map_err(Error::socket)? == 0 {
+            return Err(Error::Socket("Proxy closed the connection".into()));
+        }
+        head.push(byte[0]);
+        if head.len() > 4096 {
+            return Err(Error::Socket("Proxy response headers too long".into()));
+        }
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let status = head.lines().next().unwrap_or("");
+    match status.split_whitespace().nth(1) {
+        Some("200") => Ok(stream),
+        _ => Err(Error::Socket(format!(
+            "Proxy refused the tunnel: {}",
+            status
+        ))),
+    }
+}
+
+// the host a URL's TLS certificate has to be for, when the URL is a
+// forwarder's
+pub fn tunnelled_host(url: &str) -> Option<String> {
+    let authority = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = authority.split('/').next().unwrap_or(authority);
+    let (host, port) = authority.rsplit_once(':')?;
+    if host != "127.0.0.1" {
+        return None;
+    }
+    let port: u16 = port.parse().ok()?;
+    let tunnels = TUNNELS.lock().ok()?;
+    tunnels
+        .iter()
+        .find(|(local, _)| *local == port)
+        .map(|(_, host)| host.clone())
+}
+
+// forward both ways on the one thread, each thread's stack being a good
+// part of the free memory
+fn pump(mut client: TcpStream, mut upstream: TcpStream) {
+    let _ = client.set_read_timeout(Some(TURN));
+    let _ = upstream.set_read_timeout(Some(TURN));
+    let mut buf = [0_u8; 512];
+    while forward(&mut client, &mut upstream, &mut buf)
+        && forward(&mut upstream, &mut client, &mut buf)
+    {}
+    let _ = client.shutdown(Shutdown::Both);
+    let _ = upstream.shutdown(Shutdown::Both);
+}
+
+// pass on what's arrived, false once either end has closed
+fn forward(from: &mut TcpStream, to: &mut TcpStream, buf: &mut [u8]) -> bool {
+    match from.read(buf) {
+        Ok(0) => false,
+        Ok(read) => to.write_all(&buf[..read]).is_ok(),
+        Err(e) => matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+    }
+}
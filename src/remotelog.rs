@@ -0,0 +1,144 @@
+use std::{
+    cell::Cell,
+    net::UdpSocket,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crossbeam::channel::{bounded, Sender};
+use embedded_svc::mqtt::client::{Publish, QoS};
+use esp_idf_svc::mqtt::client::{EspMqttClient, MqttClientConfiguration};
+use log::{Level, LevelFilter, Record};
+
+use crate::{
+    config::{MqttConfig, RemoteLogConfig},
+    Error, Result,
+};
+
+// lines waiting to be sent. Until the network's up the first ones are
+// kept and the rest dropped
+const QUEUE: usize = 64;
+
+const SYSLOG_PORT: u16 = 514;
+
+struct Forward {
+    level: LevelFilter,
+    tx: Sender<(Level, String)>,
+}
+
+static FORWARD: Mutex<Option<Forward>> = Mutex::new(None);
+
+thread_local! {
+    // the forwarder's own lines (e.g. from the MQTT client) aren't sent,
+    // sending them could log more
+    static FORWARDING: Cell<bool> = Cell::new(false);
+}
+
+// queue a line for the forwarder, if there is one. It's dropped rather
+// than waited for when the queue's full
+pub fn forward_log(record: &Record) {
+    if FORWARDING.with(|f| f.get()) {
+        return;
+    }
+    if let Ok(forward) = FORWARD.lock() {
+        if let Some(forward) = forward.as_ref() {
+            if record.level() <= forward.level {
+                let line = format!("{}: {}", record.target(), record.args());
+                let _ = forward.tx.try_send((record.level(), line));
+            }
+        }
+    }
+}
+
+// send the log to a syslog server over UDP and publish it to an MQTT topic,
+// from when the network comes up
+pub fn run_remote_log(
+    config: RemoteLogConfig,
+    mqtt: Option<MqttConfig>,
+    hostname: String,
+    has_network: &AtomicBool,
+) -> Result<()> {
+    FORWARDING.with(|f| f.set(true));
+    let (tx, rx) = bounded(QUEUE);
+    if let Ok(mut forward) = FORWARD.lock() {
+        *forward = Some(Forward {
+            level: config.level.parse().unwrap_or(LevelFilter::Info),
+            tx,
+        });
+    }
+
+    // wait until there's a network stack
+    while !has_network.load(Ordering::Relaxed) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    let syslog = match &config.syslog {
+        Some(server) => {
+            let socket = UdpSocket::bind("0.0.0.0:0").map_err(Error::socket)?;
+            if server.contains(':') {
+                socket.connect(server).map_err(Error::socket)?;
+            } else {
+                socket
+                    .connect((server.as_str(), SYSLOG_PORT))
+                    .map_err(Error::socket)?;
+            }
+            Some(socket)
+        }
+        None => None,
+    };
+
+    // the broker's credentials come with the `mqtt` section's URL, another
+    // URL can have its own in it
+    let mut client = match (&config.mqtt_topic, &config.mqtt_url, &mqtt) {
+        (Some(_), Some(url), _) => Some(mqtt_client(url, &hostname, None, None)?),
+        (Some(_), None, Some(mqtt)) => Some(mqtt_client(
+            &mqtt.url,
+            &hostname,
+            mqtt.username.as_deref(),
+            mqtt.password.as_deref(),
+        )?),
+        _ => None,
+    };
+
+    for (level, line) in rx.iter() {
+        if let Some(socket) = &syslog {
+            let _ = socket.send(syslog_message(level, &hostname, &line).as_bytes());
+        }
+        if let (Some(client), Some(topic)) = (client.as_mut(), &config.mqtt_topic) {
+            let text = format!("{} {}", level, line);
+            let _ = client.publish(topic, QoS::AtMostOnce, false, text.as_bytes());
+        }
+    }
+    Ok(())
+}
+
+// a client of its own, so the log works with the websocket too
+fn mqtt_client(
+    url: &str,
+    hostname: &str,
+    username: Option<&str>,
+    password: Option<&str>,
+) -> Result<EspMqttClient<'static>> {
+    let client_id = format!("{}-log", hostname);
+    let conf = MqttClientConfiguration {
+        client_id: Some(&client_id),
+        username,
+        password,
+        ..Default::default()
+    };
+    EspMqttClient::new(url, &conf, |_| {}).map_err(Error::socket)
+}
+
+// RFC 5424 with the user facility, the server stamps the time
+fn syslog_message(level: Level, hostname: &str, line: &str) -> String {
+    let severity = match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    format!("<{}>1 - {} homer - - - {}", 8 + severity, hostname, line)
+}
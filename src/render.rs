@@ -0,0 +1,301 @@
+use std::{collections::HashMap, sync::mpsc::Sender};
+
+use embedded_graphics::{
+    pixelcolor::{raw::RawU16, Rgb565},
+    prelude::{Point, RgbColor, Size},
+    primitives::Rectangle,
+};
+
+use crate::{
+    display::{DrawCmd, DrawPos},
+    fonts::line_font,
+    states::{Entity, States},
+    util::HAConnect,
+    widgets::{self, WidgetPart, WidgetState},
+};
+
+// the color of values from before a reboot or while HA is unreachable
+pub const STALE_COLOR: Rgb565 = Rgb565::new(16, 32, 16);
+
+// the unfilled part of the bars widgets draw
+const BAR_BACKGROUND: Rgb565 = Rgb565::new(28, 56, 28);
+
+// widgets' own colors aren't used for stale values
+fn draw_widget_part(part: WidgetPart, color: Rgb565, stale: bool, display_tx: &Sender<DrawCmd>) {
+    match part {
+        WidgetPart::Text(pos, text, own_color) => {
+            let color = match own_color {
+                Some(c) if !stale => c,
+                _ => color,
+            };
+            let font = match pos {
+                DrawPos::Button(_) => None,
+                _ => Some(line_font()),
+            };
+            display_tx
+                .send(DrawCmd::Text {
+                    pos,
+                    font,
+                    text,
+                    text_color: color,
+                    background: Some(RgbColor::WHITE),
+                })
+                .unwrap();
+        }
+        WidgetPart::Icon(top_left, icon, own_color) => {
+            let color = match own_color {
+                Some(c) if !stale => Some(c),
+                _ => stale.then_some(color),
+            };
+            display_tx
+                .send(DrawCmd::Icon {
+                    top_left,
+                    icon,
+                    color,
+                    background: RgbColor::WHITE,
+                })
+                .unwrap();
+        }
+        WidgetPart::Bar(area, percent) => {
+            let filled = area.size.width * percent as u32 / 100;
+            display_tx
+                .send(DrawCmd::Clear {
+                    color: BAR_BACKGROUND,
+                    pos: DrawPos::Box(area),
+                })
+                .unwrap();
+            if filled > 0 {
+                display_tx
+                    .send(DrawCmd::Clear {
+                        color,
+                        pos: DrawPos::Box(Rectangle::new(
+                            area.top_left,
+                            Size::new(filled, area.size.height),
+                        )),
+                    })
+                    .unwrap();
+            }
+        }
+    }
+}
+
+// draws the home page, remembering what's on the screen so only the
+// changes are drawn again
+pub struct Renderer<'a> {
+    connect: &'a [HAConnect],
+    display_tx: &'a Sender<DrawCmd>,
+    placeholder: &'a str,
+    last_state: HashMap<String, String>,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn new(
+        connect: &'a [HAConnect],
+        display_tx: &'a Sender<DrawCmd>,
+        placeholder: &'a str,
+    ) -> Renderer<'a> {
+        Renderer {
+            connect,
+            display_tx,
+            placeholder,
+            last_state: HashMap::new(),
+        }
+    }
+
+    pub fn render(&mut self, states: &States, stale: bool, widget_state: &WidgetState) {
+        render_states(
+            self.connect,
+            states,
+            &mut self.last_state,
+            self.display_tx,
+            stale,
+            self.placeholder,
+            widget_state,
+        );
+    }
+
+    // forget what's on the screen, e.g. once it's been erased, so it's
+    // all drawn the next time
+    pub fn clear(&mut self) {
+        self.last_state.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.last_state.is_empty()
+    }
+}
+
+// update the display, only rendering states that have changed. Stale
+// values (cached, or while HA is offline) are greyed out
+pub fn render_states(
+    connect: &[HAConnect],
+    states: &States,
+    last_state: &mut HashMap<String, String>,
+    display_tx: &Sender<DrawCmd>,
+    stale: bool,
+    placeholder: &str,
+    widget_state: &WidgetState,
+) {
+    let value_color = |color: u16| {
+        if stale {
+            STALE_COLOR
+        } else {
+            RawU16::new(color).into()
+        }
+    };
+    // unavailable entities are dimmed
+    let entity_color = |color: u16, entity: Option<&Entity>| {
+        if entity.map_or(true, |e| e.is_available()) {
+            value_color(color)
+        } else {
+            STALE_COLOR
+        }
+    };
+
+    for c in connect {
+        match c {
+            HAConnect::Text { line, text, color } => {
+                let cu16: RawU16 = (*color).into();
+
+                // don't redisplay
+                if Some(text) != last_state.get(text) {
+                    last_state.insert(text.clone(), text.clone());
+                    display_tx
+                        .send(DrawCmd::Text {
+                            pos: DrawPos::Pos(Point::new(10, 30 * (*line as i32 + 2))),
+                            font: Some(line_font()),
+                            text: text.clone(),
+                            text_color: cu16.into(),
+                            background: Some(RgbColor::WHITE),
+                        })
+                        .unwrap();
+                }
+            }
+            HAConnect::Template { line, color, .. } => {
+                let key = c.state_key();
+                if let Some(entity) = states.get(&key) {
+                    if Some(&entity.state) != last_state.get(&key) {
+                        last_state.insert(key, entity.state.clone());
+
+                        display_tx
+                            .send(DrawCmd::Text {
+                                pos: DrawPos::Pos(Point::new(10, 30 * (*line as i32 + 2))),
+                                font: Some(line_font()),
+                                text: entity.state.clone(),
+                                text_color: value_color(*color),
+                                background: Some(RgbColor::WHITE),
+                            })
+                            .unwrap();
+                    }
+                }
+            }
+            HAConnect::Line {
+                line,
+                text,
+                make_int,
+                color,
+                ..
+            } => {
+                let key = c.state_key();
+                if let Some(entity) = states.get(&key) {
+                    let line_str = entity.format_line(text, *make_int, placeholder);
+
+                    if Some(&line_str) != last_state.get(&key) {
+                        last_state.insert(key, line_str.clone());
+
+                        display_tx
+                            .send(DrawCmd::Text {
+                                pos: DrawPos::Pos(Point::new(10, 30 * (*line as i32 + 2))),
+                                font: Some(line_font()),
+                                text: line_str,
+                                text_color: entity_color(*color, Some(entity)),
+                                background: Some(RgbColor::WHITE),
+                            })
+                            .unwrap();
+                    }
+                }
+            }
+            HAConnect::Attribute {
+                line,
+                attribute,
+                text,
+                make_int,
+                color,
+                ..
+            } => {
+                // the same entity can also be on a Line
+                let key = c.state_key();
+                if let Some(entity) = states.get(&key) {
+                    let key = format!("{}#{}", key, attribute);
+                    let line_str = entity.format_attribute(attribute, text, *make_int, placeholder);
+
+                    if Some(&line_str) != last_state.get(&key) {
+                        last_state.insert(key, line_str.clone());
+
+                        display_tx
+                            .send(DrawCmd::Text {
+                                pos: DrawPos::Pos(Point::new(10, 30 * (*line as i32 + 2))),
+                                font: Some(line_font()),
+                                text: line_str,
+                                text_color: entity_color(*color, Some(entity)),
+                                background: Some(RgbColor::WHITE),
+                            })
+                            .unwrap();
+                    }
+                }
+            }
+            HAConnect::Button {
+                button,
+                text_on,
+                text_off,
+                color,
+                ..
+            } => {
+                let key = c.state_key();
+                let entity = states.get(&key);
+                let disp = if !entity.map_or(true, |e| e.is_available()) {
+                    placeholder
+                } else if c.is_on(states) {
+                    text_on
+                } else {
+                    text_off
+                };
+                let last = last_state.get(&key).map(|s| s.as_str());
+                if Some(disp) != last {
+                    last_state.insert(key, disp.to_string());
+                    display_tx
+                        .send(DrawCmd::Text {
+                            pos: DrawPos::Button(*button),
+                            font: None,
+                            text: disp.to_string(),
+                            text_color: entity_color(*color, entity),
+                            background: Some(RgbColor::WHITE),
+                        })
+                        .unwrap();
+                }
+            }
+            // widgets draw a line and button labels each
+            c => {
+                let key = c.state_key();
+                let color = entity_color(c.color(), states.get(&key));
+                let parts = widgets::view(c, states, placeholder, widget_state);
+                // all the parts are redrawn if any changed, as drawing text
+                // clears the rest of its line
+                let mut changed = false;
+                for (i, part) in parts.iter().enumerate() {
+                    let slot = format!("{}#{}", key, i);
+                    let summary = part.summary();
+                    if Some(&summary) != last_state.get(&slot) {
+                        last_state.insert(slot, summary);
+                        changed = true;
+                    }
+                }
+                if changed {
+                    for part in parts {
+                        draw_widget_part(part, color, stale, display_tx);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use embedded_svc::http::client::Client;
+use esp_idf_svc::http::client::EspHttpConnection;
+use log::*;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{config::HaConfig, proxy::tunnelled_host, status::Status, Error, Result};
+
+// minimum time between the end of one request to HA and the start of the next
+const SPACING: Duration = Duration::from_millis(200);
+
+// attempts for requests HA answers with 429 or 5xx
+const ATTEMPTS: u32 = 4;
+
+// the first retry waits this long, doubling for each one after
+const BACKOFF: Duration = Duration::from_millis(500);
+
+// the most of a response that's read. Anything bigger (a camera's full
+// resolution image, say) would run the device out of memory
+const MAX_BODY: usize = 64 * 1024;
+
+// only one request to HA at a time. Holds when the last one finished
+static LAST_REQUEST: Mutex<Option<Instant>> = Mutex::new(None);
+
+pub struct RestResponse {
+    pub status: u16,
+    pub date: Option<String>,
+    pub body: Vec<u8>,
+}
+
+fn ha_client(url: &str, ha: &HaConfig) -> Result<Client<EspHttpConnection>> {
+    use esp_idf_svc::http::client::*;
+
+    // through the proxy forwarder the certificate is for the host behind it
+    let common_name = tunnelled_host(url);
+    Ok(Client::wrap(
+        EspHttpConnection::new(&Configuration {
+            crt_bundle_attach: if ha.ca_cert.is_some() {
+                None
+            } else {
+                Some(esp_idf_sys::esp_crt_bundle_attach)
+            },
+            use_global_ca_store: ha.ca_cert.is_some(),
+            common_name: common_name.as_deref(),
+
+            ..Default::default()
+        })
+        .map_err(Error::socket)?,
+    ))
+}
+
+fn request_once(
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+    ha: &HaConfig,
+) -> Result<RestResponse> {
+    use embedded_svc::http::{client::*, Headers};
+    use embedded_svc::io::Write;
+    use embedded_svc::utils::io;
+
+    let mut client = ha_client(url, ha)?;
+
+    let mut response = match body {
+        None => client
+            .request(Method::Get, url, headers)
+            .and_then(|request| request.submit())
+            .map_err(Error::socket)?,
+        Some(body) => {
+            let len = body.len().to_string();
+            let mut with_len: Vec<(&str, &str)> = headers.to_vec();
+            with_len.push(("Content-Length", &len));
+            let mut request = client
+                .request(Method::Post, url, &with_len)
+                .map_err(Error::socket)?;
+            request.write_all(body).map_err(Error::socket)?;
+            request.flush().map_err(Error::socket)?;
+            request.submit().map_err(Error::socket)?
+        }
+    };
+
+    let status = response.status();
+    let date = response.header("Date").map(|d| d.to_string());
+    let too_big = || Error::Socket(format!("Response from {} is over {} bytes", url, MAX_BODY));
+
+    let length = response
+        .header("Content-Length")
+        .and_then(|l| l.parse::<usize>().ok());
+    if length.map_or(false, |l| l > MAX_BODY) {
+        return Err(too_big());
+    }
+
+    let mut body: Vec<u8> = Vec::with_capacity(length.unwrap_or(0));
+    let mut buf = [0_u8; 512];
+
+    loop {
+        let read =
+            io::try_read_full(&mut response, &mut buf).map_err(|err| Error::socket(err.0))?;
+        if read == 0 {
+            break;
+        }
+        // chunked responses don't say how long they are
+        if body.len() + read > MAX_BODY {
+            return Err(too_big());
+        }
+        body.extend_from_slice(&buf[0..read]);
+    }
+
+    Ok(RestResponse { status, date, body })
+}
+
+// a random delay so a bunch of devices restarting together don't all
+// retry at the same moment
+fn jitter(max: Duration) -> Duration {
+    let r = unsafe { esp_idf_sys::esp_random() };
+    Duration::from_millis(r as u64 % (max.as_millis() as u64 + 1))
+}
+
+// GET a URL on HA's API. Requests are serialized and spaced out so a big
+// config doesn't trip HA's rate limiting at startup, and 429 or 5xx
+// responses are retried with a jittered backoff
+pub fn ha_get(
+    url: &str,
+    headers: &[(&str, &str)],
+    ha: &HaConfig,
+    status: &Status,
+) -> Result<RestResponse> {
+    ha_request(url, headers, None, ha, status)
+}
+
+// POST a body to a URL on HA's API, like ha_get
+pub fn ha_post(
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    ha: &HaConfig,
+    status: &Status,
+) -> Result<RestResponse> {
+    ha_request(url, headers, Some(body), ha, status)
+}
+
+fn ha_request(
+    url: &str,
+    headers: &[(&str, &str)],
+    body: Option<&[u8]>,
+    ha: &HaConfig,
+    status: &Status,
+) -> Result<RestResponse> {
+    let mut last = match LAST_REQUEST.lock() {
+        Ok(last) => last,
+        Err(_) => return Err(Error::Socket("REST client lock poisoned".into())),
+    };
+
+    // an OAuth access token replaces the configured one
+    let bearer = status
+        .access_token
+        .lock()
+        .ok()
+        .and_then(|t| t.as_ref().map(|t| format!("Bearer {}", t)));
+    let headers: Vec<(&str, &str)> = headers
+        .iter()
+        .map(|&(name, value)| match &bearer {
+            Some(b) if name == "Authorization" => (name, b.as_str()),
+            _ => (name, value),
+        })
+        .collect();
+
+    let mut backoff = BACKOFF;
+    let mut attempt = 1;
+    loop {
+        if let Some(since) = last.map(|l| l.elapsed()) {
+            if since < SPACING {
+                std::thread::sleep(SPACING - since);
+            }
+        }
+
+        let result = request_once(url, &headers, body, ha);
+        *last = Some(Instant::now());
+
+        let response = result?;
+        status.traffic.rest_received(response.body.len());
+        if response.status != 429 && response.status < 500 {
+            return Ok(response);
+        }
+        if attempt >= ATTEMPTS {
+            return Ok(response);
+        }
+
+        let wait = backoff + jitter(backoff);
+        info!(
+            "Request for {} yielded {}, retrying in {:?}",
+            url, response.status, wait
+        );
+        std::thread::sleep(wait);
+        backoff *= 2;
+        attempt += 1;
+    }
+}
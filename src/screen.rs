@@ -0,0 +1,121 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+
+use display_interface_spi::SPIInterfaceNoCS;
+use esp_idf_hal::{
+    delay, gpio,
+    ledc::{config::TimerConfig, LedcDriver, LedcTimerDriver, CHANNEL0, TIMER0},
+    prelude::*,
+    spi,
+};
+use log::info;
+
+use crate::{
+    display::{draw, DrawCmd},
+    watchdog::{TaskWatch, FEED_INTERVAL},
+    Error, Result,
+};
+
+// the backlight is on while the pin is low
+fn set_backlight(
+    backlight: &mut LedcDriver,
+    asleep: bool,
+    brightness: u8,
+    dim: Option<u8>,
+) -> Result<()> {
+    let percent = match asleep {
+        true => 0,
+        false => brightness.min(dim.unwrap_or(100)),
+    };
+    let max = backlight.get_max_duty();
+    backlight
+        .set_duty(max - max * percent as u32 / 100)
+        .map_err(Error::display)?;
+    Ok(())
+}
+
+// the TTGO's ST7789 screen, drawing the commands as they come in
+pub fn draw_loop(
+    rx: Receiver<DrawCmd>,
+    backlight: gpio::Gpio45,
+    backlight_timer: TIMER0,
+    backlight_channel: CHANNEL0,
+    dc: gpio::Gpio4,
+    rst: gpio::Gpio48,
+    spi: spi::SPI2,
+    sclk: gpio::Gpio7,
+    sdo: gpio::Gpio6,
+    cs: gpio::Gpio5,
+) -> Result<()> {
+    info!("About to initialize the TTGO ST7789 LED driver");
+    let watch = TaskWatch::new("draw");
+
+    // the backlight is PWM dimmed, it's on while the pin is low
+    let timer = LedcTimerDriver::new(
+        backlight_timer,
+        &TimerConfig::default().frequency(5.kHz().into()),
+    )
+    .map_err(Error::display)?;
+    let mut backlight =
+        LedcDriver::new(backlight_channel, &timer, backlight).map_err(Error::display)?;
+    backlight.set_duty(0).map_err(Error::display)?;
+
+    let di = SPIInterfaceNoCS::new(
+        spi::SpiDeviceDriver::new_single(
+            spi,
+            sclk,
+            sdo,
+            Option::<gpio::Gpio21>::None,
+            Some(cs),
+            &spi::SpiDriverConfig::new().dma(spi::Dma::Disabled),
+            &spi::SpiConfig::new().baudrate(26.MHz().into()),
+        )
+        .map_err(Error::display)?,
+        gpio::PinDriver::output(dc).map_err(Error::display)?,
+    );
+
+    let mut display = mipidsi::Builder::st7789(di)
+        .with_display_size(240, 320)
+        .with_invert_colors(mipidsi::ColorInversion::Inverted)
+        .with_orientation(mipidsi::options::Orientation::LandscapeInverted(true))
+        .init(
+            &mut delay::Ets,
+            Some(gpio::PinDriver::output(rst).map_err(Error::display)?),
+        )
+        .map_err(Error::display)?;
+
+    let mut asleep = false;
+    let mut brightness = 100;
+    let mut dim: Option<u8> = None;
+    loop {
+        watch.feed();
+        let v = match rx.recv_timeout(FEED_INTERVAL) {
+            Ok(v) => v,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(e) => return Err(Error::display(e)),
+        };
+        if asleep
+            && !matches!(
+                v,
+                DrawCmd::Backlight(_) | DrawCmd::Dim(_) | DrawCmd::Sleep(_) | DrawCmd::Theme(_)
+            )
+        {
+            continue;
+        }
+
+        match v {
+            DrawCmd::Backlight(percent) => {
+                brightness = percent.min(100);
+                set_backlight(&mut backlight, asleep, brightness, dim)?;
+            }
+            DrawCmd::Dim(cap) => {
+                dim = cap;
+                set_backlight(&mut backlight, asleep, brightness, dim)?;
+            }
+            DrawCmd::Sleep(sleep) => {
+                asleep = sleep;
+                set_backlight(&mut backlight, asleep, brightness, dim)?;
+            }
+            v => draw(&mut display, v)?,
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsCustom, NvsDefault, NvsPartitionId};
+use esp_idf_sys::{esp, nvs_flash_erase};
+use log::*;
+
+use crate::{Error, Result};
+
+// the NVS partition the secrets go in with the `encrypted-nvs` feature,
+// encrypted with the keys in the nvs_keys partition
+#[cfg(feature = "encrypted-nvs")]
+const SECRETS_PARTITION: &str = "homersec";
+
+// the settings that are secrets: tokens, passwords and keys. Each HA
+// instance's token is ha_token_<name>
+const SECRET_KEYS: &[&str] = &[
+    "ha_token",
+    "ha_refresh_token",
+    "eap_password",
+    "wg_private_key",
+    "wg_psk",
+    "web_password",
+];
+
+fn is_secret(key: &str) -> bool {
+    SECRET_KEYS.contains(&key) || key.starts_with("ha_token_")
+}
+
+// settings stored in the "homer" NVS namespace. These survive
+// re-flashing the configs partition and override the config file. With
+// encrypted NVS the secrets are kept apart, in an encrypted partition
+#[derive(Clone)]
+pub struct Settings {
+    nvs: Option<Arc<Mutex<EspNvs<NvsDefault>>>>,
+    secrets: Option<Arc<Mutex<EspNvs<NvsCustom>>>>,
+}
+
+impl Settings {
+    pub fn new(partition: EspDefaultNvsPartition) -> Settings {
+        let nvs = match EspNvs::new(partition, "homer", true) {
+            Ok(nvs) => Some(Arc::new(Mutex::new(nvs))),
+            Err(e) => {
+                info!("Failed to open the NVS namespace error {:?}", e);
+                None
+            }
+        };
+        Settings {
+            nvs,
+            secrets: open_secrets().map(|s| Arc::new(Mutex::new(s))),
+        }
+    }
+
+    // a secret saved before NVS was encrypted is moved to the encrypted
+    // partition the first time it's read, and wiped from the plain one
+    pub fn get_str(&self, key: &str) -> Option<String> {
+        let nvs = self.nvs.as_ref();
+        match (&self.secrets, is_secret(key)) {
+            (Some(secrets), true) => get_str(secrets, key).or_else(|| {
+                let value = get_str(nvs?, key)?;
+                match set_str(secrets, key, &value) {
+                    Ok(()) => {
+                        let _ = remove(nvs?, key);
+                        info!("Moved {} to encrypted NVS", key);
+                    }
+                    Err(e) => info!("Failed to move {} to encrypted NVS error {:?}", key, e),
+                }
+                Some(value)
+            }),
+            _ => get_str(nvs?, key),
+        }
+    }
+
+    pub fn set_str(&self, key: &str, value: &str) -> Result<()> {
+        match (&self.secrets, is_secret(key)) {
+            (Some(secrets), true) => {
+                set_str(secrets, key, value)?;
+                // no plain copy left behind
+                if let Some(nvs) = &self.nvs {
+                    let _ = remove(nvs, key);
+                }
+                Ok(())
+            }
+            _ => set_str(
+                self.nvs
+                    .as_ref()
+                    .ok_or_else(|| Error::Fs("NVS not available".into()))?,
+                key,
+                value,
+            ),
+        }
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        match (&self.secrets, is_secret(key)) {
+            (Some(secrets), true) => {
+                if let Some(nvs) = &self.nvs {
+                    let _ = remove(nvs, key);
+                }
+                remove(secrets, key)
+            }
+            _ => remove(
+                self.nvs
+                    .as_ref()
+                    .ok_or_else(|| Error::Fs("NVS not available".into()))?,
+                key,
+            ),
+        }
+    }
+}
+
+fn get_str<T: NvsPartitionId>(nvs: &Mutex<EspNvs<T>>, key: &str) -> Option<String> {
+    let nvs = nvs.lock().ok()?;
+    let len = nvs.str_len(key).ok()??;
+    let mut buf = vec![0_u8; len + 1];
+    nvs.get_str(key, &mut buf)
+        .ok()
+        .flatten()
+        .map(|s| s.to_string())
+}
+
+fn set_str<T: NvsPartitionId>(nvs: &Mutex<EspNvs<T>>, key: &str, value: &str) -> Result<()> {
+    nvs.lock()
+        .map_err(|_| Error::Fs("NVS lock poisoned".into()))?
+        .set_str(key, value)?;
+    Ok(())
+}
+
+fn remove<T: NvsPartitionId>(nvs: &Mutex<EspNvs<T>>, key: &str) -> Result<()> {
+    nvs.lock()
+        .map_err(|_| Error::Fs("NVS lock poisoned".into()))?
+        .remove(key)?;
+    Ok(())
+}
+
+// the encrypted partition, with the keys from nvs_keys (generated on the
+// first boot). The keys are only safe with flash encryption on, which
+// encrypts nvs_keys and the firmware with its built in WiFi password
+#[cfg(feature = "encrypted-nvs")]
+fn open_secrets() -> Option<EspNvs<NvsCustom>> {
+    use esp_idf_svc::nvs::EspCustomNvsPartition;
+    use esp_idf_sys::{
+        esp_flash_encryption_enabled, esp_partition_find_first,
+        esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_NVS_KEYS,
+        esp_partition_type_t_ESP_PARTITION_TYPE_DATA, nvs_flash_erase_partition,
+        nvs_flash_generate_keys, nvs_flash_read_security_cfg, nvs_flash_secure_init_partition,
+        nvs_sec_cfg_t,
+    };
+
+    let open = || -> Result<EspNvs<NvsCustom>> {
+        if !unsafe { esp_flash_encryption_enabled() } {
+            info!("Flash encryption is off, the NVS keys can be read off the flash");
+        }
+        let keys = unsafe {
+            esp_partition_find_first(
+                esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_DATA_NVS_KEYS,
+                std::ptr::null(),
+            )
+        };
+        if keys.is_null() {
+            return Err(Error::Fs("No nvs_keys partition".into()));
+        }
+        let mut cfg: nvs_sec_cfg_t = unsafe { std::mem::zeroed() };
+        let label = std::ffi::CString::new(SECRETS_PARTITION).map_err(Error::fs)?;
+        if esp!(unsafe { nvs_flash_read_security_cfg(keys, &mut cfg) }).is_err() {
+            // new keys can't read what was there before
+            info!("Generating the NVS keys");
+            esp!(unsafe { nvs_flash_generate_keys(keys, &mut cfg) })?;
+            esp!(unsafe { nvs_flash_erase_partition(label.as_ptr()) })?;
+        }
+        esp!(unsafe { nvs_flash_secure_init_partition(label.as_ptr(), &mut cfg) })?;
+        // already initialized, so this just takes it
+        let partition = EspCustomNvsPartition::take(SECRETS_PARTITION)?;
+        EspNvs::new(partition, "homer", true).map_err(Error::fs)
+    };
+    match open() {
+        Ok(secrets) => Some(secrets),
+        Err(e) => {
+            info!(
+                "Failed to open encrypted NVS, secrets stay plain error {:?}",
+                e
+            );
+            None
+        }
+    }
+}
+
+#[cfg(not(feature = "encrypted-nvs"))]
+fn open_secrets() -> Option<EspNvs<NvsCustom>> {
+    None
+}
+
+// erase the whole NVS partition: these settings, the saved tokens and
+// states, and the WiFi driver's and RF calibration data, and the
+// encrypted secrets. The device has to restart straight after
+pub fn factory_reset() -> Result<()> {
+    esp!(unsafe { nvs_flash_erase() })?;
+    #[cfg(feature = "encrypted-nvs")]
+    {
+        let label = std::ffi::CString::new(SECRETS_PARTITION).map_err(Error::fs)?;
+        esp!(unsafe { esp_idf_sys::nvs_flash_erase_partition(label.as_ptr()) })?;
+    }
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+use crate::{Error, Result};
+
+// the ed25519 public key that firmware (and configs, with `signed_configs`)
+// has to be signed with, as 64 hex digits. Without one nothing is checked
+const SIGNING_KEY: Option<&str> = option_env!("HOMER_SIGNING_KEY");
+
+// the header with a signed upload's signature
+pub const SIGNATURE_HEADER: &str = "X-Homer-Signature";
+
+pub fn signing_enabled() -> bool {
+    SIGNING_KEY.is_some()
+}
+
+fn verifying_key() -> Result<Option<VerifyingKey>> {
+    let hex = match SIGNING_KEY {
+        Some(hex) => hex.trim(),
+        None => return Ok(None),
+    };
+    let bytes: Vec<u8> = (0..hex.len() / 2)
+        .map(|i| u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16))
+        .collect::<Result<_, _>>()
+        .map_err(Error::config)?;
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| Error::Config("HOMER_SIGNING_KEY isn't 32 bytes".into()))?;
+    Ok(Some(
+        VerifyingKey::from_bytes(&bytes).map_err(Error::config)?,
+    ))
+}
+
+// a payload is signed by signing its SHA-256, so a firmware image can be
+// hashed as it streams in rather than kept to check at the end
+pub fn verify_digest(digest: &[u8], signature: Option<&str>) -> Result<()> {
+    let key = match verifying_key()? {
+        Some(key) => key,
+        None => return Ok(()),
+    };
+    let signature = match signature {
+        Some(s) => STANDARD.decode(s.trim()).map_err(Error::config)?,
+        None => return Err(Error::Config("It isn't signed".into())),
+    };
+    let signature = Signature::from_slice(&signature).map_err(Error::config)?;
+    key.verify(digest, &signature)
+        .map_err(|_| Error::Config("The signature doesn't match".into()))
+}
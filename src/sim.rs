@@ -0,0 +1,199 @@
+use std::{collections::HashMap, fs::File, path::PathBuf, sync::mpsc::Sender, time::SystemTime};
+
+use log::*;
+use serde_json::{json, Value};
+
+use crate::{ha::EntityState, util::next_message_id, Error, Result};
+
+// plays HA's part of the websocket for the simulator: the states file is
+// the reply to get_states, and a change to it is sent as state_changed
+// events
+pub struct MockHa {
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    states: HashMap<String, EntityState>,
+    frames: Sender<String>,
+}
+
+impl MockHa {
+    pub fn new(path: Option<PathBuf>, frames: Sender<String>) -> MockHa {
+        MockHa {
+            path,
+            modified: None,
+            states: HashMap::new(),
+            frames,
+        }
+    }
+
+    // the states file in the form of HA's /api/states, read again when
+    // it changes
+    pub fn poll(&mut self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified())?;
+        if self.modified == Some(modified) {
+            return Ok(());
+        }
+        let snapshot: Vec<EntityState> = serde_json::from_reader(File::open(path)?)?;
+        if self.modified.is_none() {
+            self.frame(json!({
+                "id": next_message_id(),
+                "type": "result",
+                "success": true,
+                "result": snapshot,
+            }))?;
+        } else {
+            for state in snapshot
+                .iter()
+                .filter(|s| self.states.get(&s.entity_id) != Some(*s))
+            {
+                self.frame(json!({
+                    "id": 1,
+                    "type": "event",
+                    "event": {
+                        "event_type": "state_changed",
+                        "data": {"entity_id": state.entity_id, "new_state": state},
+                    },
+                }))?;
+            }
+        }
+        self.modified = Some(modified);
+        self.states = snapshot
+            .into_iter()
+            .map(|s| (s.entity_id.clone(), s))
+            .collect();
+        Ok(())
+    }
+
+    // what the panel sends HA is logged, and the call answered
+    pub fn send(&self, msg: &Value, description: &str) -> Result<()> {
+        info!("To HA: {} {}", description, msg);
+        self.frame(json!({
+            "id": msg["id"],
+            "type": "result",
+            "success": true,
+            "result": null,
+        }))
+    }
+
+    fn frame(&self, frame: Value) -> Result<()> {
+        self.frames.send(frame.to_string()).map_err(Error::socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, Receiver};
+
+    use super::*;
+    use crate::{
+        display::DrawCmd,
+        ha::{parse_frame, HaMessage},
+        render::Renderer,
+        states::{apply_message, Optimistic, States},
+        util::HAConnect,
+        widgets::WidgetState,
+    };
+
+    const STATES: &str = r#"[
+        {"entity_id": "sensor.outside", "state": "21.5", "attributes": {"unit_of_measurement": "°C"}},
+        {"entity_id": "light.kitchen", "state": "on"},
+        {"entity_id": "sensor.not_shown", "state": "1"}
+    ]"#;
+
+    fn items() -> Vec<HAConnect> {
+        serde_json::from_value(json!([
+            {"Line": {"line": 0, "ha_id": "sensor.outside", "text": "Outside {state}{unit_of_measurement}",
+                "make_int": false, "color": 0}},
+            {"Line": {"line": 1, "ha_id": "light.kitchen", "text": "Kitchen ", "make_int": false, "color": 0}},
+        ]))
+        .unwrap()
+    }
+
+    fn empty_states(items: &[HAConnect]) -> States {
+        items
+            .iter()
+            .flat_map(|c| c.state_keys())
+            .map(|key| (key, Default::default()))
+            .collect()
+    }
+
+    // the mock HA's first frames, from a states file
+    fn serve(name: &str, states: &str) -> Vec<HaMessage> {
+        let path = std::env::temp_dir().join(format!("homer-{}-{}.json", name, std::process::id()));
+        std::fs::write(&path, states).unwrap();
+        let (frames_tx, frames_rx) = channel();
+        MockHa::new(Some(path.clone()), frames_tx).poll().unwrap();
+        std::fs::remove_file(path).unwrap();
+        frames_rx
+            .try_iter()
+            .flat_map(|frame| parse_frame(&frame).unwrap())
+            .collect()
+    }
+
+    fn texts(display_rx: &Receiver<DrawCmd>) -> Vec<String> {
+        display_rx
+            .try_iter()
+            .filter_map(|cmd| match cmd {
+                DrawCmd::Text { text, .. } => Some(text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn snapshot_fills_the_lines() {
+        let items = items();
+        let mut states = empty_states(&items);
+        let mut optimistic = Optimistic::new();
+        for msg in serve("snapshot", STATES) {
+            let applied = apply_message(&msg, None, &mut states, &mut optimistic);
+            assert!(applied.snapshot && applied.changed);
+        }
+
+        let (display_tx, display_rx) = channel();
+        Renderer::new(&items, &display_tx, "--").render(&states, false, &WidgetState::new());
+        assert_eq!(texts(&display_rx), vec!["Outside 21.5°C", "Kitchen on"]);
+    }
+
+    #[test]
+    fn a_change_redraws_its_line() {
+        let items = items();
+        let mut states = empty_states(&items);
+        let mut optimistic = Optimistic::new();
+        for msg in serve("change", STATES) {
+            apply_message(&msg, None, &mut states, &mut optimistic);
+        }
+        let (display_tx, display_rx) = channel();
+        let mut renderer = Renderer::new(&items, &display_tx, "--");
+        renderer.render(&states, false, &WidgetState::new());
+        assert_eq!(texts(&display_rx).len(), 2);
+
+        let kitchen: EntityState =
+            serde_json::from_value(json!({"entity_id": "light.kitchen", "state": "unavailable"}))
+                .unwrap();
+        let applied = apply_message(
+            &HaMessage::state_changed(&kitchen),
+            None,
+            &mut states,
+            &mut optimistic,
+        );
+        assert_eq!(applied.entity_id.as_deref(), Some("light.kitchen"));
+        renderer.render(&states, false, &WidgetState::new());
+        assert_eq!(texts(&display_rx), vec!["Kitchen --"]);
+    }
+
+    #[test]
+    fn entities_that_arent_shown_are_ignored() {
+        let items = items();
+        let mut states = empty_states(&items);
+        let mut optimistic = Optimistic::new();
+        let unshown = r#"[{"entity_id": "sensor.not_shown", "state": "2"}]"#;
+        for msg in serve("ignored", unshown) {
+            assert!(!apply_message(&msg, None, &mut states, &mut optimistic).changed);
+        }
+        assert_eq!(states.len(), 2);
+    }
+}
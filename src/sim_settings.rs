@@ -0,0 +1,39 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{Error, Result};
+
+// the simulator's stand-in for the NVS settings, kept in memory until it
+// exits so each run starts like a fresh device
+#[derive(Clone, Default)]
+pub struct Settings {
+    values: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Settings {
+    pub fn new() -> Settings {
+        Settings::default()
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<String> {
+        self.values.lock().ok()?.get(key).cloned()
+    }
+
+    pub fn set_str(&self, key: &str, value: &str) -> Result<()> {
+        self.values
+            .lock()
+            .map_err(|_| Error::Fs("Settings lock poisoned".into()))?
+            .insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    pub fn remove(&self, key: &str) -> Result<()> {
+        self.values
+            .lock()
+            .map_err(|_| Error::Fs("Settings lock poisoned".into()))?
+            .remove(key);
+        Ok(())
+    }
+}
@@ -0,0 +1,530 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    ha::{EntityState, HaMessage, StateChanged},
+    settings::Settings,
+    Error, Result,
+};
+
+// the NVS key of the states saved for the next boot
+const CACHE_KEY: &str = "state_cache";
+// NVS strings are limited to 4000 bytes
+const MAX_CACHE: usize = 4000;
+
+// the attributes kept for each entity, the rest are dropped to save memory
+pub const KEPT_ATTRIBUTES: &[&str] = &[
+    "unit_of_measurement",
+    "friendly_name",
+    "brightness",
+    "temperature",
+    // light
+    "color_temp_kelvin",
+    "min_color_temp_kelvin",
+    "max_color_temp_kelvin",
+    // climate
+    "current_temperature",
+    "target_temp_step",
+    "min_temp",
+    "max_temp",
+    "hvac_modes",
+    // humidifier
+    "humidity",
+    "current_humidity",
+    "min_humidity",
+    "max_humidity",
+    "available_modes",
+    "mode",
+    // water_heater
+    "operation_list",
+    "away_mode",
+    // cover
+    "current_position",
+    // alarm_control_panel
+    "code_format",
+    "code_arm_required",
+    // input_select
+    "options",
+    // input_number
+    "min",
+    "max",
+    "step",
+    // irrigation
+    "next_start",
+    // vacuum
+    "battery_level",
+    // fan
+    "percentage",
+    "percentage_step",
+    "preset_mode",
+    // sun
+    "next_rising",
+    "next_setting",
+    // timer
+    "finishes_at",
+    "remaining",
+    "duration",
+    // weather
+    "temperature_unit",
+    "forecast",
+];
+
+// forecasts are long, only the next few days are kept
+const KEPT_FORECASTS: usize = 3;
+
+// what the device knows about an HA entity
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Entity {
+    pub state: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub attributes: HashMap<String, String>,
+}
+
+// the entities the config uses, by `state_key`
+pub type States = HashMap<String, Entity>;
+
+// entities of instances other than the default one are kept under
+// <instance>/<entity id>, so the same entity id can be used in each
+pub fn state_key(instance: Option<&str>, entity_id: &str) -> String {
+    match instance {
+        Some(name) => format!("{}/{}", name, entity_id),
+        None => entity_id.to_string(),
+    }
+}
+
+// attributes the config's Attribute items show, kept as well
+static SHOWN_ATTRIBUTES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn keep_attributes(names: Vec<String>) {
+    if let Ok(mut shown) = SHOWN_ATTRIBUTES.lock() {
+        *shown = names;
+    }
+}
+
+impl Entity {
+    pub fn from_state(state: &EntityState) -> Entity {
+        let mut attributes = HashMap::new();
+        for name in KEPT_ATTRIBUTES {
+            if let Some(v) = state.attributes.get(*name) {
+                let text = match (*name, v) {
+                    ("forecast", Value::Array(days)) => value_text(&Value::Array(
+                        days.iter().take(KEPT_FORECASTS).map(forecast_day).collect(),
+                    )),
+                    _ => value_text(v),
+                };
+                attributes.insert(name.to_string(), text);
+            }
+        }
+        if let Ok(shown) = SHOWN_ATTRIBUTES.lock() {
+            for name in shown.iter() {
+                if let Some(v) = state.attributes.get(name) {
+                    attributes.insert(name.clone(), value_text(v));
+                }
+            }
+        }
+        Entity {
+            state: state.state.clone(),
+            attributes,
+        }
+    }
+
+    // false for the "unavailable" and "unknown" states HA uses for
+    // entities it can't reach or hasn't heard from
+    pub fn is_available(&self) -> bool {
+        !matches!(self.state.as_str(), "unavailable" | "unknown")
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(|s| s.as_str())
+    }
+
+    // the text for a Line item. `{state}` and `{<attribute>}` in the text
+    // are replaced, otherwise the state is appended to the text. The
+    // placeholder stands in for the state of an unavailable entity
+    pub fn format_line(&self, text: &str, make_int: bool, placeholder: &str) -> String {
+        let state = if !self.is_available() {
+            placeholder.to_string()
+        } else if make_int {
+            self.state
+                .parse::<f64>()
+                .ok()
+                .map_or("".to_string(), |f| f.round().to_string())
+        } else {
+            self.state.clone()
+        };
+
+        if !text.contains('{') {
+            return format!("{}{}", text, state);
+        }
+
+        let mut line = text.replace("{state}", &state);
+        for (name, value) in &self.attributes {
+            line = line.replace(&format!("{{{}}}", name), value);
+        }
+        drop_missing(&line)
+    }
+
+    // the text for an Attribute item, as for a Line item with the
+    // attribute in place of the state
+    pub fn format_attribute(
+        &self,
+        name: &str,
+        text: &str,
+        make_int: bool,
+        placeholder: &str,
+    ) -> String {
+        let value = match self.attribute(name) {
+            Some(v) if self.is_available() => v.to_string(),
+            _ => "unknown".into(),
+        };
+        Entity {
+            state: value,
+            attributes: self.attributes.clone(),
+        }
+        .format_line(text, make_int, placeholder)
+    }
+}
+
+// leave out the `{<attribute>}`s the entity doesn't have, e.g. the unit of
+// a sensor without one
+fn drop_missing(line: &str) -> String {
+    let mut filled = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after.find('}') {
+            Some(end)
+                if end > 0
+                    && after[..end]
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+            {
+                rest = &after[end + 1..];
+            }
+            _ => {
+                filled.push('{');
+                rest = after;
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+// button states flipped as soon as the button is pressed rather than
+// waiting for HA, with the state to go back to if HA doesn't confirm
+#[derive(Default)]
+pub struct Optimistic {
+    // the entity to go back to, the state it was flipped to and when
+    flipped: HashMap<String, (Entity, String, Instant)>,
+}
+
+impl Optimistic {
+    pub fn new() -> Optimistic {
+        Optimistic::default()
+    }
+
+    pub fn flip(&mut self, key: String, states: &mut States, state: String) {
+        let entity = states.entry(key.clone()).or_default();
+        let previous = entity.clone();
+        entity.state = state.clone();
+        // a second press before HA answers keeps the original state
+        self.flipped
+            .entry(key)
+            .and_modify(|(_, flipped, at)| {
+                *flipped = state.clone();
+                *at = Instant::now();
+            })
+            .or_insert((previous, state, Instant::now()));
+    }
+
+    // HA sent the entity. It confirms the flip if it has the flipped
+    // state, otherwise (e.g. only an attribute changed) the flipped state
+    // is kept, and HA's entity is what the timeout puts back
+    pub fn confirm(&mut self, key: &str, entity: &mut Entity) {
+        if let Some((previous, flipped, _)) = self.flipped.get_mut(key) {
+            if entity.state == *flipped {
+                self.flipped.remove(key);
+            } else {
+                *previous = entity.clone();
+                entity.state = flipped.clone();
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.flipped.clear();
+    }
+
+    // put back the states HA didn't confirm within the timeout, returns
+    // true if any were put back
+    pub fn expire(&mut self, states: &mut States, timeout: Duration) -> bool {
+        let mut reverted = false;
+        self.flipped.retain(|key, (previous, _, at)| {
+            if at.elapsed() < timeout {
+                return true;
+            }
+            states.insert(key.clone(), previous.clone());
+            reverted = true;
+            false
+        });
+        reverted
+    }
+}
+
+// what a message from HA did to the states
+#[derive(Debug, Default, PartialEq)]
+pub struct Applied {
+    // it was the reply to get_states, sent each time the websocket connects
+    pub snapshot: bool,
+    // the entity of a state change, whether or not it's one that's shown
+    pub entity_id: Option<String>,
+    pub changed: bool,
+}
+
+// keep the states up to date from a message of an HA instance, the same
+// on the device and in the simulator. The rest of the message (templates,
+// notifications...) is up to the caller
+pub fn apply_message(
+    msg: &HaMessage,
+    instance: Option<&str>,
+    states: &mut States,
+    optimistic: &mut Optimistic,
+) -> Applied {
+    match msg {
+        HaMessage::Result(result) => match result.states() {
+            Some(snapshot) => {
+                optimistic.clear();
+                Applied {
+                    snapshot: true,
+                    changed: apply_snapshot(&snapshot, instance, states),
+                    ..Default::default()
+                }
+            }
+            None => Applied::default(),
+        },
+        HaMessage::Event { event, .. } => match event.state_changed() {
+            Some(StateChanged {
+                entity_id,
+                new_state: Some(new_state),
+                ..
+            }) => {
+                let key = state_key(instance, &entity_id);
+                let changed = match states.get_mut(&key) {
+                    Some(current) => {
+                        let mut entity = Entity::from_state(&new_state);
+                        optimistic.confirm(&key, &mut entity);
+                        *current = entity;
+                        true
+                    }
+                    None => false,
+                };
+                Applied {
+                    entity_id: Some(entity_id),
+                    changed,
+                    ..Default::default()
+                }
+            }
+            _ => Applied::default(),
+        },
+        _ => Applied::default(),
+    }
+}
+
+// update the states from the result of a get_states request, returns
+// true if any state changed
+fn apply_snapshot(snapshot: &[EntityState], instance: Option<&str>, states: &mut States) -> bool {
+    let mut changed = false;
+    for entity in snapshot {
+        if let Some(current) = states.get_mut(&state_key(instance, &entity.entity_id)) {
+            let new = Entity::from_state(entity);
+            if *current != new {
+                *current = new;
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+// save the states so they can be shown right away after a reboot. The
+// rendered text is made from the states so it isn't saved
+pub fn save_cache(settings: &Settings, states: &States) -> Result<()> {
+    let json = serde_json::to_string(states)?;
+    if json.len() > MAX_CACHE {
+        return Err(Error::Fs(format!(
+            "The states are too big to cache ({} bytes)",
+            json.len()
+        )));
+    }
+    settings.set_str(CACHE_KEY, &json)
+}
+
+// the states saved before the last reboot, empty if there aren't any
+pub fn load_cache(settings: &Settings) -> States {
+    settings
+        .get_str(CACHE_KEY)
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+// the parts of a forecast entry the weather widget shows
+fn forecast_day(day: &Value) -> Value {
+    let mut kept = serde_json::Map::new();
+    for name in ["condition", "temperature", "templow"] {
+        if let Some(v) = day.get(name) {
+            kept.insert(name.into(), v.clone());
+        }
+    }
+    Value::Object(kept)
+}
+
+// attribute values as they'd be displayed
+pub fn value_text(v: &Value) -> String {
+    match v {
+        Value::String(s) => s.clone(),
+        Value::Null => "".into(),
+        v => v.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIGHT: &str = "light.kitchen";
+    const LONG: Duration = Duration::from_secs(60);
+
+    fn entity(state: &str, brightness: &str) -> Entity {
+        Entity {
+            state: state.into(),
+            attributes: [("brightness".to_string(), brightness.to_string())]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    fn flipped_light() -> (States, Optimistic) {
+        let mut states: States = [(LIGHT.to_string(), entity("off", "0"))]
+            .into_iter()
+            .collect();
+        let mut optimistic = Optimistic::new();
+        optimistic.flip(LIGHT.into(), &mut states, "on".into());
+        (states, optimistic)
+    }
+
+    #[test]
+    fn a_flip_shows_the_state_right_away() {
+        let (mut states, mut optimistic) = flipped_light();
+        assert_eq!(states[LIGHT].state, "on");
+        assert!(!optimistic.expire(&mut states, LONG));
+        assert_eq!(states[LIGHT].state, "on");
+    }
+
+    #[test]
+    fn a_second_flip_keeps_the_original_state() {
+        let (mut states, mut optimistic) = flipped_light();
+        optimistic.flip(LIGHT.into(), &mut states, "off".into());
+        assert_eq!(states[LIGHT].state, "off");
+        // HA answered neither, so it goes back to how it was before both
+        assert!(optimistic.expire(&mut states, Duration::ZERO));
+        assert_eq!(states[LIGHT], entity("off", "0"));
+    }
+
+    #[test]
+    fn ha_confirms_the_flipped_state() {
+        let (mut states, mut optimistic) = flipped_light();
+        let mut from_ha = entity("on", "255");
+        optimistic.confirm(LIGHT, &mut from_ha);
+        assert_eq!(from_ha, entity("on", "255"));
+        states.insert(LIGHT.into(), from_ha);
+        // nothing is left to put back
+        assert!(!optimistic.expire(&mut states, Duration::ZERO));
+        assert_eq!(states[LIGHT].state, "on");
+    }
+
+    #[test]
+    fn an_attribute_change_doesnt_confirm() {
+        let (mut states, mut optimistic) = flipped_light();
+        let mut from_ha = entity("off", "10");
+        optimistic.confirm(LIGHT, &mut from_ha);
+        assert_eq!(from_ha, entity("on", "10"));
+        states.insert(LIGHT.into(), from_ha);
+        // the timeout puts back what HA sent last
+        assert!(optimistic.expire(&mut states, Duration::ZERO));
+        assert_eq!(states[LIGHT], entity("off", "10"));
+    }
+
+    #[test]
+    fn an_unconfirmed_flip_expires() {
+        let (mut states, mut optimistic) = flipped_light();
+        assert!(optimistic.expire(&mut states, Duration::ZERO));
+        assert_eq!(states[LIGHT], entity("off", "0"));
+        assert!(!optimistic.expire(&mut states, Duration::ZERO));
+    }
+
+    fn sensor(state: &str) -> Entity {
+        Entity {
+            state: state.into(),
+            attributes: [("unit_of_measurement".to_string(), "°C".to_string())]
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn the_state_goes_in_its_place_or_on_the_end() {
+        assert_eq!(
+            sensor("21.5").format_line("Out {state} now", false, "--"),
+            "Out 21.5 now"
+        );
+        assert_eq!(sensor("21.5").format_line("Out ", false, "--"), "Out 21.5");
+    }
+
+    #[test]
+    fn attributes_are_filled_in() {
+        assert_eq!(
+            sensor("21.5").format_line("{state}{unit_of_measurement}", false, "--"),
+            "21.5°C"
+        );
+    }
+
+    #[test]
+    fn missing_attributes_are_left_out() {
+        assert_eq!(
+            sensor("40").format_line("{state}{humidity_unit} wet", false, "--"),
+            "40 wet"
+        );
+    }
+
+    #[test]
+    fn a_brace_that_isnt_an_attribute_stays() {
+        assert_eq!(
+            sensor("3").format_line("{ {state} {not one} {}", false, "--"),
+            "{ 3 {not one} {}"
+        );
+    }
+
+    #[test]
+    fn an_unavailable_entity_shows_the_placeholder() {
+        assert_eq!(
+            sensor("unavailable").format_line("{state}{unit_of_measurement}", true, "--"),
+            "--°C"
+        );
+        assert_eq!(sensor("unknown").format_line("Out ", false, "?"), "Out ?");
+    }
+
+    #[test]
+    fn make_int_rounds_the_state() {
+        assert_eq!(sensor("21.5").format_line("", true, "--"), "22");
+        assert_eq!(sensor("21.4").format_line("{state}", true, "--"), "21");
+        assert_eq!(sensor("on").format_line("Was ", true, "--"), "Was ");
+    }
+}
@@ -0,0 +1,149 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use esp_idf_sys::{
+    esp_reset_reason, esp_reset_reason_t_ESP_RST_BROWNOUT, esp_reset_reason_t_ESP_RST_DEEPSLEEP,
+    esp_reset_reason_t_ESP_RST_EXT, esp_reset_reason_t_ESP_RST_INT_WDT,
+    esp_reset_reason_t_ESP_RST_PANIC, esp_reset_reason_t_ESP_RST_POWERON,
+    esp_reset_reason_t_ESP_RST_SDIO, esp_reset_reason_t_ESP_RST_SW,
+    esp_reset_reason_t_ESP_RST_TASK_WDT, esp_reset_reason_t_ESP_RST_WDT,
+};
+use log::*;
+
+use crate::{settings::Settings, status::Status};
+
+// the NVS keys of the counts kept across restarts
+const BOOT_COUNT_KEY: &str = "boot_count";
+const UPTIME_KEY: &str = "uptime_total";
+const WIFI_RECONNECTS_KEY: &str = "wifi_reconn";
+const WS_RECONNECTS_KEY: &str = "ws_reconn";
+const LAST_CRASH_KEY: &str = "last_crash";
+const LAST_RESET_KEY: &str = "last_reset";
+const BROWNOUTS_KEY: &str = "brownouts";
+
+// the counts are saved this often, NVS wears out
+pub const STATS_SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// what was saved in NVS up to this boot, this boot's uptime and
+// reconnects are added on top
+#[derive(Debug, Clone, Default)]
+pub struct SavedStats {
+    pub boot_count: u32,
+    pub uptime_before: u64,
+    pub wifi_reconnects_before: u32,
+    pub ws_reconnects_before: u32,
+    pub last_crash: Option<String>,
+    // why this boot happened, from reset_reason
+    pub reset_reason: &'static str,
+    // including this boot
+    pub brownouts: u32,
+}
+
+// the counts over all boots, including this one
+pub struct StatsTotals {
+    pub boot_count: u32,
+    // seconds
+    pub uptime: u64,
+    pub wifi_reconnects: u32,
+    pub ws_reconnects: u32,
+    pub last_crash: Option<String>,
+    pub reset_reason: &'static str,
+    pub brownouts: u32,
+}
+
+// why the chip last reset. A brownout is the supply dipping, not the
+// firmware
+pub fn reset_reason() -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        esp_reset_reason_t_ESP_RST_EXT => "external",
+        esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_reset_reason_t_ESP_RST_INT_WDT => "int_watchdog",
+        esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
+// a reset nobody asked for, worth showing at boot
+pub fn unexpected_reset(reason: &str) -> bool {
+    !matches!(reason, "power_on" | "external" | "software" | "deep_sleep")
+}
+
+// read the counts, count this boot and note why the last one ended if it
+// crashed (a panic or the network watchdog). A panic or watchdog reset
+// that left no message (e.g. in the IDF) is noted by its reset reason
+pub fn load_stats(settings: &Settings, status: &Status, crash: Option<String>) {
+    let number = |key: &str| settings.get_str(key).and_then(|v| v.parse::<u64>().ok());
+    let mut stats = SavedStats {
+        boot_count: number(BOOT_COUNT_KEY).unwrap_or(0) as u32 + 1,
+        uptime_before: number(UPTIME_KEY).unwrap_or(0),
+        wifi_reconnects_before: number(WIFI_RECONNECTS_KEY).unwrap_or(0) as u32,
+        ws_reconnects_before: number(WS_RECONNECTS_KEY).unwrap_or(0) as u32,
+        last_crash: settings.get_str(LAST_CRASH_KEY),
+        reset_reason: reset_reason(),
+        brownouts: number(BROWNOUTS_KEY).unwrap_or(0) as u32,
+    };
+    if let Err(e) = settings.set_str(BOOT_COUNT_KEY, &stats.boot_count.to_string()) {
+        info!("Failed to save the boot count error {:?}", e);
+    }
+    let _ = settings.set_str(LAST_RESET_KEY, stats.reset_reason);
+    if stats.reset_reason == "brownout" {
+        stats.brownouts += 1;
+        let _ = settings.set_str(BROWNOUTS_KEY, &stats.brownouts.to_string());
+    }
+    let crash = match stats.reset_reason {
+        "panic" | "int_watchdog" | "task_watchdog" | "watchdog" if crash.is_none() => {
+            Some(format!("Reset by {}", stats.reset_reason.replace('_', " ")))
+        }
+        _ => crash,
+    };
+    if let Some(crash) = crash {
+        let _ = settings.set_str(LAST_CRASH_KEY, &crash);
+        stats.last_crash = Some(crash);
+    }
+    info!(
+        "Boot {} reset reason {}",
+        stats.boot_count, stats.reset_reason
+    );
+    if let Ok(mut saved) = status.saved_stats.lock() {
+        *saved = stats;
+    }
+}
+
+pub fn stats_totals(status: &Status) -> StatsTotals {
+    let saved = status
+        .saved_stats
+        .lock()
+        .map(|s| s.clone())
+        .unwrap_or_default();
+    let uptime = unsafe { esp_idf_sys::esp_timer_get_time() } as u64 / 1_000_000;
+    StatsTotals {
+        boot_count: saved.boot_count,
+        uptime: saved.uptime_before + uptime,
+        wifi_reconnects: saved.wifi_reconnects_before
+            + status.wifi_reconnects.load(Ordering::Relaxed),
+        ws_reconnects: saved.ws_reconnects_before + status.ws_reconnects.load(Ordering::Relaxed),
+        last_crash: saved.last_crash,
+        reset_reason: saved.reset_reason,
+        brownouts: saved.brownouts,
+    }
+}
+
+// save the running totals, every STATS_SAVE_INTERVAL or so
+pub fn save_stats(settings: &Settings, status: &Status) {
+    let totals = stats_totals(status);
+    for (key, value) in [
+        (UPTIME_KEY, totals.uptime.to_string()),
+        (WIFI_RECONNECTS_KEY, totals.wifi_reconnects.to_string()),
+        (WS_RECONNECTS_KEY, totals.ws_reconnects.to_string()),
+    ] {
+        if let Err(e) = settings.set_str(key, &value) {
+            info!("Failed to save {} error {:?}", key, e);
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    net::Ipv4Addr,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicI8, AtomicU32, AtomicU8, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::stats::SavedStats;
+
+// how many RSSI samples to keep for the diagnostics sparkline
+const RSSI_HISTORY: usize = 60;
+
+// how much text fits in the status bar
+const STATUS_BAR_CHARS: usize = 12;
+
+// the access point we're associated with
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApInfo {
+    pub ssid: String,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+}
+
+// the address info from DHCP (or the static config)
+#[derive(Debug, Clone, PartialEq)]
+pub struct LeaseInfo {
+    pub ip: Ipv4Addr,
+    pub mask: u8,
+    pub gateway: Ipv4Addr,
+    pub dns: Option<Ipv4Addr>,
+    pub dhcp: bool,
+}
+
+// the last time the clock was set
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeSync {
+    pub at: Instant,
+    // "SNTP" or "HA"
+    pub source: &'static str,
+}
+
+// the connection to one of the other HA instances
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstanceLink {
+    pub name: String,
+    pub connected: bool,
+    pub auth_failed: bool,
+}
+
+// websocket and REST traffic counters, for spotting chatty HA instances
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TrafficCounts {
+    pub ws_rx_messages: u32,
+    pub ws_rx_bytes: u32,
+    pub ws_tx_messages: u32,
+    pub ws_tx_bytes: u32,
+    pub rest_requests: u32,
+    pub rest_bytes: u32,
+}
+
+impl TrafficCounts {
+    // the counts per minute between `earlier` and now
+    fn per_minute(&self, earlier: &TrafficCounts, elapsed: Duration) -> TrafficCounts {
+        let ms = elapsed.as_millis().max(1) as u64;
+        let rate = |now: u32, then: u32| (now.wrapping_sub(then) as u64 * 60_000 / ms) as u32;
+        TrafficCounts {
+            ws_rx_messages: rate(self.ws_rx_messages, earlier.ws_rx_messages),
+            ws_rx_bytes: rate(self.ws_rx_bytes, earlier.ws_rx_bytes),
+            ws_tx_messages: rate(self.ws_tx_messages, earlier.ws_tx_messages),
+            ws_tx_bytes: rate(self.ws_tx_bytes, earlier.ws_tx_bytes),
+            rest_requests: rate(self.rest_requests, earlier.rest_requests),
+            rest_bytes: rate(self.rest_bytes, earlier.rest_bytes),
+        }
+    }
+}
+
+// how often the traffic rates are recomputed
+const TRAFFIC_WINDOW: Duration = Duration::from_secs(30);
+
+pub struct Traffic {
+    ws_rx_messages: AtomicU32,
+    ws_rx_bytes: AtomicU32,
+    ws_tx_messages: AtomicU32,
+    ws_tx_bytes: AtomicU32,
+    rest_requests: AtomicU32,
+    rest_bytes: AtomicU32,
+    // when the rates were last computed, the counts then and the rates
+    window: Mutex<Option<(Instant, TrafficCounts, TrafficCounts)>>,
+}
+
+impl Traffic {
+    pub const fn new() -> Traffic {
+        Traffic {
+            ws_rx_messages: AtomicU32::new(0),
+            ws_rx_bytes: AtomicU32::new(0),
+            ws_tx_messages: AtomicU32::new(0),
+            ws_tx_bytes: AtomicU32::new(0),
+            rest_requests: AtomicU32::new(0),
+            rest_bytes: AtomicU32::new(0),
+            window: Mutex::new(None),
+        }
+    }
+
+    pub fn ws_received(&self, bytes: usize) {
+        self.ws_rx_messages.fetch_add(1, Ordering::Relaxed);
+        self.ws_rx_bytes.fetch_add(bytes as u32, Ordering::Relaxed);
+    }
+
+    pub fn ws_sent(&self, bytes: usize) {
+        self.ws_tx_messages.fetch_add(1, Ordering::Relaxed);
+        self.ws_tx_bytes.fetch_add(bytes as u32, Ordering::Relaxed);
+    }
+
+    pub fn rest_received(&self, bytes: usize) {
+        self.rest_requests.fetch_add(1, Ordering::Relaxed);
+        self.rest_bytes.fetch_add(bytes as u32, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> TrafficCounts {
+        TrafficCounts {
+            ws_rx_messages: self.ws_rx_messages.load(Ordering::Relaxed),
+            ws_rx_bytes: self.ws_rx_bytes.load(Ordering::Relaxed),
+            ws_tx_messages: self.ws_tx_messages.load(Ordering::Relaxed),
+            ws_tx_bytes: self.ws_tx_bytes.load(Ordering::Relaxed),
+            rest_requests: self.rest_requests.load(Ordering::Relaxed),
+            rest_bytes: self.rest_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    // the counts per minute, averaged over the last window
+    pub fn rates(&self) -> TrafficCounts {
+        let now = self.counts();
+        let mut window = match self.window.lock() {
+            Ok(w) => w,
+            Err(_) => return TrafficCounts::default(),
+        };
+        match *window {
+            Some((at, _, rates)) if at.elapsed() < TRAFFIC_WINDOW => rates,
+            Some((at, then, _)) => {
+                let rates = now.per_minute(&then, at.elapsed());
+                *window = Some((Instant::now(), now, rates));
+                rates
+            }
+            None => {
+                *window = Some((Instant::now(), now, TrafficCounts::default()));
+                TrafficCounts::default()
+            }
+        }
+    }
+}
+
+impl Default for Traffic {
+    fn default() -> Self {
+        Traffic::new()
+    }
+}
+
+// live device status shared between the threads, used by the status bar
+// and reported to Home Assistant
+pub struct Status {
+    // the station RSSI in dBm, 0 if not connected
+    pub rssi: AtomicI32,
+    // the network link (WiFi association or Ethernet link) is up
+    pub network_ok: AtomicBool,
+    // the HA websocket is connected and authenticated
+    pub ha_connected: AtomicBool,
+    // HA rejected the access token
+    pub ha_auth_failed: AtomicBool,
+    // how many times the WiFi had to be reconnected
+    pub wifi_reconnects: AtomicU32,
+    // and the HA websocket
+    pub ws_reconnects: AtomicU32,
+    pub rssi_history: Mutex<Vec<i32>>,
+    pub ap: Mutex<Option<ApInfo>>,
+    pub lease: Mutex<Option<LeaseInfo>>,
+    pub hostname: Mutex<String>,
+    pub time_sync: Mutex<Option<TimeSync>>,
+    pub traffic: Traffic,
+    // the backlight brightness in percent
+    pub backlight: AtomicU8,
+    // HA messages dropped because the main loop fell behind
+    pub ha_dropped: AtomicU32,
+    // websocket messages lost because pieces went missing or they were
+    // too big to put back together
+    pub ws_truncated: AtomicU32,
+    // from the OAuth refresh token, used in place of the configured token
+    pub access_token: Mutex<Option<String>>,
+    // the newest firmware the OTA manifest offers
+    pub latest_firmware: Mutex<Option<String>>,
+    // how much of a firmware update has been written, -1 when there's
+    // none going
+    pub ota_percent: AtomicI8,
+    // why the last firmware update failed
+    pub ota_error: Mutex<Option<String>>,
+    // the counts kept in NVS from before this boot
+    pub saved_stats: Mutex<SavedStats>,
+    // the battery's voltage and charge, -1 without a battery
+    pub battery_mv: AtomicU32,
+    pub battery_percent: AtomicI8,
+    // below the battery's low_percent, so connecting less often
+    pub battery_low: AtomicBool,
+    // the other HA instances' connections. The counters above are
+    // theirs too
+    pub instances: Mutex<Vec<InstanceLink>>,
+}
+
+impl Status {
+    pub const fn new() -> Status {
+        Status {
+            rssi: AtomicI32::new(0),
+            network_ok: AtomicBool::new(false),
+            ha_connected: AtomicBool::new(false),
+            ha_auth_failed: AtomicBool::new(false),
+            wifi_reconnects: AtomicU32::new(0),
+            ws_reconnects: AtomicU32::new(0),
+            rssi_history: Mutex::new(Vec::new()),
+            ap: Mutex::new(None),
+            lease: Mutex::new(None),
+            hostname: Mutex::new(String::new()),
+            time_sync: Mutex::new(None),
+            traffic: Traffic::new(),
+            backlight: AtomicU8::new(100),
+            ha_dropped: AtomicU32::new(0),
+            ws_truncated: AtomicU32::new(0),
+            access_token: Mutex::new(None),
+            latest_firmware: Mutex::new(None),
+            ota_percent: AtomicI8::new(-1),
+            ota_error: Mutex::new(None),
+            saved_stats: Mutex::new(SavedStats {
+                boot_count: 0,
+                uptime_before: 0,
+                wifi_reconnects_before: 0,
+                ws_reconnects_before: 0,
+                last_crash: None,
+                reset_reason: "unknown",
+                brownouts: 0,
+            }),
+            battery_mv: AtomicU32::new(0),
+            battery_percent: AtomicI8::new(-1),
+            battery_low: AtomicBool::new(false),
+            instances: Mutex::new(Vec::new()),
+        }
+    }
+
+    // whether an HA instance's websocket is connected and authenticated,
+    // None for the default instance
+    pub fn instance_connected(&self, instance: Option<&str>) -> bool {
+        match instance {
+            None => self.ha_connected.load(Ordering::Relaxed),
+            Some(name) => self.instance_link(name).map_or(false, |l| l.connected),
+        }
+    }
+
+    pub fn set_instance_connected(&self, instance: Option<&str>, connected: bool) {
+        match instance {
+            None => self.ha_connected.store(connected, Ordering::Relaxed),
+            Some(name) => self.update_instance(name, |l| l.connected = connected),
+        }
+    }
+
+    // whether HA rejected an instance's access token
+    pub fn instance_auth_failed(&self, instance: Option<&str>) -> bool {
+        match instance {
+            None => self.ha_auth_failed.load(Ordering::Relaxed),
+            Some(name) => self.instance_link(name).map_or(false, |l| l.auth_failed),
+        }
+    }
+
+    pub fn set_instance_auth_failed(&self, instance: Option<&str>, failed: bool) {
+        match instance {
+            None => self.ha_auth_failed.store(failed, Ordering::Relaxed),
+            Some(name) => self.update_instance(name, |l| l.auth_failed = failed),
+        }
+    }
+
+    fn instance_link(&self, name: &str) -> Option<InstanceLink> {
+        let instances = self.instances.lock().ok()?;
+        instances.iter().find(|l| l.name == name).cloned()
+    }
+
+    fn update_instance(&self, name: &str, update: impl FnOnce(&mut InstanceLink)) {
+        if let Ok(mut instances) = self.instances.lock() {
+            match instances.iter_mut().find(|l| l.name == name) {
+                Some(link) => update(link),
+                None => {
+                    let mut link = InstanceLink {
+                        name: name.to_string(),
+                        connected: false,
+                        auth_failed: false,
+                    };
+                    update(&mut link);
+                    instances.push(link);
+                }
+            }
+        }
+    }
+
+    // the text for the right hand side of the status bar. There's only
+    // room for one thing so the caller alternates between the hostname
+    // and the RSSI
+    pub fn status_bar_text(&self, show_hostname: bool) -> String {
+        if show_hostname {
+            if let Ok(hostname) = self.hostname.lock() {
+                if !hostname.is_empty() {
+                    return hostname.chars().take(STATUS_BAR_CHARS).collect();
+                }
+            }
+        }
+        match self.rssi.load(Ordering::Relaxed) {
+            0 => "".into(),
+            rssi => format!("{}dB", rssi),
+        }
+    }
+
+    // the battery's charge, if there's one
+    pub fn battery(&self) -> Option<u8> {
+        let percent = self.battery_percent.load(Ordering::Relaxed);
+        (percent >= 0).then_some(percent as u8)
+    }
+
+    pub fn record_sync(&self, source: &'static str) {
+        if let Ok(mut sync) = self.time_sync.lock() {
+            *sync = Some(TimeSync {
+                at: Instant::now(),
+                source,
+            });
+        }
+    }
+
+    // how long since the clock was last set, None if it never was
+    pub fn sync_age(&self) -> Option<Duration> {
+        self.time_sync
+            .lock()
+            .ok()
+            .and_then(|s| s.map(|s| s.at.elapsed()))
+    }
+
+    pub fn record_rssi(&self, rssi: i32) {
+        self.rssi.store(rssi, Ordering::Relaxed);
+        if let Ok(mut history) = self.rssi_history.lock() {
+            if history.len() >= RSSI_HISTORY {
+                history.remove(0);
+            }
+            history.push(rssi);
+        }
+    }
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status::new()
+    }
+}
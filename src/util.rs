@@ -1,10 +1,9 @@
-use std::{
-    collections::HashMap,
-    sync::atomic::{AtomicI64, Ordering},
-};
+use std::sync::atomic::{AtomicI64, Ordering};
 
-use json::{object, JsonValue};
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::states::{state_key, States};
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum CmpValue {
@@ -59,6 +58,26 @@ impl PartialEq<Option<&String>> for &CmpValue {
     }
 }
 
+impl CmpValue {
+    // a state that matches
+    pub fn on_state(&self) -> String {
+        match self {
+            CmpValue::Int(i) => i.to_string(),
+            CmpValue::Str(s) => s.clone(),
+            CmpValue::Float(f) => f.to_string(),
+        }
+    }
+
+    // a state that doesn't match
+    pub fn off_state(&self) -> String {
+        if *self == "off".to_string() {
+            "on".into()
+        } else {
+            "off".into()
+        }
+    }
+}
+
 impl PartialEq<i64> for CmpValue {
     fn eq(&self, other: &i64) -> bool {
         match self {
@@ -69,40 +88,138 @@ impl PartialEq<i64> for CmpValue {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Hash)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum HAAction {
     Scene(String),
-    Service { ha_id: String, service: String },
+    // any service call. `domain` defaults to "light" for older configs and
+    // `ha_id` may be empty when the target is only areas or devices
+    Service {
+        #[serde(default)]
+        ha_id: String,
+        service: String,
+        #[serde(default = "default_service_domain")]
+        domain: String,
+        #[serde(default)]
+        service_data: Option<Value>,
+        #[serde(default)]
+        area_id: Vec<String>,
+        #[serde(default)]
+        device_id: Vec<String>,
+        // more entities to target in the same call
+        #[serde(default)]
+        entity_id: Vec<String>,
+    },
+}
+
+// a button's action, one or a list sent in order (e.g. a scene and
+// then a switch it leaves on)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Actions {
+    // boxed, so a button isn't the size of an action
+    One(Box<HAAction>),
+    Many(Vec<HAAction>),
+}
+
+impl Actions {
+    pub fn as_slice(&self) -> &[HAAction] {
+        match self {
+            Actions::One(action) => std::slice::from_ref(action.as_ref()),
+            Actions::Many(actions) => actions,
+        }
+    }
+}
+
+fn default_service_domain() -> String {
+    "light".into()
 }
 
 static HAACTION_ID: AtomicI64 = AtomicI64::new(1024);
 
+// ids for messages sent over the websocket must increase for each message
+pub fn next_message_id() -> i64 {
+    HAACTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 impl HAAction {
-    pub fn as_json(&self) -> JsonValue {
+    // a service call on one entity
+    pub fn service(domain: &str, service: &str, ha_id: &str, data: Value) -> HAAction {
+        HAAction::Service {
+            ha_id: ha_id.into(),
+            service: service.into(),
+            domain: domain.into(),
+            service_data: Some(data),
+            area_id: vec![],
+            device_id: vec![],
+            entity_id: vec![],
+        }
+    }
+
+    // a short description for error messages
+    pub fn describe(&self) -> String {
+        match self {
+            HAAction::Scene(s) => format!("scene {}", s),
+            HAAction::Service {
+                ha_id,
+                service,
+                domain,
+                ..
+            } => format!("{}.{} {}", domain, service, ha_id),
+        }
+    }
+
+    pub fn as_json(&self) -> Value {
         match self {
-            HAAction::Scene(s) => object! {
+            HAAction::Scene(s) => json!({
               "type": "call_service",
               "domain": "scene",
               "service": "turn_on",
               "target": {
-                "entity_id": s.clone()
+                "entity_id": s
               },
 
               "service_data": {},
-              "id": HAACTION_ID.fetch_add(1, Ordering::Relaxed)
-            },
-
-            HAAction::Service { ha_id, service } => object! {
-                "type": "call_service",
-              "domain": "light",
-              "service": service.clone(),
-              "target": {
-                "entity_id": ha_id.clone()
-              },
+              "id": next_message_id()
+            }),
 
-              "service_data": {},
-              "id": HAACTION_ID.fetch_add(1, Ordering::Relaxed)
-            },
+            HAAction::Service {
+                ha_id,
+                service,
+                domain,
+                service_data,
+                area_id,
+                device_id,
+                entity_id,
+            } => {
+                let mut target = serde_json::Map::new();
+                let entities: Vec<&String> = std::iter::once(ha_id)
+                    .filter(|id| !id.is_empty())
+                    .chain(entity_id)
+                    .collect();
+                match entities.as_slice() {
+                    [] => {}
+                    [one] => {
+                        target.insert("entity_id".into(), json!(one));
+                    }
+                    many => {
+                        target.insert("entity_id".into(), json!(many));
+                    }
+                }
+                if !area_id.is_empty() {
+                    target.insert("area_id".into(), json!(area_id));
+                }
+                if !device_id.is_empty() {
+                    target.insert("device_id".into(), json!(device_id));
+                }
+                json!({
+                  "type": "call_service",
+                  "domain": domain,
+                  "service": service,
+                  "target": target,
+                  "service_data": service_data.clone().unwrap_or(json!({})),
+                  "id": next_message_id()
+                })
+            }
         }
     }
 }
@@ -120,9 +237,13 @@ pub enum HAConnect {
         cmp: CmpValue,
         text_on: String,
         text_off: String,
-        action_on: HAAction,
-        action_off: HAAction,
+        action_on: Actions,
+        action_off: Actions,
         color: u16,
+        // the name of the HA instance the entity and actions belong to,
+        // the default instance if not set
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
     },
     Line {
         line: u8,
@@ -130,14 +251,356 @@ pub enum HAConnect {
         text: String,
         make_int: bool,
         color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // any attribute of an entity, formatted as for Line with the
+    // attribute in place of the state
+    Attribute {
+        line: u8,
+        ha_id: String,
+        attribute: String,
+        text: String,
+        #[serde(default)]
+        make_int: bool,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // a line rendered by HA from a Jinja template, updated as the
+    // entities in the template change
+    Template {
+        line: u8,
+        template: String,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // a thermostat: the current temperature, setpoint and mode on a line,
+    // with buttons to move the setpoint. A long press on either cycles
+    // through the HVAC modes
+    Climate {
+        line: u8,
+        ha_id: String,
+        down: u8,
+        up: u8,
+        // the setpoint change per press, the entity's target_temp_step
+        // (or 0.5) if not set
+        #[serde(default)]
+        step: Option<f64>,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // a humidifier or dehumidifier, like Climate with the humidity
+    Humidifier {
+        line: u8,
+        ha_id: String,
+        down: u8,
+        up: u8,
+        // the setpoint change per press, 1 if not set
+        #[serde(default)]
+        step: Option<f64>,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // a water heater, like Climate with its operation modes, and a button
+    // for away mode
+    WaterHeater {
+        line: u8,
+        ha_id: String,
+        down: u8,
+        up: u8,
+        #[serde(default)]
+        away: Option<u8>,
+        // the setpoint change per press, the entity's target_temp_step
+        // (or 0.5) if not set
+        #[serde(default)]
+        step: Option<f64>,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // a blind or garage door, with its position as a bar. A long press
+    // on any of its buttons moves it to `preset` percent open
+    Cover {
+        line: u8,
+        ha_id: String,
+        open: u8,
+        close: u8,
+        #[serde(default)]
+        stop: Option<u8>,
+        #[serde(default = "default_cover_preset")]
+        preset: u8,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // an alarm panel, the button arms it (away) or disarms it, asking
+    // for the code if HA needs one
+    Alarm {
+        line: u8,
+        ha_id: String,
+        button: u8,
+        text: String,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // a lock, green when locked and red when not (flashing if jammed).
+    // Unlocking takes two presses of the button
+    Lock {
+        line: u8,
+        ha_id: String,
+        button: u8,
+        // shown before the state, e.g. "Front door"
+        text: String,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // a dimmable light with its brightness on a line. The button toggles
+    // it, a long press opens a page for adjusting the brightness (and the
+    // color temperature if the light has one)
+    Light {
+        line: u8,
+        ha_id: String,
+        button: u8,
+        text: String,
+        // the brightness change per press, in percent
+        #[serde(default = "default_light_step")]
+        step: u8,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // the next events of a calendar, fetched every `refresh` minutes and
+    // word-wrapped over `lines` lines
+    Calendar {
+        line: u8,
+        ha_id: String,
+        #[serde(default = "default_calendar_lines")]
+        lines: u8,
+        #[serde(default = "default_calendar_refresh")]
+        refresh: u64,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // the unchecked items of a todo or shopping list, word-wrapped over
+    // `lines` lines. The button checks off the first one
+    Todo {
+        line: u8,
+        ha_id: String,
+        #[serde(default = "default_calendar_lines")]
+        lines: u8,
+        button: u8,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // people home or away, each with an icon and their name on one line
+    Presence {
+        line: u8,
+        // person (or device_tracker) entities
+        people: Vec<String>,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // where someone is: the zone they're in, or while they're away how
+    // far from home they are, how long until they're back (e.g. from
+    // Waze) and an arrow for which way they're heading
+    Travel {
+        line: u8,
+        // the person (or device_tracker) entity
+        ha_id: String,
+        text: String,
+        // proximity's distance and direction of travel sensors
+        #[serde(default)]
+        distance: Option<String>,
+        #[serde(default)]
+        direction: Option<String>,
+        // a travel time sensor, in minutes
+        #[serde(default)]
+        eta: Option<String>,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // the power used now and the energy used today, with the power as a
+    // bar that's full at `max_power` watts
+    Energy {
+        line: u8,
+        power: String,
+        energy: String,
+        #[serde(default = "default_max_power")]
+        max_power: f64,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // a countdown that ticks on the device between HA's updates. The
+    // button starts and cancels it, a long press pauses it
+    Timer {
+        line: u8,
+        ha_id: String,
+        button: u8,
+        text: String,
+        // e.g. "00:10:00", the timer's own duration if not set
+        #[serde(default)]
+        duration: Option<String>,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // a robot vacuum's state and battery, red when it reports an error
+    Vacuum {
+        line: u8,
+        ha_id: String,
+        start: u8,
+        dock: u8,
+        // the button that makes it beep so it can be found
+        #[serde(default)]
+        locate: Option<u8>,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // a camera's image in an area of the screen, fetched every `refresh`
+    // seconds from the default HA instance
+    Camera {
+        ha_id: String,
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+        #[serde(default = "default_camera_refresh")]
+        refresh: u64,
+    },
+    // an input_select (e.g. a house mode) shown as `<text> <option>`, the
+    // button steps to the next option and a long press to the previous one
+    Select {
+        line: u8,
+        ha_id: String,
+        button: u8,
+        text: String,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
     },
+    // an input_number shown as `<text> <value>`, with buttons to step it
+    // down and up. A long press takes 10 steps
+    Number {
+        line: u8,
+        ha_id: String,
+        down: u8,
+        up: u8,
+        text: String,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // how many of a list of binary sensors are on, e.g. "3 doors open",
+    // in red while any are. The button lists them
+    Sensors {
+        line: u8,
+        sensors: Vec<String>,
+        button: u8,
+        // shown after the count
+        text: String,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // garden irrigation: the zones (switches) that are running,
+    // or when the next run is. The buttons run and stop the zones, in order
+    Irrigation {
+        line: u8,
+        zones: Vec<String>,
+        #[serde(default)]
+        buttons: Vec<u8>,
+        // a timestamp sensor, or an entity with a next_start attribute
+        #[serde(default)]
+        next_run: Option<String>,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // the next sunrise and sunset. While the sun is down the backlight
+    // dims to `night_backlight` percent, if it's set, and with `dark` the
+    // pages switch to the night theme
+    Sun {
+        line: u8,
+        #[serde(default = "default_sun")]
+        ha_id: String,
+        #[serde(default)]
+        night_backlight: Option<u8>,
+        #[serde(default)]
+        dark: bool,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // a fan's speed as a percentage (or its preset mode) and a bar. The
+    // button steps up through its speeds, a long press turns it off or on
+    Fan {
+        line: u8,
+        ha_id: String,
+        button: u8,
+        text: String,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+    // the current conditions on a line with the next days' forecast (if
+    // the entity has a forecast attribute) on the line below
+    Weather {
+        line: u8,
+        ha_id: String,
+        color: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+}
+
+// for items without an entity of their own
+static NO_ID: String = String::new();
+
+fn default_cover_preset() -> u8 {
+    50
+}
+
+fn default_light_step() -> u8 {
+    10
+}
+
+fn default_max_power() -> f64 {
+    5000.0
+}
+
+fn default_sun() -> String {
+    "sun.sun".into()
+}
+
+fn default_camera_refresh() -> u64 {
+    30
+}
+
+fn default_calendar_lines() -> u8 {
+    4
+}
+
+fn default_calendar_refresh() -> u64 {
+    15
 }
 
 impl HAConnect {
-    pub fn is_on(&self, state: &HashMap<String, String>) -> bool {
+    pub fn is_on(&self, states: &States) -> bool {
         match self {
-            HAConnect::Button { ha_id, cmp, .. } => {
-                let st = state.get(ha_id);
+            HAConnect::Button { cmp, .. } => {
+                let st = states.get(&self.state_key()).map(|e| &e.state);
                 cmp == st
             }
             _ => false,
@@ -146,36 +609,187 @@ impl HAConnect {
 }
 
 impl HAConnect {
-    pub fn ha_id<'d>(&'d self) -> &'d String {
+    pub fn ha_id(&self) -> &String {
         match self {
             HAConnect::Text { text, .. } => text,
             HAConnect::Button { ha_id, .. } => ha_id,
             HAConnect::Line { ha_id, .. } => ha_id,
+            HAConnect::Attribute { ha_id, .. } => ha_id,
+            HAConnect::Template { template, .. } => template,
+            HAConnect::Climate { ha_id, .. } => ha_id,
+            HAConnect::Cover { ha_id, .. } => ha_id,
+            HAConnect::Lock { ha_id, .. } => ha_id,
+            HAConnect::Alarm { ha_id, .. } => ha_id,
+            HAConnect::Light { ha_id, .. } => ha_id,
+            HAConnect::Weather { ha_id, .. } => ha_id,
+            HAConnect::Calendar { ha_id, .. } => ha_id,
+            HAConnect::Todo { ha_id, .. } => ha_id,
+            HAConnect::Presence { people, .. } => people.first().unwrap_or(&NO_ID),
+            HAConnect::Energy { power, .. } => power,
+            HAConnect::Travel { ha_id, .. } => ha_id,
+            HAConnect::Timer { ha_id, .. } => ha_id,
+            HAConnect::Vacuum { ha_id, .. } => ha_id,
+            HAConnect::Camera { ha_id, .. } => ha_id,
+            HAConnect::Select { ha_id, .. } => ha_id,
+            HAConnect::Number { ha_id, .. } => ha_id,
+            HAConnect::Sun { ha_id, .. } => ha_id,
+            HAConnect::Fan { ha_id, .. } => ha_id,
+            HAConnect::Humidifier { ha_id, .. } => ha_id,
+            HAConnect::WaterHeater { ha_id, .. } => ha_id,
+            HAConnect::Sensors { sensors, .. } => sensors.first().unwrap_or(&NO_ID),
+            HAConnect::Irrigation { zones, .. } => zones.first().unwrap_or(&NO_ID),
         }
     }
-}
 
-pub fn traverse(json: &JsonValue, path: &[&str]) -> Option<String> {
-    let mut thing = json;
-    for item in path {
-        match thing {
-            JsonValue::Object(v) => match v.get(*item) {
-                None => {
-                    return None;
-                }
-                Some(v) => {
-                    thing = v;
-                }
-            },
-            _ => {
-                return None;
+    pub fn color(&self) -> u16 {
+        match self {
+            HAConnect::Text { color, .. }
+            | HAConnect::Button { color, .. }
+            | HAConnect::Line { color, .. }
+            | HAConnect::Attribute { color, .. }
+            | HAConnect::Template { color, .. }
+            | HAConnect::Climate { color, .. }
+            | HAConnect::Cover { color, .. }
+            | HAConnect::Lock { color, .. }
+            | HAConnect::Alarm { color, .. }
+            | HAConnect::Light { color, .. }
+            | HAConnect::Weather { color, .. }
+            | HAConnect::Calendar { color, .. }
+            | HAConnect::Todo { color, .. }
+            | HAConnect::Presence { color, .. }
+            | HAConnect::Energy { color, .. }
+            | HAConnect::Travel { color, .. }
+            | HAConnect::Timer { color, .. }
+            | HAConnect::Vacuum { color, .. }
+            | HAConnect::Select { color, .. }
+            | HAConnect::Number { color, .. }
+            | HAConnect::Sensors { color, .. }
+            | HAConnect::Irrigation { color, .. }
+            | HAConnect::Sun { color, .. }
+            | HAConnect::Fan { color, .. }
+            | HAConnect::Humidifier { color, .. }
+            | HAConnect::WaterHeater { color, .. } => *color,
+            // the image has its own colors
+            HAConnect::Camera { .. } => 0,
+        }
+    }
+
+    // the HA entity the item shows, None for static text and items that
+    // fetch what they show
+    pub fn entity_id(&self) -> Option<&String> {
+        match self {
+            HAConnect::Text { .. }
+            | HAConnect::Template { .. }
+            | HAConnect::Calendar { .. }
+            | HAConnect::Camera { .. } => None,
+            _ => Some(self.ha_id()),
+        }
+    }
+
+    // all the entities the item shows
+    pub fn entities(&self) -> Vec<&String> {
+        match self {
+            HAConnect::Presence { people, .. } => people.iter().collect(),
+            HAConnect::Energy { power, energy, .. } => vec![power, energy],
+            HAConnect::Travel {
+                ha_id,
+                distance,
+                direction,
+                eta,
+                ..
+            } => [
+                Some(ha_id),
+                distance.as_ref(),
+                direction.as_ref(),
+                eta.as_ref(),
+            ]
+            .into_iter()
+            .flatten()
+            .collect(),
+            HAConnect::Sensors { sensors, .. } => sensors.iter().collect(),
+            HAConnect::Irrigation {
+                zones, next_run, ..
+            } => zones.iter().chain(next_run).collect(),
+            _ => self.entity_id().into_iter().collect(),
+        }
+    }
+
+    pub fn instance(&self) -> Option<&str> {
+        match self {
+            HAConnect::Text { .. } | HAConnect::Camera { .. } => None,
+            HAConnect::Button { instance, .. } => instance.as_deref(),
+            HAConnect::Line { instance, .. } => instance.as_deref(),
+            HAConnect::Attribute { instance, .. } => instance.as_deref(),
+            HAConnect::Template { instance, .. } => instance.as_deref(),
+            HAConnect::Climate { instance, .. } => instance.as_deref(),
+            HAConnect::Cover { instance, .. } => instance.as_deref(),
+            HAConnect::Lock { instance, .. } => instance.as_deref(),
+            HAConnect::Alarm { instance, .. } => instance.as_deref(),
+            HAConnect::Light { instance, .. } => instance.as_deref(),
+            HAConnect::Weather { instance, .. } => instance.as_deref(),
+            HAConnect::Calendar { instance, .. } => instance.as_deref(),
+            HAConnect::Todo { instance, .. } => instance.as_deref(),
+            HAConnect::Presence { instance, .. } => instance.as_deref(),
+            HAConnect::Energy { instance, .. } => instance.as_deref(),
+            HAConnect::Travel { instance, .. } => instance.as_deref(),
+            HAConnect::Timer { instance, .. } => instance.as_deref(),
+            HAConnect::Vacuum { instance, .. } => instance.as_deref(),
+            HAConnect::Select { instance, .. } => instance.as_deref(),
+            HAConnect::Number { instance, .. } => instance.as_deref(),
+            HAConnect::Sensors { instance, .. } => instance.as_deref(),
+            HAConnect::Irrigation { instance, .. } => instance.as_deref(),
+            HAConnect::Sun { instance, .. } => instance.as_deref(),
+            HAConnect::Fan { instance, .. } => instance.as_deref(),
+            HAConnect::Humidifier { instance, .. } => instance.as_deref(),
+            HAConnect::WaterHeater { instance, .. } => instance.as_deref(),
+        }
+    }
+
+    // where the item's state is kept in the state store. A calendar's
+    // events and a todo list's items are kept apart from the state of
+    // their entity
+    pub fn state_key(&self) -> String {
+        match self {
+            HAConnect::Calendar { .. } => {
+                format!("{}#agenda", state_key(self.instance(), self.ha_id()))
+            }
+            HAConnect::Todo { .. } => {
+                format!("{}#items", state_key(self.instance(), self.ha_id()))
+            }
+            _ => state_key(self.instance(), self.ha_id()),
+        }
+    }
+
+    // the keys of all the states the item uses
+    pub fn state_keys(&self) -> Vec<String> {
+        match self {
+            HAConnect::Presence { .. }
+            | HAConnect::Energy { .. }
+            | HAConnect::Sensors { .. }
+            | HAConnect::Irrigation { .. }
+            | HAConnect::Travel { .. } => self
+                .entities()
+                .iter()
+                .map(|id| state_key(self.instance(), id))
+                .collect(),
+            // a todo list's items are fetched again when its count changes
+            HAConnect::Todo { .. } => {
+                vec![self.state_key(), state_key(self.instance(), self.ha_id())]
             }
+            _ => vec![self.state_key()],
         }
     }
+}
 
-    match thing {
-        JsonValue::String(s) => Some(s.clone()),
-        JsonValue::Short(s) => Some(s.to_string()),
-        _ => None,
+// the entities of an HA instance used by the items, without duplicates
+pub fn entity_ids(items: &[HAConnect], instance: Option<&str>) -> Vec<String> {
+    let mut ids: Vec<String> = vec![];
+    for c in items.iter().filter(|c| c.instance() == instance) {
+        for id in c.entities() {
+            if !ids.contains(id) {
+                ids.push(id.clone());
+            }
+        }
     }
+    ids
 }
@@ -0,0 +1,160 @@
+use std::{
+    ffi::CString,
+    sync::{atomic::Ordering, Mutex},
+    time::{Duration, Instant},
+};
+
+use esp_idf_sys::{
+    esp, esp_task_wdt_add_user, esp_task_wdt_config_t, esp_task_wdt_reconfigure,
+    esp_task_wdt_reset_user, esp_task_wdt_user_handle_t,
+};
+use log::*;
+
+use crate::{flashlog::flush_log, memory::track_stack, settings::Settings, status::Status};
+
+// the NVS key holding why the watchdog last restarted the device
+pub const REBOOT_REASON_KEY: &str = "reboot_reason";
+
+// how long a watched thread can go without feeding the watchdog before
+// the device restarts. A TLS handshake or a big camera image takes a few
+// seconds, so it's generous
+pub const TASK_TIMEOUT: Duration = Duration::from_secs(60);
+
+// a watched thread blocked on a channel wakes this often to feed it
+pub const FEED_INTERVAL: Duration = Duration::from_secs(5);
+
+// the IDF's task watchdog only fires if the check below couldn't run
+const HARDWARE_TIMEOUT: Duration = Duration::from_secs(90);
+
+struct Watched {
+    name: &'static str,
+    fed: Instant,
+}
+
+static WATCHED: Mutex<Vec<Watched>> = Mutex::new(Vec::new());
+
+// a thread that has to feed the watchdog at least every TASK_TIMEOUT. It's
+// also a user of the IDF's task watchdog, which names it if it fires
+pub struct TaskWatch {
+    index: usize,
+    handle: esp_task_wdt_user_handle_t,
+}
+
+impl TaskWatch {
+    pub fn new(name: &'static str) -> TaskWatch {
+        track_stack(name);
+        let mut handle: esp_task_wdt_user_handle_t = std::ptr::null_mut();
+        // the watchdog keeps the name's pointer
+        let c_name = CString::new(name).unwrap_or_default().into_raw();
+        if let Err(e) = esp!(unsafe { esp_task_wdt_add_user(c_name, &mut handle) }) {
+            info!("Failed to watch the {} thread error {:?}", name, e);
+            handle = std::ptr::null_mut();
+        }
+        let index = WATCHED.lock().map_or(usize::MAX, |mut watched| {
+            watched.push(Watched {
+                name,
+                fed: Instant::now(),
+            });
+            watched.len() - 1
+        });
+        TaskWatch { index, handle }
+    }
+
+    pub fn feed(&self) {
+        if !self.handle.is_null() {
+            unsafe { esp_task_wdt_reset_user(self.handle) };
+        }
+        if let Ok(mut watched) = WATCHED.lock() {
+            if let Some(w) = watched.get_mut(self.index) {
+                w.fed = Instant::now();
+            }
+        }
+    }
+
+    // sleep for a while longer than the watchdog allows
+    pub fn sleep(&self, wait: Duration) {
+        let until = Instant::now() + wait;
+        loop {
+            self.feed();
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return;
+            }
+            std::thread::sleep(left.min(FEED_INTERVAL));
+        }
+    }
+}
+
+// give the IDF's task watchdog time for the check below to go first, and
+// make it reset the device rather than just log
+pub fn configure_task_watchdog() {
+    let config = esp_task_wdt_config_t {
+        timeout_ms: HARDWARE_TIMEOUT.as_millis() as u32,
+        // both cores' idle tasks, as by default
+        idle_core_mask: 0b11,
+        trigger_panic: true,
+    };
+    if let Err(e) = esp!(unsafe { esp_task_wdt_reconfigure(&config) }) {
+        info!("Failed to configure the task watchdog error {:?}", e);
+    }
+}
+
+// restart the device, saying which thread it was, when a watched thread
+// hasn't fed the watchdog for TASK_TIMEOUT. A hung SPI transfer or a
+// blocked channel leaves the screen frozen otherwise
+pub fn run_task_watchdog(settings: Settings) {
+    loop {
+        std::thread::sleep(FEED_INTERVAL);
+
+        let stuck = WATCHED.lock().ok().and_then(|watched| {
+            watched
+                .iter()
+                .find(|w| w.fed.elapsed() > TASK_TIMEOUT)
+                .map(|w| (w.name, w.fed.elapsed()))
+        });
+        if let Some((name, stuck_for)) = stuck {
+            let reason = format!(
+                "Watchdog restart: the {} thread was stuck for {}s",
+                name,
+                stuck_for.as_secs()
+            );
+            info!("{}", reason);
+            if let Err(e) = settings.set_str(REBOOT_REASON_KEY, &reason) {
+                info!("Failed to save the reboot reason error {:?}", e);
+            }
+            flush_log();
+            esp_idf_hal::reset::restart();
+        }
+    }
+}
+
+// restart the device if neither the network nor the HA websocket has been
+// healthy for `timeout`. Some failure combinations (e.g. the WiFi driver
+// giving up) leave the device wedged forever otherwise
+pub fn run_network_watchdog(status: &Status, settings: Settings, timeout: Duration) {
+    let mut last_healthy = Instant::now();
+
+    loop {
+        std::thread::sleep(Duration::from_secs(10));
+
+        if status.network_ok.load(Ordering::Relaxed) || status.ha_connected.load(Ordering::Relaxed)
+        {
+            last_healthy = Instant::now();
+            continue;
+        }
+
+        if last_healthy.elapsed() > timeout {
+            let reason = format!(
+                "Watchdog restart: no network or HA websocket for {}s",
+                last_healthy.elapsed().as_secs()
+            );
+            info!("{}", reason);
+            // NVS rather than SPIFFS so it survives re-flashing the configs
+            if let Err(e) = settings.set_str(REBOOT_REASON_KEY, &reason) {
+                info!("Failed to save the reboot reason error {:?}", e);
+            }
+            flush_log();
+            esp_idf_hal::reset::restart();
+        }
+    }
+}
@@ -0,0 +1,708 @@
+use std::{
+    io::{Read, Write as _},
+    sync::{mpsc::Sender, Mutex},
+};
+
+use crossbeam::channel::Sender as XBSender;
+use embedded_graphics::{
+    pixelcolor::{raw::RawU16, Rgb565},
+    prelude::{Point, Size},
+    primitives::Rectangle,
+};
+use embedded_svc::{
+    http::{Headers, Method, Query},
+    io::Write,
+    utils::io,
+};
+use esp_idf_svc::http::server::{Configuration, EspHttpServer};
+use log::*;
+use profont::PROFONT_24_POINT;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::{WebConfig, MAX_CONFIG},
+    config_store::{export_config, import_config, save_config},
+    display::{DrawCmd, DrawPos},
+    files::{
+        check_name, delete_file, list_files, make_dirs, open_file, replace_file_with, sd_file,
+    },
+    flashlog::{flush_log, read_log},
+    ha::{EntityState, FromHa, HaMessage},
+    oauth::{query_param, OAuth, CALLBACK_PATH},
+    settings::Settings,
+    signing::{signing_enabled, verify_digest, SIGNATURE_HEADER},
+    status::Status,
+    webauth::WebAuth,
+    wifi::{ha_token, queue_for_main, HA_TOKEN_KEY},
+    Error, Result,
+};
+
+// the largest request body accepted
+const MAX_BODY: usize = 4096;
+
+const MIN_PASSWORD: usize = 8;
+
+// replacing the HA token takes the current one in this, as a bearer token
+const TOKEN_HEADER: &str = "X-HA-Token";
+
+// the page at /, to back up and restore the device's setup
+const INDEX_PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta name="viewport" content="width=device-width"><title>homer</title></head>
+<body><h1>homer</h1>
+<p><a href="/export" download="homer-backup.json"><button>Download backup</button></a></p>
+<p><input type="file" id="backup" accept=".json"> <button onclick="restore()">Restore</button></p>
+<p><a href="/files">Files</a> <a href="/log">Log</a></p>
+<p><input type="password" id="password" placeholder="New password"> <button onclick="setPassword()">Set password</button></p>
+<pre id="result"></pre>
+<script>
+async function setPassword() {
+  const body = document.getElementById('password').value;
+  const response = await fetch('/password', {method: 'POST', body});
+  document.getElementById('result').textContent = await response.text();
+}
+async function restore() {
+  const file = document.getElementById('backup').files[0];
+  if (!file) return;
+  const response = await fetch('/import', {method: 'POST', body: await file.text()});
+  document.getElementById('result').textContent = await response.text();
+}
+</script>
+</body></html>
+"#;
+
+// the file browser at /files, for the config partition and SD card
+const FILES_PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta name="viewport" content="width=device-width"><title>homer files</title></head>
+<body><h1>Files</h1>
+<table id="files"></table>
+<p><input type="file" id="upload"> as <input id="name" placeholder="e.g. fonts/big.bdf">
+<button onclick="upload()">Upload</button></p>
+<pre id="result"></pre>
+<script>
+const param = name => 'name=' + encodeURIComponent(name);
+async function list() {
+  const files = await (await fetch('/files/list')).json();
+  const table = document.getElementById('files');
+  table.innerHTML = '<tr><th>Name</th><th>Size</th><th></th></tr>';
+  for (const f of files) {
+    const row = table.insertRow();
+    const link = document.createElement('a');
+    link.href = '/file?' + param(f.name) + (f.sd ? '&sd=1' : '');
+    link.textContent = (f.sd ? 'sd: ' : '') + f.name;
+    row.insertCell().appendChild(link);
+    row.insertCell().textContent = f.size;
+    if (!f.sd) {
+      const remove = document.createElement('button');
+      remove.textContent = 'Delete';
+      remove.onclick = () => act(fetch('/file?' + param(f.name), {method: 'DELETE'}));
+      row.insertCell().appendChild(remove);
+    }
+  }
+}
+async function act(request) {
+  document.getElementById('result').textContent = await (await request).text();
+  list();
+}
+function upload() {
+  const file = document.getElementById('upload').files[0];
+  if (!file) return;
+  const name = document.getElementById('name').value || file.name;
+  act(fetch('/file?' + param(name), {method: 'POST', body: file}));
+}
+list();
+</script>
+</body></html>
+"#;
+
+// a draw command pushed over HTTP. `line` is the same as the `line` of
+// the config items and colors are RGB565 like in the config
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum WebDrawCmd {
+    Text {
+        line: u8,
+        text: String,
+        #[serde(default)]
+        color: u16,
+        #[serde(default = "white")]
+        background: u16,
+    },
+    Clear {
+        line: u8,
+        #[serde(default = "white")]
+        color: u16,
+    },
+    Erase {
+        #[serde(default = "white")]
+        color: u16,
+    },
+}
+
+fn white() -> u16 {
+    0xffff
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum WebDrawCmds {
+    One(WebDrawCmd),
+    Many(Vec<WebDrawCmd>),
+}
+
+// the body of POST /states, one state or a list of them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PushedStates {
+    One(EntityState),
+    Many(Vec<EntityState>),
+}
+
+fn rgb(color: u16) -> Rgb565 {
+    RawU16::new(color).into()
+}
+
+impl WebDrawCmd {
+    pub fn to_draw_cmd(&self) -> DrawCmd {
+        let line_y = |line: u8| 30 * (line as i32 + 2);
+        match self {
+            WebDrawCmd::Text {
+                line,
+                text,
+                color,
+                background,
+            } => DrawCmd::Text {
+                pos: DrawPos::Pos(Point::new(10, line_y(*line))),
+                font: Some(PROFONT_24_POINT.into()),
+                text: text.clone(),
+                text_color: rgb(*color),
+                background: Some(rgb(*background)),
+            },
+            WebDrawCmd::Clear { line, color } => DrawCmd::Clear {
+                color: rgb(*color),
+                pos: DrawPos::Box(Rectangle::new(
+                    Point::new(0, line_y(*line)),
+                    Size::new(320, 32),
+                )),
+            },
+            WebDrawCmd::Erase { color } => DrawCmd::Erase { color: rgb(*color) },
+        }
+    }
+}
+
+// with `signed_configs` an upload has to have a good signature, and a
+// firmware without a signing key can't check one so it takes none
+fn check_signature(signed: bool, digest: &[u8], signature: Option<&str>) -> Result<()> {
+    if !signed {
+        return Ok(());
+    }
+    if !signing_enabled() {
+        return Err(Error::Config(
+            "signed_configs needs firmware built with HOMER_SIGNING_KEY".into(),
+        ));
+    }
+    verify_digest(digest, signature)
+}
+
+// compared in full, so the time doesn't give away how much matched
+fn same_secret(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |d, (x, y)| d | (x ^ y)) == 0
+}
+
+// parse a request body, either one command or a list of them
+pub fn parse_draw_cmds(body: &[u8]) -> Result<Vec<WebDrawCmd>> {
+    Ok(match serde_json::from_slice(body)? {
+        WebDrawCmds::One(cmd) => vec![cmd],
+        WebDrawCmds::Many(cmds) => cmds,
+    })
+}
+
+// a small HTTP server on the device so local systems (Node-RED, scripts)
+// can put text on the screen, even when HA is down. The server stops
+// when the returned value is dropped
+pub fn start_web_server(
+    web: &WebConfig,
+    display_tx: Sender<DrawCmd>,
+    settings: Settings,
+    ha_tx: XBSender<FromHa>,
+    status: &'static Status,
+    oauth: OAuth,
+    device: String,
+) -> Result<EspHttpServer> {
+    let mut server = EspHttpServer::new(&Configuration {
+        http_port: web.port,
+        ..Default::default()
+    })
+    .map_err(Error::socket)?;
+
+    // every request is checked against the allow list and password
+    let auth: &'static WebAuth = Box::leak(Box::new(WebAuth::new(web, settings.clone())));
+    let display_tx = Mutex::new(display_tx);
+    let signed = web.signed_configs;
+    let config_settings = settings.clone();
+    let export_settings = settings.clone();
+    let import_settings = settings.clone();
+    let export_device = device.clone();
+    let import_device = device.clone();
+
+    server
+        .fn_handler("/draw", Method::Post, move |req| {
+            let mut req = match auth.check(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            let len = req.content_len().unwrap_or(0) as usize;
+            if len > MAX_BODY {
+                req.into_status_response(413)?;
+                return Ok(());
+            }
+
+            let mut body = vec![0_u8; len];
+            io::try_read_full(&mut req, &mut body).map_err(|err| err.0)?;
+
+            let cmds = match parse_draw_cmds(&body) {
+                Ok(cmds) => cmds,
+                Err(e) => {
+                    info!("Bad draw request error {:?}", e);
+                    req.into_status_response(400)?
+                        .write_all(format!("{}", e).as_bytes())?;
+                    return Ok(());
+                }
+            };
+
+            let tx = display_tx
+                .lock()
+                .map_err(|_| Error::Display("Display channel lock poisoned".into()))?;
+            for cmd in &cmds {
+                tx.send(cmd.to_draw_cmd())?;
+            }
+
+            req.into_ok_response()?.write_all(b"ok")?;
+            Ok(())
+        })
+        .map_err(Error::socket)?;
+
+    // a new HA access token, used from the next websocket connection
+    server
+        .fn_handler("/token", Method::Post, move |req| {
+            let mut req = match auth.check_admin(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            // so whoever can reach the server can't swap the HA credentials
+            let current = ha_token(&settings);
+            let shown = req
+                .header(TOKEN_HEADER)
+                .and_then(|h| h.strip_prefix("Bearer "))
+                .unwrap_or_default()
+                .trim()
+                .to_string();
+            if !current.is_empty() && !same_secret(&shown, &current) {
+                req.into_status_response(401)?
+                    .write_all(b"the current token is needed")?;
+                return Ok(());
+            }
+            let len = req.content_len().unwrap_or(0) as usize;
+            if len > MAX_BODY {
+                req.into_status_response(413)?;
+                return Ok(());
+            }
+
+            let mut body = vec![0_u8; len];
+            io::try_read_full(&mut req, &mut body).map_err(|err| err.0)?;
+
+            let token = String::from_utf8_lossy(&body).trim().to_string();
+            if token.is_empty() {
+                req.into_status_response(400)?.write_all(b"no token")?;
+                return Ok(());
+            }
+
+            settings.set_str(HA_TOKEN_KEY, &token)?;
+            info!("Saved a new HA access token");
+            req.into_ok_response()?.write_all(b"ok")?;
+            Ok(())
+        })
+        .map_err(Error::socket)?;
+
+    // link the device to HA with a login in the browser rather than a
+    // long-lived token. HA sends the browser back to the callback
+    let login = oauth.clone();
+    server
+        .fn_handler("/oauth", Method::Get, move |req| {
+            let req = match auth.check_admin(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            let host = match req.header("Host") {
+                Some(host) => host.to_string(),
+                None => {
+                    req.into_status_response(400)?
+                        .write_all(b"no Host header")?;
+                    return Ok(());
+                }
+            };
+            let url = login.authorize_url(&OAuth::client_id(&host));
+            req.into_response(302, Some("Found"), &[("Location", &url)])?;
+            Ok(())
+        })
+        .map_err(Error::socket)?;
+
+    server
+        .fn_handler(CALLBACK_PATH, Method::Get, move |req| {
+            let req = match auth.check_admin(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            let code = query_param(req.uri(), "code");
+            let host = req.header("Host").map(|h| h.to_string());
+            let (code, host) = match (code, host) {
+                (Some(code), Some(host)) => (code, host),
+                _ => {
+                    req.into_status_response(400)?.write_all(b"no code")?;
+                    return Ok(());
+                }
+            };
+            match oauth.exchange_code(&OAuth::client_id(&host), &code) {
+                Ok(_) => {
+                    req.into_ok_response()?
+                        .write_all(b"Linked to Home Assistant, you can close this page")?;
+                }
+                Err(e) => {
+                    info!("OAuth code exchange failed error {:?}", e);
+                    req.into_status_response(502)?
+                        .write_all(format!("{}", e).as_bytes())?;
+                }
+            }
+            Ok(())
+        })
+        .map_err(Error::socket)?;
+
+    // states pushed by HA automations, for the webhook mode
+    server
+        .fn_handler("/states", Method::Post, move |req| {
+            let mut req = match auth.check(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            let len = req.content_len().unwrap_or(0) as usize;
+            if len > MAX_BODY {
+                req.into_status_response(413)?;
+                return Ok(());
+            }
+
+            let mut body = vec![0_u8; len];
+            io::try_read_full(&mut req, &mut body).map_err(|err| err.0)?;
+            status.traffic.rest_received(len);
+
+            let states = match serde_json::from_slice(&body) {
+                Ok(PushedStates::One(state)) => vec![state],
+                Ok(PushedStates::Many(states)) => states,
+                Err(e) => {
+                    info!("Bad states request error {:?}", e);
+                    req.into_status_response(400)?
+                        .write_all(format!("{}", e).as_bytes())?;
+                    return Ok(());
+                }
+            };
+
+            for state in &states {
+                queue_for_main(
+                    &ha_tx,
+                    FromHa {
+                        instance: None,
+                        msg: HaMessage::state_changed(state),
+                    },
+                    status,
+                );
+            }
+
+            req.into_ok_response()?.write_all(b"ok")?;
+            Ok(())
+        })
+        .map_err(Error::socket)?;
+
+    // a new config for the device, which restarts with it. The one it
+    // replaces comes back if the new one crashes the device
+    server
+        .fn_handler("/config", Method::Post, move |req| {
+            let mut req = match auth.check_admin(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            let len = req.content_len().unwrap_or(0) as usize;
+            if len > MAX_CONFIG {
+                req.into_status_response(413)?;
+                return Ok(());
+            }
+
+            let signature = req.header(SIGNATURE_HEADER).map(String::from);
+            let mut body = vec![0_u8; len];
+            io::try_read_full(&mut req, &mut body).map_err(|err| err.0)?;
+
+            let saved = check_signature(signed, &Sha256::digest(&body), signature.as_deref())
+                .and_then(|_| Ok(std::str::from_utf8(&body)?))
+                .and_then(|config| save_config(&config_settings, &device, config));
+            if let Err(e) = saved {
+                info!("Bad config request error {:?}", e);
+                req.into_status_response(400)?
+                    .write_all(format!("{}", e).as_bytes())?;
+                return Ok(());
+            }
+
+            req.into_ok_response()?.write_all(b"ok, restarting")?;
+            flush_log();
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            esp_idf_hal::reset::restart();
+        })
+        .map_err(Error::socket)?;
+
+    server
+        .fn_handler("/", Method::Get, move |req| {
+            let req = match auth.check(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            req.into_response(200, None, &[("Content-Type", "text/html")])?
+                .write_all(INDEX_PAGE.as_bytes())?;
+            Ok(())
+        })
+        .map_err(Error::socket)?;
+
+    // the config and settings as one JSON document, for /import here or
+    // on another device
+    server
+        .fn_handler("/export", Method::Get, move |req| {
+            let req = match auth.check_admin(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            match export_config(&export_settings, &export_device) {
+                Ok(backup) => {
+                    req.into_response(200, None, &[("Content-Type", "application/json")])?
+                        .write_all(backup.to_string().as_bytes())?;
+                }
+                Err(e) => {
+                    info!("Failed to export the config error {:?}", e);
+                    req.into_status_response(500)?
+                        .write_all(format!("{}", e).as_bytes())?;
+                }
+            }
+            Ok(())
+        })
+        .map_err(Error::socket)?;
+
+    server
+        .fn_handler("/import", Method::Post, move |req| {
+            let mut req = match auth.check_admin(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            let len = req.content_len().unwrap_or(0) as usize;
+            if len > MAX_CONFIG {
+                req.into_status_response(413)?;
+                return Ok(());
+            }
+
+            let signature = req.header(SIGNATURE_HEADER).map(String::from);
+            let mut body = vec![0_u8; len];
+            io::try_read_full(&mut req, &mut body).map_err(|err| err.0)?;
+
+            let imported = check_signature(signed, &Sha256::digest(&body), signature.as_deref())
+                .and_then(|_| import_config(&import_settings, &import_device, &body));
+            if let Err(e) = imported {
+                info!("Bad import request error {:?}", e);
+                req.into_status_response(400)?
+                    .write_all(format!("{}", e).as_bytes())?;
+                return Ok(());
+            }
+
+            req.into_ok_response()?.write_all(b"ok, restarting")?;
+            flush_log();
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            esp_idf_hal::reset::restart();
+        })
+        .map_err(Error::socket)?;
+
+    server
+        .fn_handler("/files", Method::Get, move |req| {
+            let req = match auth.check_admin(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            req.into_response(200, None, &[("Content-Type", "text/html")])?
+                .write_all(FILES_PAGE.as_bytes())?;
+            Ok(())
+        })
+        .map_err(Error::socket)?;
+
+    server
+        .fn_handler("/files/list", Method::Get, move |req| {
+            let req = match auth.check_admin(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            match list_files() {
+                Ok(files) => {
+                    req.into_response(200, None, &[("Content-Type", "application/json")])?
+                        .write_all(serde_json::to_string(&files)?.as_bytes())?;
+                }
+                Err(e) => {
+                    info!("Failed to list the files error {:?}", e);
+                    req.into_status_response(500)?
+                        .write_all(format!("{}", e).as_bytes())?;
+                }
+            }
+            Ok(())
+        })
+        .map_err(Error::socket)?;
+
+    // a file's contents, from the SD card with `sd=1`
+    server
+        .fn_handler("/file", Method::Get, move |req| {
+            let req = match auth.check_admin(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            let name = query_param(req.uri(), "name").unwrap_or_default();
+            let sd = query_param(req.uri(), "sd").is_some();
+            let file = check_name(&name).and_then(|_| match sd {
+                true => {
+                    sd_file(&name).ok_or_else(|| Error::Fs(format!("No {} on the SD card", name)))
+                }
+                false => open_file(&name),
+            });
+            let mut file = match file {
+                Ok(file) => file,
+                Err(e) => {
+                    req.into_status_response(404)?
+                        .write_all(format!("{}", e).as_bytes())?;
+                    return Ok(());
+                }
+            };
+            let mut response =
+                req.into_response(200, None, &[("Content-Type", "application/octet-stream")])?;
+            let mut buf = [0_u8; 1024];
+            loop {
+                let read = file.read(&mut buf)?;
+                if read == 0 {
+                    break;
+                }
+                response.write_all(&buf[0..read])?;
+            }
+            Ok(())
+        })
+        .map_err(Error::socket)?;
+
+    // upload a file to the config partition, replacing any with the name
+    server
+        .fn_handler("/file", Method::Post, move |req| {
+            let mut req = match auth.check_admin(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            let name = query_param(req.uri(), "name").unwrap_or_default();
+            let signature = req.header(SIGNATURE_HEADER).map(String::from);
+            let saved = check_name(&name).and_then(|_| {
+                make_dirs(&name);
+                replace_file_with(&name, |file| {
+                    let mut hasher = Sha256::new();
+                    let mut buf = [0_u8; 1024];
+                    loop {
+                        let read = io::try_read_full(&mut req, &mut buf)
+                            .map_err(|err| Error::socket(err.0))?;
+                        if read == 0 {
+                            return check_signature(
+                                signed,
+                                &hasher.finalize(),
+                                signature.as_deref(),
+                            );
+                        }
+                        hasher.update(&buf[0..read]);
+                        file.write_all(&buf[0..read])?;
+                    }
+                })
+            });
+            match saved {
+                Ok(()) => {
+                    info!("Uploaded {}", name);
+                    req.into_ok_response()?
+                        .write_all(format!("saved {}", name).as_bytes())?;
+                }
+                Err(e) => {
+                    info!("Failed to upload {} error {:?}", name, e);
+                    req.into_status_response(400)?
+                        .write_all(format!("{}", e).as_bytes())?;
+                }
+            }
+            Ok(())
+        })
+        .map_err(Error::socket)?;
+
+    server
+        .fn_handler("/file", Method::Delete, move |req| {
+            let req = match auth.check_admin(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            let name = query_param(req.uri(), "name").unwrap_or_default();
+            match delete_file(&name) {
+                Ok(()) => {
+                    info!("Deleted {}", name);
+                    req.into_ok_response()?
+                        .write_all(format!("deleted {}", name).as_bytes())?;
+                }
+                Err(e) => {
+                    req.into_status_response(400)?
+                        .write_all(format!("{}", e).as_bytes())?;
+                }
+            }
+            Ok(())
+        })
+        .map_err(Error::socket)?;
+
+    // change the password, with the old one
+    server
+        .fn_handler("/password", Method::Post, move |req| {
+            let mut req = match auth.check_admin(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            let len = req.content_len().unwrap_or(0) as usize;
+            if len > MAX_BODY {
+                req.into_status_response(413)?;
+                return Ok(());
+            }
+
+            let mut body = vec![0_u8; len];
+            io::try_read_full(&mut req, &mut body).map_err(|err| err.0)?;
+
+            let password = String::from_utf8_lossy(&body).trim().to_string();
+            if password.len() < MIN_PASSWORD {
+                req.into_status_response(400)?.write_all(
+                    format!("the password needs {} characters or more", MIN_PASSWORD).as_bytes(),
+                )?;
+                return Ok(());
+            }
+            auth.set_password(&password)?;
+            info!("Web password changed");
+            req.into_ok_response()?.write_all(b"ok")?;
+            Ok(())
+        })
+        .map_err(Error::socket)?;
+
+    // the log kept on flash, from before the last crash or restart too
+    server
+        .fn_handler("/log", Method::Get, move |req| {
+            let req = match auth.check(req)? {
+                Some(req) => req,
+                None => return Ok(()),
+            };
+            req.into_response(200, None, &[("Content-Type", "text/plain")])?
+                .write_all(read_log().as_bytes())?;
+            Ok(())
+        })
+        .map_err(Error::socket)?;
+
+    info!("Web server listening on port {}", web.port);
+    Ok(server)
+}
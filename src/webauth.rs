@@ -0,0 +1,277 @@
+use std::{
+    ffi::c_void,
+    net::Ipv4Addr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use embedded_svc::{
+    http::{server::Request, Headers},
+    io::Write,
+};
+use esp_idf_svc::http::server::EspHttpConnection;
+use esp_idf_sys::{
+    esp_fill_random, httpd_req_to_sockfd, lwip_getpeername, sockaddr, sockaddr_in, sockaddr_in6,
+    socklen_t, AF_INET, AF_INET6,
+};
+use log::*;
+use sha2::{Digest, Sha256};
+
+use crate::{config::WebConfig, settings::Settings, Error, Result};
+
+// the NVS key of the web UI's password, salted and hashed
+pub const WEB_PASSWORD_KEY: &str = "web_password";
+
+// the password is hashed this many times, to slow down guessing it from
+// a copy of NVS
+const ROUNDS: u32 = 1000;
+
+// after a wrong password, passwords aren't checked for this long. The
+// answer doesn't wait, that would hold up the server's task
+const LOCKOUT: Duration = Duration::from_secs(1);
+
+// the password the device starts with, saved on the first boot
+const WEB_PASSWORD: Option<&str> = option_env!("HOMER_WEB_PASSWORD");
+
+// who can use the web server: the addresses in the allow list (any
+// without one) and, once a password is set, only with the password
+pub struct WebAuth {
+    settings: Settings,
+    allow: Vec<(Ipv4Addr, u32)>,
+    // the digest of the last Authorization header that was right, so the
+    // password isn't hashed again for each request
+    verified: Mutex<Option<[u8; 32]>>,
+    failed_at: Mutex<Option<Instant>>,
+}
+
+impl WebAuth {
+    pub fn new(web: &WebConfig, settings: Settings) -> WebAuth {
+        let allow = web
+            .allow
+            .iter()
+            .filter_map(|entry| match parse_network(entry) {
+                Some(network) => Some(network),
+                None => {
+                    info!("Ignoring {} in the web allow list", entry);
+                    None
+                }
+            })
+            .collect();
+        let auth = WebAuth {
+            settings,
+            allow,
+            verified: Mutex::new(None),
+            failed_at: Mutex::new(None),
+        };
+        if let Some(password) = WEB_PASSWORD.filter(|_| !auth.has_password()) {
+            match auth.set_password(password) {
+                Ok(()) => info!("Web password set from the firmware"),
+                Err(e) => info!("Failed to save the web password error {:?}", e),
+            }
+        }
+        auth
+    }
+
+    pub fn has_password(&self) -> bool {
+        self.settings.get_str(WEB_PASSWORD_KEY).is_some()
+    }
+
+    pub fn set_password(&self, password: &str) -> Result<()> {
+        self.settings
+            .set_str(WEB_PASSWORD_KEY, &hash_password(password))?;
+        if let Ok(mut verified) = self.verified.lock() {
+            *verified = None;
+        }
+        Ok(())
+    }
+
+    // whether the allow list lets a client in, one without an IPv4
+    // address only gets in without a list
+    pub fn allows(&self, ip: Option<Ipv4Addr>) -> bool {
+        self.allow.is_empty()
+            || ip.map_or(false, |ip| {
+                self.allow
+                    .iter()
+                    .any(|(network, mask)| u32::from(ip) & mask == u32::from(*network) & mask)
+            })
+    }
+
+    // whether `password` is the password, any is without one. None are
+    // right during a lockout
+    pub fn password_ok(&self, password: &str) -> bool {
+        let stored = match self.settings.get_str(WEB_PASSWORD_KEY) {
+            Some(stored) => stored,
+            None => return true,
+        };
+        let right = !self.locked_out() && check_password(&stored, password).unwrap_or(false);
+        if !right {
+            self.failed();
+        }
+        right
+    }
+
+    // hand the request back if it's allowed, otherwise answer it (403 for
+    // an address that isn't allowed, 401 for a missing or wrong password,
+    // 429 for one too soon after a wrong one)
+    pub fn check<'r, 'c>(
+        &self,
+        req: Request<&'r mut EspHttpConnection<'c>>,
+    ) -> Result<Option<Request<&'r mut EspHttpConnection<'c>>>> {
+        self.check_request(req, false)
+    }
+
+    // like check, for the endpoints that change the device or read its
+    // secrets. They're refused until there's a password, so a device that
+    // hasn't been given one can't be taken over by whoever gets to it first
+    pub fn check_admin<'r, 'c>(
+        &self,
+        req: Request<&'r mut EspHttpConnection<'c>>,
+    ) -> Result<Option<Request<&'r mut EspHttpConnection<'c>>>> {
+        self.check_request(req, true)
+    }
+
+    fn check_request<'r, 'c>(
+        &self,
+        mut req: Request<&'r mut EspHttpConnection<'c>>,
+        admin: bool,
+    ) -> Result<Option<Request<&'r mut EspHttpConnection<'c>>>> {
+        if !self.allow.is_empty() && !self.allows(peer_ip(&mut req)) {
+            req.into_status_response(403).map_err(Error::socket)?;
+            return Ok(None);
+        }
+
+        let stored = match self.settings.get_str(WEB_PASSWORD_KEY) {
+            Some(stored) => stored,
+            None if admin => {
+                req.into_status_response(403)
+                    .map_err(Error::socket)?
+                    .write_all(b"no web password, build the firmware with HOMER_WEB_PASSWORD")
+                    .map_err(Error::socket)?;
+                return Ok(None);
+            }
+            None => return Ok(Some(req)),
+        };
+        let header = req.header("Authorization").unwrap_or_default().to_string();
+        if !header.is_empty() && self.locked_out() {
+            req.into_status_response(429).map_err(Error::socket)?;
+            return Ok(None);
+        }
+        if self.authorized(&stored, &header) {
+            return Ok(Some(req));
+        }
+        if !header.is_empty() {
+            info!("Wrong web password for {}", req.uri());
+            self.failed();
+        }
+        req.into_response(
+            401,
+            Some("Unauthorized"),
+            &[("WWW-Authenticate", "Basic realm=\"homer\"")],
+        )
+        .map_err(Error::socket)?;
+        Ok(None)
+    }
+
+    fn locked_out(&self) -> bool {
+        self.failed_at
+            .lock()
+            .map_or(false, |f| f.map_or(false, |at| at.elapsed() < LOCKOUT))
+    }
+
+    fn failed(&self) {
+        if let Ok(mut failed_at) = self.failed_at.lock() {
+            *failed_at = Some(Instant::now());
+        }
+    }
+
+    // HTTP basic auth, any user name will do
+    fn authorized(&self, stored: &str, header: &str) -> bool {
+        let digest: [u8; 32] = Sha256::digest(header.as_bytes()).into();
+        if self.verified.lock().map_or(false, |v| *v == Some(digest)) {
+            return true;
+        }
+        let password = header
+            .strip_prefix("Basic ")
+            .and_then(|b| STANDARD.decode(b.trim()).ok())
+            .and_then(|b| String::from_utf8(b).ok())
+            .and_then(|b| b.split_once(':').map(|(_, p)| p.to_string()));
+        let right = password.map_or(false, |p| check_password(stored, &p).unwrap_or(false));
+        if right {
+            if let Ok(mut verified) = self.verified.lock() {
+                *verified = Some(digest);
+            }
+        }
+        right
+    }
+}
+
+// a random salt and the salted password hashed ROUNDS times, in base64
+fn hash_password(password: &str) -> String {
+    let mut salt = [0_u8; 16];
+    unsafe { esp_fill_random(salt.as_mut_ptr() as *mut c_void, salt.len()) };
+    format!(
+        "{}:{}",
+        STANDARD.encode(salt),
+        STANDARD.encode(stretch(&salt, password))
+    )
+}
+
+fn check_password(stored: &str, password: &str) -> Result<bool> {
+    let (salt, hash) = stored
+        .split_once(':')
+        .ok_or_else(|| Error::Config("Bad password hash".into()))?;
+    let salt = STANDARD.decode(salt).map_err(Error::config)?;
+    let hash = STANDARD.decode(hash).map_err(Error::config)?;
+    let attempt = stretch(&salt, password);
+    // compared in full, so the time doesn't give away how much matched
+    Ok(hash.len() == attempt.len()
+        && hash.iter().zip(attempt).fold(0, |d, (a, b)| d | (a ^ b)) == 0)
+}
+
+fn stretch(salt: &[u8], password: &str) -> [u8; 32] {
+    let mut hash: [u8; 32] = Sha256::new()
+        .chain_update(salt)
+        .chain_update(password.as_bytes())
+        .finalize()
+        .into();
+    for _ in 1..ROUNDS {
+        hash = Sha256::digest(hash).into();
+    }
+    hash
+}
+
+// an address, or a network like 192.168.1.0/24, as the address and mask
+fn parse_network(entry: &str) -> Option<(Ipv4Addr, u32)> {
+    let (ip, bits) = match entry.split_once('/') {
+        Some((ip, bits)) => (ip, bits.parse::<u32>().ok().filter(|b| *b <= 32)?),
+        None => (entry, 32),
+    };
+    let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+    Some((ip.trim().parse().ok()?, mask))
+}
+
+// the client's address. The server's sockets are IPv6 when the IDF has
+// IPv6 on, with IPv4 clients as mapped addresses
+fn peer_ip(req: &mut Request<&mut EspHttpConnection>) -> Option<Ipv4Addr> {
+    let raw = req.connection().raw_connection().ok()?;
+    let fd = unsafe { httpd_req_to_sockfd(raw) };
+    let mut addr: sockaddr_in6 = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<sockaddr_in6>() as socklen_t;
+    if unsafe { lwip_getpeername(fd, &mut addr as *mut _ as *mut sockaddr, &mut len) } != 0 {
+        return None;
+    }
+    match addr.sin6_family as u32 {
+        AF_INET => {
+            let addr = unsafe { &*(&addr as *const _ as *const sockaddr_in) };
+            Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+        }
+        AF_INET6 => {
+            let bytes = unsafe { addr.sin6_addr.un.u8_addr };
+            let v4 = [bytes[12], bytes[13], bytes[14], bytes[15]];
+            (bytes[..10].iter().all(|b| *b == 0) && bytes[10..12] == [0xff, 0xff])
+                .then_some(Ipv4Addr::from(v4))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    sync::{atomic::Ordering, mpsc::RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use log::*;
+use serde_json::{json, Value};
+
+use crate::{
+    config::HaConfig,
+    ha::{FromHa, HaError, HaMessage, ResultMsg},
+    rest::ha_post,
+    status::Status,
+    wifi::{queue_for_main, SocketCmd, SocketCtx},
+    Error, Result,
+};
+
+// how often the panel tells HA where to push the states
+const REGISTER_INTERVAL: Duration = Duration::from_secs(300);
+
+// the event that tells HA automations where to push the states
+pub const REGISTER_EVENT: &str = "homer_register";
+
+// push mode, for networks where long lived websockets get killed (e.g.
+// by a proxy). HA automations push state changes to the device's web
+// server (POST /states) and everything the device sends goes over HA's
+// REST API as short requests
+pub fn handle_webhook(
+    ctx: SocketCtx,
+    ha_url: &'static str,
+    ha_headers: [(&'static str, &'static str); 2],
+    device: String,
+    port: u16,
+) -> Result<()> {
+    let SocketCtx {
+        has_network,
+        socket_rx,
+        ha_tx,
+        ha,
+        status,
+        entities,
+        ..
+    } = ctx;
+    // wait until there's a network stack
+    while !has_network.load(Ordering::Relaxed) {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    let mut last_register: Option<Instant> = None;
+    loop {
+        if last_register.map_or(true, |at| at.elapsed() > REGISTER_INTERVAL) {
+            last_register = Some(Instant::now());
+            let ip = status
+                .lease
+                .lock()
+                .ok()
+                .and_then(|l| l.as_ref().map(|l| l.ip));
+            let data = json!({
+                "device": device,
+                "url": ip.map(|ip| format!("http://{}:{}/states", ip, port)),
+                "entities": entities,
+            });
+            let ok = post(
+                &event_url(&ha, ha_url, REGISTER_EVENT),
+                &data,
+                &ha_headers,
+                &ha,
+                status,
+            );
+            status.ha_connected.store(ok.is_ok(), Ordering::Relaxed);
+            if let Err(e) = ok {
+                info!("Failed to register with HA error {:?}", e);
+            }
+        }
+
+        match socket_rx.recv_timeout(Duration::from_secs(1)) {
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(e) => {
+                return Err(Error::Socket(format!(
+                    "Webhook command channel closed {:?}",
+                    e
+                )))
+            }
+            Ok(SocketCmd::SendJson(msg)) => {
+                let id = msg["id"].as_i64().unwrap_or_default();
+                let result = match msg["type"].as_str() {
+                    Some("call_service") => call_service(&msg, ha_url, &ha_headers, &ha, status),
+                    Some("fire_event") => post(
+                        &event_url(&ha, ha_url, msg["event_type"].as_str().unwrap_or_default()),
+                        &msg["event_data"],
+                        &ha_headers,
+                        &ha,
+                        status,
+                    ),
+                    // subscriptions and the like need the websocket
+                    _ => continue,
+                };
+
+                // answer like the websocket would, so failures get shown
+                let error = result.err().map(|e| HaError {
+                    code: "rest".into(),
+                    message: e.to_string(),
+                });
+                let msg = HaMessage::Result(ResultMsg {
+                    id,
+                    success: error.is_none(),
+                    result: Value::Null,
+                    error,
+                });
+                queue_for_main(
+                    &ha_tx,
+                    FromHa {
+                        instance: None,
+                        msg,
+                    },
+                    status,
+                );
+            }
+            Ok(_) => {}
+        }
+    }
+}
+
+fn event_url(ha: &HaConfig, ha_url: &str, event_type: &str) -> String {
+    ha.rest_url(ha_url, &format!("events/{}", event_type))
+}
+
+// the REST API takes the target next to the service data
+fn call_service(
+    msg: &Value,
+    ha_url: &str,
+    headers: &[(&str, &str)],
+    ha: &HaConfig,
+    status: &Status,
+) -> Result<()> {
+    let mut data = match &msg["service_data"] {
+        Value::Object(data) => data.clone(),
+        _ => Default::default(),
+    };
+    if let Value::Object(target) = &msg["target"] {
+        data.extend(target.clone());
+    }
+    let url = ha.rest_url(
+        ha_url,
+        &format!(
+            "services/{}/{}",
+            msg["domain"].as_str().unwrap_or_default(),
+            msg["service"].as_str().unwrap_or_default()
+        ),
+    );
+    post(&url, &Value::Object(data), headers, ha, status)
+}
+
+fn post(
+    url: &str,
+    body: &Value,
+    headers: &[(&str, &str)],
+    ha: &HaConfig,
+    status: &Status,
+) -> Result<()> {
+    let response = ha_post(url, headers, body.to_string().as_bytes(), ha, status)?;
+    if response.status >= 300 {
+        return Err(Error::Socket(format!("HA answered {}", response.status)));
+    }
+    Ok(())
+}
@@ -0,0 +1,2186 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Local, NaiveDate};
+use embedded_graphics::{
+    pixelcolor::Rgb565,
+    prelude::{Point, RgbColor, Size},
+    primitives::Rectangle,
+};
+use serde_json::{json, Value};
+
+use crate::{
+    adjust::brightness_pct,
+    buttons::ButtonEvent,
+    display::{DrawPos, Icon, Theme, ICON_SIZE},
+    states::{state_key, Entity, States},
+    util::{HAAction, HAConnect},
+};
+
+// how long a widget waits for the second press of a confirmation
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(3);
+
+// alerts flash on and off this often
+const FLASH: Duration = Duration::from_secs(1);
+
+// how often a running timer is redrawn
+const COUNTDOWN: Duration = Duration::from_secs(1);
+
+// the events of a calendar (or items of a todo list) that are kept, and
+// how much of their summaries
+const AGENDA_EVENTS: usize = 5;
+const SUMMARY_CHARS: usize = 60;
+
+// how often a todo list is fetched, besides when its count changes
+const TODO_REFRESH: Duration = Duration::from_secs(60 * 60);
+
+// the characters that fit on a line in the big font
+const LINE_CHARS: usize = 24;
+
+// something a widget puts on the home page
+pub enum WidgetPart {
+    // text in the item's color unless it has its own
+    Text(DrawPos, String, Option<Rgb565>),
+    // a bar filled to a percentage
+    Bar(Rectangle, u8),
+    // an icon at the top left point, in its own colors unless one is set
+    Icon(Point, Icon, Option<Rgb565>),
+}
+
+impl WidgetPart {
+    // for telling whether the part needs redrawing
+    pub fn summary(&self) -> String {
+        match self {
+            WidgetPart::Text(_, text, color) => format!("{}{:?}", text, color),
+            WidgetPart::Bar(_, percent) => format!("{}%", percent),
+            WidgetPart::Icon(_, icon, color) => format!("{:?}{:?}", icon, color),
+        }
+    }
+}
+
+// what the widgets remember between presses and redraws
+#[derive(Default)]
+pub struct WidgetState {
+    // widgets waiting for a second press, by state key
+    armed: HashMap<String, Instant>,
+    // alerts are shown while this is set
+    flash_on: bool,
+    last_flash: Option<Instant>,
+    alerting: bool,
+    // running timers are redrawn each second
+    last_countdown: Option<Instant>,
+}
+
+impl WidgetState {
+    pub fn new() -> WidgetState {
+        WidgetState::default()
+    }
+
+    // call often (at least once a second). Returns true if the widgets
+    // need redrawing because a confirmation ran out or an alert flashed
+    pub fn tick(&mut self, items: &[HAConnect], states: &States) -> bool {
+        let armed = self.armed.len();
+        self.armed.retain(|_, at| at.elapsed() < CONFIRM_TIMEOUT);
+        let mut changed = armed != self.armed.len();
+
+        let alerting = items
+            .iter()
+            .any(|c| states.get(&c.state_key()).map_or(false, |e| alert(c, e)));
+        if alerting && self.last_flash.map_or(true, |at| at.elapsed() >= FLASH) {
+            self.last_flash = Some(Instant::now());
+            self.flash_on = !self.flash_on;
+            changed = true;
+        }
+        // put things back when the alert is over
+        if alerting != self.alerting {
+            self.alerting = alerting;
+            self.flash_on = alerting && self.flash_on;
+            changed = true;
+        }
+
+        let counting = items.iter().any(|c| {
+            matches!(c, HAConnect::Timer { .. })
+                && states
+                    .get(&c.state_key())
+                    .map_or(false, |e| e.state == "active")
+        });
+        if counting
+            && self
+                .last_countdown
+                .map_or(true, |at| at.elapsed() >= COUNTDOWN)
+        {
+            self.last_countdown = Some(Instant::now());
+            changed = true;
+        }
+        changed
+    }
+
+    fn is_armed(&self, key: &str) -> bool {
+        self.armed.contains_key(key)
+    }
+
+    // the first press of a confirmation returns false, a second one
+    // within the timeout true
+    fn confirm(&mut self, key: String) -> bool {
+        if self.armed.remove(&key).is_some() {
+            return true;
+        }
+        self.armed.insert(key, Instant::now());
+        false
+    }
+}
+
+// widgets showing a problem that needs attention
+fn alert(c: &HAConnect, entity: &Entity) -> bool {
+    match c {
+        HAConnect::Lock { .. } => entity.state == "jammed",
+        HAConnect::Alarm { .. } => entity.state == "triggered",
+        _ => false,
+    }
+}
+
+// a triggered alarm takes over the screen, flashing
+pub fn alarm_flash(items: &[HAConnect], states: &States, widget_state: &WidgetState) -> bool {
+    widget_state.flash_on
+        && items.iter().any(|c| {
+            matches!(c, HAConnect::Alarm { .. })
+                && states.get(&c.state_key()).map_or(false, |e| alert(c, e))
+        })
+}
+
+fn line_y(line: u8) -> i32 {
+    30 * (line as i32 + 2)
+}
+
+fn line_pos(line: u8) -> DrawPos {
+    DrawPos::Pos(Point::new(10, line_y(line)))
+}
+
+fn text(pos: DrawPos, text: impl Into<String>) -> WidgetPart {
+    WidgetPart::Text(pos, text.into(), None)
+}
+
+fn colored(pos: DrawPos, text: impl Into<String>, color: Rgb565) -> WidgetPart {
+    WidgetPart::Text(pos, text.into(), Some(color))
+}
+
+// an icon at the start of a line, or further along it
+fn icon(line: u8, x: i32, icon: Icon) -> WidgetPart {
+    WidgetPart::Icon(Point::new(x, line_y(line) - ICON_SIZE as i32), icon, None)
+}
+
+// a bar on the right half of a line
+fn bar(line: u8, percent: u8) -> WidgetPart {
+    WidgetPart::Bar(
+        Rectangle::new(Point::new(170, line_y(line) - 20), Size::new(140, 18)),
+        percent.min(100),
+    )
+}
+
+// the buttons a widget responds to
+pub fn uses_button(c: &HAConnect, button: usize) -> bool {
+    match c {
+        HAConnect::Climate { down, up, .. }
+        | HAConnect::Humidifier { down, up, .. }
+        | HAConnect::Number { down, up, .. } => [*down, *up].contains(&(button as u8)),
+        HAConnect::WaterHeater { down, up, away, .. } => {
+            [Some(*down), Some(*up), *away].contains(&Some(button as u8))
+        }
+        HAConnect::Cover {
+            open, close, stop, ..
+        } => [Some(*open), Some(*close), *stop].contains(&Some(button as u8)),
+        HAConnect::Vacuum {
+            start,
+            dock,
+            locate,
+            ..
+        } => [Some(*start), Some(*dock), *locate].contains(&Some(button as u8)),
+        HAConnect::Lock { button: b, .. }
+        | HAConnect::Alarm { button: b, .. }
+        | HAConnect::Light { button: b, .. }
+        | HAConnect::Timer { button: b, .. }
+        | HAConnect::Select { button: b, .. }
+        | HAConnect::Sensors { button: b, .. }
+        | HAConnect::Todo { button: b, .. }
+        | HAConnect::Fan { button: b, .. } => *b as usize == button,
+        HAConnect::Irrigation { buttons, .. } => buttons.contains(&(button as u8)),
+        _ => false,
+    }
+}
+
+// what a widget shows for its entity. Nothing until HA has sent the state
+pub fn view(
+    c: &HAConnect,
+    states: &States,
+    placeholder: &str,
+    widget_state: &WidgetState,
+) -> Vec<WidgetPart> {
+    // widgets showing more than one entity
+    let get = |id: &str| states.get(&state_key(c.instance(), id));
+    match c {
+        HAConnect::Presence { line, people, .. } => {
+            let entities: Vec<(&String, Option<&Entity>)> =
+                people.iter().map(|p| (p, get(p))).collect();
+            return presence(*line, &entities, placeholder);
+        }
+        HAConnect::Energy {
+            line,
+            power,
+            energy,
+            max_power,
+            ..
+        } => return energy_view(*line, get(power), get(energy), *max_power, placeholder),
+        HAConnect::Travel {
+            line,
+            ha_id,
+            text,
+            distance,
+            direction,
+            eta,
+            ..
+        } => {
+            let get_opt = |id: &Option<String>| id.as_deref().and_then(get);
+            return travel(
+                *line,
+                text,
+                get(ha_id),
+                get_opt(distance),
+                get_opt(direction),
+                get_opt(eta),
+                placeholder,
+            );
+        }
+        HAConnect::Sensors {
+            line, button, text, ..
+        } => {
+            let on = sensors_on(c, states).len();
+            let line_text = format!("{:<20}", format!("{} {}", on, text));
+            let line_part = if on > 0 {
+                colored(line_pos(*line), line_text, Rgb565::RED)
+            } else {
+                self::text(line_pos(*line), line_text)
+            };
+            return vec![line_part, self::text(DrawPos::Button(*button), "List")];
+        }
+        HAConnect::Irrigation {
+            line,
+            zones,
+            buttons,
+            next_run,
+            ..
+        } => {
+            let zones: Vec<(&String, Option<&Entity>)> =
+                zones.iter().map(|z| (z, get(z))).collect();
+            let next = match next_run {
+                None => "Idle".to_string(),
+                Some(id) => match get(id) {
+                    Some(entity) => next_start(entity)
+                        .map_or("Nothing planned".into(), |t| format!("Next {}", t)),
+                    None => placeholder.to_string(),
+                },
+            };
+            return irrigation(*line, &zones, buttons, &next, placeholder);
+        }
+        _ => {}
+    }
+    let entity = match states.get(&c.state_key()) {
+        Some(e) => e,
+        None => return vec![],
+    };
+    match c {
+        HAConnect::Climate { line, down, up, .. } => {
+            setpoint_view(&CLIMATE, *line, *down, *up, entity, placeholder)
+        }
+        HAConnect::Humidifier { line, down, up, .. } => {
+            setpoint_view(&HUMIDIFIER, *line, *down, *up, entity, placeholder)
+        }
+        HAConnect::WaterHeater {
+            line,
+            down,
+            up,
+            away,
+            ..
+        } => {
+            let mut parts = setpoint_view(&WATER_HEATER, *line, *down, *up, entity, placeholder);
+            if let Some(away) = away {
+                let label = if entity.attribute("away_mode") == Some("on") {
+                    "Home"
+                } else {
+                    "Away"
+                };
+                parts.push(text(DrawPos::Button(*away), label));
+            }
+            parts
+        }
+        HAConnect::Cover {
+            line,
+            open,
+            close,
+            stop,
+            ..
+        } => {
+            let mut parts = vec![
+                text(DrawPos::Button(*open), "Open"),
+                text(DrawPos::Button(*close), "Close"),
+            ];
+            if let Some(stop) = stop {
+                parts.push(text(DrawPos::Button(*stop), "Stop"));
+            }
+            if !entity.is_available() {
+                parts.push(text(line_pos(*line), placeholder));
+                return parts;
+            }
+            // covers without position support are only open or closed
+            let position = number(entity, "current_position").map(|p| p.round() as u8);
+            let position = position.unwrap_or(if entity.state == "closed" { 0 } else { 100 });
+            // padded so a shorter state covers a longer one
+            parts.push(text(line_pos(*line), format!("{:<9}", entity.state)));
+            parts.push(bar(*line, position));
+            parts
+        }
+        HAConnect::Lock {
+            line, button, text, ..
+        } => {
+            if !entity.is_available() {
+                return vec![
+                    self::text(line_pos(*line), format!("{} {}", text, placeholder)),
+                    self::text(DrawPos::Button(*button), placeholder),
+                ];
+            }
+            let line_text = format!("{} {:<9}", text, entity.state);
+            let line_part = match entity.state.as_str() {
+                "locked" => colored(line_pos(*line), line_text, Rgb565::GREEN),
+                "jammed" if widget_state.flash_on => {
+                    colored(line_pos(*line), line_text, Rgb565::RED)
+                }
+                // flashes between red and the item's color
+                "jammed" => self::text(line_pos(*line), line_text),
+                _ => colored(line_pos(*line), line_text, Rgb565::RED),
+            };
+            let label = if widget_state.is_armed(&c.state_key()) {
+                "Sure?"
+            } else if entity.state == "locked" {
+                "Unlock"
+            } else {
+                "Lock"
+            };
+            vec![line_part, self::text(DrawPos::Button(*button), label)]
+        }
+        HAConnect::Alarm {
+            line, button, text, ..
+        } => {
+            if !entity.is_available() {
+                return vec![
+                    self::text(line_pos(*line), format!("{} {}", text, placeholder)),
+                    self::text(DrawPos::Button(*button), placeholder),
+                ];
+            }
+            let line_text = format!("{} {:<18}", text, entity.state.replace('_', " "));
+            let line_part = match entity.state.as_str() {
+                "disarmed" => self::text(line_pos(*line), line_text),
+                "triggered" => colored(line_pos(*line), line_text, Rgb565::RED),
+                _ => colored(line_pos(*line), line_text, Rgb565::GREEN),
+            };
+            let label = if entity.state == "disarmed" {
+                "Arm"
+            } else {
+                "Disarm"
+            };
+            vec![line_part, self::text(DrawPos::Button(*button), label)]
+        }
+        HAConnect::Light {
+            line, button, text, ..
+        } => {
+            if !entity.is_available() {
+                return vec![
+                    self::text(line_pos(*line), format!("{} {}", text, placeholder)),
+                    self::text(DrawPos::Button(*button), placeholder),
+                ];
+            }
+            let pct = brightness_pct(entity);
+            let label = if entity.state == "on" { "Off" } else { "On" };
+            vec![
+                self::text(
+                    line_pos(*line),
+                    format!("{} {:<4}", text, format!("{}%", pct)),
+                ),
+                bar(*line, pct),
+                self::text(DrawPos::Button(*button), label),
+            ]
+        }
+        HAConnect::Weather { line, .. } => weather(*line, entity, placeholder),
+        HAConnect::Fan {
+            line, button, text, ..
+        } => {
+            if !entity.is_available() {
+                return vec![
+                    self::text(line_pos(*line), format!("{} {}", text, placeholder)),
+                    self::text(DrawPos::Button(*button), placeholder),
+                ];
+            }
+            let percent = fan_percent(entity);
+            let speed = match entity.attribute("preset_mode").filter(|p| !p.is_empty()) {
+                Some(preset) => preset.to_string(),
+                None if entity.state == "off" => "off".into(),
+                None => format!("{}%", percent),
+            };
+            // the speed on the left of the bar
+            let line_text: String = format!("{} {:<10}", text, speed).chars().take(13).collect();
+            vec![
+                self::text(line_pos(*line), line_text),
+                bar(*line, percent),
+                self::text(DrawPos::Button(*button), "Speed"),
+            ]
+        }
+        HAConnect::Sun { line, .. } => {
+            // HA gives the times in UTC
+            let local = |name: &str| {
+                entity
+                    .attribute(name)
+                    .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+                    .map_or(placeholder.to_string(), |t| {
+                        t.with_timezone(&Local).format("%H:%M").to_string()
+                    })
+            };
+            vec![text(
+                line_pos(*line),
+                format!(
+                    "Rise {} Set {}",
+                    local("next_rising"),
+                    local("next_setting")
+                ),
+            )]
+        }
+        HAConnect::Number {
+            line,
+            down,
+            up,
+            text,
+            ..
+        } => {
+            let value = if entity.is_available() {
+                let unit = entity.attribute("unit_of_measurement").unwrap_or("");
+                format!("{}{}", entity.state, unit)
+            } else {
+                placeholder.to_string()
+            };
+            vec![
+                self::text(line_pos(*line), format!("{} {:<12}", text, value)),
+                self::text(DrawPos::Button(*down), "-"),
+                self::text(DrawPos::Button(*up), "+"),
+            ]
+        }
+        HAConnect::Select {
+            line, button, text, ..
+        } => {
+            let option = if entity.is_available() {
+                entity.state.as_str()
+            } else {
+                placeholder
+            };
+            vec![
+                self::text(line_pos(*line), format!("{} {:<16}", text, option)),
+                self::text(DrawPos::Button(*button), "Next"),
+            ]
+        }
+        HAConnect::Vacuum {
+            line,
+            start,
+            dock,
+            locate,
+            ..
+        } => {
+            let mut parts = vec![];
+            // cleaning, paused, docked, returning or error
+            if !entity.is_available() {
+                parts.push(text(line_pos(*line), placeholder));
+            } else {
+                let battery = entity
+                    .attribute("battery_level")
+                    .map_or(String::new(), |b| format!(" {}%", b));
+                let line_text = format!("{:<20}", format!("{}{}", entity.state, battery));
+                parts.push(match entity.state.as_str() {
+                    "error" => colored(line_pos(*line), line_text, Rgb565::RED),
+                    _ => text(line_pos(*line), line_text),
+                });
+            }
+            let start_label = if entity.state == "cleaning" {
+                "Pause"
+            } else {
+                "Start"
+            };
+            parts.push(text(DrawPos::Button(*start), start_label));
+            parts.push(text(DrawPos::Button(*dock), "Dock"));
+            if let Some(locate) = locate {
+                parts.push(text(DrawPos::Button(*locate), "Find"));
+            }
+            parts
+        }
+        HAConnect::Timer {
+            line, button, text, ..
+        } => {
+            if !entity.is_available() {
+                return vec![
+                    self::text(line_pos(*line), format!("{} {}", text, placeholder)),
+                    self::text(DrawPos::Button(*button), placeholder),
+                ];
+            }
+            let left = match entity.state.as_str() {
+                "active" => entity
+                    .attribute("finishes_at")
+                    .and_then(|f| DateTime::parse_from_rfc3339(f).ok())
+                    .map(|f| {
+                        (f.with_timezone(&Local) - Local::now())
+                            .num_seconds()
+                            .max(0)
+                    }),
+                "paused" => entity.attribute("remaining").and_then(seconds),
+                _ => entity.attribute("duration").and_then(seconds),
+            };
+            let left = left.map_or(placeholder.to_string(), countdown);
+            let label = if entity.state == "active" {
+                "Cancel"
+            } else {
+                "Start"
+            };
+            vec![
+                self::text(line_pos(*line), format!("{} {:<9}", text, left)),
+                self::text(DrawPos::Button(*button), label),
+            ]
+        }
+        HAConnect::Calendar { line, lines, .. } => agenda(*line, *lines, entity),
+        HAConnect::Todo {
+            ha_id,
+            line,
+            lines,
+            button,
+            ..
+        } => {
+            // the items are fetched, it's the list that goes unavailable
+            if get(ha_id).map_or(false, |e| !e.is_available()) {
+                let mut parts = padded_lines(*line, *lines, &[placeholder.to_string()]);
+                parts.push(text(DrawPos::Button(*button), placeholder));
+                return parts;
+            }
+            let items = todo_items(entity);
+            let mut wrapped = vec![];
+            for item in &items {
+                let summary = item["summary"].as_str().unwrap_or("");
+                wrapped.extend(word_wrap(&format!("- {}", summary), LINE_CHARS));
+            }
+            if items.is_empty() {
+                wrapped.push("Nothing to do".into());
+            }
+            let mut parts = padded_lines(*line, *lines, &wrapped);
+            parts.push(text(DrawPos::Button(*button), "Done"));
+            parts
+        }
+        _ => vec![],
+    }
+}
+
+// the people spread along the line, each with an icon that's green when
+// they're home, orange when they're in another zone and grey when away
+fn presence(line: u8, people: &[(&String, Option<&Entity>)], placeholder: &str) -> Vec<WidgetPart> {
+    if people.is_empty() {
+        return vec![];
+    }
+    let width = 310 / people.len() as i32;
+    // the big font is 12 pixels a character
+    let name_chars = ((width - ICON_SIZE as i32 - 6) / 12).max(1) as usize;
+    let mut parts = vec![];
+    let mut icons = vec![];
+    for (i, (id, entity)) in people.iter().enumerate() {
+        let x = 10 + width * i as i32;
+        let name = entity
+            .and_then(|e| e.attribute("friendly_name"))
+            .unwrap_or_else(|| id.split_once('.').map_or(id.as_str(), |(_, n)| n));
+        let name: String = name.chars().take(name_chars).collect();
+        let pos = DrawPos::Pos(Point::new(x + ICON_SIZE as i32 + 2, line_y(line)));
+        // the state is the zone they're in
+        let zone = entity
+            .filter(|e| e.is_available() && !e.state.is_empty())
+            .map(|e| e.state.as_str());
+        let color = match zone {
+            Some("home") => Some(Rgb565::GREEN),
+            Some("not_home") => Some(Rgb565::new(16, 32, 16)),
+            Some(_) => Some(Rgb565::new(31, 40, 0)),
+            None => None,
+        };
+        match color {
+            Some(color) => parts.push(colored(pos, name, color)),
+            None => parts.push(text(pos, format!("{} {}", name, placeholder))),
+        }
+        icons.push(WidgetPart::Icon(
+            Point::new(x, line_y(line) - ICON_SIZE as i32),
+            Icon::Person,
+            color,
+        ));
+    }
+    // drawing text clears the rest of the line, so the icons go last
+    parts.extend(icons);
+    parts
+}
+
+// the zone someone's in, or how far away and how long until they're
+// back, with an arrow at the end of the line for which way they're going
+fn travel(
+    line: u8,
+    name: &str,
+    person: Option<&Entity>,
+    distance: Option<&Entity>,
+    direction: Option<&Entity>,
+    eta: Option<&Entity>,
+    placeholder: &str,
+) -> Vec<WidgetPart> {
+    let zone = match person.filter(|p| p.is_available()) {
+        Some(p) => p.state.as_str(),
+        None => return vec![text(line_pos(line), format!("{} {}", name, placeholder))],
+    };
+    // padded to just short of where the arrow goes
+    let padded = |t: String| format!("{:<22}", t).chars().take(22).collect::<String>();
+    if zone != "not_home" {
+        let zone = if zone == "home" { "Home" } else { zone };
+        return vec![
+            colored(
+                line_pos(line),
+                padded(format!("{} {}", name, zone)),
+                Rgb565::GREEN,
+            ),
+            icon(line, 290, Icon::Stationary),
+        ];
+    }
+
+    let mut line_text = name.to_string();
+    if let Some(d) = available(distance) {
+        let away = match d.attribute("unit_of_measurement") {
+            Some("m") | Some("km") => scaled(d, &["m", "km"]),
+            unit => d
+                .state
+                .parse::<f64>()
+                .ok()
+                .map(|v| format!("{:.0}{}", v, unit.unwrap_or(""))),
+        };
+        line_text.push(' ');
+        line_text.push_str(away.as_deref().unwrap_or(placeholder));
+    }
+    if let Some(minutes) = available(eta).and_then(|e| e.state.parse::<f64>().ok()) {
+        line_text.push_str(&format!(" {:.0}min", minutes));
+    }
+    // proximity says arrived, away_from, stationary, towards or unknown
+    let arrow = match available(direction).map(|d| d.state.as_str()) {
+        Some("towards") => Icon::Towards,
+        Some("away_from") => Icon::AwayFrom,
+        _ => Icon::Stationary,
+    };
+    vec![
+        text(line_pos(line), padded(line_text)),
+        icon(line, 290, arrow),
+    ]
+}
+
+// the entity, if HA can reach it
+fn available(e: Option<&Entity>) -> Option<&Entity> {
+    e.filter(|e| e.is_available())
+}
+
+// a reading scaled to the unit that keeps it short, e.g. 1234 W as
+// 1.2kW. `unit` is the entity's unit, one of the `units` in steps of 1000
+fn scaled(entity: &Entity, units: &[&str]) -> Option<String> {
+    let mut value: f64 = entity.state.parse().ok()?;
+    let unit = entity.attribute("unit_of_measurement").unwrap_or(units[0]);
+    let mut i = units.iter().position(|u| *u == unit).unwrap_or(0);
+    while value.abs() >= 1000.0 && i + 1 < units.len() {
+        value /= 1000.0;
+        i += 1;
+    }
+    while value.abs() < 1.0 && value != 0.0 && i > 0 {
+        value *= 1000.0;
+        i -= 1;
+    }
+    let digits = if value.abs() < 10.0 && i > 0 { 1 } else { 0 };
+    Some(format!("{:.*}{}", digits, value, units[i]))
+}
+
+// the power now and the energy today, with the power as a bar
+fn energy_view(
+    line: u8,
+    power: Option<&Entity>,
+    energy: Option<&Entity>,
+    max_power: f64,
+    placeholder: &str,
+) -> Vec<WidgetPart> {
+    let power_text = power.and_then(|p| scaled(p, &["W", "kW", "MW"]));
+    let energy_text = energy.and_then(|e| scaled(e, &["Wh", "kWh", "MWh"]));
+    let line_text = format!(
+        "{} {}",
+        power_text.as_deref().unwrap_or(placeholder),
+        energy_text.as_deref().unwrap_or(placeholder)
+    );
+    let mut parts = vec![text(line_pos(line), format!("{:<13}", line_text))];
+    // the bar is in watts whatever the unit
+    let watts = power.and_then(|p| {
+        let value: f64 = p.state.parse().ok()?;
+        Some(match p.attribute("unit_of_measurement") {
+            Some("kW") => value * 1000.0,
+            Some("MW") => value * 1_000_000.0,
+            _ => value,
+        })
+    });
+    if let Some(watts) = watts {
+        let percent = (watts.max(0.0) * 100.0 / max_power.max(1.0)).round();
+        parts.push(bar(line, percent.min(100.0) as u8));
+    }
+    parts
+}
+
+// the seconds in one of HA's "1:02:03" durations
+fn seconds(duration: &str) -> Option<i64> {
+    duration.split(':').try_fold(0, |total, part| {
+        Some(total * 60 + part.trim().parse::<i64>().ok()?)
+    })
+}
+
+// seconds as m:ss, or h:mm:ss from an hour
+fn countdown(seconds: i64) -> String {
+    let (h, m, s) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if h > 0 {
+        format!("{}:{:02}:{:02}", h, m, s)
+    } else {
+        format!("{}:{:02}", m, s)
+    }
+}
+
+// HA's weather conditions
+fn condition_icon(condition: &str) -> Icon {
+    match condition {
+        "sunny" => Icon::Sun,
+        "clear-night" => Icon::Moon,
+        "partlycloudy" => Icon::PartlyCloudy,
+        "rainy" | "pouring" => Icon::Rain,
+        "snowy" | "snowy-rainy" | "hail" => Icon::Snow,
+        "lightning" | "lightning-rainy" | "exceptional" => Icon::Storm,
+        "fog" => Icon::Fog,
+        _ => Icon::Cloud,
+    }
+}
+
+// a temperature from HA, rounded
+fn degrees(v: Option<&serde_json::Value>) -> String {
+    v.and_then(|v| v.as_f64())
+        .map_or("-".into(), |t| t.round().to_string())
+}
+
+fn weather(line: u8, entity: &Entity, placeholder: &str) -> Vec<WidgetPart> {
+    let text_at = |line: u8, x: i32| DrawPos::Pos(Point::new(x, line_y(line)));
+    if !entity.is_available() {
+        return vec![text(text_at(line, 34), placeholder)];
+    }
+    // the font has no degree sign
+    let unit = entity
+        .attribute("temperature_unit")
+        .unwrap_or("")
+        .replace('°', "");
+    let now = format!(
+        "{}{} {}",
+        number(entity, "temperature").map_or("-".into(), |t| t.round().to_string()),
+        unit,
+        entity.state.replace('-', " ")
+    );
+    let mut parts = vec![
+        text(text_at(line, 34), format!("{:<20}", now)),
+        icon(line, 8, condition_icon(&entity.state)),
+    ];
+
+    // the days' texts go before the icons, drawing text clears the rest
+    // of the line
+    let days: Vec<serde_json::Value> = entity
+        .attribute("forecast")
+        .and_then(|f| serde_json::from_str(f).ok())
+        .unwrap_or_default();
+    for (i, day) in days.iter().enumerate() {
+        let high_low = format!(
+            "{}/{}",
+            degrees(day.get("temperature")),
+            degrees(day.get("templow"))
+        );
+        parts.push(text(text_at(line + 1, 34 + 100 * i as i32), high_low));
+    }
+    for (i, day) in days.iter().enumerate() {
+        let condition = day.get("condition").and_then(|c| c.as_str()).unwrap_or("");
+        parts.push(icon(
+            line + 1,
+            8 + 100 * i as i32,
+            condition_icon(condition),
+        ));
+    }
+    parts
+}
+
+// how often an item's contents are fetched, for those that fetch them
+pub fn fetch_refresh(c: &HAConnect) -> Option<Duration> {
+    match c {
+        HAConnect::Calendar { refresh, .. } => Some(Duration::from_secs(60 * (*refresh).max(1))),
+        HAConnect::Todo { .. } => Some(TODO_REFRESH),
+        _ => None,
+    }
+}
+
+// the get_events call for the next week of a calendar item, or the
+// todo list's items
+pub fn fetch_request(c: &HAConnect, id: i64) -> Option<Value> {
+    match c {
+        HAConnect::Todo { ha_id, .. } => Some(json!({
+            "id": id,
+            "type": "todo/item/list",
+            "entity_id": ha_id,
+        })),
+        HAConnect::Calendar { ha_id, .. } => Some(json!({
+            "id": id,
+            "type": "call_service",
+            "domain": "calendar",
+            "service": "get_events",
+            "target": { "entity_id": ha_id },
+            "service_data": { "duration": { "days": 7 } },
+            "return_response": true,
+        })),
+        _ => None,
+    }
+}
+
+// the events or items from the reply to a fetch_request, to keep in the
+// state store. Only the first few are kept, with what the widget shows
+pub fn fetched_entity(c: &HAConnect, result: &Value) -> Option<Entity> {
+    if let HAConnect::Todo { .. } = c {
+        return todo_entity(result);
+    }
+    let events = result
+        .get("response")?
+        .get(c.ha_id())?
+        .get("events")?
+        .as_array()?;
+    let kept: Vec<Value> = events
+        .iter()
+        .take(AGENDA_EVENTS)
+        .map(|e| {
+            let summary = e.get("summary").and_then(Value::as_str).unwrap_or("");
+            json!({
+                "start": e.get("start").and_then(Value::as_str).unwrap_or(""),
+                "summary": summary.chars().take(SUMMARY_CHARS).collect::<String>(),
+            })
+        })
+        .collect();
+    let mut attributes = HashMap::new();
+    attributes.insert("events".into(), Value::Array(kept).to_string());
+    Some(Entity {
+        state: events.len().to_string(),
+        attributes,
+    })
+}
+
+// the unchecked items, the list's count is the state
+fn todo_entity(result: &Value) -> Option<Entity> {
+    let items = result.get("items")?.as_array()?;
+    let unchecked: Vec<&Value> = items
+        .iter()
+        .filter(|i| i.get("status").and_then(Value::as_str) == Some("needs_action"))
+        .collect();
+    let kept: Vec<Value> = unchecked
+        .iter()
+        .take(AGENDA_EVENTS)
+        .map(|i| {
+            let summary = i.get("summary").and_then(Value::as_str).unwrap_or("");
+            json!({
+                "uid": i.get("uid").and_then(Value::as_str).unwrap_or(""),
+                "summary": summary.chars().take(SUMMARY_CHARS).collect::<String>(),
+            })
+        })
+        .collect();
+    let mut attributes = HashMap::new();
+    attributes.insert("items".into(), Value::Array(kept).to_string());
+    Some(Entity {
+        state: unchecked.len().to_string(),
+        attributes,
+    })
+}
+
+fn todo_items(entity: &Entity) -> Vec<Value> {
+    entity
+        .attribute("items")
+        .and_then(|i| serde_json::from_str(i).ok())
+        .unwrap_or_default()
+}
+
+// when an event starts, the time for today's events and the day too for
+// later ones. All day events only have a date
+fn event_start(start: &str) -> String {
+    let today = Local::now().date_naive();
+    if let Ok(at) = DateTime::parse_from_rfc3339(start) {
+        let at = at.with_timezone(&Local);
+        let format = if at.date_naive() == today {
+            "%H:%M"
+        } else {
+            "%a %H:%M"
+        };
+        at.format(format).to_string()
+    } else if let Ok(day) = NaiveDate::parse_from_str(start, "%Y-%m-%d") {
+        if day == today {
+            "Today".into()
+        } else {
+            day.format("%a").to_string()
+        }
+    } else {
+        String::new()
+    }
+}
+
+// split text into lines of up to `width` characters at spaces, words
+// longer than a line are cut
+fn word_wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![];
+    let mut current = String::new();
+    for word in text.split_whitespace() {
+        let word: String = word.chars().take(width).collect();
+        if !current.is_empty() && current.chars().count() + 1 + word.chars().count() > width {
+            lines.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(&word);
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+    lines
+}
+
+// the events one after the other until the lines run out
+fn agenda(line: u8, lines: u8, entity: &Entity) -> Vec<WidgetPart> {
+    let events: Vec<Value> = entity
+        .attribute("events")
+        .and_then(|e| serde_json::from_str(e).ok())
+        .unwrap_or_default();
+    let mut wrapped = vec![];
+    for event in &events {
+        let start = event_start(event["start"].as_str().unwrap_or(""));
+        let summary = event["summary"].as_str().unwrap_or("");
+        wrapped.extend(word_wrap(&format!("{} {}", start, summary), LINE_CHARS));
+    }
+    if events.is_empty() {
+        wrapped.push("No events".into());
+    }
+    padded_lines(line, lines, &wrapped)
+}
+
+// the wrapped lines from `line` on, padded so each covers what was
+// there before
+fn padded_lines(line: u8, lines: u8, wrapped: &[String]) -> Vec<WidgetPart> {
+    (0..lines)
+        .map(|i| {
+            let line_text = wrapped.get(i as usize).map_or("", |l| l.as_str());
+            text(line_pos(line + i), format!("{:<1$}", line_text, LINE_CHARS))
+        })
+        .collect()
+}
+
+// the zones that are running, in blue, or else when the next run is.
+// Each button shows its zone's name, or Stop while it runs
+fn irrigation(
+    line: u8,
+    zones: &[(&String, Option<&Entity>)],
+    buttons: &[u8],
+    next: &str,
+    placeholder: &str,
+) -> Vec<WidgetPart> {
+    let name = |id: &String, entity: &Entity| {
+        entity
+            .attribute("friendly_name")
+            .map_or(id.clone(), String::from)
+    };
+    let running: Vec<String> = zones
+        .iter()
+        .filter_map(|(id, entity)| entity.filter(|e| e.state == "on").map(|e| name(id, e)))
+        .collect();
+    // padded so a shorter line covers a longer one
+    let line_text = if running.is_empty() {
+        next.to_string()
+    } else {
+        format!("On {}", running.join(", "))
+    };
+    let line_text: String = format!("{:<1$}", line_text, LINE_CHARS)
+        .chars()
+        .take(LINE_CHARS)
+        .collect();
+    let mut parts = vec![if running.is_empty() {
+        text(line_pos(line), line_text)
+    } else {
+        colored(line_pos(line), line_text, Rgb565::BLUE)
+    }];
+    for ((id, entity), button) in zones.iter().zip(buttons) {
+        let label = match entity {
+            Some(e) if e.state == "on" => "Stop".to_string(),
+            Some(e) if e.is_available() => name(id, e).chars().take(6).collect(),
+            _ => placeholder.to_string(),
+        };
+        parts.push(text(DrawPos::Button(*button), label));
+    }
+    parts
+}
+
+// when an irrigation schedule runs next, from a timestamp sensor or the
+// next_start attribute of a controller
+fn next_start(entity: &Entity) -> Option<String> {
+    [Some(entity.state.as_str()), entity.attribute("next_start")]
+        .into_iter()
+        .flatten()
+        .find_map(|t| DateTime::parse_from_rfc3339(t).ok())
+        .map(|t| t.with_timezone(&Local).format("%a %H:%M").to_string())
+}
+
+// where the state of the entity a button acts on is kept. Each of an
+// Irrigation item's buttons has a zone of its own
+pub fn button_key(c: &HAConnect, button: usize) -> String {
+    match c {
+        HAConnect::Irrigation { zones, buttons, .. } => buttons
+            .iter()
+            .position(|b| *b as usize == button)
+            .and_then(|i| zones.get(i))
+            .map_or(c.state_key(), |zone| state_key(c.instance(), zone)),
+        _ => c.state_key(),
+    }
+}
+
+// whether HA can't reach the entity a button acts on. A todo list's
+// items are fetched, so it's the list entity that says
+pub fn button_unavailable(c: &HAConnect, states: &States, button: usize) -> bool {
+    let key = match c {
+        HAConnect::Todo { ha_id, .. } => state_key(c.instance(), ha_id),
+        _ => button_key(c, button),
+    };
+    states.get(&key).map_or(false, |e| !e.is_available())
+}
+
+// the names of a Sensors item's sensors that are on (or open, for
+// covers and groups of them)
+pub fn sensors_on(c: &HAConnect, states: &States) -> Vec<String> {
+    let sensors = match c {
+        HAConnect::Sensors { sensors, .. } => sensors,
+        _ => return vec![],
+    };
+    sensors
+        .iter()
+        .filter_map(|id| {
+            let entity = states.get(&state_key(c.instance(), id))?;
+            matches!(entity.state.as_str(), "on" | "open").then(|| {
+                entity
+                    .attribute("friendly_name")
+                    .map_or(id.clone(), String::from)
+            })
+        })
+        .collect()
+}
+
+// a press (long or short) on a Sensors item lists the ones that are on
+pub fn opens_list(c: &HAConnect, event: ButtonEvent) -> bool {
+    matches!(c, HAConnect::Sensors { .. })
+        && matches!(event, ButtonEvent::Press(_) | ButtonEvent::LongPress(_))
+}
+
+// the backlight a Sun item wants while the sun is down, None in the day
+pub fn night_backlight(items: &[HAConnect], states: &States) -> Option<u8> {
+    items.iter().find_map(|c| match c {
+        HAConnect::Sun {
+            night_backlight: Some(percent),
+            ..
+        } if sun_down(c, states) => Some(*percent),
+        _ => None,
+    })
+}
+
+// the night theme while the sun is down, for a Sun item with `dark`
+pub fn theme(items: &[HAConnect], states: &States) -> Theme {
+    let night = items
+        .iter()
+        .any(|c| matches!(c, HAConnect::Sun { dark: true, .. }) && sun_down(c, states));
+    if night {
+        Theme::Night
+    } else {
+        Theme::Day
+    }
+}
+
+fn sun_down(c: &HAConnect, states: &States) -> bool {
+    states
+        .get(&c.state_key())
+        .map_or(false, |e| e.state == "below_horizon")
+}
+
+// a long press on a light opens the adjust page
+pub fn opens_adjust(c: &HAConnect, event: ButtonEvent) -> bool {
+    matches!(c, HAConnect::Light { .. }) && matches!(event, ButtonEvent::LongPress(_))
+}
+
+// the alarm service a button press calls if it needs a code first
+pub fn code_service(
+    c: &HAConnect,
+    entity: Option<&Entity>,
+    event: ButtonEvent,
+) -> Option<&'static str> {
+    let entity = entity?;
+    if !matches!(c, HAConnect::Alarm { .. }) || !matches!(event, ButtonEvent::Press(_)) {
+        return None;
+    }
+    // code_format is null for panels without a code
+    let has_code = entity
+        .attribute("code_format")
+        .map_or(false, |f| !f.is_empty());
+    match alarm_service(entity) {
+        "alarm_arm_away" if has_code && entity.attribute("code_arm_required") != Some("false") => {
+            Some("alarm_arm_away")
+        }
+        "alarm_disarm" if has_code => Some("alarm_disarm"),
+        _ => None,
+    }
+}
+
+fn alarm_service(entity: &Entity) -> &'static str {
+    if entity.state == "disarmed" {
+        "alarm_arm_away"
+    } else {
+        "alarm_disarm"
+    }
+}
+
+// the actions for a button event on one of a widget's buttons. The
+// entity is updated right away so presses in a row add up before HA
+// sends the new state
+pub fn on_button(
+    c: &HAConnect,
+    entity: Option<&mut Entity>,
+    event: ButtonEvent,
+    widget_state: &mut WidgetState,
+) -> Vec<HAAction> {
+    let entity = match entity {
+        Some(e) => e,
+        None => return vec![],
+    };
+    match c {
+        HAConnect::Climate {
+            ha_id,
+            down,
+            up,
+            step,
+            ..
+        } => setpoint_button(&CLIMATE, ha_id, *down, *up, *step, entity, event),
+        HAConnect::Humidifier {
+            ha_id,
+            down,
+            up,
+            step,
+            ..
+        } => setpoint_button(&HUMIDIFIER, ha_id, *down, *up, *step, entity, event),
+        HAConnect::WaterHeater {
+            ha_id,
+            away: Some(away),
+            ..
+        } if event == ButtonEvent::Press(*away as usize) => {
+            let away_mode = entity.attribute("away_mode") != Some("on");
+            entity.attributes.insert(
+                "away_mode".into(),
+                if away_mode { "on" } else { "off" }.into(),
+            );
+            vec![HAAction::service(
+                "water_heater",
+                "set_away_mode",
+                ha_id,
+                json!({ "away_mode": away_mode }),
+            )]
+        }
+        HAConnect::WaterHeater {
+            ha_id,
+            down,
+            up,
+            step,
+            ..
+        } => setpoint_button(&WATER_HEATER, ha_id, *down, *up, *step, entity, event),
+        HAConnect::Cover {
+            ha_id,
+            open,
+            close,
+            preset,
+            ..
+        } => {
+            let cover = |service: &str, data| HAAction::service("cover", service, ha_id, data);
+            match event {
+                ButtonEvent::Press(b) if b == *open as usize => {
+                    vec![cover("open_cover", json!({}))]
+                }
+                ButtonEvent::Press(b) if b == *close as usize => {
+                    vec![cover("close_cover", json!({}))]
+                }
+                ButtonEvent::Press(_) => vec![cover("stop_cover", json!({}))],
+                ButtonEvent::LongPress(_) => {
+                    entity
+                        .attributes
+                        .insert("current_position".into(), preset.to_string());
+                    vec![cover("set_cover_position", json!({ "position": preset }))]
+                }
+                ButtonEvent::Hold(_) => vec![],
+            }
+        }
+        HAConnect::Alarm { ha_id, .. } => match event {
+            ButtonEvent::Press(_) => vec![HAAction::service(
+                "alarm_control_panel",
+                alarm_service(entity),
+                ha_id,
+                json!({}),
+            )],
+            _ => vec![],
+        },
+        HAConnect::Lock { ha_id, .. } => match event {
+            // unlocking takes a second press, so a bump doesn't open the door
+            ButtonEvent::Press(_) if entity.state == "locked" => {
+                if !widget_state.confirm(c.state_key()) {
+                    return vec![];
+                }
+                entity.state = "unlocking".into();
+                vec![HAAction::service("lock", "unlock", ha_id, json!({}))]
+            }
+            ButtonEvent::Press(_) => {
+                entity.state = "locking".into();
+                vec![HAAction::service("lock", "lock", ha_id, json!({}))]
+            }
+            _ => vec![],
+        },
+        HAConnect::Timer {
+            ha_id, duration, ..
+        } => {
+            let timer = |service: &str, data| HAAction::service("timer", service, ha_id, data);
+            match event {
+                ButtonEvent::Press(_) if entity.state == "active" => {
+                    entity.state = "idle".into();
+                    vec![timer("cancel", json!({}))]
+                }
+                // a paused timer carries on where it was
+                ButtonEvent::Press(_) => {
+                    let data = match (entity.state.as_str(), duration) {
+                        ("idle", Some(d)) => json!({ "duration": d }),
+                        _ => json!({}),
+                    };
+                    vec![timer("start", data)]
+                }
+                ButtonEvent::LongPress(_) if entity.state == "active" => {
+                    vec![timer("pause", json!({}))]
+                }
+                _ => vec![],
+            }
+        }
+        HAConnect::Vacuum {
+            ha_id, start, dock, ..
+        } => {
+            let vacuum = |service: &str| HAAction::service("vacuum", service, ha_id, json!({}));
+            match event {
+                ButtonEvent::Press(b) if b == *start as usize && entity.state == "cleaning" => {
+                    entity.state = "paused".into();
+                    vec![vacuum("pause")]
+                }
+                ButtonEvent::Press(b) if b == *start as usize => {
+                    entity.state = "cleaning".into();
+                    vec![vacuum("start")]
+                }
+                ButtonEvent::Press(b) if b == *dock as usize => {
+                    entity.state = "returning".into();
+                    vec![vacuum("return_to_base")]
+                }
+                ButtonEvent::Press(_) => vec![vacuum("locate")],
+                _ => vec![],
+            }
+        }
+        HAConnect::Select { ha_id, .. } => {
+            let (service, step) = match event {
+                ButtonEvent::Press(_) => ("select_next", 1),
+                ButtonEvent::LongPress(_) => ("select_previous", -1),
+                ButtonEvent::Hold(_) => return vec![],
+            };
+            // the options wrap around, as HA's do
+            let options: Vec<String> = entity
+                .attribute("options")
+                .and_then(|o| serde_json::from_str(o).ok())
+                .unwrap_or_default();
+            if let Some(i) = options.iter().position(|o| *o == entity.state) {
+                let next = (i as i64 + step).rem_euclid(options.len() as i64) as usize;
+                entity.state = options[next].clone();
+            }
+            vec![HAAction::service(
+                "input_select",
+                service,
+                ha_id,
+                json!({ "cycle": true }),
+            )]
+        }
+        HAConnect::Number { ha_id, down, .. } => {
+            let (b, steps) = match event {
+                ButtonEvent::Press(b) => (b, 1.0),
+                ButtonEvent::LongPress(b) => (b, 10.0),
+                ButtonEvent::Hold(_) => return vec![],
+            };
+            let direction = if b == *down as usize { -1.0 } else { 1.0 };
+            number_step(ha_id, entity, direction * steps)
+        }
+        HAConnect::Fan { ha_id, .. } => {
+            let fan = |service: &str, data| HAAction::service("fan", service, ha_id, data);
+            match event {
+                // up a speed, from the top back round to the lowest
+                ButtonEvent::Press(_) => {
+                    let step = number(entity, "percentage_step")
+                        .filter(|s| *s > 0.0)
+                        .unwrap_or(25.0);
+                    let next = fan_percent(entity) as f64 + step;
+                    let next = if next > 100.5 { step } else { next.min(100.0) };
+                    let next = next.round() as u8;
+                    entity.state = "on".into();
+                    entity
+                        .attributes
+                        .insert("percentage".into(), next.to_string());
+                    entity.attributes.remove("preset_mode");
+                    vec![fan("set_percentage", json!({ "percentage": next }))]
+                }
+                ButtonEvent::LongPress(_) if entity.state == "on" => {
+                    entity.state = "off".into();
+                    vec![fan("turn_off", json!({}))]
+                }
+                ButtonEvent::LongPress(_) => {
+                    entity.state = "on".into();
+                    vec![fan("turn_on", json!({}))]
+                }
+                ButtonEvent::Hold(_) => vec![],
+            }
+        }
+        HAConnect::Todo { ha_id, .. } => {
+            let mut items = todo_items(entity);
+            if !matches!(event, ButtonEvent::Press(_)) || items.is_empty() {
+                return vec![];
+            }
+            let first = items.remove(0);
+            let uid = first["uid"].as_str().unwrap_or("").to_string();
+            let left = entity.state.parse::<usize>().unwrap_or(1).saturating_sub(1);
+            entity.state = left.to_string();
+            entity
+                .attributes
+                .insert("items".into(), Value::Array(items).to_string());
+            vec![HAAction::service(
+                "todo",
+                "update_item",
+                ha_id,
+                json!({ "item": uid, "status": "completed" }),
+            )]
+        }
+        HAConnect::Irrigation { zones, buttons, .. } => match event {
+            // a zone runs until it's stopped, or HA's own run time is up
+            ButtonEvent::Press(b) => {
+                let zone = match buttons
+                    .iter()
+                    .position(|p| *p as usize == b)
+                    .and_then(|i| zones.get(i))
+                {
+                    Some(zone) => zone,
+                    None => return vec![],
+                };
+                let domain = zone.split('.').next().unwrap_or("switch");
+                let service = if entity.state == "on" {
+                    "turn_off"
+                } else {
+                    "turn_on"
+                };
+                entity.state = if entity.state == "on" { "off" } else { "on" }.into();
+                vec![HAAction::service(domain, service, zone, json!({}))]
+            }
+            _ => vec![],
+        },
+        HAConnect::Light { ha_id, .. } => match event {
+            ButtonEvent::Press(_) => {
+                entity.state = if entity.state == "on" { "off" } else { "on" }.into();
+                vec![HAAction::service("light", "toggle", ha_id, json!({}))]
+            }
+            _ => vec![],
+        },
+        _ => vec![],
+    }
+}
+
+fn number(entity: &Entity, name: &str) -> Option<f64> {
+    entity.attribute(name)?.parse().ok()
+}
+
+// a fan's speed, 0 when it's off
+fn fan_percent(entity: &Entity) -> u8 {
+    if entity.state != "on" {
+        return 0;
+    }
+    number(entity, "percentage").map_or(100, |p| p.round().clamp(0.0, 100.0) as u8)
+}
+
+// step an input_number by a number of its steps, staying within its
+// min and max
+fn number_step(ha_id: &str, entity: &mut Entity, steps: f64) -> Vec<HAAction> {
+    let value: f64 = match entity.state.parse() {
+        Ok(v) => v,
+        Err(_) => return vec![],
+    };
+    let step = number(entity, "step").filter(|s| *s > 0.0).unwrap_or(1.0);
+    let mut new = ((value + steps * step) / step).round() * step;
+    if let Some(min) = number(entity, "min") {
+        new = new.max(min);
+    }
+    if let Some(max) = number(entity, "max") {
+        new = new.min(max);
+    }
+    entity.state = new.to_string();
+    vec![HAAction::service(
+        "input_number",
+        "set_value",
+        ha_id,
+        json!({ "value": new }),
+    )]
+}
+
+// the setpoint widgets, a thermostat and a humidifier, work the same way
+// with different attributes and services
+struct Setpoint {
+    domain: &'static str,
+    // the attribute with the setpoint, also the key in the service data
+    target: &'static str,
+    current: &'static str,
+    unit: &'static str,
+    // the entity's step attribute, if it has one
+    step: Option<&'static str>,
+    default_step: f64,
+    min: &'static str,
+    max: &'static str,
+    set_service: &'static str,
+    // the modes to cycle through, and the mode attribute (the state if None)
+    modes: &'static str,
+    mode: Option<&'static str>,
+    mode_service: &'static str,
+    mode_key: &'static str,
+}
+
+const CLIMATE: Setpoint = Setpoint {
+    domain: "climate",
+    target: "temperature",
+    current: "current_temperature",
+    unit: "",
+    step: Some("target_temp_step"),
+    default_step: 0.5,
+    min: "min_temp",
+    max: "max_temp",
+    set_service: "set_temperature",
+    modes: "hvac_modes",
+    mode: None,
+    mode_service: "set_hvac_mode",
+    mode_key: "hvac_mode",
+};
+
+const HUMIDIFIER: Setpoint = Setpoint {
+    domain: "humidifier",
+    target: "humidity",
+    current: "current_humidity",
+    unit: "%",
+    step: None,
+    default_step: 1.0,
+    min: "min_humidity",
+    max: "max_humidity",
+    set_service: "set_humidity",
+    modes: "available_modes",
+    mode: Some("mode"),
+    mode_service: "set_mode",
+    mode_key: "mode",
+};
+
+// the state is the current operation mode
+const WATER_HEATER: Setpoint = Setpoint {
+    domain: "water_heater",
+    target: "temperature",
+    current: "current_temperature",
+    unit: "",
+    step: Some("target_temp_step"),
+    default_step: 0.5,
+    min: "min_temp",
+    max: "max_temp",
+    set_service: "set_temperature",
+    modes: "operation_list",
+    mode: None,
+    mode_service: "set_operation_mode",
+    mode_key: "operation_mode",
+};
+
+// `<current> > <setpoint> <mode>` with - and + buttons
+fn setpoint_view(
+    sp: &Setpoint,
+    line: u8,
+    down: u8,
+    up: u8,
+    entity: &Entity,
+    placeholder: &str,
+) -> Vec<WidgetPart> {
+    let line_text = if !entity.is_available() {
+        placeholder.to_string()
+    } else {
+        let current = entity
+            .attribute(sp.current)
+            .map_or(placeholder.to_string(), |c| format!("{}{}", c, sp.unit));
+        let mode = sp
+            .mode
+            .and_then(|m| entity.attribute(m))
+            .unwrap_or(&entity.state);
+        match entity.attribute(sp.target) {
+            Some(target) => format!("{} > {}{} {}", current, target, sp.unit, mode),
+            None => format!("{} {}", current, mode),
+        }
+    };
+    vec![
+        text(line_pos(line), format!("{:<24}", line_text)),
+        text(DrawPos::Button(down), "-"),
+        text(DrawPos::Button(up), "+"),
+    ]
+}
+
+// the buttons move the setpoint, a long press on either goes to the
+// next mode
+fn setpoint_button(
+    sp: &Setpoint,
+    ha_id: &str,
+    down: u8,
+    up: u8,
+    step: Option<f64>,
+    entity: &mut Entity,
+    event: ButtonEvent,
+) -> Vec<HAAction> {
+    match event {
+        ButtonEvent::Press(b) => {
+            let direction = if b == up as usize {
+                1.0
+            } else if b == down as usize {
+                -1.0
+            } else {
+                return vec![];
+            };
+            move_setpoint(sp, ha_id, entity, step, direction)
+        }
+        ButtonEvent::LongPress(_) => next_mode(sp, ha_id, entity),
+        ButtonEvent::Hold(_) => vec![],
+    }
+}
+
+fn move_setpoint(
+    sp: &Setpoint,
+    ha_id: &str,
+    entity: &mut Entity,
+    step: Option<f64>,
+    direction: f64,
+) -> Vec<HAAction> {
+    // no setpoint when it's off
+    let target = match number(entity, sp.target) {
+        Some(t) => t,
+        None => return vec![],
+    };
+    let step = step
+        .or_else(|| sp.step.and_then(|s| number(entity, s)))
+        .unwrap_or(sp.default_step);
+    let mut new = ((target + direction * step) / step).round() * step;
+    if let Some(min) = number(entity, sp.min) {
+        new = new.max(min);
+    }
+    if let Some(max) = number(entity, sp.max) {
+        new = new.min(max);
+    }
+    entity.attributes.insert(sp.target.into(), new.to_string());
+    vec![HAAction::service(
+        sp.domain,
+        sp.set_service,
+        ha_id,
+        json!({ sp.target: new }),
+    )]
+}
+
+fn next_mode(sp: &Setpoint, ha_id: &str, entity: &mut Entity) -> Vec<HAAction> {
+    let modes: Vec<String> = entity
+        .attribute(sp.modes)
+        .and_then(|m| serde_json::from_str(m).ok())
+        .unwrap_or_default();
+    if modes.is_empty() {
+        return vec![];
+    }
+    let current = sp
+        .mode
+        .and_then(|m| entity.attribute(m))
+        .unwrap_or(&entity.state)
+        .to_string();
+    let next = match modes.iter().position(|m| *m == current) {
+        Some(i) => modes[(i + 1) % modes.len()].clone(),
+        None => modes[0].clone(),
+    };
+    match sp.mode {
+        Some(m) => {
+            entity.attributes.insert(m.into(), next.clone());
+        }
+        None => entity.state = next.clone(),
+    }
+    vec![HAAction::service(
+        sp.domain,
+        sp.mode_service,
+        ha_id,
+        json!({ sp.mode_key: next }),
+    )]
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        home::{on_home_button, HomePress},
+        states::Optimistic,
+    };
+
+    fn item(config: Value) -> HAConnect {
+        serde_json::from_value(config).unwrap()
+    }
+
+    fn entity(state: &str, attributes: &[(&str, &str)]) -> Entity {
+        Entity {
+            state: state.into(),
+            attributes: attributes
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    fn unavailable() -> Entity {
+        entity("unavailable", &[])
+    }
+
+    fn states_of(entities: &[(&str, Entity)]) -> States {
+        entities
+            .iter()
+            .map(|(key, entity)| (key.to_string(), entity.clone()))
+            .collect()
+    }
+
+    // the text of the parts, without the padding
+    fn texts(c: &HAConnect, states: &States, widget_state: &WidgetState) -> Vec<String> {
+        view(c, states, "--", widget_state)
+            .iter()
+            .filter_map(|part| match part {
+                WidgetPart::Text(_, text, _) => Some(text.trim_end().to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn bars(c: &HAConnect, states: &States) -> Vec<u8> {
+        view(c, states, "--", &WidgetState::new())
+            .iter()
+            .filter_map(|part| match part {
+                WidgetPart::Bar(_, percent) => Some(*percent),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn press(
+        c: &HAConnect,
+        event: ButtonEvent,
+        states: &mut States,
+        widget_state: &mut WidgetState,
+    ) -> HomePress {
+        on_home_button(c, event, states, &mut Optimistic::new(), 5, widget_state)
+    }
+
+    fn sends(domain: &str, service: &str, ha_id: &str, data: Value) -> HomePress {
+        HomePress::Send(vec![HAAction::service(domain, service, ha_id, data)])
+    }
+
+    fn cover() -> HAConnect {
+        item(
+            json!({"Cover": {"line": 1, "ha_id": "cover.garage", "open": 0, "close": 1,
+            "stop": 2, "color": 0}}),
+        )
+    }
+
+    #[test]
+    fn cover_opens_closes_and_presets() {
+        let c = cover();
+        let mut states = states_of(&[(
+            "cover.garage",
+            entity("open", &[("current_position", "40")]),
+        )]);
+        assert_eq!(
+            texts(&c, &states, &WidgetState::new()),
+            vec!["Open", "Close", "Stop", "open"]
+        );
+        assert_eq!(bars(&c, &states), vec![40]);
+
+        let mut widget_state = WidgetState::new();
+        let mut press = |event| press(&c, event, &mut states, &mut widget_state);
+        assert_eq!(
+            press(ButtonEvent::Press(0)),
+            sends("cover", "open_cover", "cover.garage", json!({}))
+        );
+        assert_eq!(
+            press(ButtonEvent::Press(1)),
+            sends("cover", "close_cover", "cover.garage", json!({}))
+        );
+        assert_eq!(
+            press(ButtonEvent::Press(2)),
+            sends("cover", "stop_cover", "cover.garage", json!({}))
+        );
+        assert_eq!(
+            press(ButtonEvent::LongPress(1)),
+            sends(
+                "cover",
+                "set_cover_position",
+                "cover.garage",
+                json!({"position": 50})
+            )
+        );
+        assert_eq!(bars(&c, &states), vec![50]);
+    }
+
+    #[test]
+    fn unavailable_cover() {
+        let c = cover();
+        let mut states = states_of(&[("cover.garage", unavailable())]);
+        assert_eq!(
+            texts(&c, &states, &WidgetState::new()),
+            vec!["Open", "Close", "Stop", "--"]
+        );
+        assert!(bars(&c, &states).is_empty());
+        let press = press(
+            &c,
+            ButtonEvent::Press(0),
+            &mut states,
+            &mut WidgetState::new(),
+        );
+        assert_eq!(press, HomePress::Unavailable);
+    }
+
+    fn lock() -> HAConnect {
+        item(
+            json!({"Lock": {"line": 1, "ha_id": "lock.front", "button": 0, "text": "Front",
+            "color": 0}}),
+        )
+    }
+
+    #[test]
+    fn unlocking_takes_two_presses() {
+        let c = lock();
+        let mut states = states_of(&[("lock.front", entity("locked", &[]))]);
+        let mut widget_state = WidgetState::new();
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["Front locked", "Unlock"]
+        );
+
+        let first = press(&c, ButtonEvent::Press(0), &mut states, &mut widget_state);
+        assert_eq!(first, HomePress::Send(vec![]));
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["Front locked", "Sure?"]
+        );
+        let second = press(&c, ButtonEvent::Press(0), &mut states, &mut widget_state);
+        assert_eq!(second, sends("lock", "unlock", "lock.front", json!({})));
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["Front unlocking", "Lock"]
+        );
+    }
+
+    #[test]
+    fn locking_takes_one_press() {
+        let c = lock();
+        let mut states = states_of(&[("lock.front", entity("unlocked", &[]))]);
+        let mut widget_state = WidgetState::new();
+        let press = press(&c, ButtonEvent::Press(0), &mut states, &mut widget_state);
+        assert_eq!(press, sends("lock", "lock", "lock.front", json!({})));
+        assert_eq!(states["lock.front"].state, "locking");
+    }
+
+    #[test]
+    fn unavailable_lock() {
+        let c = lock();
+        let mut states = states_of(&[("lock.front", unavailable())]);
+        let mut widget_state = WidgetState::new();
+        assert_eq!(texts(&c, &states, &widget_state), vec!["Front --", "--"]);
+        let press = press(&c, ButtonEvent::Press(0), &mut states, &mut widget_state);
+        assert_eq!(press, HomePress::Unavailable);
+    }
+
+    fn alarm() -> HAConnect {
+        item(
+            json!({"Alarm": {"line": 2, "ha_id": "alarm_control_panel.home", "button": 1,
+            "text": "Alarm", "color": 0}}),
+        )
+    }
+
+    #[test]
+    fn the_alarm_arms_and_disarms() {
+        let c = alarm();
+        let mut widget_state = WidgetState::new();
+        let mut states = states_of(&[("alarm_control_panel.home", entity("disarmed", &[]))]);
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["Alarm disarmed", "Arm"]
+        );
+        assert_eq!(
+            press(&c, ButtonEvent::Press(1), &mut states, &mut widget_state),
+            sends(
+                "alarm_control_panel",
+                "alarm_arm_away",
+                "alarm_control_panel.home",
+                json!({})
+            )
+        );
+
+        let mut states = states_of(&[("alarm_control_panel.home", entity("armed_away", &[]))]);
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["Alarm armed away", "Disarm"]
+        );
+        assert_eq!(
+            press(&c, ButtonEvent::Press(1), &mut states, &mut widget_state),
+            sends(
+                "alarm_control_panel",
+                "alarm_disarm",
+                "alarm_control_panel.home",
+                json!({})
+            )
+        );
+    }
+
+    #[test]
+    fn the_alarm_asks_for_its_code() {
+        let c = alarm();
+        let mut states = states_of(&[(
+            "alarm_control_panel.home",
+            entity("armed_away", &[("code_format", "number")]),
+        )]);
+        let press = press(
+            &c,
+            ButtonEvent::Press(1),
+            &mut states,
+            &mut WidgetState::new(),
+        );
+        assert_eq!(press, HomePress::Code("alarm_disarm"));
+    }
+
+    #[test]
+    fn unavailable_alarm() {
+        let c = alarm();
+        let mut states = states_of(&[("alarm_control_panel.home", unavailable())]);
+        let mut widget_state = WidgetState::new();
+        assert_eq!(texts(&c, &states, &widget_state), vec!["Alarm --", "--"]);
+        let press = press(&c, ButtonEvent::Press(1), &mut states, &mut widget_state);
+        assert_eq!(press, HomePress::Unavailable);
+    }
+
+    fn climate() -> HAConnect {
+        item(
+            json!({"Climate": {"line": 3, "ha_id": "climate.hall", "down": 0, "up": 1,
+            "color": 0}}),
+        )
+    }
+
+    #[test]
+    fn climate_moves_the_setpoint_and_mode() {
+        let c = climate();
+        let mut states = states_of(&[(
+            "climate.hall",
+            entity(
+                "heat",
+                &[
+                    ("temperature", "20"),
+                    ("current_temperature", "19.5"),
+                    ("max_temp", "20.5"),
+                    ("hvac_modes", r#"["off", "heat"]"#),
+                ],
+            ),
+        )]);
+        let mut widget_state = WidgetState::new();
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["19.5 > 20 heat", "-", "+"]
+        );
+        let set = |t: f64| {
+            sends(
+                "climate",
+                "set_temperature",
+                "climate.hall",
+                json!({"temperature": t}),
+            )
+        };
+        assert_eq!(
+            press(&c, ButtonEvent::Press(1), &mut states, &mut widget_state),
+            set(20.5)
+        );
+        // no higher than max_temp
+        assert_eq!(
+            press(&c, ButtonEvent::Press(1), &mut states, &mut widget_state),
+            set(20.5)
+        );
+        assert_eq!(
+            press(&c, ButtonEvent::Press(0), &mut states, &mut widget_state),
+            set(20.0)
+        );
+        assert_eq!(
+            press(
+                &c,
+                ButtonEvent::LongPress(0),
+                &mut states,
+                &mut widget_state
+            ),
+            sends(
+                "climate",
+                "set_hvac_mode",
+                "climate.hall",
+                json!({"hvac_mode": "off"})
+            )
+        );
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["19.5 > 20 off", "-", "+"]
+        );
+    }
+
+    #[test]
+    fn unavailable_climate() {
+        let c = climate();
+        let mut states = states_of(&[("climate.hall", unavailable())]);
+        let mut widget_state = WidgetState::new();
+        assert_eq!(texts(&c, &states, &widget_state), vec!["--", "-", "+"]);
+        let press = press(&c, ButtonEvent::Press(1), &mut states, &mut widget_state);
+        assert_eq!(press, HomePress::Unavailable);
+    }
+
+    #[test]
+    fn humidifier_moves_the_setpoint_and_mode() {
+        let c = item(
+            json!({"Humidifier": {"line": 3, "ha_id": "humidifier.bedroom",
+            "down": 0, "up": 1, "color": 0}}),
+        );
+        let mut states = states_of(&[(
+            "humidifier.bedroom",
+            entity(
+                "on",
+                &[
+                    ("humidity", "45"),
+                    ("current_humidity", "50"),
+                    ("mode", "normal"),
+                    ("available_modes", r#"["normal", "eco"]"#),
+                ],
+            ),
+        )]);
+        let mut widget_state = WidgetState::new();
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["50% > 45% normal", "-", "+"]
+        );
+        assert_eq!(
+            press(&c, ButtonEvent::Press(0), &mut states, &mut widget_state),
+            sends(
+                "humidifier",
+                "set_humidity",
+                "humidifier.bedroom",
+                json!({"humidity": 44.0})
+            )
+        );
+        assert_eq!(
+            press(
+                &c,
+                ButtonEvent::LongPress(1),
+                &mut states,
+                &mut widget_state
+            ),
+            sends(
+                "humidifier",
+                "set_mode",
+                "humidifier.bedroom",
+                json!({"mode": "eco"})
+            )
+        );
+
+        let mut states = states_of(&[("humidifier.bedroom", unavailable())]);
+        assert_eq!(texts(&c, &states, &widget_state), vec!["--", "-", "+"]);
+        assert_eq!(
+            press(&c, ButtonEvent::Press(0), &mut states, &mut widget_state),
+            HomePress::Unavailable
+        );
+    }
+
+    #[test]
+    fn water_heater_setpoint_and_away_mode() {
+        let c = item(
+            json!({"WaterHeater": {"line": 3, "ha_id": "water_heater.tank",
+            "down": 0, "up": 1, "away": 2, "color": 0}}),
+        );
+        let mut states = states_of(&[(
+            "water_heater.tank",
+            entity(
+                "eco",
+                &[("temperature", "50"), ("current_temperature", "45")],
+            ),
+        )]);
+        let mut widget_state = WidgetState::new();
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["45 > 50 eco", "-", "+", "Away"]
+        );
+        assert_eq!(
+            press(&c, ButtonEvent::Press(0), &mut states, &mut widget_state),
+            sends(
+                "water_heater",
+                "set_temperature",
+                "water_heater.tank",
+                json!({"temperature": 49.5})
+            )
+        );
+        assert_eq!(
+            press(&c, ButtonEvent::Press(2), &mut states, &mut widget_state),
+            sends(
+                "water_heater",
+                "set_away_mode",
+                "water_heater.tank",
+                json!({"away_mode": true})
+            )
+        );
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["45 > 49.5 eco", "-", "+", "Home"]
+        );
+
+        let mut states = states_of(&[("water_heater.tank", unavailable())]);
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["--", "-", "+", "Away"]
+        );
+        assert_eq!(
+            press(&c, ButtonEvent::Press(2), &mut states, &mut widget_state),
+            HomePress::Unavailable
+        );
+    }
+
+    fn todo() -> HAConnect {
+        item(
+            json!({"Todo": {"line": 4, "ha_id": "todo.shopping", "lines": 2, "button": 3,
+            "color": 0}}),
+        )
+    }
+
+    fn shopping(list: Entity) -> States {
+        let items = r#"[{"uid": "a", "summary": "Milk"}, {"uid": "b", "summary": "Eggs"}]"#;
+        states_of(&[
+            ("todo.shopping", list),
+            ("todo.shopping#items", entity("2", &[("items", items)])),
+        ])
+    }
+
+    #[test]
+    fn todo_checks_off_the_first_item() {
+        let c = todo();
+        let mut states = shopping(entity("2", &[]));
+        let mut widget_state = WidgetState::new();
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["- Milk", "- Eggs", "Done"]
+        );
+        assert_eq!(
+            press(&c, ButtonEvent::Press(3), &mut states, &mut widget_state),
+            sends(
+                "todo",
+                "update_item",
+                "todo.shopping",
+                json!({"item": "a", "status": "completed"})
+            )
+        );
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["- Eggs", "", "Done"]
+        );
+        assert_eq!(states["todo.shopping#items"].state, "1");
+    }
+
+    #[test]
+    fn unavailable_todo() {
+        let c = todo();
+        let mut states = shopping(unavailable());
+        let mut widget_state = WidgetState::new();
+        assert_eq!(texts(&c, &states, &widget_state), vec!["--", "", "--"]);
+        let press = press(&c, ButtonEvent::Press(3), &mut states, &mut widget_state);
+        assert_eq!(press, HomePress::Unavailable);
+        assert_eq!(states["todo.shopping#items"].state, "2");
+    }
+
+    fn irrigation() -> HAConnect {
+        item(
+            json!({"Irrigation": {"line": 5, "zones": ["switch.front", "switch.back"],
+            "buttons": [0, 1], "color": 0}}),
+        )
+    }
+
+    #[test]
+    fn irrigation_runs_and_stops_zones() {
+        let c = irrigation();
+        let mut states = states_of(&[
+            (
+                "switch.front",
+                entity("off", &[("friendly_name", "Front lawn")]),
+            ),
+            ("switch.back", entity("off", &[("friendly_name", "Back")])),
+        ]);
+        let mut widget_state = WidgetState::new();
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["Idle", "Front", "Back"]
+        );
+        assert_eq!(
+            press(&c, ButtonEvent::Press(0), &mut states, &mut widget_state),
+            sends("switch", "turn_on", "switch.front", json!({}))
+        );
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["On Front lawn", "Stop", "Back"]
+        );
+        assert_eq!(
+            press(&c, ButtonEvent::Press(0), &mut states, &mut widget_state),
+            sends("switch", "turn_off", "switch.front", json!({}))
+        );
+    }
+
+    #[test]
+    fn unavailable_irrigation_zone() {
+        let c = irrigation();
+        let mut states = states_of(&[
+            ("switch.front", unavailable()),
+            ("switch.back", entity("off", &[("friendly_name", "Back")])),
+        ]);
+        let mut widget_state = WidgetState::new();
+        assert_eq!(
+            texts(&c, &states, &widget_state),
+            vec!["Idle", "--", "Back"]
+        );
+        assert_eq!(
+            press(&c, ButtonEvent::Press(0), &mut states, &mut widget_state),
+            HomePress::Unavailable
+        );
+        // the other zone still runs
+        assert_eq!(
+            press(&c, ButtonEvent::Press(1), &mut states, &mut widget_state),
+            sends("switch", "turn_on", "switch.back", json!({}))
+        );
+    }
+}
@@ -1,125 +1,249 @@
-use anyhow::{bail, Result};
-use crossbeam::channel::Sender as XBSender;
+use crossbeam::channel::{Sender as XBSender, TrySendError};
 
-use embedded_graphics::{
-    prelude::{Point, RgbColor, Size},
-    primitives::Rectangle,
-};
 use embedded_svc::{
-    wifi::{ClientConfiguration, Configuration},
+    wifi::{AuthMethod, ClientConfiguration, Configuration},
     ws::FrameType,
 };
-use esp_idf_hal::{modem::Modem, peripheral, io::EspIOError};
+use esp_idf_hal::{io::EspIOError, peripheral};
 use esp_idf_svc::{
-    eventloop::{EspEventLoop, EspSystemEventLoop, System},
-    sntp::{self, SyncStatus},
-    wifi::{BlockingWifi, EspWifi},
+    eventloop::EspSystemEventLoop,
+    netif::{EspNetif, NetifConfiguration, NetifStack},
+    wifi::{BlockingWifi, EspWifi, WifiDriver},
     ws::client::{
         EspWebSocketClient, EspWebSocketClientConfig, WebSocketEvent, WebSocketEventType,
     },
 };
-use json::{object, JsonValue};
 use log::*;
-use profont::PROFONT_24_POINT;
+use serde_json::{json, Value};
 use std::{
+    collections::VecDeque,
     sync::{
-        atomic::{AtomicBool, AtomicI32, Ordering},
-        mpsc::{Receiver, Sender},
-        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
     },
-    time::Duration,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    buttons::ButtonEvent,
+    config::{EnterpriseConfig, HaConfig, NetConfig, PowerSave, TtlsPhase2},
+    files::read_file,
+    ha::{EntityState, FrameAssembler, FromHa, HaMessage},
+    network::client_netif,
+    pending::RESULT_TIMEOUT,
+    proxy::tunnelled_host,
+    rest::ha_get,
+    settings::Settings,
+    status::{ApInfo, Status},
+    util::next_message_id,
+    watchdog::TaskWatch,
+    Error, Result,
+};
+
+// the NVS key of an access token that replaces the built in one
+pub const HA_TOKEN_KEY: &str = "ha_token";
+
+// optional, the device can be linked to HA with OAuth instead
+pub const HA_AUTH: &str = match option_env!("HOMER_HA_AUTH") {
+    Some(token) => token,
+    None => "",
 };
 
-use crate::display::{DrawCmd, DrawPos};
+// the long-lived access token, empty without one
+pub fn ha_token(settings: &Settings) -> String {
+    settings
+        .get_str(HA_TOKEN_KEY)
+        .unwrap_or_else(|| HA_AUTH.to_string())
+}
 
 pub enum SocketCmd {
     Reconnect,
     SendString(String),
-    SendJson(JsonValue),
+    SendJson(Value),
+    // subscribe to state changes of the entities (after auth)
+    Subscribe,
+    // HA accepted the token, commands queued meanwhile can be sent
+    Authenticated,
+    // a physical button event, published as a device trigger over MQTT
+    Button(ButtonEvent),
 }
 
-fn js(s: &str) -> JsonValue {
-    JsonValue::String(s.into())
+// commands kept while the websocket is down, so button presses during a
+// reconnect aren't lost. Older ones have already been reported as having
+// no reply so are dropped
+const QUEUE_LIMIT: usize = 16;
+
+// the id of supported_features, which has to come before the queued
+// commands but HA wants the ids to increase. next_message_id starts higher
+const FEATURES_ID: i64 = 1;
+
+// the HA instance a websocket connects to
+pub struct HaTarget {
+    // None for the default instance
+    pub instance: Option<String>,
+    pub url: &'static str,
+    pub token: String,
+    // the NVS key of a token that replaces `token`
+    pub token_key: String,
 }
 
-pub fn handle_websocket(
-    has_wifi: &AtomicBool,
-    socket_tx: Sender<SocketCmd>,
-    socket_rx: Receiver<SocketCmd>,
-    ha_tx: XBSender<Arc<JsonValue>>,
-    auth_token: &'static str,
-    ha_url: &'static str,
-) -> Result<()> {
-    // wait until there's a wifi stack
-    while !has_wifi.load(Ordering::Relaxed) {
+// what the thread talking to HA gets, whichever way it talks to it
+// (the websocket, MQTT or webhooks)
+pub struct SocketCtx {
+    pub has_network: &'static AtomicBool,
+    // the thread's own commands, for its callbacks to send
+    pub socket_tx: Sender<SocketCmd>,
+    pub socket_rx: Receiver<SocketCmd>,
+    // where what HA sends goes
+    pub ha_tx: XBSender<FromHa>,
+    pub ha: HaConfig,
+    pub status: &'static Status,
+    // the entities whose states are wanted
+    pub entities: Vec<String>,
+}
+
+pub fn handle_websocket(ctx: SocketCtx, target: HaTarget, settings: Settings) -> Result<()> {
+    let SocketCtx {
+        has_network,
+        socket_tx,
+        socket_rx,
+        ha_tx,
+        ha,
+        status,
+        entities,
+    } = ctx;
+    let watch = TaskWatch::new("websocket");
+    // wait until there's a network stack
+    while !has_network.load(Ordering::Relaxed) {
+        watch.feed();
         std::thread::sleep(Duration::from_millis(50));
     }
 
-    let socket_to_me = move |info: & Result<WebSocketEvent<'_>, EspIOError>| {
-        let auth_okay = js("auth_ok");
+    // when we last heard from HA, shared with the socket callback so a
+    // half-open connection can be detected
+    let last_seen = Arc::new(Mutex::new(Instant::now()));
+    let cb_last_seen = last_seen.clone();
 
-        match info {
-            Err(e) => {
-                info!("Web socket error {:?}", e);
-                socket_tx.send(SocketCmd::Reconnect).unwrap();
-            }
-            Ok(WebSocketEvent {
-                event_type: WebSocketEventType::Connected,
-                ..
-            }) => {
-                socket_tx
-                    .send(SocketCmd::SendJson(
-                        object! {type: "auth", access_token: auth_token},
-                    ))
-                    .unwrap();
-            }
-            Ok(WebSocketEvent {
-                event_type: WebSocketEventType::Disconnected,
-                ..
-            }) => {
-                socket_tx.send(SocketCmd::Reconnect).unwrap();
+    // the access token, re-read before each connection so a new one
+    // saved in NVS gets used
+    let token = Arc::new(Mutex::new(String::new()));
+    let cb_token = token.clone();
+
+    // text frames too big for the socket buffer arrive in pieces
+    let frames = Arc::new(Mutex::new(FrameAssembler::default()));
+    let cb_frames = frames.clone();
+
+    let ha_url = target.url;
+    let instance = target.instance.clone();
+
+    // state changes of other entities are dropped before they're queued
+    let wanted = entities.clone();
+
+    let socket_to_me = move |info: &Result<WebSocketEvent<'_>, EspIOError>| match info {
+        Err(e) => {
+            info!("Web socket error {:?}", e);
+            socket_tx.send(SocketCmd::Reconnect).unwrap();
+        }
+        Ok(WebSocketEvent {
+            event_type: WebSocketEventType::Disconnected,
+            ..
+        }) => {
+            socket_tx.send(SocketCmd::Reconnect).unwrap();
+        }
+        Ok(WebSocketEvent {
+            event_type: WebSocketEventType::Text(data),
+            ..
+        }) => {
+            if let Ok(mut seen) = cb_last_seen.lock() {
+                *seen = Instant::now();
             }
-            Ok(WebSocketEvent {
-                event_type: WebSocketEventType::Text(data),
-                ..
-            }) => {
-                match json::parse(data) {
-                    Ok(json) => {
-                        if json["type"] == auth_okay {
-                            socket_tx
-                                .send(SocketCmd::SendJson(object! {
-                                 id: 42,
-                                type: "subscribe_events"}))
-                                .unwrap();
-                        } else {
-                            ha_tx.send(Arc::new(json)).unwrap();
-                        }
-                    }
-                    Err(_e) => {
-                        // info!("Failed to parse JSON {}", e);
-                    }
-                }
+            status.traffic.ws_received(data.len());
+            let messages = cb_frames
+                .lock()
+                .ok()
+                .and_then(|mut f| f.push(data, &status.ws_truncated))
+                .unwrap_or_default();
+            for msg in messages {
+                on_message(
+                    msg, &instance, &socket_tx, &ha_tx, &cb_token, &wanted, status,
+                );
             }
-
-            _ => {}
         }
+
+        _ => {}
     };
 
+    let ping_interval = Duration::from_secs(ha.ping_interval);
+    let stall_timeout = Duration::from_secs(ha.stall_timeout);
+    let mut last_ping = Instant::now();
+
+    // consecutive connections rejected by HA, for the backoff
+    let mut auth_failures: u32 = 0;
+
+    // commands waiting for the connection to be authenticated
+    let mut queued: VecDeque<(Instant, Value)> = VecDeque::new();
+
     let mut socket_client: Option<EspWebSocketClient> = None;
     loop {
+        watch.feed();
         match &socket_client {
             None => {
+                if status.instance_auth_failed(target.instance.as_deref()) {
+                    // don't hammer HA with a bad token (it bans IPs after
+                    // too many failed logins), the token may be updated meanwhile
+                    let wait = Duration::from_secs((30 << auth_failures.min(5)).min(600));
+                    auth_failures += 1;
+                    info!("Retrying HA auth in {:?}", wait);
+                    watch.sleep(wait);
+                } else {
+                    auth_failures = 0;
+                }
+                // an OAuth access token (the default instance's) wins over one
+                // saved in NVS, which wins over the built in one
+                let oauth_token = match target.instance {
+                    None => status.access_token.lock().ok().and_then(|t| t.clone()),
+                    Some(_) => None,
+                };
+                if let Ok(mut t) = token.lock() {
+                    *t = oauth_token.unwrap_or_else(|| {
+                        settings
+                            .get_str(&target.token_key)
+                            .unwrap_or_else(|| target.token.clone())
+                    });
+                }
                 info!("Connecting to web socket at {}", ha_url);
+                // through the proxy forwarder the URL's host is 127.0.0.1, the
+                // certificate has to be for the host behind it
+                let common_name = tunnelled_host(ha_url);
                 let mut config = EspWebSocketClientConfig::default();
-                config.buffer_size = 2048;
+                config.buffer_size = ha.buffer_size.clamp(MIN_BUFFER, MAX_BUFFER);
+                if ha.tls {
+                    // the TLS handshake needs more stack than the default
+                    config.task_stack = 6144;
+                    config.common_name = common_name.as_deref();
+                    if ha.ca_cert.is_some() {
+                        config.use_global_ca_store = true;
+                    } else {
+                        config.crt_bundle_attach = Some(esp_idf_sys::esp_crt_bundle_attach);
+                    }
+                }
                 let tmp_socket_client = EspWebSocketClient::new(
-                    &format!("ws://{}/api/websocket", ha_url),
+                    &ha.websocket_url(ha_url),
                     &config,
                     Duration::from_secs(35),
                     socket_to_me.clone(),
                 )
                 .ok();
                 socket_client = tmp_socket_client;
+                status.set_instance_connected(target.instance.as_deref(), false);
+                if let Ok(mut f) = frames.lock() {
+                    *f = FrameAssembler::default();
+                }
+                if let Ok(mut seen) = last_seen.lock() {
+                    *seen = Instant::now();
+                }
+                last_ping = Instant::now();
                 if socket_client.is_none() {
                     // if we didn't get a socket, wait...
                     std::thread::sleep(Duration::from_millis(250));
@@ -129,189 +253,454 @@ pub fn handle_websocket(
         }
 
         if socket_client.is_some() {
-            match socket_rx.recv() {
+            match socket_rx.recv_timeout(Duration::from_secs(1)) {
+                Err(RecvTimeoutError::Timeout) => {
+                    let quiet = last_seen
+                        .lock()
+                        .map(|seen| seen.elapsed())
+                        .unwrap_or_default();
+                    if quiet > stall_timeout {
+                        // nothing (not even a pong) for too long, the connection is dead
+                        info!("Web socket stalled for {:?}, reconnecting", quiet);
+                        socket_client = None;
+                    } else if status.instance_connected(target.instance.as_deref())
+                        && last_ping.elapsed() > ping_interval
+                    {
+                        last_ping = Instant::now();
+                        let ping = json!({"id": next_message_id(), "type": "ping"});
+                        send_text(&mut socket_client, &ping.to_string(), status);
+                    }
+                }
                 Err(e) => {
                     info!("Socket error {:?}", e);
-                    bail!("Socket Error {:?}", e); // the socket has been closed
+                    // the socket has been closed
+                    return Err(Error::Socket(format!("Socket Error {:?}", e)));
                 }
-                Ok(SocketCmd::Reconnect) => socket_client = None,
-                Ok(SocketCmd::SendString(str)) => match &mut socket_client {
-                    Some(e) => {
-                        match e.send(FrameType::Text(false), str.as_bytes()) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                info!("Socket send error {:?}", e);
-                                socket_client = None;
-                            }
-                        };
+                Ok(SocketCmd::Reconnect) => {
+                    status.ws_reconnects.fetch_add(1, Ordering::Relaxed);
+                    socket_client = None;
+                }
+                Ok(SocketCmd::SendString(str)) => send_text(&mut socket_client, &str, status),
+                // the auth message is the only one without an id
+                Ok(SocketCmd::SendJson(json))
+                    if !status.instance_connected(target.instance.as_deref())
+                        && json.get("id").is_some() =>
+                {
+                    if queued.len() == QUEUE_LIMIT {
+                        queued.pop_front();
                     }
-                    None => {}
-                },
-                Ok(SocketCmd::SendJson(json)) => match &mut socket_client {
-                    Some(e) => {
-                        match e.send(FrameType::Text(false), json.to_string().as_bytes()) {
-                            Ok(_) => {}
-                            Err(e) => {
-                                info!("Socket send error {:?}", e);
-                                socket_client = None;
-                            }
-                        };
+                    queued.push_back((Instant::now(), json));
+                }
+                Ok(SocketCmd::SendJson(json)) => {
+                    send_text(&mut socket_client, &json.to_string(), status)
+                }
+                Ok(SocketCmd::Authenticated) => {
+                    // has to be the first message after auth
+                    let features = json!({
+                        "id": FEATURES_ID,
+                        "type": "supported_features",
+                        "features": {"coalesce_messages": 1},
+                    });
+                    send_text(&mut socket_client, &features.to_string(), status);
+                    for (at, json) in queued.drain(..) {
+                        if at.elapsed() < RESULT_TIMEOUT {
+                            info!("Sending queued {}", json["type"]);
+                            send_text(&mut socket_client, &json.to_string(), status);
+                        }
+                    }
+                    // commands can go straight out from now on
+                    if socket_client.is_some() {
+                        status.set_instance_connected(target.instance.as_deref(), true);
                     }
-                    None => {}
+                }
+                Ok(SocketCmd::Subscribe) => subscribe(&mut socket_client, &entities, status),
+                Ok(SocketCmd::Button(_)) => {}
+            }
+        }
+    }
+}
+
+// limits on the configured socket buffer, it comes out of the heap
+const MIN_BUFFER: usize = 512;
+const MAX_BUFFER: usize = 16 * 1024;
+
+// deal with a message from HA
+fn on_message(
+    msg: HaMessage,
+    instance: &Option<String>,
+    socket_tx: &Sender<SocketCmd>,
+    ha_tx: &XBSender<FromHa>,
+    token: &Mutex<String>,
+    wanted: &[String],
+    status: &Status,
+) {
+    match msg {
+        // nothing to do, just proof of life
+        HaMessage::Pong => {}
+        HaMessage::AuthRequired => {
+            let access_token = token.lock().map(|t| t.clone()).unwrap_or_default();
+            socket_tx
+                .send(SocketCmd::SendJson(
+                    json!({"type": "auth", "access_token": access_token}),
+                ))
+                .unwrap();
+        }
+        HaMessage::AuthInvalid { message } => {
+            info!("HA auth failed: {}", message);
+            status.set_instance_auth_failed(instance.as_deref(), true);
+            socket_tx.send(SocketCmd::Reconnect).unwrap();
+        }
+        HaMessage::AuthOk => {
+            status.set_instance_auth_failed(instance.as_deref(), false);
+            // connected once supported_features has gone, when this is handled
+            socket_tx.send(SocketCmd::Authenticated).unwrap();
+            socket_tx.send(SocketCmd::Subscribe).unwrap();
+            // the current states, for the initial display and
+            // to catch up on changes missed while disconnected
+            socket_tx
+                .send(SocketCmd::SendJson(json!({
+                    "id": next_message_id(),
+                    "type": "get_states",
+                })))
+                .unwrap();
+            // main subscribes the templates on each connection
+            queue_for_main(
+                ha_tx,
+                FromHa {
+                    instance: instance.clone(),
+                    msg: HaMessage::AuthOk,
                 },
+                status,
+            );
+        }
+        HaMessage::Event { ref event, .. }
+            if event
+                .state_changed()
+                .map_or(false, |c| !wanted.contains(&c.entity_id)) => {}
+        msg => {
+            queue_for_main(
+                ha_tx,
+                FromHa {
+                    instance: instance.clone(),
+                    msg,
+                },
+                status,
+            );
+        }
+    }
+}
+
+// pass a message on towards the main loop. If it's falling behind (e.g.
+// an event storm while HA starts) state changes are dropped and counted
+// rather than blocking the socket thread. Anything else (auth, the
+// get_states snapshot, results) waits for room, losing it would leave
+// the screen stale or a command without a reply. Returns false if the
+// receiving end has gone
+pub fn queue_for_main(ha_tx: &XBSender<FromHa>, msg: FromHa, status: &Status) -> bool {
+    let sheddable = match &msg.msg {
+        HaMessage::Event { event, .. } => event.state_changed().is_some(),
+        _ => false,
+    };
+    if !sheddable {
+        if ha_tx.send(msg).is_err() {
+            info!("The HA message receiver has gone");
+            return false;
+        }
+        return true;
+    }
+    match ha_tx.try_send(msg) {
+        Ok(_) => true,
+        Err(TrySendError::Full(_)) => {
+            let dropped = status.ha_dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            // don't flood the log during a storm
+            if dropped.is_power_of_two() {
+                info!("HA messages are backing up, {} dropped", dropped);
             }
+            true
+        }
+        Err(TrySendError::Disconnected(_)) => {
+            info!("The HA message receiver has gone");
+            false
         }
     }
 }
 
-fn wifi(
+// send a text frame, dropping the client (so it reconnects) if that fails
+fn send_text(socket_client: &mut Option<EspWebSocketClient>, text: &str, status: &Status) {
+    if let Some(e) = socket_client {
+        match e.send(FrameType::Text(false), text.as_bytes()) {
+            Ok(_) => status.traffic.ws_sent(text.len()),
+            Err(e) => {
+                info!("Socket send error {:?}", e);
+                *socket_client = None;
+            }
+        }
+    }
+}
+
+// have HA only send state changes for the entities in the config,
+// rather than every event on the bus
+fn subscribe(socket_client: &mut Option<EspWebSocketClient>, entities: &[String], status: &Status) {
+    if entities.is_empty() {
+        return;
+    }
+    let msg = json!({
+        "id": next_message_id(),
+        "type": "subscribe_trigger",
+        "trigger": {
+            "platform": "state",
+            "entity_id": entities,
+        },
+    });
+    send_text(socket_client, &msg.to_string(), status);
+}
+
+pub(crate) fn wifi(
     ssid: &'static str,
     password: &'static str,
-    has_wifi: &AtomicBool,
-    last_quad: &AtomicI32,
-
+    net: &NetConfig,
+    hostname: &str,
     modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
     sysloop: EspSystemEventLoop,
 ) -> Result<Box<EspWifi<'static>>> {
-    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), None)?;
+    let driver = WifiDriver::new(modem, sysloop.clone(), None).map_err(Error::wifi)?;
+    let mut esp_wifi = EspWifi::wrap_all(
+        driver,
+        client_netif(net, hostname, NetifConfiguration::wifi_default_client())?,
+        EspNetif::new(NetifStack::Ap).map_err(Error::wifi)?,
+    )
+    .map_err(Error::wifi)?;
+
+    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop).map_err(Error::wifi)?;
+
+    wifi.start().map_err(Error::wifi)?;
+
+    let bssid = net.bssid()?;
+
+    match &net.enterprise {
+        None => wifi
+            .set_configuration(&Configuration::Client(ClientConfiguration {
+                ssid: ssid.into(),
+                password: password.into(),
+                bssid,
+
+                ..Default::default()
+            }))
+            .map_err(Error::wifi)?,
+        Some(eap) => {
+            wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+                ssid: ssid.into(),
+                auth_method: AuthMethod::WPA2Enterprise,
+                bssid,
+
+                ..Default::default()
+            }))
+            .map_err(Error::wifi)?;
+            enable_enterprise(eap)?;
+        }
+    }
 
-    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
+    set_roaming(net)?;
+    set_power_save(net.power_save)?;
 
-    wifi.start()?;
+    wifi.connect().map_err(Error::wifi)?;
 
-    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: ssid.into(),
-        password: password.into(),
+    wifi.wait_netif_up().map_err(Error::wifi)?;
 
-        ..Default::default()
-    }))?;
+    Ok(Box::new(esp_wifi))
+}
 
-    wifi.connect()?;
+// settings ClientConfiguration doesn't cover: always connect to the
+// strongest AP for the SSID (rather than the first one found) and
+// optionally enable 802.11k/v so the AP can steer us
+fn set_roaming(net: &NetConfig) -> Result<()> {
+    use esp_idf_sys::*;
+
+    unsafe {
+        let mut cfg: wifi_config_t = core::mem::zeroed();
+        esp!(esp_wifi_get_config(wifi_interface_t_WIFI_IF_STA, &mut cfg))?;
+
+        cfg.sta.scan_method = wifi_scan_method_t_WIFI_ALL_CHANNEL_SCAN;
+        cfg.sta.sort_method = wifi_sort_method_t_WIFI_CONNECT_AP_BY_SIGNAL;
+        if net.roam_11kv {
+            cfg.sta.set_rm_enabled(1);
+            cfg.sta.set_btm_enabled(1);
+        }
 
-    wifi.wait_netif_up()?;
+        esp!(esp_wifi_set_config(wifi_interface_t_WIFI_IF_STA, &mut cfg))?;
+    }
 
-    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+    Ok(())
+}
 
-    last_quad.store((ip_info.ip.octets()[3]) as i32, Ordering::Relaxed);
+// drop the current AP and associate again, picking the strongest AP
+pub(crate) fn roam() -> Result<()> {
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_disconnect() })?;
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_connect() })?;
+    Ok(())
+}
 
-    has_wifi.store(true, Ordering::Relaxed);
+fn set_power_save(mode: PowerSave) -> Result<()> {
+    use esp_idf_sys::*;
 
-    info!(
-        "Wifi DHCP info: {:?} quad {}",
-        ip_info,
-        last_quad.load(Ordering::Relaxed)
-    );
+    let ps = match mode {
+        PowerSave::None => wifi_ps_type_t_WIFI_PS_NONE,
+        PowerSave::Min => wifi_ps_type_t_WIFI_PS_MIN_MODEM,
+        PowerSave::Max => wifi_ps_type_t_WIFI_PS_MAX_MODEM,
+    };
+    esp!(unsafe { esp_wifi_set_ps(ps) })?;
 
-    Ok(Box::new(esp_wifi))
+    info!("WiFi power save {}", mode.as_str());
+    Ok(())
 }
 
-pub fn create_wifi(
-    ssid: &'static str,
-    password: &'static str,
-    has_wifi: &AtomicBool,
-    last_quad: &AtomicI32,
-    display_tx: Sender<DrawCmd>,
-    modem: Modem,
-    sysloop: EspEventLoop<System>,
-    has_time: &AtomicBool,
-) -> Result<()> {
-    // display a message while searching for WiFi
-    display_tx.send(DrawCmd::Text {
-        pos: DrawPos::Pos(Point::new(10, 20)),
-        font: Some(PROFONT_24_POINT),
-        text: "Looking for WiFi".into(),
-        text_color: RgbColor::BLACK,
-        background: Some(RgbColor::WHITE),
-    })?;
-    let wifi = wifi(ssid, password, has_wifi, last_quad, modem, sysloop)?;
-    let ip_info = wifi.sta_netif().get_ip_info()?;
-
-    // clear the message area
-    display_tx.send(DrawCmd::Clear {
-        color: RgbColor::WHITE,
-        pos: DrawPos::Box(Rectangle::new(Point::new(0, 0), Size::new(400, 30))),
-    })?;
-
-    // display a message with the IP address while waiting for SNTP
-    display_tx.send(DrawCmd::Text {
-        pos: DrawPos::Pos(Point::new(10, 22)),
-        font: None,
-        text: format!("IP Addr {}, SNTP init", ip_info.ip),
-        text_color: RgbColor::BLACK,
-        background: Some(RgbColor::WHITE),
-    })?;
-    let mut sntp_reset_cnt = 0;
-
-    let _sntp = sntp::EspSntp::new_default()?;
-
-    info!("SNTP initialized");
-
-    let mut not_sync = true;
-    loop {
-        std::thread::sleep(Duration::from_secs(7));
-        if not_sync {
-            let status: SyncStatus = _sntp.get_sync_status();
-            match status {
-                SyncStatus::Completed => {
-                    has_time.store(true, Ordering::Relaxed);
-                    not_sync = false;
-                }
-                SyncStatus::InProgress => {
-                    info!("Sync in progress");
-                }
-                SyncStatus::Reset => {
-                    info!("SNTP reset");
-                    sntp_reset_cnt += 1;
-                    // if we're struggling to get the SNTP stuff set up
-                    // after 700 seconds (> 10 minutes), reset the box
-                    if sntp_reset_cnt > 100 {
-                        esp_idf_hal::reset::restart();
-                    }
-                }
-            }
+// set the WPA2-Enterprise credentials. Must be done after the WiFi driver
+// is started and before connecting
+fn enable_enterprise(eap: &EnterpriseConfig) -> Result<()> {
+    use esp_idf_sys::*;
+
+    info!("Using WPA2-Enterprise identity {}", eap.identity);
+
+    unsafe {
+        esp!(esp_wifi_sta_wpa2_ent_set_identity(
+            eap.identity.as_ptr(),
+            eap.identity.len() as i32
+        ))?;
+        esp!(esp_wifi_sta_wpa2_ent_set_username(
+            eap.username.as_ptr(),
+            eap.username.len() as i32
+        ))?;
+        esp!(esp_wifi_sta_wpa2_ent_set_password(
+            eap.password.as_ptr(),
+            eap.password.len() as i32
+        ))?;
+
+        if let Some(name) = &eap.ca_cert {
+            // the driver keeps a pointer to the cert (rather than copying it)
+            // so it has to live forever. It also has to be null terminated
+            let mut pem = read_file(name)?.into_bytes();
+            pem.push(0);
+            let pem: &'static [u8] = Box::leak(pem.into_boxed_slice());
+            esp!(esp_wifi_sta_wpa2_ent_set_ca_cert(
+                pem.as_ptr(),
+                pem.len() as i32
+            ))?;
+        }
+
+        if let Some(phase2) = eap.ttls_phase2 {
+            let method = match phase2 {
+                TtlsPhase2::Eap => esp_eap_ttls_phase2_types_ESP_EAP_TTLS_PHASE2_EAP,
+                TtlsPhase2::Mschapv2 => esp_eap_ttls_phase2_types_ESP_EAP_TTLS_PHASE2_MSCHAPV2,
+                TtlsPhase2::Mschap => esp_eap_ttls_phase2_types_ESP_EAP_TTLS_PHASE2_MSCHAP,
+                TtlsPhase2::Pap => esp_eap_ttls_phase2_types_ESP_EAP_TTLS_PHASE2_PAP,
+                TtlsPhase2::Chap => esp_eap_ttls_phase2_types_ESP_EAP_TTLS_PHASE2_CHAP,
+            };
+            esp!(esp_wifi_sta_wpa2_ent_set_ttls_phase2_method(method))?;
         }
+
+        esp!(esp_wifi_sta_wpa2_ent_enable())?;
     }
+
+    Ok(())
 }
 
-// make a REST request on Home Assistant's API to get the state of
-// a particular item
-pub fn get_ha_state(item: &str, ha_url: &str, ha_headers: &[(&str, &str)]) -> Result<JsonValue> {
-    use embedded_svc::http::client::*;
-    use embedded_svc::utils::io;
-    use esp_idf_svc::http::client::*;
+// the AP we're connected to and its signal strength, None if
+// we're not associated
+pub(crate) fn ap_info() -> Option<(ApInfo, i32)> {
+    let mut rec: esp_idf_sys::wifi_ap_record_t = Default::default();
+    let ret = unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut rec) };
+    if ret != esp_idf_sys::ESP_OK {
+        return None;
+    }
 
-    let mut client = Client::wrap(EspHttpConnection::new(&Configuration {
-        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+    let ssid_len = rec
+        .ssid
+        .iter()
+        .position(|c| *c == 0)
+        .unwrap_or(rec.ssid.len());
+    Some((
+        ApInfo {
+            ssid: String::from_utf8_lossy(&rec.ssid[..ssid_len]).into(),
+            bssid: rec.bssid,
+            channel: rec.primary,
+        },
+        rec.rssi as i32,
+    ))
+}
 
-        ..Default::default()
-    })?);
+// ask the driver to associate again after losing the AP
+pub(crate) fn reconnect() -> Result<()> {
+    esp_idf_sys::esp!(unsafe { esp_idf_sys::esp_wifi_connect() })?;
+    Ok(())
+}
 
-    let full_url = format!("http://{}/api/states/{}", ha_url, item);
+// load a CA certificate (PEM) from SPIFFS into the global CA store
+// used by the websocket and HTTP clients
+pub fn install_ca_cert(name: &str) -> Result<()> {
+    let mut pem = read_file(name)?.into_bytes();
+    pem.push(0);
+
+    unsafe {
+        esp_idf_sys::esp!(esp_idf_sys::esp_tls_set_global_ca_store(
+            pem.as_ptr(),
+            pem.len() as u32
+        ))?;
+    }
 
-    let mut response = client
-        .request(Method::Get, &full_url, ha_headers)?
-        .submit()?;
+    info!("Installed CA certificate {}", name);
+    Ok(())
+}
 
-    if response.status() != 200 {
-        bail!(format!(
-            "Request for {} yielded {}",
-            item,
-            response.status()
-        ));
-    }
+// set the clock from the Date header of a response from Home Assistant's API,
+// for networks where SNTP is blocked
+pub fn set_time_from_ha(
+    ha_url: &str,
+    ha_headers: &[(&str, &str)],
+    ha: &HaConfig,
+    status: &Status,
+) -> Result<()> {
+    let response = ha_get(&ha.rest_url(ha_url, ""), ha_headers, ha, status)?;
 
-    let mut source: Vec<u8> = vec![];
-    let mut body = [0_u8; 512];
+    let date = match &response.date {
+        Some(d) => chrono::DateTime::parse_from_rfc2822(d).map_err(Error::socket)?,
+        None => return Err(Error::Socket("No Date header from HA".into())),
+    };
 
-    loop {
-        let read = io::try_read_full(&mut response, &mut body).map_err(|err| err.0)?;
-        if read == 0 {
-            break;
-        }
-        source.extend_from_slice(&body[0..read]);
+    set_clock(date);
+    Ok(())
+}
+
+pub fn set_clock(date: chrono::DateTime<chrono::FixedOffset>) {
+    let tv = esp_idf_sys::timeval {
+        tv_sec: date.timestamp() as _,
+        tv_usec: 0,
+    };
+    unsafe {
+        esp_idf_sys::settimeofday(&tv, std::ptr::null());
     }
 
-    let json = json::parse(&String::from_utf8_lossy(&source))?;
+    info!("Set the time from HA to {}", date);
+}
+
+// make a REST request on Home Assistant's API to get the state of
+// a particular item
+pub fn get_ha_state(
+    item: &str,
+    ha_url: &str,
+    ha_headers: &[(&str, &str)],
+    ha: &HaConfig,
+    status: &Status,
+) -> Result<EntityState> {
+    let full_url = ha.rest_url(ha_url, &format!("states/{}", item));
+
+    let response = ha_get(&full_url, ha_headers, ha, status)?;
+
+    if response.status != 200 {
+        return Err(Error::Socket(format!(
+            "Request for {} yielded {}",
+            item, response.status
+        )));
+    }
 
-    Ok(json)
+    Ok(serde_json::from_slice(&response.body)?)
 }
@@ -0,0 +1,82 @@
+use std::{
+    ffi::CString,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use esp_idf_sys::{esp, wireguard::*, ESP_OK};
+use log::*;
+
+use crate::{config::WireguardConfig, Error, Result};
+
+// the strings and context handed to esp_wireguard, which keeps pointers
+// to them for as long as the tunnel is up
+struct Tunnel {
+    _strings: Vec<CString>,
+    _config: Box<wireguard_config_t>,
+    ctx: Box<wireguard_ctx_t>,
+}
+
+fn start(wg: &WireguardConfig) -> Result<Tunnel> {
+    let mut strings = vec![];
+    let mut c_str = |s: &str| -> Result<*mut std::ffi::c_char> {
+        let c = CString::new(s).map_err(Error::config)?;
+        let ptr = c.as_ptr() as *mut _;
+        strings.push(c);
+        Ok(ptr)
+    };
+
+    let mut config = Box::new(wireguard_config_t {
+        private_key: c_str(&wg.private_key)?,
+        public_key: c_str(&wg.peer_public_key)?,
+        preshared_key: match &wg.preshared_key {
+            Some(psk) => c_str(psk)?,
+            None => std::ptr::null_mut(),
+        },
+        allowed_ip: c_str(&wg.address.to_string())?,
+        allowed_ip_mask: c_str(&wg.netmask.to_string())?,
+        endpoint: c_str(&wg.endpoint)?,
+        port: wg.port as _,
+        persistent_keepalive: wg.keepalive as _,
+        ..unsafe { std::mem::zeroed() }
+    });
+
+    let mut ctx: Box<wireguard_ctx_t> = Box::new(unsafe { std::mem::zeroed() });
+
+    unsafe {
+        esp!(esp_wireguard_init(config.as_mut(), ctx.as_mut()))?;
+        esp!(esp_wireguard_connect(ctx.as_mut()))?;
+    }
+
+    Ok(Tunnel {
+        _strings: strings,
+        _config: config,
+        ctx,
+    })
+}
+
+// bring up the tunnel once the network is up and the clock is set (the
+// handshake needs the time), then keep an eye on the peer
+pub fn run_wireguard(
+    wg: WireguardConfig,
+    has_network: &AtomicBool,
+    has_time: &AtomicBool,
+) -> Result<()> {
+    while !has_network.load(Ordering::Relaxed) || !has_time.load(Ordering::Relaxed) {
+        std::thread::sleep(Duration::from_secs(1));
+    }
+
+    info!("Starting WireGuard to {}:{}", wg.endpoint, wg.port);
+    let mut tunnel = start(&wg)?;
+
+    let mut was_up = false;
+    loop {
+        std::thread::sleep(Duration::from_secs(10));
+
+        let up = unsafe { esp_wireguardif_peer_is_up(tunnel.ctx.as_mut()) } == ESP_OK;
+        if up != was_up {
+            info!("WireGuard peer is {}", if up { "up" } else { "down" });
+            was_up = up;
+        }
+    }
+}